@@ -0,0 +1,175 @@
+/* # Micro-benchmarks: is this patch slower than the last one?
+cargo bench --bench micro                   => all of them
+cargo bench --bench micro -- http_parse     => only those whose name contains "http_parse"
+Run it before and after a change and compare the numbers.
+- threadpool_throughput: 1000 no-op jobs through a ThreadPool of 1, 2, 4 and 8 workers.
+- http_parse: read_request on a GET of about 200 bytes, and on bigger ones (more headers).
+- response_write: ResponseBuilder serializing an HTML page of 1, 10 and 100 KB.
+- static_file: StaticFileHandler serving a 1, 10 and 100 KB file over a loopback
+connection (the client side only reads and throws the bytes away).
+No benchmarking crate: like work_stealing.rs, std::time::Instant and the best of a few runs. */
+
+use std::hint::black_box;
+use std::io::{self, Cursor};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use surff::http::{read_request, Request, RequestLimits, ResponseBuilder, StatusCode};
+use surff::router::Router;
+use surff::static_files::StaticFileHandler;
+use surff::ThreadPool;
+
+const RUNS: usize = 5;
+const POOL_JOBS: usize = 1000;
+
+fn best_of(runs: usize, mut f: impl FnMut() -> Duration) -> Duration {
+    (0..runs).map(|_| f()).min().unwrap_or_default()
+}
+
+// One line per parameter: how long one iteration took, and how many that makes a second.
+fn report(name: &str, parameter: &str, iterations: usize, elapsed: Duration) {
+    let per_iteration = elapsed / iterations as u32;
+    println!(
+        "{:<22} {:>10} {:>12.2?} {:>14.0}/s",
+        name,
+        parameter,
+        per_iteration,
+        iterations as f64 / elapsed.as_secs_f64(),
+    );
+}
+
+fn bench_threadpool_throughput() {
+    for workers in [1, 2, 4, 8] {
+        let pool = ThreadPool::new(workers).expect("failed to start the pool");
+        let elapsed = best_of(RUNS, || {
+            let (done, finished) = mpsc::channel();
+            let started = Instant::now();
+            for _ in 0..POOL_JOBS {
+                let done = done.clone();
+                pool.execute(move || {
+                    let _ = done.send(());
+                }).expect("job rejected");
+            }
+            for _ in 0..POOL_JOBS {
+                finished.recv().expect("a job went missing");
+            }
+            started.elapsed()
+        });
+        report("threadpool_throughput", &format!("{} thr", workers), POOL_JOBS, elapsed);
+    }
+}
+
+// A browser-like GET with `extra_headers` more headers than the usual ones.
+fn get_request(extra_headers: usize) -> Vec<u8> {
+    let mut request = String::from(
+        "GET /static/css/site.css HTTP/1.1\r\nHost: localhost:1998\r\nUser-Agent: Mozilla/5.0 (X11; Linux x86_64)\r\n\
+         Accept: text/css,*/*;q=0.1\r\nAccept-Encoding: gzip, deflate\r\nConnection: keep-alive\r\n",
+    );
+    for i in 0..extra_headers {
+        request.push_str(&format!("X-Extra-{}: {}\r\n", i, "v".repeat(40)));
+    }
+    request.push_str("\r\n");
+    request.into_bytes()
+}
+
+fn bench_http_parse() {
+    let limits = RequestLimits::default();
+    for extra_headers in [0, 10, 50] {
+        let raw = get_request(extra_headers);
+        let iterations = 20_000;
+        let elapsed = best_of(RUNS, || {
+            let started = Instant::now();
+            for _ in 0..iterations {
+                let request = read_request(&mut Cursor::new(black_box(&raw[..])), &limits).expect("a valid request");
+                black_box(request);
+            }
+            started.elapsed()
+        });
+        report("http_parse", &format!("{} B", raw.len()), iterations, elapsed);
+    }
+}
+
+fn bench_response_write() {
+    for kilobytes in [1, 10, 100] {
+        let html = format!("<!DOCTYPE html><html><body>{}</body></html>", "<p>hi</p>".repeat(kilobytes * 1024 / 9));
+        let iterations = 20_000 / kilobytes;
+        let mut out = Vec::with_capacity(html.len() + 256);
+        let elapsed = best_of(RUNS, || {
+            let started = Instant::now();
+            for _ in 0..iterations {
+                out.clear();
+                ResponseBuilder::new(StatusCode::Ok)
+                    .header("Content-Type", "text/html; charset=utf-8")
+                    .body_str(black_box(&html))
+                    .write_to(&mut out)
+                    .expect("writing to a Vec");
+                black_box(&out);
+            }
+            started.elapsed()
+        });
+        report("response_write", &format!("{} KB", kilobytes), iterations, elapsed);
+    }
+}
+
+fn bench_static_file() -> io::Result<()> {
+    let root = std::env::temp_dir().join(format!("surff-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&root)?;
+
+    for kilobytes in [1, 10, 100] {
+        let name = format!("{}k.bin", kilobytes);
+        std::fs::write(root.join(&name), vec![b'x'; kilobytes * 1024])?;
+
+        let files = StaticFileHandler::new(&root)?.strip_prefix("/static");
+        let mut router = Router::new();
+        router.get("/static", move |request, response| files.handle(request, response));
+
+        // one keep-alive connection; the client thread just reads everything.
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let client = TcpStream::connect(listener.local_addr()?)?;
+        let (mut server, _) = listener.accept()?;
+        let reader = thread::spawn(move || io::copy(&mut &client, &mut io::sink()));
+
+        let request = Request::parse(format!("GET /static/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", name).as_bytes())
+            .expect("a valid request");
+        let iterations = 2_000;
+        let elapsed = best_of(RUNS, || {
+            let started = Instant::now();
+            for _ in 0..iterations {
+                router.dispatch(&request, &mut server).expect("serving the file");
+            }
+            started.elapsed()
+        });
+        report("static_file", &format!("{} KB", kilobytes), iterations, elapsed);
+
+        drop(server);
+        let received = reader.join().expect("the reader panicked")?;
+        // an error page would be a lot faster, and measure the wrong thing.
+        assert!(received >= (iterations * RUNS * kilobytes * 1024) as u64, "the file wasn't served");
+    }
+
+    std::fs::remove_dir_all(&root)
+}
+
+fn main() -> io::Result<()> {
+    // cargo bench passes --bench; everything else that isn't a flag is a filter.
+    let filters: Vec<String> = std::env::args().skip(1).filter(|arg| !arg.starts_with("--")).collect();
+    let selected = |name: &str| filters.is_empty() || filters.iter().any(|filter| name.contains(filter.as_str()));
+
+    println!("best of {} runs", RUNS);
+    println!("{:<22} {:>10} {:>12} {:>16}", "benchmark", "input", "per iter", "throughput");
+    if selected("threadpool_throughput") {
+        bench_threadpool_throughput();
+    }
+    if selected("http_parse") {
+        bench_http_parse();
+    }
+    if selected("response_write") {
+        bench_response_write();
+    }
+    if selected("static_file") {
+        bench_static_file()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+/* # sendfile(2) vs. the read/write loop!
+cargo bench --bench send_file
+Sends the same file over a loopback connection with surff::os::send_file (on Linux the
+kernel copies file => socket itself) and with surff::os::send_file_buffered (file =>
+64 KB buffer in user space => socket), for a small, a medium and a big file.
+A thread on the other end reads everything and throws it away. About 64 MB go over
+the connection per run whatever the size, so the numbers compare; the best of a few
+runs. Off Linux both are the read/write loop, and should come out about the same. */
+
+use std::fs::File;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use surff::os;
+
+const RUNS: usize = 5;
+const BYTES_PER_RUN: usize = 64 * 1024 * 1024;
+
+// (label, file size)
+const SIZES: [(&str, usize); 3] = [
+    ("64 KB", 64 * 1024),
+    ("1 MB", 1024 * 1024),
+    ("16 MB", 16 * 1024 * 1024),
+];
+
+type SendFile = fn(&File, &TcpStream, u64, u64) -> io::Result<u64>;
+
+fn best_of(runs: usize, mut f: impl FnMut() -> io::Result<Duration>) -> io::Result<Duration> {
+    let mut best = Duration::MAX;
+    for _ in 0..runs {
+        best = best.min(f()?);
+    }
+    Ok(best)
+}
+
+fn bench(send: SendFile, file: &File, size: usize) -> io::Result<Duration> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+    let (server, _) = listener.accept()?;
+    let reader = thread::spawn(move || io::copy(&mut &client, &mut io::sink()));
+
+    let times = BYTES_PER_RUN / size;
+    let elapsed = best_of(RUNS, || {
+        let started = Instant::now();
+        for _ in 0..times {
+            let sent = send(file, &server, 0, size as u64)?;
+            if sent != size as u64 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file was cut short"));
+            }
+        }
+        Ok(started.elapsed())
+    })?;
+
+    drop(server);
+    let received = reader.join().expect("the reader panicked")?;
+    assert_eq!(received, (times * size * RUNS) as u64);
+    Ok(elapsed)
+}
+
+fn main() -> io::Result<()> {
+    let dir = std::env::temp_dir().join(format!("surff-send-file-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+
+    println!("{} MB per run, best of {} runs", BYTES_PER_RUN / (1024 * 1024), RUNS);
+    println!("{:<8} {:>14} {:>14} {:>9}", "file", "sendfile", "read/write", "speedup");
+    for (label, size) in SIZES {
+        let path = dir.join(format!("{}.bin", size));
+        std::fs::write(&path, vec![b'x'; size])?;
+        let file = File::open(&path)?;
+
+        let zero_copy = bench(os::send_file, &file, size)?;
+        let buffered = bench(os::send_file_buffered, &file, size)?;
+        let mb_per_second = |elapsed: Duration| (BYTES_PER_RUN / (1024 * 1024)) as f64 / elapsed.as_secs_f64();
+        println!(
+            "{:<8} {:>9.0} MB/s {:>9.0} MB/s {:>8.2}x",
+            label,
+            mb_per_second(zero_copy),
+            mb_per_second(buffered),
+            buffered.as_secs_f64() / zero_copy.as_secs_f64(),
+        );
+    }
+
+    std::fs::remove_dir_all(&dir)
+}
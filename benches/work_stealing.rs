@@ -0,0 +1,123 @@
+/* # Work stealing vs. one shared queue!
+cargo bench --bench work_stealing
+Runs the same batches of jobs through surff::ThreadPool (a queue per worker, work stealing)
+and through SharedQueuePool, a copy of the pool's previous design: one channel
+whose Receiver sits behind a Mutex shared by every worker.
+8 workers each; tiny jobs show the cost of the queue itself, bigger ones how well
+the work is spread. No benchmarking crate: std::time::Instant and the best of a few runs. */
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use surff::ThreadPool;
+
+const WORKERS: usize = 8;
+const RUNS: usize = 5;
+
+// (label, jobs per batch, spin iterations per job)
+const WORKLOADS: [(&str, usize, u64); 4] = [
+    ("empty", 200_000, 0),
+    ("tiny", 100_000, 100),
+    ("small", 20_000, 10_000),
+    ("medium", 2_000, 200_000),
+];
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct SharedQueuePool {
+    sender: Option<mpsc::Sender<Job>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl SharedQueuePool {
+    fn new(size: usize) -> SharedQueuePool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => {
+                            // surff's workers log every job too; keep the comparison fair.
+                            println!("Worker got a job; executing.");
+                            job()
+                        },
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        SharedQueuePool { sender: Some(sender), threads }
+    }
+
+    fn execute(&self, job: Job) {
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+}
+
+impl Drop for SharedQueuePool {
+    fn drop(&mut self) {
+        // closing the channel ends every worker's loop.
+        drop(self.sender.take());
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn spin(iterations: u64) -> u64 {
+    let mut x = 0u64;
+    for i in 0..iterations {
+        x = black_box(x.wrapping_mul(31).wrapping_add(i));
+    }
+    x
+}
+
+// Queues `jobs` jobs with `execute` and waits for the last one to finish.
+fn run_batch(jobs: usize, iterations: u64, execute: &dyn Fn(Job)) -> Duration {
+    let done = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    for _ in 0..jobs {
+        let done = Arc::clone(&done);
+        execute(Box::new(move || {
+            black_box(spin(iterations));
+            done.fetch_add(1, Ordering::Release);
+        }));
+    }
+    while done.load(Ordering::Acquire) < jobs {
+        thread::yield_now();
+    }
+    started.elapsed()
+}
+
+fn best_of(runs: usize, mut f: impl FnMut() -> Duration) -> Duration {
+    (0..runs).map(|_| f()).min().unwrap_or_default()
+}
+
+fn main() {
+    let stealing = ThreadPool::new(WORKERS).expect("failed to start the pool");
+    let shared = SharedQueuePool::new(WORKERS);
+
+    println!("{} workers, best of {} runs", WORKERS, RUNS);
+    println!("{:<8} {:>8} {:>14} {:>14} {:>8}", "jobs", "count", "shared queue", "work stealing", "speedup");
+
+    for (label, jobs, iterations) in WORKLOADS {
+        let shared_time = best_of(RUNS, || run_batch(jobs, iterations, &|job| shared.execute(job)));
+        let stealing_time = best_of(RUNS, || {
+            run_batch(jobs, iterations, &|job| stealing.execute(job).expect("job rejected"))
+        });
+        println!(
+            "{:<8} {:>8} {:>11.1} ms {:>11.1} ms {:>7.2}x",
+            label,
+            jobs,
+            shared_time.as_secs_f64() * 1000.0,
+            stealing_time.as_secs_f64() * 1000.0,
+            shared_time.as_secs_f64() / stealing_time.as_secs_f64(),
+        );
+    }
+}
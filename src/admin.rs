@@ -0,0 +1,253 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::http::{Request, ResponseBuilder, StatusCode};
+use crate::router::{Middleware, Response, Router};
+use crate::server::ServerHandle;
+
+/* # Admin API: running the pool without restarting the server!
+surff --admin-bind 127.0.0.1:1999 --admin-token s3cret
+curl -H "Authorization: Bearer s3cret" 127.0.0.1:1999/admin/pool
+GET    /admin/pool            {"workers": 4, "queue_depth": 12, "jobs_completed": 5000}
+POST   /admin/pool            {"workers": 8} => ThreadPool::resize(8), then the same as GET
+POST   /admin/pool/drain      waits (at most DRAIN_TIMEOUT) for the queued jobs: {"drained": true}
+DELETE /admin/pool/terminate  starts ServerHandle::drain_connections and answers 202 right
+                              away; the process exits once the drain is done.
+Every request needs the token (401 otherwise), and every answer is JSON.
+The router goes on a listener of its own (see main.rs), with a Server of its own:
+user traffic never reaches it, and it still answers when every one of the main
+pool's workers is busy, which is when it's needed most. */
+
+// Unless drain_timeout says otherwise (main.rs passes the one it uses on SIGTERM).
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+// A typo like {"workers": 80000} shouldn't spawn that many threads.
+pub const MAX_ADMIN_WORKERS: usize = 1024;
+
+pub struct AdminApi {
+    handle: ServerHandle,
+    token: String,
+    drain_timeout: Duration,
+}
+
+impl AdminApi {
+    // `handle` is the server being administered (Server::handle).
+    pub fn new(handle: ServerHandle, token: impl Into<String>) -> AdminApi {
+        AdminApi { handle, token: token.into(), drain_timeout: DRAIN_TIMEOUT }
+    }
+
+    // How long an admin-triggered drain waits for the running requests.
+    pub fn drain_timeout(mut self, timeout: Duration) -> AdminApi {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    pub fn into_router(self) -> Router {
+        let mut router = Router::new();
+
+        let handle = self.handle.clone();
+        router.get("/admin/pool", move |_, response| pool_state(&handle, response));
+
+        let handle = self.handle.clone();
+        router.post("/admin/pool", move |request, response| {
+            let workers = match requested_workers(&request.body) {
+                Ok(workers) => workers,
+                Err(message) => return error(response, StatusCode::BadRequest, message),
+            };
+            if let Err(e) = handle.pool().resize(workers) {
+                return error(response, StatusCode::InternalServerError, &e.to_string());
+            }
+            println!("Admin: resized the pool to {} workers.", workers);
+            pool_state(&handle, response)
+        });
+
+        let handle = self.handle.clone();
+        let drain_timeout = self.drain_timeout;
+        router.post("/admin/pool/drain", move |_, response| {
+            let drained = handle.pool().drain(drain_timeout);
+            json(response, StatusCode::Ok, &format!(
+                "{{\"drained\": {}, \"queue_depth\": {}}}",
+                drained,
+                handle.pool().stats().queue_depth(),
+            ))
+        });
+
+        let handle = self.handle;
+        router.delete("/admin/pool/terminate", move |_, response| {
+            println!("Admin: terminating.");
+            json(response, StatusCode::Accepted, "{\"terminating\": true}")?;
+            // drain_connections returns once it's done; the answer shouldn't wait for that.
+            let handle = handle.clone();
+            thread::spawn(move || handle.drain_connections(drain_timeout));
+            Ok(())
+        });
+
+        router.not_found(|_, response| error(response, StatusCode::NotFound, "no such admin endpoint"));
+        router.wrap(BearerToken { token: self.token });
+        router
+    }
+}
+
+// # Authorization: Bearer <token>, or 401 before any handler runs.
+struct BearerToken {
+    token: String,
+}
+
+impl Middleware for BearerToken {
+    fn before(&self, request: &Request, response: &mut Response) -> io::Result<bool> {
+        let presented = request
+            .header("Authorization")
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+            .map(|(_, token)| token.trim());
+        if presented.is_some_and(|token| same_token(token, &self.token)) {
+            return Ok(true);
+        }
+        response.send(
+            ResponseBuilder::new(StatusCode::Unauthorized)
+                .header("WWW-Authenticate", "Bearer")
+                .header("Content-Type", "application/json")
+                .body_str("{\"error\": \"a valid bearer token is required\"}"),
+        )?;
+        Ok(false)
+    }
+}
+
+// Compares every byte whatever the first difference, so the time taken doesn't tell
+// an attacker how much of a guess was right.
+fn same_token(presented: &str, expected: &str) -> bool {
+    let (presented, expected) = (presented.as_bytes(), expected.as_bytes());
+    presented.len() == expected.len()
+        && presented.iter().zip(expected).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+fn pool_state(handle: &ServerHandle, response: &mut Response) -> io::Result<()> {
+    let stats = handle.pool().stats();
+    json(response, StatusCode::Ok, &format!(
+        "{{\"workers\": {}, \"queue_depth\": {}, \"jobs_completed\": {}}}",
+        stats.worker_count(),
+        stats.queue_depth(),
+        stats.total_completed(),
+    ))
+}
+
+// {"workers": 8}, the only body the API takes, so there's no need for a JSON library:
+// one key, a whole number between 1 and MAX_ADMIN_WORKERS.
+fn requested_workers(body: &[u8]) -> Result<usize, &'static str> {
+    const EXPECTED: &str = "expected {\"workers\": <n>}";
+    let body = std::str::from_utf8(body).map_err(|_| EXPECTED)?.trim();
+    let fields = body.strip_prefix('{').and_then(|body| body.strip_suffix('}')).ok_or(EXPECTED)?;
+    let (key, value) = fields.split_once(':').ok_or(EXPECTED)?;
+    if key.trim() != "\"workers\"" {
+        return Err(EXPECTED);
+    }
+    match value.trim().parse::<usize>() {
+        Ok(workers) if (1..=MAX_ADMIN_WORKERS).contains(&workers) => Ok(workers),
+        Ok(_) => Err("workers must be between 1 and MAX_ADMIN_WORKERS (1024)"),
+        Err(_) => Err(EXPECTED),
+    }
+}
+
+fn json(response: &mut Response, status: StatusCode, body: &str) -> io::Result<()> {
+    response.send(
+        ResponseBuilder::new(status)
+            .header("Content-Type", "application/json")
+            .body_str(body),
+    )
+}
+
+fn error(response: &mut Response, status: StatusCode, message: &str) -> io::Result<()> {
+    let escaped = message.replace('\\', "\\\\").replace('"', "\\\"");
+    json(response, status, &format!("{{\"error\": \"{}\"}}", escaped))
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::server::Server;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    fn admin() -> (Router, ServerHandle) {
+        let server = Server::new(&Config { threads: 2, io_threads: 0, ..Config::default() }).unwrap();
+        let handle = server.handle();
+        (AdminApi::new(handle.clone(), "s3cret").drain_timeout(Duration::from_millis(500)).into_router(), handle)
+    }
+
+    fn call(router: &Router, head: &str, body: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        let mut request = Request::parse(format!("{}\r\n\r\n", head).as_bytes()).unwrap();
+        request.body = body.as_bytes().to_vec();
+        router.dispatch(&request, &mut server).unwrap();
+        drop(server);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    const AUTHORIZED: &str = "HTTP/1.1\r\nAuthorization: Bearer s3cret";
+
+    #[test]
+    fn every_endpoint_needs_the_token() {
+        let (router, _) = admin();
+        for head in ["GET /admin/pool HTTP/1.1", "GET /admin/pool HTTP/1.1\r\nAuthorization: Bearer guess", "DELETE /admin/pool/terminate HTTP/1.1\r\nAuthorization: Basic s3cret"] {
+            let response = call(&router, head, "");
+            assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{:?}", response);
+            assert!(response.contains("\r\nWWW-Authenticate: Bearer\r\n"));
+        }
+    }
+
+    #[test]
+    fn the_pool_can_be_read_and_resized() {
+        let (router, handle) = admin();
+        let response = call(&router, &format!("GET /admin/pool {}", AUTHORIZED), "");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.contains("\r\nContent-Type: application/json\r\n"));
+        assert!(response.ends_with("{\"workers\": 2, \"queue_depth\": 0, \"jobs_completed\": 0}"), "{:?}", response);
+
+        let response = call(&router, &format!("POST /admin/pool {}", AUTHORIZED), "{ \"workers\": 5 }");
+        assert!(response.ends_with("{\"workers\": 5, \"queue_depth\": 0, \"jobs_completed\": 0}"), "{:?}", response);
+        assert_eq!(handle.pool().stats().worker_count(), 5);
+
+        for body in ["{\"workers\": 0}", "{\"threads\": 3}", "workers=3", "{\"workers\": -1}"] {
+            let response = call(&router, &format!("POST /admin/pool {}", AUTHORIZED), body);
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}: {:?}", body, response);
+        }
+        let response = call(&router, &format!("POST /admin/pool {}", AUTHORIZED), "");
+        assert!(response.ends_with("{\"error\": \"expected {\\\"workers\\\": <n>}\"}"), "{:?}", response);
+        assert_eq!(handle.pool().stats().worker_count(), 5);
+    }
+
+    #[test]
+    fn drain_waits_for_the_queued_jobs() {
+        let (router, handle) = admin();
+        handle.pool().execute(|| thread::sleep(Duration::from_millis(100))).unwrap();
+        let response = call(&router, &format!("POST /admin/pool/drain {}", AUTHORIZED), "");
+        assert!(response.ends_with("{\"drained\": true, \"queue_depth\": 0}"), "{:?}", response);
+
+        handle.pool().execute(|| thread::sleep(Duration::from_secs(1))).unwrap();
+        let response = call(&router, &format!("POST /admin/pool/drain {}", AUTHORIZED), "");
+        assert!(response.ends_with("{\"drained\": false, \"queue_depth\": 1}"), "{:?}", response);
+    }
+
+    #[test]
+    fn terminate_answers_before_draining() {
+        let (router, _) = admin();
+        let response = call(&router, &format!("DELETE /admin/pool/terminate {}", AUTHORIZED), "");
+        assert!(response.starts_with("HTTP/1.1 202 Accepted\r\n") && response.ends_with("{\"terminating\": true}"), "{:?}", response);
+        let response = call(&router, &format!("GET /admin/nothing {}", AUTHORIZED), "");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{:?}", response);
+    }
+
+    #[test]
+    fn tokens_are_compared_whole() {
+        assert!(same_token("s3cret", "s3cret"));
+        assert!(!same_token("s3cre", "s3cret"));
+        assert!(!same_token("s3cres", "s3cret"));
+        assert!(!same_token("", "s3cret"));
+    }
+}
@@ -0,0 +1,71 @@
+/* # Base64 (RFC 4648), the standard alphabet!
+base64::decode(b"aGVsbG8=")  => Some(b"hello")
+base64::encode(b"hello")  => "aGVsbG8="
+Every 4 characters are 3 bytes; '=' pads the last group out to 4, and may be left off.
+Anything outside the alphabet (whitespace included) makes the input invalid. */
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn value(character: u8) -> Option<u32> {
+    ALPHABET.iter().position(|&c| c == character).map(|position| position as u32)
+}
+
+pub(crate) fn decode(input: &[u8]) -> Option<Vec<u8>> {
+    let unpadded = match input.len() % 4 {
+        0 if input.ends_with(b"==") => &input[..input.len() - 2],
+        0 if input.ends_with(b"=") => &input[..input.len() - 1],
+        1 => return None,
+        _ => input,
+    };
+
+    let mut output = Vec::with_capacity(unpadded.len() / 4 * 3 + 2);
+    for group in unpadded.chunks(4) {
+        let mut bits = 0u32;
+        for &character in group {
+            bits = bits << 6 | value(character)?;
+        }
+        // A short last group: 2 characters are 1 byte, 3 are 2.
+        bits <<= 6 * (4 - group.len()) as u32;
+        let bytes = bits.to_be_bytes();
+        output.extend_from_slice(&bytes[1..group.len()]);
+    }
+    Some(output)
+}
+
+// Always padded.
+pub(crate) fn encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for group in input.chunks(3) {
+        let mut bytes = [0; 4];
+        bytes[1..1 + group.len()].copy_from_slice(group);
+        let bits = u32::from_be_bytes(bytes);
+        // 1 byte is 2 characters, 2 are 3, 3 are 4.
+        for i in 0..4 {
+            if i <= group.len() {
+                output.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4648, section 10.
+    #[test]
+    fn codes_the_rfc_vectors() {
+        let vectors = [("", ""), ("Zg==", "f"), ("Zm8=", "fo"), ("Zm9v", "foo"), ("Zm9vYg==", "foob"), ("Zm9vYmE=", "fooba"), ("Zm9vYmFy", "foobar")];
+        for (encoded, decoded) in vectors {
+            assert_eq!(decode(encoded.as_bytes()).unwrap(), decoded.as_bytes(), "{}", encoded);
+            assert_eq!(decode(encoded.trim_end_matches('=').as_bytes()).unwrap(), decoded.as_bytes(), "{}", encoded);
+            assert_eq!(encode(decoded.as_bytes()), encoded);
+        }
+        for invalid in ["Z", "Zm9v!", "Zm 9v", "Zg=a", "===="] {
+            assert_eq!(decode(invalid.as_bytes()), None, "{}", invalid);
+        }
+    }
+}
@@ -13,98 +13,250 @@ Compiler-Driven Development: write the code that calls the fn you want
 & then look at errors from the compiler to determine what should be changed next 
 to get the code to work (cargo check) */
 
-// Using the net module to listen to a TCP connection: 
-use std::net::TcpListener;
-
-// Using std::io::prelude 
-// to get access to certain traits that let us read from and write to the stream: 
-use std::io::prelude::*;
-use std::net::TcpStream;
+// Reading requests and keeping connections alive happens in surff::server; 
+// the handlers below only write responses through ResponseBuilder: 
+use std::io;
 
 // Using the std lib filesystem module to read files: 
 use std::fs;
 
 // Multithread capabilities: 
+use std::sync::Arc;
 use std::thread; 
 use std::time::Duration; 
-use surff::ThreadPool; 
+use surff::http::{ResponseBuilder, StatusCode};
+use surff::admin::AdminApi;
+use surff::config::{Action, Config, USAGE};
+use surff::rate_limit::RateLimiter;
+use surff::middleware::ServerHeaderMiddleware;
+use surff::range_cache::RangeCache;
+use surff::router::{Response, Router, Routes};
+use surff::server::{Counter, ConnectionStats, JsonLinesLogger, Server};
+use surff::static_files::StaticFileHandler;
+use surff::PoolStats; 
+
+// # Graceful shutdown: on SIGTERM the running requests get this long to finish.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const SIGTERM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// # Rate limiting: each client IP gets 20 requests at once, then 10 per second, and may
+// overdraw by 30 more (a page load's images and scripts, see surff::rate_limit).
+const RATE_LIMIT_PER_SECOND: f64 = 10.0;
+const RATE_LIMIT_CAPACITY: u32 = 20;
+const RATE_LIMIT_BURST: f64 = 30.0;
+
+// # Range cache: byte ranges asked for again (a video's segments) come from memory, up to this much.
+const RANGE_CACHE_BYTES: u64 = 64 * 1024 * 1024;
 
 fn main() {
-    // # Listening to the TCP connection: 
-    let listener = TcpListener::bind("0.0.0.0:1998").unwrap(); 
-    // "local-IP-address: port".
-    // bind fn returns a new instance of TcpListener - 
-    // connecting to a port to listen to, aka binding to a port. 
+    // # Configuration from the command line (see surff::config::USAGE): 
+    let config = match Config::from_args() {
+        Ok(Action::Serve(config)) => config,
+        Ok(Action::DryRun(config)) => std::process::exit(dry_run(&config)),
+        Ok(Action::Help) => {
+            println!("{}", USAGE);
+            return;
+        },
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+
+    // # Thread pool => the Server's pool has a configurable number of threads (--threads, 4 by default): 
+    // one pool for all listeners, shared with every accept thread. 
+    let mut server = match Server::new(&config) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to start the thread pool: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // # Thread pool => use ThreadPool struct with a configurable number of threads (4): 
-    let pool = ThreadPool::new(4); 
-
-    for stream in listener.incoming() {
-        // streams of type TcpStream: 
-        let stream = stream.unwrap();       
-        // a stream represents an open connection between client & server. 
-        println!("Connection established!");
-
-        // thread::spawn (|| { ...  
-        // creates a new thread and runs the code in the closure in the new thread
-        // DoS risk. 
-        pool.execute (|| {      // takes a closure the pool should run for each stream. 
-            handle_connection(stream);
-        });
+    // # Routes: every request goes through the Router, shared by all workers via Arc.
+    let mut router = Router::new();
+    router.get("/", |_, response| serve_html(response, StatusCode::Ok, "hello.html"));
+    router.get("/sleep", |_, response| {
+        thread::sleep(Duration::from_secs(5));
+        serve_html(response, StatusCode::Ok, "hello.html")
+    });
+    // Files under --static-root are served at /static/..., if the directory exists.
+    let range_cache = Arc::new(RangeCache::new(RANGE_CACHE_BYTES));
+    if let Ok(files) = StaticFileHandler::new(&config.static_root) {
+        println!("Serving static files from {}", files.root().display());
+        let files = files.strip_prefix("/static").range_cache(Arc::clone(&range_cache));
+        router.get("/static", move |request, response| files.handle(request, response));
     }
-}
+    // --routes: the files a TOML file lists (see surff::router::Routes); routes added in code win over them.
+    if let Some(file) = &config.routes_file {
+        match Routes::from_toml(file) {
+            Ok(routes) => {
+                println!("Serving {} route(s) from {}", routes.routes.len(), file.display());
+                routes.register(&mut router);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    // --debug-endpoints enables GET /debug/pool (only answered for loopback clients). 
+    if config.debug_endpoints {
+        let stats = server.stats();
+        router.get("/debug/pool", move |_, response| debug_pool(&stats, response));
+    }
+    // shared by all workers; behind --trusted-proxy proxies it goes by the client's address, not the proxy's. 
+    let limiter = RateLimiter::builder(RATE_LIMIT_PER_SECOND, RATE_LIMIT_CAPACITY).burst(RATE_LIMIT_BURST).build();
+    server.rate_limit(limiter.clone());
+    // --log-file: the request log as JSON Lines, for a log aggregator.
+    if let Some(path) = &config.log_file {
+        match JsonLinesLogger::new(path) {
+            Ok(logger) => server.log_to(Arc::new(logger.max_bytes(config.log_max_bytes))),
+            Err(e) => {
+                eprintln!("Failed to open the log file: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // # Metrics: GET /metrics in the Prometheus text format, also for loopback clients only. 
+    let connections = server.connection_stats();
+    let counters = [
+        ("surff_connections_closed_idle_total", "Connections closed for not sending a request in time.", server.closed_idle_connections()),
+        ("surff_handlers_detached_total", "Handlers given up on by --handler-timeout.", server.detached_handlers()),
+        ("surff_rate_limit_burst_rejections_total", "429s for bursts beyond the overdraft.", limiter.burst_rejections()),
+        ("surff_rate_limit_sustained_rejections_total", "429s for clients above the rate for a while.", limiter.sustained_rejections()),
+    ];
+    router.get("/metrics", move |_, response| metrics(&connections, &counters, &range_cache, response));
+    // Browsers ask for it on every visit; a blank icon beats a 404 in the log each time. 
+    router.serve_favicon(surff::BLANK_FAVICON);
+    router.not_found(|_, response| serve_html(response, StatusCode::NotFound, "404.html"));
+    // # Middleware: wrapped last => outermost, so every response says who sent it. 
+    router.wrap(ServerHeaderMiddleware::default());
+    let router = Arc::new(router);
 
-// # Reading the request from the browser and writing a response! 
-// Using the fn "handle_connection" for processing connections.
+    // # Listening to the TCP connection(s): 
+    // all or nothing: better to fail now than to run without one of the addresses. 
+    if let Err(e) = server.bind_all(&config.binds, Arc::clone(&router)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    // "local-IP-address: port".
+    // binding returns a new instance of TcpListener - 
+    // connecting to a port to listen to, aka binding to a port. 
 
-pub fn handle_connection(mut stream: TcpStream) {
-    // TcpStream keeps an internal track of what data it returns.
-    
-    let mut buffer = [0; 1024];      
-    // buffer on the stack to hold the data that is read in (512 bytes in size). 
+    // # SIGTERM (e.g. from a deploy) drains the server: no new connections, the running 
+    // requests finish, then run returns and the process exits. 
+    match surff::os::watch_sigterm() {
+        Ok(()) => {
+            let handle = server.handle();
+            thread::spawn(move || {
+                while !surff::os::sigterm_received() {
+                    thread::sleep(SIGTERM_POLL_INTERVAL);
+                }
+                handle.drain_connections(DRAIN_TIMEOUT);
+            });
+        },
+        Err(e) => eprintln!("Failed to handle SIGTERM: {}", e),
+    }
 
-    stream.read(&mut buffer).unwrap();      
-    // .read bytes from stream and put them in the buffer. 
+    // # Admin API (--admin-bind): a server of its own with one worker, so it answers
+    // even when the main pool is saturated, and user traffic never reaches it. 
+    if let (Some(addr), Some(token)) = (config.admin_bind, &config.admin_token) {
+        let admin_config = Config { threads: 1, io_threads: 0, ..config.clone() };
+        let admin_router = Arc::new(AdminApi::new(server.handle(), token.clone()).drain_timeout(DRAIN_TIMEOUT).into_router());
+        let mut admin = match Server::new(&admin_config) {
+            Ok(admin) => admin,
+            Err(e) => {
+                eprintln!("Failed to start the admin API: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = admin.listen(addr, admin_router) {
+            eprintln!("Failed to bind the admin API to {}: {}", addr, e);
+            std::process::exit(1);
+        }
+        thread::spawn(move || admin.run());
+    }
 
-    // # Printing the request data:
-    println!("Request: {}", String::from_utf8_lossy(&buffer[..]));      
-    // &[u8] as input. 
+    // # One accept thread per listener; run only returns once they've all stopped. 
+    if let Err(e) = server.run() {
+        eprintln!("Failed to start the server: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    // # Responses have the following format:
-    // HTTP-Version Status-Code Reason-Phrase CRLF
-    // headers CRLF
-    // message-body
-    let success_response = "HTTP/1.1 200 OK\r\n\r\n";       
-    // standard success response - no headers and no body. 
+// # --dry-run: the config's problems, on stderr (for CI); the exit status says if any were fatal.
+fn dry_run(config: &Config) -> i32 {
+    match Server::validate_config(config) {
+        Ok(warnings) => {
+            for warning in &warnings {
+                eprintln!("warning: {}", warning);
+            }
+            println!("The configuration is valid ({} warning(s)).", warnings.len());
+            0
+        },
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("error: {}", error);
+            }
+            1
+        },
+    }
+}
 
-    // The status code 404 signals that the content for the request was not found: 
-    let status_line_404 = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
+// # Responses have the following format:
+// HTTP-Version Status-Code Reason-Phrase CRLF
+// headers CRLF
+// message-body
+// ResponseBuilder writes the status line and Content-Length for us. 
+// The status code 404 signals that the content for the request was not found. 
+// HEAD requests (sent to the GET routes by the Router) get the same headers; Response leaves the HTML out.
 
-    // # Functionality to check what the browser is requesting:
-    let get = b"GET / HTTP/1.1\r\n";        
-    // hardcode the data corresponding to the / requests into get variable. 
-    // b"" byte string syntax to transform get into raw bytes. 
+fn serve_html(response: &mut Response, status: StatusCode, filename: &str) -> io::Result<()> {
+    // Return the HTML:
+    let contents = fs::read_to_string(filename)?;
 
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+    response.send(
+        ResponseBuilder::new(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body_str(&contents),
+    )
+    // .send passes the response through the middleware, then writes the bytes down the 
+    // connection and flushes, which waits until all bytes are written to the connection. 
+    // Errors are returned with ? instead of unwrap() so a client that hangs up 
+    // doesn't panic the worker thread. 
+}
 
-    let (status_line, filename) = if buffer.starts_with(get) {
-        (success_response, "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        (success_response, "hello.html")
-    } else {
-        (status_line_404, "404.html")
-    };
+// # Debug endpoint: a JSON snapshot of the thread pool, for loopback clients only.
+fn debug_pool(stats: &PoolStats, response: &mut Response) -> io::Result<()> {
+    let is_local = response.peer_addr().is_ok_and(|addr| addr.ip().is_loopback());
+    if !is_local {
+        return serve_html(response, StatusCode::NotFound, "404.html");
+    }
 
-    // Return the HTML:
-    let contents = fs::read_to_string(filename).unwrap();
+    response.send(
+        ResponseBuilder::new(StatusCode::Ok)
+            .header("Content-Type", "application/json")
+            .body_str(&stats.to_json()),
+    )
+}
 
-    let response = format!("{}{}", status_line, contents);
+// # Metrics: how well keep-alive works (requests per connection), the server's counters, and the range cache.
+fn metrics(connections: &ConnectionStats, counters: &[(&str, &str, Counter)], range_cache: &RangeCache, response: &mut Response) -> io::Result<()> {
+    let is_local = response.peer_addr().is_ok_and(|addr| addr.ip().is_loopback());
+    if !is_local {
+        return serve_html(response, StatusCode::NotFound, "404.html");
+    }
 
-    stream.write_all(response.as_bytes()).unwrap();     
-    // .write takes a &[u8] and sends those bytes down the connection. 
-    
-    stream.flush().unwrap();
-    // .flush() waits & prevents the program from continuing 
-    // until all bytes are written to the connection. 
-}
\ No newline at end of file
+    let mut text = connections.to_prometheus();
+    for (name, help, counter) in counters {
+        text.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, help, name, name, counter.get()));
+    }
+    text.push_str(&range_cache.to_prometheus());
+    response.send(
+        ResponseBuilder::new(StatusCode::Ok)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body_str(&text),
+    )
+}
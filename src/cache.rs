@@ -0,0 +1,467 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::crypto::{sha256, to_hex};
+use crate::http::{Method, Request, ResponseBuilder, StatusCode};
+
+/* # Caching responses, Vary and all!
+let cache = ResponseCache::new(1000);
+if let Some(mut cached) = cache.get(&request) { return response.send(&mut cached); }
+... answer it ...
+cache.store(&request, &builder);
+A response that says Vary: Accept-Encoding was chosen by that request header as much
+as by the URL: the gzipped one mustn't go to clients that didn't ask for gzip. So the
+key is the method, the URI (path and query) and the value of every header the
+response's Vary names, as the request sent it (CacheKey). A header the request didn't
+send is left out, which is not the same as sending it empty.
+Looking a request up, before there's a response, needs the Vary of what was stored
+for its URI: the cache keeps that per (method, URI), and builds the key from it.
+Vary: * means the response depends on more than headers, and it isn't stored.
+DiskCache (below) keeps responses in files instead, so they outlive a restart. */
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub method: Method,
+    pub uri: String,
+    // lowercased header name => the request's value(s) for it, joined with ", ".
+    pub vary_fields: BTreeMap<String, String>,
+}
+
+impl CacheKey {
+    // None for Vary: *.
+    pub fn new(request: &Request, vary: &[&str]) -> Option<CacheKey> {
+        let mut vary_fields = BTreeMap::new();
+        for name in vary {
+            let name = name.trim().to_ascii_lowercase();
+            if name == "*" {
+                return None;
+            }
+            if name.is_empty() {
+                continue;
+            }
+            let values: Vec<&str> = request
+                .headers
+                .iter()
+                .filter(|(header, _)| header.eq_ignore_ascii_case(&name))
+                .map(|(_, value)| value.as_str())
+                .collect();
+            if !values.is_empty() {
+                vary_fields.insert(name, values.join(", "));
+            }
+        }
+        Some(CacheKey { method: request.method.clone(), uri: uri(request), vary_fields })
+    }
+
+    // The key `response` should be stored under.
+    pub fn for_response(request: &Request, response: &ResponseBuilder) -> Option<CacheKey> {
+        CacheKey::new(request, &vary_names(response))
+    }
+}
+
+fn uri(request: &Request) -> String {
+    match &request.query {
+        Some(query) => format!("{}?{}", request.path, query),
+        None => request.path.clone(),
+    }
+}
+
+// Every name in every Vary header of the response.
+fn vary_names(response: &ResponseBuilder) -> Vec<&str> {
+    response.header_values("Vary").flat_map(|value| value.split(',')).map(str::trim).collect()
+}
+
+#[derive(Default)]
+struct Entries {
+    // the Vary of what's stored for a (method, URI), lowercased.
+    vary: HashMap<(Method, String), Vec<String>>,
+    responses: HashMap<CacheKey, ResponseBuilder>,
+}
+
+// In memory, at most `max_entries` responses: once it's full, new ones aren't stored
+// until it's cleared. What's cacheable, and for how long, is the caller's call.
+pub struct ResponseCache {
+    max_entries: usize,
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize) -> ResponseCache {
+        ResponseCache { max_entries, entries: Mutex::new(Entries::default()) }
+    }
+
+    // False if it wasn't stored: Vary: *, or the cache is full.
+    pub fn store(&self, request: &Request, response: &ResponseBuilder) -> bool {
+        let Some(key) = CacheKey::for_response(request, response) else {
+            return false;
+        };
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.responses.len() >= self.max_entries && !entries.responses.contains_key(&key) {
+            return false;
+        }
+        let names: Vec<String> = vary_names(response).iter().map(|name| name.to_ascii_lowercase()).collect();
+        let resource = (key.method.clone(), key.uri.clone());
+        if entries.vary.get(&resource).is_some_and(|vary| *vary != names) {
+            // keyed on other headers: get() couldn't find them anymore.
+            entries.responses.retain(|stored, _| stored.method != resource.0 || stored.uri != resource.1);
+        }
+        entries.vary.insert(resource, names);
+        entries.responses.insert(key, response.clone());
+        true
+    }
+
+    pub fn get(&self, request: &Request) -> Option<ResponseBuilder> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let vary = entries.vary.get(&(request.method.clone(), uri(request)))?;
+        let names: Vec<&str> = vary.iter().map(String::as_str).collect();
+        let key = CacheKey::new(request, &names)?;
+        entries.responses.get(&key).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.vary.clear();
+        entries.responses.clear();
+    }
+}
+
+/* # Responses on disk, still there after a restart!
+let cache = Arc::new(DiskCache::new("/var/cache/surff")?);
+cache.spawn_cleanup(Duration::from_secs(60))?;
+if let Some(mut cached) = cache.get(&key) { return response.send(&mut cached); }
+cache.put(&key, &builder, Duration::from_secs(3600))?;
+1. A response is stored as <hex>, where <hex> is the SHA-256 of its key (method, URI and
+the Vary fields, one per line), and its expiry as <hex>.meta next to it.
+2. <hex> holds the status (2 bytes), the number of headers (4), each name and value
+after its length (4 each), then the body after its length (8), all big-endian.
+<hex>.meta is the expiry, in seconds since the Unix epoch (8 bytes).
+3. Both are written to a .tmp file first and renamed into place, the response before its
+.meta: a reader sees the old entry or the new one, never half of one. get reads the .meta
+first, so an entry without one (a put that didn't finish) isn't found.
+4. An expired entry isn't found either; remove_expired deletes those files, every
+so often from spawn_cleanup's thread, which stops once the cache is dropped.
+5. Unlike ResponseCache, the caller brings the key: the Vary a response was looked up
+with has to be known before there's a response (Accept-Encoding, say). Only responses
+with a body in the builder can be stored; send_file's aren't. */
+
+const META_EXTENSION: &str = "meta";
+const TMP_EXTENSION: &str = "tmp";
+
+#[derive(Debug)]
+pub struct DiskCache {
+    directory: PathBuf,
+}
+
+impl CacheKey {
+    // The name of its files in a DiskCache.
+    fn file_name(&self) -> String {
+        let mut text = format!("{} {}\n", self.method, self.uri);
+        for (name, value) in &self.vary_fields {
+            text.push_str(&format!("{}: {}\n", name, value));
+        }
+        to_hex(&sha256(text.as_bytes()))
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn invalid(path: &Path, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message))
+}
+
+// Through a .tmp file of its own, renamed to `path`.
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let tmp = path.with_extension(format!("{}.{}.{}", std::process::id(), WRITES.fetch_add(1, Ordering::Relaxed), TMP_EXTENSION));
+    let written = fs::File::create(&tmp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+    match written.and_then(|_| fs::rename(&tmp, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+fn encode(response: &ResponseBuilder, body: &[u8]) -> Vec<u8> {
+    let mut data = response.status().code().to_be_bytes().to_vec();
+    data.extend_from_slice(&(response.headers().len() as u32).to_be_bytes());
+    for (name, value) in response.headers() {
+        for part in [name, value] {
+            data.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            data.extend_from_slice(part.as_bytes());
+        }
+    }
+    data.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    data.extend_from_slice(body);
+    data
+}
+
+fn decode(path: &Path, mut data: &[u8]) -> io::Result<ResponseBuilder> {
+    fn take<'a>(data: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+        let part = data.get(..length)?;
+        *data = &data[length..];
+        Some(part)
+    }
+    fn number<const N: usize>(data: &mut &[u8]) -> Option<[u8; N]> {
+        take(data, N)?.try_into().ok()
+    }
+    fn text(data: &mut &[u8]) -> Option<String> {
+        let length = u32::from_be_bytes(number(data)?) as usize;
+        String::from_utf8(take(data, length)?.to_vec()).ok()
+    }
+
+    let truncated = || invalid(path, "truncated or malformed cache file");
+    let code = u16::from_be_bytes(number(&mut data).ok_or_else(truncated)?);
+    let status = StatusCode::from_code(code).ok_or_else(|| invalid(path, &format!("unknown status {}", code)))?;
+    let mut response = ResponseBuilder::new(status);
+    let headers = u32::from_be_bytes(number(&mut data).ok_or_else(truncated)?);
+    for _ in 0..headers {
+        let name = text(&mut data).ok_or_else(truncated)?;
+        let value = text(&mut data).ok_or_else(truncated)?;
+        response.header(&name, &value);
+    }
+    let length = u64::from_be_bytes(number(&mut data).ok_or_else(truncated)?);
+    if length != data.len() as u64 {
+        return Err(truncated());
+    }
+    response.body_bytes(data.to_vec());
+    Ok(response)
+}
+
+impl DiskCache {
+    // Creates the directory if it isn't there.
+    pub fn new(directory: impl AsRef<Path>) -> io::Result<DiskCache> {
+        fs::create_dir_all(&directory)?;
+        Ok(DiskCache { directory: directory.as_ref().to_path_buf() })
+    }
+
+    fn paths(&self, key: &CacheKey) -> (PathBuf, PathBuf) {
+        let data = self.directory.join(key.file_name());
+        let meta = data.with_extension(META_EXTENSION);
+        (data, meta)
+    }
+
+    // None when it isn't there, has expired, or can't be read (which is logged).
+    pub fn get(&self, key: &CacheKey) -> Option<ResponseBuilder> {
+        let (data, meta) = self.paths(key);
+        let expires = fs::read(&meta).ok()?;
+        let expires = u64::from_be_bytes(expires.try_into().ok()?);
+        if expires <= now_secs() {
+            return None;
+        }
+        match fs::read(&data).and_then(|bytes| decode(&data, &bytes)) {
+            Ok(response) => Some(response),
+            Err(e) => {
+                eprintln!("Disk cache: {}", e);
+                None
+            }
+        }
+    }
+
+    // Ok(false) if it wasn't stored: the body isn't in the builder.
+    pub fn put(&self, key: &CacheKey, response: &ResponseBuilder, ttl: Duration) -> io::Result<bool> {
+        let Some(body) = response.body() else {
+            return Ok(false);
+        };
+        let (data, meta) = self.paths(key);
+        write_atomically(&data, &encode(response, body))?;
+        write_atomically(&meta, &now_secs().saturating_add(ttl.as_secs()).to_be_bytes())?;
+        Ok(true)
+    }
+
+    pub fn remove(&self, key: &CacheKey) -> io::Result<()> {
+        let (data, meta) = self.paths(key);
+        // the .meta first: without it, the entry is gone already.
+        for path in [meta, data] {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // The expired entries' files, and .tmp files a put left behind; how many entries.
+    pub fn remove_expired(&self) -> io::Result<usize> {
+        let now = now_secs();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some(META_EXTENSION) => {
+                    let expired = fs::read(&path)
+                        .ok()
+                        .and_then(|bytes| bytes.try_into().ok())
+                        .is_none_or(|expires: [u8; 8]| u64::from_be_bytes(expires) <= now);
+                    if expired {
+                        fs::remove_file(&path)?;
+                        let _ = fs::remove_file(path.with_extension(""));
+                        removed += 1;
+                    }
+                }
+                // one that's old enough not to be a put still writing it.
+                Some(TMP_EXTENSION) => {
+                    let old = entry_age(&path).is_some_and(|age| age > Duration::from_secs(3600));
+                    if old {
+                        let _ = fs::remove_file(&path);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(removed)
+    }
+
+    // remove_expired every `interval`, until the cache is dropped.
+    pub fn spawn_cleanup(self: &Arc<DiskCache>, interval: Duration) -> io::Result<thread::JoinHandle<()>> {
+        let cache = Arc::downgrade(self);
+        thread::Builder::new().name("surff-disk-cache".to_string()).spawn(move || loop {
+            thread::sleep(interval);
+            let Some(cache) = cache.upgrade() else {
+                return;
+            };
+            if let Err(e) = cache.remove_expired() {
+                eprintln!("Disk cache: cleaning up {}: {}", cache.directory.display(), e);
+            }
+        })
+    }
+}
+
+fn entry_age(path: &Path) -> Option<Duration> {
+    fs::metadata(path).ok()?.modified().ok()?.elapsed().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::StatusCode;
+
+    fn request(headers: &str) -> Request {
+        Request::parse(format!("GET /app.js?v=2 HTTP/1.1\r\n{}\r\n", headers).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn keys_include_the_varying_headers() {
+        let gzip = request("Accept-Encoding: gzip\r\nAccept-Language: en\r\n");
+        let key = CacheKey::new(&gzip, &["accept-encoding", "X-Missing"]).unwrap();
+        assert_eq!(key.uri, "/app.js?v=2");
+        assert_eq!(key.vary_fields, BTreeMap::from([("accept-encoding".to_string(), "gzip".to_string())]));
+        // the same headers, in any case and order: the same key.
+        assert_eq!(CacheKey::new(&request("accept-encoding: gzip\r\n"), &["Accept-Encoding"]).unwrap(), key);
+        assert_ne!(CacheKey::new(&request("Accept-Encoding: br\r\n"), &["Accept-Encoding"]).unwrap(), key);
+        // empty is not missing.
+        assert_ne!(CacheKey::new(&request("Accept-Encoding:\r\n"), &["Accept-Encoding"]), CacheKey::new(&request(""), &["Accept-Encoding"]));
+        assert_eq!(CacheKey::new(&gzip, &["Accept-Encoding", "*"]), None);
+    }
+
+    #[test]
+    fn gzip_and_identity_are_separate_entries() {
+        let cache = ResponseCache::new(10);
+        let mut gzipped = ResponseBuilder::new(StatusCode::Ok);
+        gzipped.header("Vary", "Accept-Encoding").header("Content-Encoding", "gzip").body_str("gzipped");
+        let mut plain = ResponseBuilder::new(StatusCode::Ok);
+        plain.header("Vary", "Accept-Encoding").body_str("plain");
+
+        assert!(cache.get(&request("Accept-Encoding: gzip\r\n")).is_none());
+        assert!(cache.store(&request("Accept-Encoding: gzip\r\n"), &gzipped));
+        assert!(cache.store(&request(""), &plain));
+        assert_eq!(cache.len(), 2);
+
+        let hit = cache.get(&request("Accept-Encoding: gzip\r\nUser-Agent: other\r\n")).unwrap();
+        assert_eq!(hit.body(), Some(&b"gzipped"[..]));
+        assert_eq!(cache.get(&request("")).unwrap().body(), Some(&b"plain"[..]));
+        assert!(cache.get(&request("Accept-Encoding: br\r\n")).is_none());
+    }
+
+    #[test]
+    fn vary_star_and_a_full_cache_store_nothing() {
+        let cache = ResponseCache::new(1);
+        let mut anything = ResponseBuilder::new(StatusCode::Ok);
+        anything.header("Vary", "*");
+        assert!(!cache.store(&request(""), &anything));
+        assert!(cache.is_empty());
+
+        let response = ResponseBuilder::new(StatusCode::Ok);
+        assert!(cache.store(&request(""), &response));
+        assert!(!cache.store(&Request::parse(b"GET /other HTTP/1.1\r\n\r\n").unwrap(), &response));
+        // replacing what's there is fine.
+        assert!(cache.store(&request(""), &response));
+    }
+
+
+    fn disk_cache(name: &str) -> (DiskCache, PathBuf) {
+        let directory = std::env::temp_dir().join(format!("surff-disk-cache-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        (DiskCache::new(&directory).unwrap(), directory)
+    }
+
+    #[test]
+    fn disk_entries_round_trip() {
+        let (cache, directory) = disk_cache("round-trip");
+        let gzip = CacheKey::new(&request("Accept-Encoding: gzip\r\n"), &["Accept-Encoding"]).unwrap();
+        let plain = CacheKey::new(&request(""), &["Accept-Encoding"]).unwrap();
+        let mut response = ResponseBuilder::new(StatusCode::NotFound);
+        response.header("Vary", "Accept-Encoding").header("Set-Cookie", "a=1").header("Set-Cookie", "b=2").body_bytes(vec![0, 1, 2, 255]);
+        assert!(cache.put(&gzip, &response, Duration::from_secs(60)).unwrap());
+        assert!(cache.get(&plain).is_none());
+
+        // a new DiskCache on the same directory: as after a restart.
+        let cache = DiskCache::new(&directory).unwrap();
+        let hit = cache.get(&gzip).unwrap();
+        assert_eq!((hit.status(), hit.headers(), hit.body()), (StatusCode::NotFound, response.headers(), Some(&[0, 1, 2, 255][..])));
+        let file = directory.join(gzip.file_name());
+        assert_eq!(file.file_name().unwrap().len(), 64);
+        assert!(file.with_extension("meta").exists());
+
+        // a file cut short is a miss, not a panic; without a body there's nothing to store.
+        let bytes = fs::read(&file).unwrap();
+        fs::write(&file, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(cache.get(&gzip).is_none());
+        assert!(!cache.put(&plain, &ResponseBuilder::new(StatusCode::Ok), Duration::from_secs(60)).unwrap());
+        cache.remove(&gzip).unwrap();
+        assert!(!file.exists());
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn expired_disk_entries_are_cleaned_up() {
+        let (cache, directory) = disk_cache("expired");
+        let key = CacheKey::new(&request(""), &[]).unwrap();
+        let other = CacheKey::new(&Request::parse(b"GET /other HTTP/1.1\r\n\r\n").unwrap(), &[]).unwrap();
+        let mut response = ResponseBuilder::new(StatusCode::Ok);
+        response.body_str("hello");
+        cache.put(&key, &response, Duration::ZERO).unwrap();
+        cache.put(&other, &response, Duration::from_secs(60)).unwrap();
+        assert!(cache.get(&key).is_none());
+
+        let cache = Arc::new(cache);
+        let cleanup = cache.spawn_cleanup(Duration::from_millis(10)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(!directory.join(key.file_name()).exists());
+        assert_eq!(cache.get(&other).unwrap().body(), Some(&b"hello"[..]));
+        assert_eq!(cache.remove_expired().unwrap(), 0);
+        // the thread stops once the cache is gone.
+        drop(cache);
+        cleanup.join().unwrap();
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,317 @@
+use std::fmt;
+use std::str::FromStr;
+
+/* # Cache-Control headers without the foot-guns!
+CacheControl::new().public()?.max_age(3600)?.must_revalidate().build()
+=> "public, max-age=3600, must-revalidate"
+
+The setters for directives that can contradict each other (public/private,
+no-store with max-age, s-maxage or immutable, private with s-maxage) return a
+Result, so .no_store().max_age(3600) fails right at the max_age call, where the
+mistake is, instead of at build() somewhere later. The others can't fail and
+return Self. Directives are always written in the same order, no matter the
+order they were set in.
+"...".parse::<CacheControl>() reads an incoming header with the same rules.
+Unknown (extension) directives are kept as-is, as RFC 7234 asks. */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheControlError {
+    // The two directives, in the order they were set.
+    Conflicting(&'static str, &'static str),
+    // A directive that needs a number of seconds didn't get one.
+    InvalidValue(String),
+}
+
+impl fmt::Display for CacheControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheControlError::Conflicting(a, b) => write!(f, "conflicting Cache-Control directives: {} and {}", a, b),
+            CacheControlError::InvalidValue(directive) => write!(f, "invalid Cache-Control directive: {}", directive),
+        }
+    }
+}
+
+impl std::error::Error for CacheControlError {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheControl {
+    public: bool,
+    private: bool,
+    no_cache: bool,
+    no_store: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    // Some(None) is a bare max-stale: any amount of staleness is fine.
+    max_stale: Option<Option<u64>>,
+    min_fresh: Option<u64>,
+    no_transform: bool,
+    only_if_cached: bool,
+    must_revalidate: bool,
+    proxy_revalidate: bool,
+    immutable: bool,
+    extensions: Vec<String>,
+}
+
+// Pairs of directives that contradict each other.
+const CONFLICTS: &[(&str, &str)] = &[
+    ("public", "private"),
+    ("no-store", "max-age"),
+    ("no-store", "s-maxage"),
+    ("no-store", "immutable"),
+    // s-maxage only applies to shared caches, which private forbids.
+    ("private", "s-maxage"),
+];
+
+impl CacheControl {
+    pub fn new() -> CacheControl {
+        CacheControl::default()
+    }
+
+    pub fn public(mut self) -> Result<Self, CacheControlError> {
+        self.check("public")?;
+        self.public = true;
+        Ok(self)
+    }
+
+    pub fn private(mut self) -> Result<Self, CacheControlError> {
+        self.check("private")?;
+        self.private = true;
+        Ok(self)
+    }
+
+    pub fn no_cache(mut self) -> Self {
+        self.no_cache = true;
+        self
+    }
+
+    pub fn no_store(mut self) -> Result<Self, CacheControlError> {
+        self.check("no-store")?;
+        self.no_store = true;
+        Ok(self)
+    }
+
+    pub fn max_age(mut self, seconds: u64) -> Result<Self, CacheControlError> {
+        self.check("max-age")?;
+        self.max_age = Some(seconds);
+        Ok(self)
+    }
+
+    pub fn s_maxage(mut self, seconds: u64) -> Result<Self, CacheControlError> {
+        self.check("s-maxage")?;
+        self.s_maxage = Some(seconds);
+        Ok(self)
+    }
+
+    pub fn max_stale(mut self, seconds: u64) -> Self {
+        self.max_stale = Some(Some(seconds));
+        self
+    }
+
+    pub fn max_stale_any(mut self) -> Self {
+        self.max_stale = Some(None);
+        self
+    }
+
+    pub fn min_fresh(mut self, seconds: u64) -> Self {
+        self.min_fresh = Some(seconds);
+        self
+    }
+
+    pub fn no_transform(mut self) -> Self {
+        self.no_transform = true;
+        self
+    }
+
+    pub fn only_if_cached(mut self) -> Self {
+        self.only_if_cached = true;
+        self
+    }
+
+    pub fn must_revalidate(mut self) -> Self {
+        self.must_revalidate = true;
+        self
+    }
+
+    pub fn proxy_revalidate(mut self) -> Self {
+        self.proxy_revalidate = true;
+        self
+    }
+
+    pub fn immutable(mut self) -> Result<Self, CacheControlError> {
+        self.check("immutable")?;
+        self.immutable = true;
+        Ok(self)
+    }
+
+    pub fn max_age_value(&self) -> Option<u64> {
+        self.max_age
+    }
+
+    pub fn s_maxage_value(&self) -> Option<u64> {
+        self.s_maxage
+    }
+
+    pub fn is_no_store(&self) -> bool {
+        self.no_store
+    }
+
+    pub fn is_no_cache(&self) -> bool {
+        self.no_cache
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.public
+    }
+
+    // The header value, e.g. "public, max-age=3600".
+    pub fn build(&self) -> String {
+        let mut directives: Vec<String> = Vec::new();
+        let flags = |list: &[(bool, &str)], directives: &mut Vec<String>| {
+            for (on, name) in list {
+                if *on {
+                    directives.push(name.to_string());
+                }
+            }
+        };
+
+        flags(&[
+            (self.public, "public"),
+            (self.private, "private"),
+            (self.no_cache, "no-cache"),
+            (self.no_store, "no-store"),
+        ], &mut directives);
+        if let Some(seconds) = self.max_age {
+            directives.push(format!("max-age={}", seconds));
+        }
+        if let Some(seconds) = self.s_maxage {
+            directives.push(format!("s-maxage={}", seconds));
+        }
+        match self.max_stale {
+            Some(Some(seconds)) => directives.push(format!("max-stale={}", seconds)),
+            Some(None) => directives.push("max-stale".to_string()),
+            None => {},
+        }
+        if let Some(seconds) = self.min_fresh {
+            directives.push(format!("min-fresh={}", seconds));
+        }
+        flags(&[
+            (self.no_transform, "no-transform"),
+            (self.only_if_cached, "only-if-cached"),
+            (self.must_revalidate, "must-revalidate"),
+            (self.proxy_revalidate, "proxy-revalidate"),
+            (self.immutable, "immutable"),
+        ], &mut directives);
+        directives.extend(self.extensions.iter().cloned());
+
+        directives.join(", ")
+    }
+
+    // Err if `directive` contradicts one that's already set.
+    fn check(&self, directive: &'static str) -> Result<(), CacheControlError> {
+        for &(a, b) in CONFLICTS {
+            let other = if a == directive {
+                b
+            } else if b == directive {
+                a
+            } else {
+                continue;
+            };
+            if self.is_set(other) {
+                return Err(CacheControlError::Conflicting(other, directive));
+            }
+        }
+        Ok(())
+    }
+
+    // Only needs to know about the directives that appear in CONFLICTS.
+    fn is_set(&self, directive: &str) -> bool {
+        match directive {
+            "public" => self.public,
+            "private" => self.private,
+            "no-store" => self.no_store,
+            "max-age" => self.max_age.is_some(),
+            "s-maxage" => self.s_maxage.is_some(),
+            "immutable" => self.immutable,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for CacheControl {
+    type Err = CacheControlError;
+
+    fn from_str(header: &str) -> Result<CacheControl, CacheControlError> {
+        let mut cc = CacheControl::new();
+
+        for directive in header.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = || -> Result<u64, CacheControlError> {
+                value
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(|| CacheControlError::InvalidValue(directive.to_string()))
+            };
+
+            cc = match name.to_ascii_lowercase().as_str() {
+                "public" => cc.public()?,
+                "private" => cc.private()?,
+                "no-cache" => cc.no_cache(),
+                "no-store" => cc.no_store()?,
+                "max-age" => cc.max_age(seconds()?)?,
+                "s-maxage" => cc.s_maxage(seconds()?)?,
+                "max-stale" if value.is_none() => cc.max_stale_any(),
+                "max-stale" => cc.max_stale(seconds()?),
+                "min-fresh" => cc.min_fresh(seconds()?),
+                "no-transform" => cc.no_transform(),
+                "only-if-cached" => cc.only_if_cached(),
+                "must-revalidate" => cc.must_revalidate(),
+                "proxy-revalidate" => cc.proxy_revalidate(),
+                "immutable" => cc.immutable()?,
+                _ => {
+                    cc.extensions.push(directive.to_string());
+                    cc
+                },
+            };
+        }
+
+        Ok(cc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_directives_in_a_fixed_order() -> Result<(), CacheControlError> {
+        let cc = CacheControl::new().must_revalidate().max_age(3600)?.public()?;
+        assert_eq!(cc.build(), "public, max-age=3600, must-revalidate");
+        Ok(())
+    }
+
+    #[test]
+    fn conflicts_fail_at_the_conflicting_call() {
+        let no_store = CacheControl::new().no_store().unwrap();
+        assert_eq!(no_store.max_age(3600), Err(CacheControlError::Conflicting("no-store", "max-age")));
+
+        let public = CacheControl::new().public().unwrap();
+        assert_eq!(public.private(), Err(CacheControlError::Conflicting("public", "private")));
+    }
+
+    #[test]
+    fn parses_headers_with_the_same_rules() {
+        let cc: CacheControl = "Public, max-age=\"60\", x-ext=1".parse().unwrap();
+        assert_eq!(cc.max_age_value(), Some(60));
+        assert_eq!(cc.build(), "public, max-age=60, x-ext=1");
+
+        let conflict = "no-store, max-age=60".parse::<CacheControl>();
+        assert_eq!(conflict, Err(CacheControlError::Conflicting("no-store", "max-age")));
+        assert!(matches!("max-age=soon".parse::<CacheControl>(), Err(CacheControlError::InvalidValue(_))));
+    }
+}
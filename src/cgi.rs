@@ -0,0 +1,369 @@
+use std::ffi::{OsStr, OsString};
+use std::io::{self, prelude::*, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::http::{HttpVersion, Method, Request, ResponseBuilder, StatusCode};
+use crate::router::Response;
+
+/* # Running a program for a response (CGI, simplified)!
+let report = CgiHandler::new("/usr/lib/cgi-bin/report.sh").arg("--html").env("LANG", "C");
+router.route(Method::Get, "/report", move |request, response| report.handle(request, response));
+1. The program is started for every request, with the request body on its stdin and
+the usual CGI variables in its environment (REQUEST_METHOD, QUERY_STRING, PATH_INFO,
+CONTENT_TYPE, HTTP_USER_AGENT, ...). Apart from PATH, nothing of the server's own
+environment is passed on, only what was added with env().
+2. Its stdout starts with headers, up to a blank line, like a response without the
+status line. "Status: 404 Not Found" sets the status; without one it's 200, or 302 if
+there's a Location.
+3. The rest of stdout is the body, passed on chunk by chunk as the program writes it
+(HTTP/1.0 clients, and HEAD requests, get it in one piece once the program is done).
+4. A program that fails (non-zero exit, no blank line after its headers, or still
+running after the timeout, which kills it) gets the client a 500 if nothing was sent
+yet. Once the body is streaming that's too late: the connection is closed instead,
+without the last chunk, so the client knows the body is incomplete. */
+
+pub const DEFAULT_CGI_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Headers longer than this are a broken program, not a response.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct CgiHandler {
+    program: PathBuf,
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+    timeout: Duration,
+}
+
+enum Exit {
+    Finished(ExitStatus),
+    TimedOut,
+}
+
+impl CgiHandler {
+    pub fn new(program: impl Into<PathBuf>) -> CgiHandler {
+        CgiHandler {
+            program: program.into(),
+            args: Vec::new(),
+            env: Vec::new(),
+            timeout: DEFAULT_CGI_TIMEOUT,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> CgiHandler {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    pub fn env(mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> CgiHandler {
+        self.env.push((key.as_ref().to_os_string(), value.as_ref().to_os_string()));
+        self
+    }
+
+    // How long the program may run, from start to exit, before it's killed.
+    pub fn timeout(mut self, timeout: Duration) -> CgiHandler {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn handle(&self, request: &Request, response: &mut Response) -> io::Result<()> {
+        let mut child = match self.command(request, response).spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("Failed to start {}: {}", self.program.display(), e);
+                return internal_error(response);
+            }
+        };
+
+        // On its own thread: a program that writes before it has read its input would
+        // otherwise wait for us while we wait for it.
+        if let Some(mut stdin) = child.stdin.take() {
+            if !request.body.is_empty() {
+                let body = request.body.clone();
+                thread::spawn(move || {
+                    let _ = stdin.write_all(&body);
+                });
+            }
+        }
+        let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let watchdog = watch(child, self.timeout);
+
+        let mut head = match read_head(&mut stdout) {
+            Ok(head) => head,
+            Err(e) => {
+                eprintln!("{} sent no valid headers: {}", self.program.display(), e);
+                // the watchdog reaps it, however long that takes.
+                return internal_error(response);
+            }
+        };
+
+        if request.method == Method::Head || request.version == HttpVersion::Http10 {
+            let mut body = Vec::new();
+            let read = stdout.read_to_end(&mut body);
+            return match read.map_err(|e| e.to_string()).and_then(|_| exit_status(watchdog)) {
+                Ok(()) => response.send(head.body_bytes(body)),
+                Err(e) => {
+                    eprintln!("{} failed: {}", self.program.display(), e);
+                    internal_error(response)
+                }
+            };
+        }
+
+        let mut body = response.start_chunked(head)?;
+        loop {
+            let chunk = stdout.fill_buf()?;
+            if chunk.is_empty() {
+                break;
+            }
+            let n = chunk.len();
+            body.write_all(chunk)?;
+            body.flush()?;
+            stdout.consume(n);
+        }
+        match exit_status(watchdog) {
+            Ok(()) => body.finish(),
+            // the Err closes the connection, without the final chunk.
+            Err(e) => Err(io::Error::other(format!("{} failed: {}", self.program.display(), e))),
+        }
+    }
+
+    fn command(&self, request: &Request, response: &Response) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .env_clear()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        // its own process group, so a timeout also kills whatever it started (which
+        // would otherwise keep stdout open).
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        if let Some(path) = std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+
+        command
+            .env("GATEWAY_INTERFACE", "CGI/1.1")
+            .env("SERVER_SOFTWARE", format!("surff/{}", env!("CARGO_PKG_VERSION")))
+            .env("SERVER_PROTOCOL", request.version.as_str())
+            .env("REQUEST_METHOD", request.method.as_str())
+            .env("PATH_INFO", &request.path)
+            .env("QUERY_STRING", request.query.as_deref().unwrap_or(""))
+            .env("CONTENT_LENGTH", request.body.len().to_string());
+        if let Some(content_type) = request.header("Content-Type") {
+            command.env("CONTENT_TYPE", content_type);
+        }
+        if let Some(host) = request.header("Host") {
+            command.env("SERVER_NAME", host.rsplit_once(':').map_or(host, |(name, _)| name));
+        }
+        if let Ok(peer) = response.peer_addr() {
+            command.env("REMOTE_ADDR", peer.ip().to_string());
+        }
+        for (name, value) in &request.headers {
+            // Proxy would become HTTP_PROXY, which many programs take as their proxy ("httpoxy").
+            if name.eq_ignore_ascii_case("Content-Type") || name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Proxy") {
+                continue;
+            }
+            command.env(format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_")), value);
+        }
+
+        for (key, value) in &self.env {
+            command.env(key, value);
+        }
+        command
+    }
+}
+
+// Waits for the program on its own thread, and kills it once the timeout is up.
+// The thread owns the Child, so nothing else has to share it.
+fn watch(mut child: Child, timeout: Duration) -> thread::JoinHandle<io::Result<Exit>> {
+    thread::spawn(move || {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Exit::Finished(status));
+            }
+            if Instant::now() >= deadline {
+                let _ = crate::os::kill_process_group(child.id());
+                child.kill()?;
+                child.wait()?;
+                return Ok(Exit::TimedOut);
+            }
+            thread::sleep(WAIT_INTERVAL);
+        }
+    })
+}
+
+fn exit_status(watchdog: thread::JoinHandle<io::Result<Exit>>) -> Result<(), String> {
+    match watchdog.join() {
+        Ok(Ok(Exit::Finished(status))) if status.success() => Ok(()),
+        Ok(Ok(Exit::Finished(status))) => Err(format!("it exited with {}", status)),
+        Ok(Ok(Exit::TimedOut)) => Err("it ran into the timeout and was killed".to_string()),
+        Ok(Err(e)) => Err(format!("couldn't wait for it: {}", e)),
+        Err(_) => Err("the watchdog thread panicked".to_string()),
+    }
+}
+
+// The program's headers, up to the blank line, as a response head.
+fn read_head(stdout: &mut BufReader<ChildStdout>) -> io::Result<ResponseBuilder> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut status = None;
+    let mut headers = Vec::new();
+    let mut head_size = 0;
+
+    loop {
+        let mut line = String::new();
+        let limit = (MAX_HEAD_SIZE - head_size + 1) as u64;
+        let n = stdout.by_ref().take(limit).read_line(&mut line)?;
+        if n == 0 {
+            return Err(invalid("output ended before the blank line after the headers".to_string()));
+        }
+        head_size += n;
+        if head_size > MAX_HEAD_SIZE {
+            return Err(invalid(format!("headers longer than {} bytes", MAX_HEAD_SIZE)));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        // A CR inside a line would end the header early in the response.
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) if !line.contains(|c: char| c.is_control()) => (name.trim(), value.trim()),
+            _ => return Err(invalid(format!("malformed header: {:?}", line))),
+        };
+
+        if name.eq_ignore_ascii_case("Status") {
+            status = Some(parse_status(value).ok_or_else(|| invalid(format!("unsupported status: {}", value)))?);
+        } else if ["Content-Length", "Transfer-Encoding", "Connection"].iter().any(|framing| framing.eq_ignore_ascii_case(name)) {
+            // how the body is framed is up to us (chunked, or buffered with our own length).
+            continue;
+        } else {
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    let has_location = headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Location"));
+    let mut head = match status {
+        Some((status, reason)) => {
+            let mut head = ResponseBuilder::new(status);
+            if let Some(reason) = reason {
+                head.reason(&reason);
+            }
+            head
+        }
+        None if has_location => ResponseBuilder::new(StatusCode::Found),
+        None => ResponseBuilder::new(StatusCode::Ok),
+    };
+    for (name, value) in &headers {
+        head.header(name, value);
+    }
+    Ok(head)
+}
+
+// "404 Not Found" => (NotFound, Some("Not Found"))
+fn parse_status(value: &str) -> Option<(StatusCode, Option<String>)> {
+    let (code, reason) = match value.split_once(' ') {
+        Some((code, reason)) => (code, Some(reason.trim().to_string()).filter(|reason| !reason.is_empty())),
+        None => (value, None),
+    };
+    let status = StatusCode::from_code(code.parse().ok()?)?;
+    Some((status, reason))
+}
+
+fn internal_error(response: &mut Response) -> io::Result<()> {
+    response.send(
+        ResponseBuilder::new(StatusCode::InternalServerError)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body_str(&format!("{}\n", StatusCode::InternalServerError)),
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::router::{exchange, Router};
+    use std::net::{TcpListener, TcpStream};
+
+    fn script(source: &str) -> CgiHandler {
+        CgiHandler::new("/bin/sh").arg("-c").arg(source)
+    }
+
+    fn router(cgi: CgiHandler) -> Router {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", move |request, response| cgi.handle(request, response));
+        router.route(Method::Post, "/", |_, response| response.send(&mut ResponseBuilder::new(StatusCode::Ok)));
+        router
+    }
+
+    #[test]
+    fn headers_and_body_come_from_stdout() {
+        let cgi = script("printf 'Status: 404 Gone Fishing\\r\\nContent-Type: text/plain\\r\\nContent-Length: 99\\r\\n\\r\\n'; printf \"$QUERY_STRING $REQUEST_METHOD $HTTP_X_TEST $SECRET$HOME\"")
+            .env("SECRET", "given");
+
+        let response = exchange(&router(cgi), "GET /?a=1 HTTP/1.1\r\nX-Test: yes\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Gone Fishing\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\n11\r\na=1 GET yes given\r\n0\r\n\r\n"), "{:?}", response);
+        assert!(!response.contains("99"));
+    }
+
+    #[test]
+    fn the_request_body_goes_to_stdin() {
+        let cgi = script("printf 'Content-Type: text/plain\\n\\n'; cat");
+        let mut router = Router::new();
+        router.route(Method::Post, "/", move |request, response| cgi.handle(request, response));
+
+        let mut request = Request::parse(b"POST / HTTP/1.0\r\n\r\n").unwrap();
+        request.body = b"echo me".to_vec();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        router.dispatch(&request, &mut server).unwrap();
+        drop(server);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\nContent-Length: 7\r\n\r\necho me"), "{:?}", response);
+    }
+
+    #[test]
+    fn failures_before_the_body_are_a_500() {
+        for source in ["exit 3", "printf 'Content-Type: text/plain\\n'", "printf 'Status: 299\\n\\n'"] {
+            let response = exchange(&router(script(source)), "GET / HTTP/1.1\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}: {:?}", source, response);
+        }
+        // HTTP/1.0 waits for the exit code before sending anything.
+        let response = exchange(&router(script("printf '\\nhalf'; exit 1")), "GET / HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{:?}", response);
+    }
+
+    #[test]
+    fn failures_while_streaming_cut_the_body_short() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        let request = Request::parse(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(router(script("printf '\\nhalf'; exit 1")).dispatch(&request, &mut server).is_err());
+        drop(server);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\n4\r\nhalf\r\n"), "{:?}", response);
+    }
+
+    #[test]
+    fn slow_programs_are_killed() {
+        let cgi = script("sleep 5").timeout(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let response = exchange(&router(cgi), "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{:?}", response);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
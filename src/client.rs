@@ -0,0 +1,688 @@
+use std::fmt;
+use std::io::{self, prelude::*, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/* # A minimal HTTP/1.1 client for outbound requests from handlers!
+HttpClient::new().get("http://api.local:8080/users?page=2")
+1. Parse the URL into host, port and path (+ query).
+2. Open a TcpStream and write a small HTTP/1.1 request with Connection: close.
+3. Read the whole response, parse the status line and headers,
+and decode the body (Content-Length, chunked, or read-until-close).
+4. Follow redirects (301/302/303/307/308) up to 5 hops by default.
+HttpClient::new().open("GET", "http://api.local:8080/big", &headers, b"") is for bodies
+not to hold in memory (a proxy's): one request, the head parsed, and the body read from
+the connection as the StreamingResponse is read, de-chunked.
+Nothing waits forever or reads without bound: connecting gives up after 10s,
+each read or write after 30s, and a response bigger than 10 MiB is an error (for open,
+a body bigger than that: reading past it is an InvalidData error).
+Only plain http:// is supported: there is no TLS in this crate yet. */
+
+const DEFAULT_MAX_REDIRECTS: usize = 5;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_RESPONSE_SIZE: usize = 10 * 1024 * 1024;
+// For open, where the limit is the body's.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_CHUNK_LINE: u64 = 1024;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    InvalidUrl(String),
+    UnsupportedScheme(String),
+    InvalidResponse(String),
+    TooManyRedirects,
+    ResponseTooLarge,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "I/O error: {}", e),
+            ClientError::InvalidUrl(url) => write!(f, "invalid URL: {}", url),
+            ClientError::UnsupportedScheme(scheme) => write!(f, "unsupported URL scheme: {}", scheme),
+            ClientError::InvalidResponse(reason) => write!(f, "invalid response: {}", reason),
+            ClientError::TooManyRedirects => write!(f, "too many redirects"),
+            ClientError::ResponseTooLarge => write!(f, "response too large"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        ClientError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl ClientResponse {
+    // Header names are case-insensitive; returns the first match.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    max_redirects: usize,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_response_size: usize,
+}
+
+impl Default for HttpClient {
+    fn default() -> HttpClient {
+        HttpClient::new()
+    }
+}
+
+impl HttpClient {
+    pub fn new() -> HttpClient {
+        HttpClient {
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+        }
+    }
+
+    // 0 disables redirect following: 3xx responses are returned as-is.
+    pub fn max_redirects(mut self, max_redirects: usize) -> HttpClient {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    // How long to wait for the TCP connection to be established.
+    pub fn connect_timeout(mut self, timeout: Duration) -> HttpClient {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    // Read/write timeout for each connection.
+    pub fn timeout(mut self, timeout: Duration) -> HttpClient {
+        self.timeout = timeout;
+        self
+    }
+
+    // The most bytes (head and body) a single response may take.
+    pub fn max_response_size(mut self, max_response_size: usize) -> HttpClient {
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    pub fn get(&self, url: &str) -> Result<ClientResponse, ClientError> {
+        self.request("GET", url, None)
+    }
+
+    pub fn post(&self, url: &str, body: &[u8], content_type: &str) -> Result<ClientResponse, ClientError> {
+        self.request("POST", url, Some((body, content_type)))
+    }
+
+    pub fn request(&self, method: &str, url: &str, body: Option<(&[u8], &str)>) -> Result<ClientResponse, ClientError> {
+        let mut method = method.to_string();
+        let mut body = body;
+        let mut url = Url::parse(url)?;
+
+        for _ in 0..=self.max_redirects {
+            let response = self.send_once(&method, &url, body)?;
+
+            let location = match (response.status, response.header("Location")) {
+                (301 | 302 | 303 | 307 | 308, Some(location)) if self.max_redirects > 0 => location.to_string(),
+                _ => return Ok(response),
+            };
+
+            // 307/308 repeat the request as-is; the others switch to a body-less GET
+            // (what browsers do for 301/302 after a POST).
+            if !matches!(response.status, 307 | 308) && method != "HEAD" {
+                method = "GET".to_string();
+                body = None;
+            }
+            url = url.join(&location)?;
+        }
+
+        Err(ClientError::TooManyRedirects)
+    }
+
+    fn send_once(&self, method: &str, url: &Url, body: Option<(&[u8], &str)>) -> Result<ClientResponse, ClientError> {
+        let mut stream = self.connect(url)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: surff/{}\r\nAccept: */*\r\nConnection: close\r\n",
+            method, url.path, url.host_header(), env!("CARGO_PKG_VERSION"),
+        );
+        if let Some((data, content_type)) = body {
+            request.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n", content_type, data.len()));
+        }
+        request.push_str("\r\n");
+
+        stream.write_all(request.as_bytes())?;
+        if let Some((data, _)) = body {
+            stream.write_all(data)?;
+        }
+        stream.flush()?;
+
+        // One byte over the limit is enough to know it's too large.
+        let mut raw = Vec::new();
+        stream.take(self.max_response_size as u64 + 1).read_to_end(&mut raw)?;
+        if raw.len() > self.max_response_size {
+            return Err(ClientError::ResponseTooLarge);
+        }
+
+        parse_response(&raw, method == "HEAD")
+    }
+
+    // `headers` go out as they are, after Host and before Content-Length (when there's
+    // a body) and Connection: close. Redirects are the caller's, like every other status.
+    pub fn open(&self, method: &str, url: &str, headers: &[(String, String)], body: &[u8]) -> Result<StreamingResponse, ClientError> {
+        let url = Url::parse(url)?;
+        let mut stream = self.connect(&url)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, url.path, url.host_header());
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !body.is_empty() {
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("Connection: close\r\n\r\n");
+        let mut request = request.into_bytes();
+        request.extend_from_slice(body);
+        stream.write_all(&request)?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        // 100 Continue and the like come before the real head: skip them.
+        let mut response = loop {
+            let response = parse_response(&read_head(&mut reader)?, true)?;
+            if !(100..200).contains(&response.status) || response.status == 101 {
+                break response;
+            }
+        };
+        let content_length = match response.header("Content-Length") {
+            Some(length) => Some(length.parse::<u64>().map_err(|_| ClientError::InvalidResponse("malformed Content-Length".to_string()))?),
+            None => None,
+        };
+        let chunked = response.header("Transfer-Encoding").is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+        let no_body = method == "HEAD" || matches!(response.status, 101..=199 | 204 | 304);
+        let body = match (no_body, chunked, content_length) {
+            (true, _, _) => Body::Length(reader.take(0)),
+            (false, true, _) => Body::Chunked { reader, remaining: 0, done: false },
+            (false, false, Some(length)) => Body::Length(reader.take(length)),
+            (false, false, None) => Body::UntilClose(reader),
+        };
+        Ok(StreamingResponse {
+            status: response.status,
+            headers: std::mem::take(&mut response.headers),
+            content_length: if chunked || no_body { None } else { content_length },
+            body,
+            read: 0,
+            max_body_size: self.max_response_size as u64,
+        })
+    }
+
+    fn connect(&self, url: &Url) -> io::Result<TcpStream> {
+        connect(url, self.connect_timeout)
+    }
+}
+
+// TcpStream::connect_timeout takes a single address, so try each one the name
+// resolves to (IPv6 and IPv4, say) and keep the last error.
+pub(crate) fn connect(url: &Url, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", url.host));
+    for addr in (url.host.as_str(), url.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Everything up to the blank line that ends a head, that included.
+pub(crate) fn read_head(reader: &mut impl BufRead) -> Result<Vec<u8>, ClientError> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let read = reader.take((MAX_HEAD_SIZE - head.len()) as u64).read_until(b'\n', &mut head)?;
+        if read == 0 {
+            return Err(if head.len() >= MAX_HEAD_SIZE {
+                ClientError::ResponseTooLarge
+            } else {
+                ClientError::InvalidResponse("missing end of headers".to_string())
+            });
+        }
+    }
+    Ok(head)
+}
+
+enum Body {
+    Length(io::Take<BufReader<TcpStream>>),
+    // `remaining`: what's left of the chunk being read.
+    Chunked { reader: BufReader<TcpStream>, remaining: u64, done: bool },
+    UntilClose(BufReader<TcpStream>),
+}
+
+// A response whose body is still on the connection: reading it reads the body.
+pub struct StreamingResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    // None when the body is chunked, or ends when the connection does.
+    pub content_length: Option<u64>,
+    body: Body,
+    read: u64,
+    max_body_size: u64,
+}
+
+impl StreamingResponse {
+    // Header names are case-insensitive; returns the first match.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl fmt::Debug for StreamingResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamingResponse")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("content_length", &self.content_length)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Read for StreamingResponse {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &mut self.body {
+            Body::Length(body) => {
+                let read = body.read(buf)?;
+                if read == 0 && body.limit() > 0 && !buf.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body shorter than Content-Length"));
+                }
+                read
+            }
+            Body::Chunked { reader, remaining, done } => read_chunked(reader, remaining, done, buf)?,
+            Body::UntilClose(reader) => reader.read(buf)?,
+        };
+        self.read += read as u64;
+        if self.read > self.max_body_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "response too large"));
+        }
+        Ok(read)
+    }
+}
+
+// decode_chunked, a piece at a time: a size line, that much data and its CRLF, ... then
+// a 0 size, trailers (skipped) and a blank line.
+fn read_chunked(reader: &mut BufReader<TcpStream>, remaining: &mut u64, done: &mut bool, buf: &mut [u8]) -> io::Result<usize> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed chunked body");
+    let mut line = Vec::new();
+    if *done || buf.is_empty() {
+        return Ok(0);
+    }
+    if *remaining == 0 {
+        read_chunk_line(reader, &mut line)?;
+        let size_line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid())?;
+        let size_field = size_line.split(';').next().unwrap_or("").trim();
+        *remaining = u64::from_str_radix(size_field, 16).map_err(|_| invalid())?;
+        if *remaining == 0 {
+            loop {
+                read_chunk_line(reader, &mut line)?;
+                if line == b"\r\n" {
+                    *done = true;
+                    return Ok(0);
+                }
+            }
+        }
+    }
+    let wanted = buf.len().min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+    let read = reader.read(&mut buf[..wanted])?;
+    if read == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunked body ends early"));
+    }
+    *remaining -= read as u64;
+    if *remaining == 0 {
+        read_chunk_line(reader, &mut line)?;
+        if line != b"\r\n" {
+            return Err(invalid());
+        }
+    }
+    Ok(read)
+}
+
+// One line, CRLF included; an error if it doesn't end in one soon enough.
+fn read_chunk_line(reader: &mut BufReader<TcpStream>, line: &mut Vec<u8>) -> io::Result<()> {
+    line.clear();
+    reader.take(MAX_CHUNK_LINE).read_until(b'\n', line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed chunked body"));
+    }
+    Ok(())
+}
+
+// http://host[:port][/path][?query]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Url {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) path: String,
+}
+
+impl Url {
+    pub(crate) fn parse(url: &str) -> Result<Url, ClientError> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| ClientError::InvalidUrl(url.to_string()))?;
+        if !scheme.eq_ignore_ascii_case("http") {
+            return Err(ClientError::UnsupportedScheme(scheme.to_string()));
+        }
+
+        let split = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(split);
+        let path = match path {
+            "" => "/".to_string(),
+            p if p.starts_with('?') => format!("/{}", p),
+            p => p.to_string(),
+        };
+        // Fragments are never sent to the server.
+        let path = path.split('#').next().unwrap_or("/").to_string();
+
+        let (host, port) = match authority.rsplit_once(':') {
+            // "[::1]:8080" or "host:8080", but not a bare "[::1]".
+            Some((host, port)) if !port.ends_with(']') => {
+                let port = port.parse().map_err(|_| ClientError::InvalidUrl(url.to_string()))?;
+                (host, port)
+            },
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(ClientError::InvalidUrl(url.to_string()));
+        }
+
+        Ok(Url { host: host.to_string(), port, path })
+    }
+
+    // Resolves a Location header against this URL.
+    fn join(&self, location: &str) -> Result<Url, ClientError> {
+        if location.contains("://") {
+            return Url::parse(location);
+        }
+
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            // Relative to the current "directory".
+            let dir_end = self.path.split('?').next().unwrap_or("/").rfind('/').map_or(0, |i| i + 1);
+            format!("{}{}", &self.path[..dir_end], location)
+        };
+
+        Ok(Url { host: self.host.clone(), port: self.port, path })
+    }
+
+    pub(crate) fn host_header(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+
+        if self.port == 80 {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+pub(crate) fn parse_response(raw: &[u8], no_body: bool) -> Result<ClientResponse, ClientError> {
+    let invalid = |reason: &str| ClientError::InvalidResponse(reason.to_string());
+
+    let header_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid("missing end of headers"))?;
+    let head = std::str::from_utf8(&raw[..header_end]).map_err(|_| invalid("headers are not UTF-8"))?;
+    let rest = &raw[header_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or("");
+    let mut parts = status_line.splitn(3, ' ');
+    if !parts.next().unwrap_or("").starts_with("HTTP/") {
+        return Err(invalid("malformed status line"));
+    }
+    let status: u16 = parts
+        .next()
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("malformed status code"))?;
+
+    // 100 Continue, 103 Early Hints: interim heads, with the real one after them. (101
+    // is the answer itself, and open() reads a head at a time and skips these itself.)
+    if (100..200).contains(&status) && status != 101 && !rest.is_empty() {
+        return parse_response(rest, no_body);
+    }
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut response = ClientResponse { status, headers, body: Vec::new() };
+
+    if no_body || status == 204 || status == 304 || (100..200).contains(&status) {
+        return Ok(response);
+    }
+
+    let chunked = response
+        .header("Transfer-Encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+
+    response.body = if chunked {
+        decode_chunked(rest)?
+    } else if let Some(length) = response.header("Content-Length") {
+        let length: usize = length.parse().map_err(|_| invalid("malformed Content-Length"))?;
+        if rest.len() < length {
+            return Err(invalid("body shorter than Content-Length"));
+        }
+        rest[..length].to_vec()
+    } else {
+        rest.to_vec()
+    };
+
+    Ok(response)
+}
+
+// <size in hex>[;extensions]\r\n<data>\r\n ... 0\r\n[trailers]\r\n
+// The size comes from the server: "ffffffffffffffff" must not overflow size + 2.
+fn decode_chunked(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed chunked body");
+    let mut body = Vec::new();
+
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n").ok_or_else(invalid)?;
+        let size_line = std::str::from_utf8(&data[..line_end]).map_err(|_| invalid())?;
+        let size_field = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_field, 16).map_err(|_| invalid())?;
+        data = &data[line_end + 2..];
+
+        if size == 0 {
+            return Ok(body);
+        }
+        let end = size.checked_add(2).ok_or_else(invalid)?;
+        if end > data.len() || &data[size..end] != b"\r\n" {
+            return Err(invalid());
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[end..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let body = decode_chunked(b"3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\n\r\n").unwrap();
+        assert_eq!(body, b"abcde");
+    }
+
+    #[test]
+    fn rejects_chunk_sizes_past_the_data() {
+        let overflow = decode_chunked(b"ffffffffffffffff\r\nabc\r\n0\r\n\r\n").unwrap_err();
+        assert_eq!(overflow.kind(), io::ErrorKind::InvalidData);
+        let short = decode_chunked(b"10\r\nabc\r\n0\r\n\r\n").unwrap_err();
+        assert_eq!(short.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn caps_the_response_size() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n");
+            let _ = stream.write_all(&[b'x'; 4096]);
+        });
+
+        let client = HttpClient::new().max_response_size(1024);
+        let result = client.get(&format!("http://127.0.0.1:{}/", port));
+        assert!(matches!(result, Err(ClientError::ResponseTooLarge)), "{:?}", result);
+        server.join().unwrap();
+    }
+
+
+    #[test]
+    fn open_streams_the_body() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            for answer in [&b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\nX-Trailer: 1\r\n\r\n"[..], b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(answer);
+            }
+        });
+
+        let url = format!("http://127.0.0.1:{}/", port);
+        let mut response = HttpClient::new().open("GET", &url, &[], b"").unwrap();
+        assert_eq!((response.status, response.content_length), (200, None));
+        let mut body = String::new();
+        response.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "abcde");
+
+        let mut response = HttpClient::new().open("GET", &url, &[], b"").unwrap();
+        assert_eq!(response.content_length, Some(10));
+        let error = response.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        server.join().unwrap();
+    }
+
+
+    #[test]
+    fn parses_and_joins_urls() {
+        let url = Url::parse("http://Example.com:8080/a/b?x=1#top").unwrap();
+        assert_eq!(url, Url { host: "Example.com".to_string(), port: 8080, path: "/a/b?x=1".to_string() });
+        assert_eq!(url.host_header(), "Example.com:8080");
+        assert_eq!(Url::parse("http://example.com").unwrap().path, "/");
+        assert_eq!(Url::parse("http://example.com?q=1").unwrap().path, "/?q=1");
+        let v6 = Url::parse("http://[::1]:8080/x").unwrap();
+        assert_eq!((v6.host.as_str(), v6.port, v6.host_header()), ("::1", 8080, "[::1]:8080".to_string()));
+        assert_eq!(Url::parse("http://[::1]").unwrap().port, 80);
+        assert!(matches!(Url::parse("https://example.com/"), Err(ClientError::UnsupportedScheme(scheme)) if scheme == "https"));
+        assert!(matches!(Url::parse("example.com/"), Err(ClientError::InvalidUrl(_))));
+        assert!(matches!(Url::parse("http://:80/"), Err(ClientError::InvalidUrl(_))));
+        assert!(matches!(Url::parse("http://example.com:http/"), Err(ClientError::InvalidUrl(_))));
+
+        assert_eq!(url.join("c").unwrap().path, "/a/c");
+        assert_eq!(url.join("/d?e").unwrap().path, "/d?e");
+        let elsewhere = url.join("http://other:81/f").unwrap();
+        assert_eq!((elsewhere.host.as_str(), elsewhere.port, elsewhere.path.as_str()), ("other", 81, "/f"));
+    }
+
+    #[test]
+    fn skips_interim_heads() {
+        let response = parse_response(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </a.css>\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok", false).unwrap();
+        assert_eq!((response.status, response.body.as_slice()), (200, &b"ok"[..]));
+        assert_eq!(response.header("Link"), None);
+        let response = parse_response(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n\x81\x00", false).unwrap();
+        assert_eq!((response.status, response.body.len()), (101, 0));
+    }
+
+    // Answers each of `answers.len()` connections in turn, and hands back the request heads.
+    fn answering(answers: Vec<String>) -> (u16, std::thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for answer in answers {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 1024];
+                let read = stream.read(&mut request).unwrap();
+                requests.push(String::from_utf8_lossy(&request[..read]).into_owned());
+                let _ = stream.write_all(answer.as_bytes());
+            }
+            requests
+        });
+        (port, server)
+    }
+
+    #[test]
+    fn gives_up_after_too_many_redirects() {
+        // the first request and 5 redirects: 6 answers, all of them redirects.
+        let (port, server) = answering(vec!["HTTP/1.1 302 Found\r\nLocation: /again\r\nContent-Length: 0\r\n\r\n".to_string(); 6]);
+        let result = HttpClient::new().get(&format!("http://127.0.0.1:{}/", port));
+        assert!(matches!(result, Err(ClientError::TooManyRedirects)), "{:?}", result);
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET / ") && requests[5].starts_with("GET /again "), "{:?}", requests);
+    }
+
+    #[test]
+    fn a_303_after_a_post_is_a_get() {
+        let (port, server) = answering(vec![
+            "HTTP/1.1 303 See Other\r\nLocation: done\r\nContent-Length: 0\r\n\r\n".to_string(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone".to_string(),
+        ]);
+        let url = format!("http://127.0.0.1:{}/forms/contact", port);
+        let response = HttpClient::new().post(&url, b"name=surff", "application/x-www-form-urlencoded").unwrap();
+        assert_eq!((response.status, response.body.as_slice()), (200, &b"done"[..]));
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /forms/contact ") && requests[0].contains("Content-Length: 10\r\n"), "{:?}", requests);
+        // relative to /forms/, and without the form.
+        assert!(requests[1].starts_with("GET /forms/done "), "{:?}", requests);
+        assert!(!requests[1].contains("Content-Length") && !requests[1].contains("name=surff"), "{:?}", requests);
+    }
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::http::Request;
+
+/* # Real client IP behind reverse proxies!
+Behind a proxy, TcpStream::peer_addr() is the proxy's address.
+Proxies append the address they received the request from to
+X-Forwarded-For: client, proxy1, proxy2
+or the RFC 7239 header
+Forwarded: for=client, for=proxy1
+
+Anyone can send those headers, so they're only believed when the socket peer
+is one of our trusted proxies (Config's --trusted-proxy). The client is then the
+leftmost address in the chain that isn't a trusted proxy. Hops we can't read
+("unknown", obfuscated identifiers like "_hidden", or Forwarded elements without
+a for=) stay in the chain as unknown and are never picked. No usable address
+at all => the peer. Since the leftmost entry is whatever the first proxy
+received, that proxy should replace an incoming X-Forwarded-For rather than
+append to it. */
+
+// An address range in CIDR notation, e.g. "10.0.0.0/8" or "2001:db8::/32".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidIpNet(String);
+
+impl fmt::Display for InvalidIpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid network: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidIpNet {}
+
+impl IpNet {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<IpNet> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max {
+            return None;
+        }
+        Some(IpNet { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = InvalidIpNet;
+
+    // A bare address is a single-host network (/32 or /128).
+    fn from_str(s: &str) -> Result<IpNet, InvalidIpNet> {
+        let invalid = || InvalidIpNet(s.to_string());
+
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => {
+                let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+                (addr, len.parse().map_err(|_| invalid())?)
+            },
+            None => {
+                let addr: IpAddr = s.parse().map_err(|_| invalid())?;
+                (addr, if addr.is_ipv4() { 32 } else { 128 })
+            },
+        };
+
+        IpNet::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpExtractor {
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl ClientIpExtractor {
+    pub fn new(trusted_proxies: Vec<IpNet>) -> ClientIpExtractor {
+        ClientIpExtractor { trusted_proxies }
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    // Every X-Forwarded-For (and Forwarded) line of the request counts, joined in order.
+    pub fn client_ip_for(&self, peer: IpAddr, request: &Request) -> IpAddr {
+        let joined = |name: &str| {
+            let values: Vec<&str> = request
+                .headers
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+                .collect();
+            if values.is_empty() { None } else { Some(values.join(", ")) }
+        };
+        self.client_ip(peer, joined("X-Forwarded-For").as_deref(), joined("Forwarded").as_deref())
+    }
+
+    // `peer` is the socket address; the header values are passed as received (if present).
+    // Forwarded wins over X-Forwarded-For when both are sent.
+    pub fn client_ip(&self, peer: IpAddr, x_forwarded_for: Option<&str>, forwarded: Option<&str>) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+
+        // None for a hop we can't read.
+        let chain: Vec<Option<IpAddr>> = match (forwarded, x_forwarded_for) {
+            (Some(header), _) => forwarded_for_values(header).into_iter().map(|hop| hop.and_then(parse_node)).collect(),
+            (None, Some(header)) => header.split(',').map(|hop| parse_node(hop.trim())).collect(),
+            (None, None) => return peer,
+        };
+
+        chain
+            .into_iter()
+            .flatten()
+            .find(|ip| !self.is_trusted(*ip))
+            .unwrap_or(peer)
+    }
+}
+
+// Forwarded: for=192.0.2.60;proto=http;by=203.0.113.43, for="[2001:db8::1]:4711"
+// One entry per element, None for an element without for=.
+fn forwarded_for_values(header: &str) -> Vec<Option<&str>> {
+    header
+        .split(',')
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    Some(value.trim().trim_matches('"'))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+// "1.2.3.4", "1.2.3.4:8080", "2001:db8::1", "[2001:db8::1]:4711"
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .and_then(|ip| ip.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn extractor() -> ClientIpExtractor {
+        ClientIpExtractor::new(vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()])
+    }
+
+    #[test]
+    fn parses_networks() {
+        let net: IpNet = "192.168.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("192.168.4.1")));
+        assert!(!net.contains(ip("192.169.0.1")));
+        assert!("10.0.0.1".parse::<IpNet>().unwrap().contains(ip("10.0.0.1")));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn single_proxy() {
+        let client = extractor().client_ip(ip("10.0.0.1"), Some("203.0.113.7"), None);
+        assert_eq!(client, ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn chained_proxies() {
+        let xff = "203.0.113.7, 10.1.1.1, 10.2.2.2";
+        assert_eq!(extractor().client_ip(ip("10.0.0.1"), Some(xff), None), ip("203.0.113.7"));
+
+        // A trusted hop at the front doesn't count as the client.
+        let xff = "10.9.9.9, 198.51.100.2, 10.1.1.1";
+        assert_eq!(extractor().client_ip(ip("10.0.0.1"), Some(xff), None), ip("198.51.100.2"));
+
+        let forwarded = "for=\"[2001:db8::1]:4711\", for=192.0.2.60;proto=http;by=10.0.0.1";
+        assert_eq!(extractor().client_ip(ip("10.0.0.1"), Some("1.1.1.1"), Some(forwarded)), ip("192.0.2.60"));
+    }
+
+    #[test]
+    fn unknown_hops_are_never_the_client() {
+        let forwarded = "for=unknown, proto=https, for=198.51.100.2";
+        assert_eq!(forwarded_for_values(forwarded), vec![Some("unknown"), None, Some("198.51.100.2")]);
+        assert_eq!(extractor().client_ip(ip("10.0.0.1"), None, Some(forwarded)), ip("198.51.100.2"));
+        assert_eq!(extractor().client_ip(ip("10.0.0.1"), Some("unknown, _hidden"), None), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn repeated_header_lines_are_one_chain() {
+        let raw = b"GET / HTTP/1.1\r\nX-Forwarded-For: 10.5.5.5\r\nX-Forwarded-For: 198.51.100.2, 10.1.1.1\r\n\r\n";
+        let request = Request::parse(raw).unwrap();
+        assert_eq!(extractor().client_ip_for(ip("10.0.0.1"), &request), ip("198.51.100.2"));
+    }
+
+    #[test]
+    fn spoofed_headers_from_untrusted_peers_are_ignored() {
+        let client = extractor().client_ip(ip("198.51.100.9"), Some("127.0.0.1"), Some("for=127.0.0.1"));
+        assert_eq!(client, ip("198.51.100.9"));
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+
+use crate::gzip;
+use crate::http::{Request, ResponseBuilder, StatusCode};
+use crate::router::Middleware;
+
+/* # Compressing responses, as the client's Accept-Encoding allows!
+router.wrap(GzipMiddleware::new().config(CompressionConfig::default().quality("text/html", 9)));
+GET / HTTP/1.1, Accept-Encoding: gzip, br;q=0.5
+=> Content-Encoding: gzip, Vary: Accept-Encoding, and the body gzipped (see gzip.rs).
+1. negotiate picks a content coding out of Accept-Encoding (RFC 9110, 12.5.3):
+the highest q wins, and between equal q the one listed first among what the server has
+(br before gzip). q=0 rules a coding out, * is every coding not listed by name, and
+identity (no coding) is what's left, unless identity;q=0 or *;q=0 rules it out too.
+No Accept-Encoding at all gets identity: that's what old clients understand.
+2. GzipMiddleware compresses buffered bodies of text-like types (text/..., JSON,
+JavaScript, XML, SVG) that aren't already encoded and aren't a 206 (a range of the
+uncompressed bytes). Streamed bodies (send_file, chunks) and images, which are
+compressed already, go out as they are.
+3. How hard it tries, and from what size on, is the CompressionConfig's, per type:
+html 6, json 4, javascript 5, css 6 and svg 9 (compressed once, cached for long),
+default_quality (6) for the rest; nothing under default_min_size (256 bytes) unless a
+type says otherwise. Qualities are an encoder's to read: gzip's go up to 9.
+4. Every response it could have compressed gets Vary: Accept-Encoding, compressed or not,
+so caches keep the two apart. A strong ETag becomes weak (W/"..."): the bytes are no
+longer the ones it was made for.
+Brotli is a ContentCoding, so negotiate can prefer it for a handler with br bytes to
+send (compressed ahead of time), but there's no middleware for it: surff has no Brotli
+encoder. */
+
+const DEFAULT_MIN_SIZE: usize = 256;
+const DEFAULT_QUALITY: u32 = 6;
+const DEFAULT_QUALITIES: &[(&str, u32)] = &[
+    ("text/html", 6),
+    ("application/json", 4),
+    ("application/javascript", 5),
+    ("text/javascript", 5),
+    ("text/css", 6),
+    ("image/svg+xml", 9),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentCoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl ContentCoding {
+    // As in Accept-Encoding and Content-Encoding.
+    pub fn token(&self) -> &'static str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Identity => "identity",
+        }
+    }
+}
+
+// `available`: the codings the server can send, most preferred first.
+// None: not even identity is acceptable (a 406, if the handler wants to be strict).
+pub fn negotiate(accept_encoding: Option<&str>, available: &[ContentCoding]) -> Option<ContentCoding> {
+    let Some(accept_encoding) = accept_encoding else {
+        return Some(ContentCoding::Identity);
+    };
+    // (coding, q in thousandths): q=0.5 is 500, so equal values compare equal.
+    let mut listed = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let coding = parts.next().unwrap_or("").to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let q = parts.find_map(|parameter| parameter.strip_prefix("q=").or_else(|| parameter.strip_prefix("Q=")));
+        match q.map_or(Some(1000), parse_q) {
+            Some(q) => listed.push((coding, q)),
+            None => continue,
+        }
+    }
+    let q_of = |token: &str| {
+        let named = listed.iter().find(|(coding, _)| coding == token || (token == "gzip" && coding == "x-gzip"));
+        named.or_else(|| listed.iter().find(|(coding, _)| coding == "*")).map(|&(_, q)| q)
+    };
+
+    let mut best: Option<(ContentCoding, u32)> = None;
+    for &coding in available.iter().filter(|&&coding| coding != ContentCoding::Identity) {
+        let q = q_of(coding.token()).unwrap_or(0);
+        if q > 0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((coding, q));
+        }
+    }
+    match best {
+        Some((coding, _)) => Some(coding),
+        // identity is acceptable unless it's ruled out, by name or by *.
+        None => (q_of("identity") != Some(0)).then_some(ContentCoding::Identity),
+    }
+}
+
+// "0.5" => 500; None for anything that isn't 0 to 1 with at most 3 decimals.
+fn parse_q(q: &str) -> Option<u32> {
+    let (whole, fraction) = q.split_once('.').unwrap_or((q, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths = format!("{:0<3}", fraction).parse::<u32>().ok()?;
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+// "Text/HTML; charset=utf-8" => "text/html"
+fn essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+// Types worth compressing: text, and the text formats that don't say text/.
+fn is_compressible(content_type: &str) -> bool {
+    let mime = essence(content_type);
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(mime.as_str(), "application/json" | "application/javascript" | "application/xml" | "image/svg+xml")
+}
+
+// W/"abc" for "abc"; weak ones stay as they are.
+fn weaken_etag(response: &mut ResponseBuilder) {
+    if let Some(etag) = response.header_value("ETag").filter(|etag| !etag.starts_with("W/")) {
+        let weak = format!("W/{}", etag);
+        response.set_header("ETag", &weak);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionConfig {
+    // by type, without parameters: "text/html".
+    qualities: BTreeMap<String, u32>,
+    min_sizes: BTreeMap<String, usize>,
+    default_quality: u32,
+    default_min_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig {
+            qualities: DEFAULT_QUALITIES.iter().map(|&(mime, quality)| (mime.to_string(), quality)).collect(),
+            min_sizes: BTreeMap::new(),
+            default_quality: DEFAULT_QUALITY,
+            default_min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn quality(mut self, mime: &str, quality: u32) -> CompressionConfig {
+        self.qualities.insert(essence(mime), quality);
+        self
+    }
+
+    // For the types without a quality of their own.
+    pub fn default_quality(mut self, quality: u32) -> CompressionConfig {
+        self.default_quality = quality;
+        self
+    }
+
+    pub fn min_size(mut self, mime: &str, bytes: usize) -> CompressionConfig {
+        self.min_sizes.insert(essence(mime), bytes);
+        self
+    }
+
+    pub fn default_min_size(mut self, bytes: usize) -> CompressionConfig {
+        self.default_min_size = bytes;
+        self
+    }
+
+    // `content_type` as in the header: parameters and case don't matter.
+    pub fn quality_for(&self, content_type: &str) -> u32 {
+        self.qualities.get(&essence(content_type)).copied().unwrap_or(self.default_quality)
+    }
+
+    pub fn min_size_for(&self, content_type: &str) -> usize {
+        self.min_sizes.get(&essence(content_type)).copied().unwrap_or(self.default_min_size)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GzipMiddleware {
+    config: CompressionConfig,
+}
+
+impl GzipMiddleware {
+    pub fn new() -> GzipMiddleware {
+        GzipMiddleware { config: CompressionConfig::default() }
+    }
+
+    pub fn config(mut self, config: CompressionConfig) -> GzipMiddleware {
+        self.config = config;
+        self
+    }
+
+    // The Content-Type, if it's one to compress at this size.
+    fn could_compress<'a>(&self, response: &'a ResponseBuilder) -> Option<&'a str> {
+        let content_type = response.header_value("Content-Type").filter(|content_type| is_compressible(content_type))?;
+        let compress = response.body().is_some_and(|body| body.len() >= self.config.min_size_for(content_type))
+            && response.status() != StatusCode::PartialContent
+            && !response.has_header("Content-Range")
+            && !response.has_header("Content-Encoding");
+        compress.then_some(content_type)
+    }
+}
+
+impl Default for GzipMiddleware {
+    fn default() -> GzipMiddleware {
+        GzipMiddleware::new()
+    }
+}
+
+impl Middleware for GzipMiddleware {
+    fn on_response(&self, request: &Request, response: &mut ResponseBuilder) {
+        let Some(content_type) = self.could_compress(response) else {
+            return;
+        };
+        // gzip's levels go up to 9; higher qualities are for encoders that have them.
+        let level = self.config.quality_for(content_type).min(9);
+        response.header("Vary", "Accept-Encoding");
+        if negotiate(request.header("Accept-Encoding"), &[ContentCoding::Gzip]) != Some(ContentCoding::Gzip) {
+            return;
+        }
+        let body = response.body().unwrap_or_default();
+        let compressed = gzip::compress(body, level);
+        if compressed.len() >= body.len() {
+            return;
+        }
+        // a Content-Length set by hand was for the uncompressed bytes.
+        response.remove_header("Content-Length").header("Content-Encoding", "gzip").body_bytes(compressed);
+        weaken_etag(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::router::{self, Router};
+
+    #[test]
+    fn negotiates_as_rfc_9110_says() {
+        let both = &[ContentCoding::Brotli, ContentCoding::Gzip];
+        let cases = [
+            (None, Some(ContentCoding::Identity)),
+            (Some(""), Some(ContentCoding::Identity)),
+            (Some("gzip"), Some(ContentCoding::Gzip)),
+            (Some("gzip, deflate, br"), Some(ContentCoding::Brotli)),
+            (Some("br;q=0.8, gzip"), Some(ContentCoding::Gzip)),
+            (Some("gzip;q=0.5, br;q=0.500"), Some(ContentCoding::Brotli)),
+            (Some("br;q=0, *"), Some(ContentCoding::Gzip)),
+            (Some("x-gzip"), Some(ContentCoding::Gzip)),
+            (Some("GZIP;Q=1.0"), Some(ContentCoding::Gzip)),
+            (Some("deflate"), Some(ContentCoding::Identity)),
+            (Some("gzip;q=2, br;q=0.x"), Some(ContentCoding::Identity)),
+            (Some("identity;q=0"), None),
+            (Some("*;q=0"), None),
+            (Some("*;q=0, identity"), Some(ContentCoding::Identity)),
+        ];
+        for (accept_encoding, expected) in cases {
+            assert_eq!(negotiate(accept_encoding, both), expected, "{:?}", accept_encoding);
+        }
+        assert_eq!(negotiate(Some("br"), &[ContentCoding::Gzip]), Some(ContentCoding::Identity));
+    }
+
+    fn gzip_router(content_type: &'static str, body: &'static str) -> Router {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", move |_request, response| {
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Content-Type", content_type).header("ETag", "\"v1\"").body_str(body))
+        });
+        router.wrap(GzipMiddleware::new());
+        router
+    }
+
+    // (head, body)
+    fn exchange(router: &Router, accept_encoding: &str) -> (String, Vec<u8>) {
+        let raw = format!("GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", accept_encoding);
+        let response = router::exchange_bytes(router, &raw);
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        (String::from_utf8(response[..end].to_vec()).unwrap(), response[end..].to_vec())
+    }
+
+    #[test]
+    fn gzips_text_for_clients_that_accept_it() {
+        let html: &'static str = "<li><a href=\"/items\">An item</a></li>\n".repeat(50).leak();
+        let router = gzip_router("text/html; charset=utf-8", html);
+
+        let (head, body) = exchange(&router, "gzip, br");
+        assert!(head.contains("\r\nContent-Encoding: gzip\r\n"), "{}", head);
+        assert!(head.contains("\r\nVary: Accept-Encoding\r\n"), "{}", head);
+        assert!(head.contains("\r\nETag: W/\"v1\"\r\n"), "{}", head);
+        assert!(head.contains(&format!("\r\nContent-Length: {}\r\n", body.len())), "{}", head);
+        assert!(body.len() < html.len() / 10);
+        assert_eq!(gzip::decompress(&body, 1 << 20).unwrap(), html.as_bytes());
+
+        // no gzip: the same bytes, and still the Vary.
+        let (head, body) = exchange(&router, "gzip;q=0");
+        assert!(!head.contains("Content-Encoding") && head.contains("\r\nVary: Accept-Encoding\r\n"), "{}", head);
+        assert!(head.contains("\r\nETag: \"v1\"\r\n"), "{}", head);
+        assert_eq!(body, html.as_bytes());
+    }
+
+    #[test]
+    fn leaves_small_and_binary_bodies_alone() {
+        for router in [gzip_router("text/plain", "short"), gzip_router("image/png", "\u{1}".repeat(1000).leak())] {
+            let (head, _) = exchange(&router, "gzip");
+            assert!(!head.contains("Content-Encoding") && !head.contains("Vary"), "{}", head);
+        }
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |_request, response| {
+            let body = "x".repeat(1000);
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Content-Type", "text/plain").header("Content-Encoding", "br").body_str(&body))
+        });
+        router.wrap(GzipMiddleware::new());
+        let (head, body) = exchange(&router, "gzip");
+        assert!(head.contains("\r\nContent-Encoding: br\r\n") && body.len() == 1000, "{}", head);
+    }
+
+
+    #[test]
+    fn compression_config_has_per_type_qualities_and_sizes() {
+        let config = CompressionConfig::default();
+        let qualities = ["text/html; charset=utf-8", "application/json", "application/javascript", "Text/CSS", "image/svg+xml", "text/plain"]
+            .map(|content_type| config.quality_for(content_type));
+        assert_eq!(qualities, [6, 4, 5, 6, 9, 6]);
+        assert_eq!(config.min_size_for("application/json"), 256);
+
+        let config = config.quality("text/html", 1).default_quality(3).min_size("Application/JSON", 0).default_min_size(1024);
+        assert_eq!((config.quality_for("text/html"), config.quality_for("text/plain")), (1, 3));
+        assert_eq!((config.min_size_for("application/json; charset=utf-8"), config.min_size_for("text/html")), (0, 1024));
+    }
+
+    #[test]
+    fn gzip_middleware_follows_its_config() {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |request, response| {
+            let content_type = if request.query.as_deref() == Some("json") { "application/json" } else { "text/html" };
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Content-Type", content_type).body_str(&"{\"a\": 1}".repeat(8)))
+        });
+        // 64 bytes: only JSON is compressed, and at level 0 (stored, and so not smaller).
+        router.wrap(GzipMiddleware::new().config(CompressionConfig::default().min_size("application/json", 10).quality("application/json", 0)));
+        let get = |path: &str| router::exchange(&router, &format!("GET {} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n", path));
+        assert!(!get("/").contains("Vary"));
+        let stored = get("/?json");
+        assert!(stored.contains("\r\nVary: Accept-Encoding\r\n") && !stored.contains("Content-Encoding"), "{}", stored);
+
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |_request, response| {
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Content-Type", "application/json").body_str(&"{\"a\": 1}".repeat(8)))
+        });
+        router.wrap(GzipMiddleware::new().config(CompressionConfig::default().min_size("application/json", 10)));
+        assert!(router::exchange(&router, "GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n").contains("\r\nContent-Encoding: gzip\r\n"));
+    }
+}
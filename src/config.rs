@@ -0,0 +1,443 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::client_ip::IpNet;
+
+/* # Command-line configuration!
+surff --bind 127.0.0.1:8080 --threads 8 --static-root ./public
+Flags can also be written as --threads=8. --bind can be repeated to listen on
+several addresses at once, --trusted-proxy to trust several proxies. Everything is optional:
+the defaults are what the server used to hardcode. Errors come back as a
+message (with the usage text attached) for main to print before exiting.
+--help isn't an error: it comes back as Action::Help, so main can print the
+usage to stdout and exit with 0. --dry-run comes back as Action::DryRun, for
+main to check the config (Server::validate_config) instead of serving it. */
+
+pub const USAGE: &str = "\
+Usage: surff [--bind <addr:port>]... [--threads <n>] [--io-threads <n>] [--static-root <path>] [--routes <file>] [--trusted-proxy <cidr>]...
+             [--backlog <n>] [--max-queued <n>] [--tcp-keepalive <idle,interval,count> | --no-tcp-keepalive]
+             [--keepalive-idle-timeout <secs>] [--handler-timeout <secs>] [--debug-endpoints]
+             [--log-sample-rate <n>] [--slow-request-threshold <ms>] [--log-file <path> [--log-max-bytes <n>]]
+             [--admin-bind <addr:port> --admin-token <token>] [--dry-run]
+
+Options:
+  --bind <addr:port>     address to listen on, may be repeated (default: 0.0.0.0:1998)
+  --threads <n>          number of worker threads (default: 4)
+  --io-threads <n>       threads that read requests (bodies included) before a worker
+                         answers them, 0 to read on the workers (default: 16)
+  --static-root <path>   directory served under /static (default: .)
+  --routes <file>        also serve the files and directories a TOML file lists
+                         (see surff::router::Routes)
+  --trusted-proxy <cidr> proxy whose X-Forwarded-For/Forwarded headers are believed,
+                         may be repeated (default: none)
+  --backlog <n>          connections the kernel queues before they're accepted (default: 1024)
+  --max-queued <n>       connections waiting for a worker before new ones get a 503 (default: 256)
+  --tcp-keepalive <idle,interval,count>
+                         TCP keepalive probes: seconds before the first, seconds between
+                         them, and how many may go unanswered (default: 60,10,5)
+  --no-tcp-keepalive     don't send TCP keepalive probes
+  --keepalive-idle-timeout <secs>
+                         how long a kept-alive connection may wait for its next request
+                         before it's closed (default: 5)
+  --handler-timeout <secs>
+                         give up on handlers that take longer, with a 503 if nothing was
+                         sent yet, 0 for no limit (default: 0)
+  --debug-endpoints      serve GET /debug/pool to loopback clients
+  --log-sample-rate <n>  log 1 in n requests; errors, slow requests and new clients
+                         are always logged (default: 1, every request)
+  --slow-request-threshold <ms>
+                         requests taking longer are always logged (default: 1000)
+  --log-file <path>      write the request log to a file as JSON Lines, not to stdout
+  --log-max-bytes <n>    rotate the log file to <path>.1 past this size, 0 for never
+                         (default: 0)
+  --admin-bind <addr:port>
+                         where the admin API listens (see surff::admin), needs --admin-token
+  --admin-token <token>  bearer token the admin API requires
+  --dry-run              check the configuration and exit, without binding anything:
+                         1 on errors, 0 otherwise (warnings included)
+  -h, --help             print this message";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub binds: Vec<SocketAddr>,
+    pub threads: usize,
+    // 0: the workers read requests themselves (see server/mod.rs).
+    pub io_threads: usize,
+    pub static_root: PathBuf,
+    // --routes: a TOML route table (see router/routes.rs).
+    pub routes_file: Option<PathBuf>,
+    pub trusted_proxies: TrustedProxiesConfig,
+    pub accept_backlog: u32,
+    pub max_queued_connections: usize,
+    // None: no TCP keepalive probes.
+    pub tcp_keepalive: Option<TcpKeepAliveConfig>,
+    // Between requests on one connection; the watcher closes it after that (server/watcher.rs).
+    pub keepalive_idle_timeout: Duration,
+    // 0: handlers may take as long as they like (see server/handler_timeout.rs).
+    pub handler_timeout_secs: u64,
+    pub debug_endpoints: bool,
+    // 1 in log_sample_rate requests is logged, and every slow one (see server/sampled_log.rs).
+    pub log_sample_rate: u32,
+    pub slow_request_threshold_ms: u64,
+    // --log-file: JSON Lines, rotated past log_max_bytes unless that's 0 (see server/json_log.rs).
+    pub log_file: Option<PathBuf>,
+    pub log_max_bytes: u64,
+    // Both or neither: the admin API only runs with a token (see admin.rs).
+    pub admin_bind: Option<SocketAddr>,
+    pub admin_token: Option<String>,
+}
+
+// Applied to every accepted connection, see os::set_tcp_keepalive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepAliveConfig {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub count: u32,
+}
+
+impl Default for TcpKeepAliveConfig {
+    fn default() -> TcpKeepAliveConfig {
+        TcpKeepAliveConfig {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            count: 5,
+        }
+    }
+}
+
+impl TcpKeepAliveConfig {
+    // "60,10,5"
+    fn parse(value: &str) -> Option<TcpKeepAliveConfig> {
+        let mut parts = value.split(',').map(|part| part.trim().parse::<u64>().ok());
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Some(idle)), Some(Some(interval)), Some(Some(count)), None) if idle > 0 && interval > 0 && count > 0 => {
+                Some(TcpKeepAliveConfig {
+                    idle: Duration::from_secs(idle),
+                    interval: Duration::from_secs(interval),
+                    count: u32::try_from(count).ok()?,
+                })
+            },
+            _ => None,
+        }
+    }
+}
+
+// The reverse proxies in front of the server, see client_ip::ClientIpExtractor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxiesConfig {
+    pub proxies: Vec<IpNet>,
+}
+
+// What the command line asked for.
+// Made once, at startup: no point in boxing the Config to keep Help small.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Serve(Config),
+    DryRun(Config),
+    Help,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            binds: vec![SocketAddr::from(([0, 0, 0, 0], 1998))],
+            threads: 4,
+            io_threads: 16,
+            static_root: PathBuf::from("."),
+            routes_file: None,
+            trusted_proxies: TrustedProxiesConfig::default(),
+            accept_backlog: 1024,
+            max_queued_connections: 256,
+            tcp_keepalive: Some(TcpKeepAliveConfig::default()),
+            keepalive_idle_timeout: crate::server::KEEP_ALIVE_IDLE_TIMEOUT,
+            handler_timeout_secs: 0,
+            debug_endpoints: false,
+            log_sample_rate: 1,
+            slow_request_threshold_ms: crate::server::DEFAULT_SLOW_REQUEST_THRESHOLD.as_millis() as u64,
+            log_file: None,
+            log_max_bytes: 0,
+            admin_bind: None,
+            admin_token: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Action, String> {
+        Config::parse(std::env::args().skip(1))
+    }
+
+    // The arguments without the program name.
+    pub fn parse<I>(args: I) -> Result<Action, String>
+        where
+            I: IntoIterator<Item = String>
+    {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        // The first --bind replaces the default address, later ones add to it.
+        let mut binds = Vec::new();
+        let mut dry_run = false;
+
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| usage_error(&format!("{} needs a value", flag)))
+            };
+
+            match flag.as_str() {
+                "--bind" => {
+                    let bind = value()?;
+                    binds.push(bind
+                        .parse()
+                        .map_err(|_| usage_error(&format!("--bind expects <addr:port>, got {:?}", bind)))?);
+                },
+                "--threads" => {
+                    let threads = value()?;
+                    config.threads = threads
+                        .parse()
+                        .map_err(|_| usage_error(&format!("--threads expects a number, got {:?}", threads)))?;
+                },
+                "--io-threads" => {
+                    let io_threads = value()?;
+                    config.io_threads = io_threads
+                        .parse()
+                        .map_err(|_| usage_error(&format!("--io-threads expects a number, got {:?}", io_threads)))?;
+                },
+                "--backlog" => {
+                    let backlog = value()?;
+                    config.accept_backlog = backlog
+                        .parse()
+                        .map_err(|_| usage_error(&format!("--backlog expects a number, got {:?}", backlog)))?;
+                },
+                "--max-queued" => {
+                    let max_queued = value()?;
+                    config.max_queued_connections = max_queued
+                        .parse()
+                        .map_err(|_| usage_error(&format!("--max-queued expects a number, got {:?}", max_queued)))?;
+                },
+                "--tcp-keepalive" => {
+                    let keepalive = value()?;
+                    config.tcp_keepalive = Some(TcpKeepAliveConfig::parse(&keepalive).ok_or_else(|| {
+                        usage_error(&format!("--tcp-keepalive expects <idle,interval,count> above 0, got {:?}", keepalive))
+                    })?);
+                },
+                "--keepalive-idle-timeout" => {
+                    let timeout = value()?;
+                    config.keepalive_idle_timeout = match timeout.parse::<u64>() {
+                        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                        _ => return Err(usage_error(&format!("--keepalive-idle-timeout expects seconds above 0, got {:?}", timeout))),
+                    };
+                },
+                "--handler-timeout" => {
+                    let timeout = value()?;
+                    config.handler_timeout_secs = timeout
+                        .parse()
+                        .map_err(|_| usage_error(&format!("--handler-timeout expects seconds, got {:?}", timeout)))?;
+                },
+                "--log-sample-rate" => {
+                    let rate = value()?;
+                    config.log_sample_rate = match rate.parse::<u32>() {
+                        Ok(rate) if rate > 0 => rate,
+                        _ => return Err(usage_error(&format!("--log-sample-rate expects a number above 0, got {:?}", rate))),
+                    };
+                },
+                "--slow-request-threshold" => {
+                    let threshold = value()?;
+                    config.slow_request_threshold_ms = threshold
+                        .parse()
+                        .map_err(|_| usage_error(&format!("--slow-request-threshold expects milliseconds, got {:?}", threshold)))?;
+                },
+                "--log-file" => config.log_file = Some(PathBuf::from(value()?)),
+                "--log-max-bytes" => {
+                    let bytes = value()?;
+                    config.log_max_bytes = bytes
+                        .parse()
+                        .map_err(|_| usage_error(&format!("--log-max-bytes expects a number, got {:?}", bytes)))?;
+                },
+                "--no-tcp-keepalive" if inline_value.is_none() => config.tcp_keepalive = None,
+                "--static-root" => config.static_root = PathBuf::from(value()?),
+                "--routes" => config.routes_file = Some(PathBuf::from(value()?)),
+                "--trusted-proxy" => {
+                    let proxy = value()?;
+                    config.trusted_proxies.proxies.push(proxy
+                        .parse()
+                        .map_err(|_| usage_error(&format!("--trusted-proxy expects <addr>[/<len>], got {:?}", proxy)))?);
+                },
+                "--debug-endpoints" if inline_value.is_none() => config.debug_endpoints = true,
+                "--dry-run" if inline_value.is_none() => dry_run = true,
+                "--admin-bind" => {
+                    let bind = value()?;
+                    config.admin_bind = Some(bind
+                        .parse()
+                        .map_err(|_| usage_error(&format!("--admin-bind expects <addr:port>, got {:?}", bind)))?);
+                },
+                "--admin-token" => {
+                    let token = value()?;
+                    if token.is_empty() || token.contains(char::is_whitespace) {
+                        return Err(usage_error("--admin-token expects a token without spaces"));
+                    }
+                    config.admin_token = Some(token);
+                },
+                "-h" | "--help" => return Ok(Action::Help),
+                _ => return Err(usage_error(&format!("unknown argument: {}", arg))),
+            }
+        }
+
+        if !binds.is_empty() {
+            config.binds = binds;
+        }
+        if config.admin_bind.is_some() != config.admin_token.is_some() {
+            return Err(usage_error("--admin-bind and --admin-token go together"));
+        }
+        Ok(if dry_run { Action::DryRun(config) } else { Action::Serve(config) })
+    }
+}
+
+fn usage_error(message: &str) -> String {
+    format!("error: {}\n\n{}", message, USAGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Action, String> {
+        Config::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn no_arguments_give_the_defaults() {
+        assert_eq!(parse(&[]), Ok(Action::Serve(Config::default())));
+    }
+
+    #[test]
+    fn repeated_bind_replaces_the_default_and_accumulates() {
+        let Ok(Action::Serve(config)) = parse(&["--bind", "127.0.0.1:8080", "--bind=[::1]:8081", "--threads=8", "--io-threads", "0"]) else {
+            panic!("expected a config");
+        };
+        assert_eq!(config.binds, vec!["127.0.0.1:8080".parse().unwrap(), "[::1]:8081".parse().unwrap()]);
+        assert_eq!(config.threads, 8);
+        assert_eq!(config.io_threads, 0);
+    }
+
+    #[test]
+    fn routes_take_a_file() {
+        let Ok(Action::Serve(config)) = parse(&["--routes", "site/routes.toml"]) else {
+            panic!("expected a config");
+        };
+        assert_eq!(config.routes_file, Some(PathBuf::from("site/routes.toml")));
+        assert!(parse(&["--routes"]).is_err());
+    }
+
+    #[test]
+    fn switches_take_no_value() {
+        let Ok(Action::Serve(config)) = parse(&["--debug-endpoints"]) else {
+            panic!("expected a config");
+        };
+        assert!(config.debug_endpoints);
+        assert!(parse(&["--debug-endpoints=yes"]).is_err());
+    }
+
+    #[test]
+    fn tcp_keepalive_takes_three_numbers() {
+        let Ok(Action::Serve(config)) = parse(&["--tcp-keepalive", "30,5,3"]) else {
+            panic!("expected a config");
+        };
+        let expected = TcpKeepAliveConfig { idle: Duration::from_secs(30), interval: Duration::from_secs(5), count: 3 };
+        assert_eq!(config.tcp_keepalive, Some(expected));
+        assert!(parse(&["--tcp-keepalive", "30,5"]).is_err());
+        assert!(parse(&["--tcp-keepalive", "30,0,3"]).is_err());
+        assert_eq!(parse(&["--no-tcp-keepalive"]).map(|action| match action {
+            Action::Serve(config) => config.tcp_keepalive,
+            Action::DryRun(_) | Action::Help => panic!("expected a config"),
+        }), Ok(None));
+    }
+
+    #[test]
+    fn timeouts_are_in_seconds() {
+        let Ok(Action::Serve(config)) = parse(&["--keepalive-idle-timeout=15"]) else {
+            panic!("expected a config");
+        };
+        assert_eq!(config.keepalive_idle_timeout, Duration::from_secs(15));
+        assert!(parse(&["--keepalive-idle-timeout", "0"]).is_err());
+        assert!(parse(&["--keepalive-idle-timeout", "1.5"]).is_err());
+
+        let Ok(Action::Serve(config)) = parse(&["--handler-timeout", "30"]) else {
+            panic!("expected a config");
+        };
+        assert_eq!(config.handler_timeout_secs, 30);
+    }
+
+    #[test]
+    fn trusted_proxies_accumulate() {
+        let Ok(Action::Serve(config)) = parse(&["--trusted-proxy", "10.0.0.0/8", "--trusted-proxy=::1"]) else {
+            panic!("expected a config");
+        };
+        assert_eq!(config.trusted_proxies.proxies, vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()]);
+        assert!(parse(&["--trusted-proxy", "10.0.0.0/40"]).is_err());
+    }
+
+    #[test]
+    fn bad_values_are_usage_errors() {
+        let err = parse(&["--threads", "many"]).unwrap_err();
+        assert!(err.starts_with("error: --threads expects a number, got \"many\""), "{}", err);
+        assert!(err.ends_with(USAGE));
+        assert!(parse(&["--threads"]).unwrap_err().starts_with("error: --threads needs a value"));
+        assert!(parse(&["--bind", "localhost"]).is_err());
+        assert!(parse(&["--verbose"]).unwrap_err().starts_with("error: unknown argument: --verbose"));
+    }
+
+    #[test]
+    fn the_admin_api_needs_a_token() {
+        let Ok(Action::Serve(config)) = parse(&["--admin-bind", "127.0.0.1:1999", "--admin-token=s3cret"]) else {
+            panic!("expected a config");
+        };
+        assert_eq!(config.admin_bind, Some("127.0.0.1:1999".parse().unwrap()));
+        assert_eq!(config.admin_token.as_deref(), Some("s3cret"));
+        assert!(parse(&["--admin-bind", "127.0.0.1:1999"]).unwrap_err().starts_with("error: --admin-bind and --admin-token go together"));
+        assert!(parse(&["--admin-token", "s3cret"]).is_err());
+        assert!(parse(&["--admin-bind", "127.0.0.1:1999", "--admin-token", ""]).is_err());
+    }
+
+    #[test]
+    fn dry_run_checks_the_config_instead() {
+        let Ok(Action::DryRun(config)) = parse(&["--threads", "2", "--dry-run"]) else {
+            panic!("expected a dry run");
+        };
+        assert_eq!(config.threads, 2);
+        assert!(parse(&["--dry-run=yes"]).is_err());
+    }
+
+    #[test]
+    fn help_is_not_an_error() {
+        assert_eq!(parse(&["--help"]), Ok(Action::Help));
+        assert_eq!(parse(&["--threads", "2", "-h"]), Ok(Action::Help));
+    }
+
+    #[test]
+    fn log_sampling_is_configurable() {
+        assert_eq!(Config::default().log_sample_rate, 1);
+        let Ok(Action::Serve(config)) = parse(&["--log-sample-rate", "100", "--slow-request-threshold=250"]) else {
+            panic!("expected a config");
+        };
+        assert_eq!(config.log_sample_rate, 100);
+        assert_eq!(config.slow_request_threshold_ms, 250);
+        assert!(parse(&["--log-sample-rate", "0"]).is_err());
+        assert!(parse(&["--slow-request-threshold", "fast"]).is_err());
+
+        let Ok(Action::Serve(config)) = parse(&["--log-file", "/var/log/surff.jsonl", "--log-max-bytes=1048576"]) else {
+            panic!("expected a config");
+        };
+        assert_eq!(config.log_file, Some(PathBuf::from("/var/log/surff.jsonl")));
+        assert_eq!(config.log_max_bytes, 1_048_576);
+        assert!(parse(&["--log-max-bytes", "1MB"]).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs::File;
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::PathBuf;
+
+use crate::http::{unsatisfiable_content_range, Method, RangeRequest, Request, ResponseBuilder, StatusCode};
+use crate::mime;
+use crate::router::Response;
+
+/* # Streaming file downloads!
+let report = FileDownloadHandler::new("reports/q3.pdf").on_progress(|sent, total| ...);
+router.get("/q3.pdf", move |request, response| report.serve(request, response).map(drop));
+fs::read_to_string loads the whole file into memory before anything is written.
+FileDownloadHandler instead:
+1. Opens the file and takes Content-Length from its metadata.
+2. Sends the head (Content-Type, Content-Disposition: attachment) through the
+Response, so the middleware sees it, and a HEAD request gets it alone. The file
+name comes from the file system, so it's cleaned up before it goes into a header:
+control characters (CR/LF would start a new header), quotes and backslashes are
+dropped. Non-ASCII names get an ASCII fallback in filename= and the real name in
+RFC 6266's filename*=UTF-8''...
+With Accept-Ranges: bytes, so a client whose download broke off can ask for the
+rest (Range: bytes=N-): that's a 206 with just those bytes and a Content-Range, a
+range past the end a 416 (see http/range.rs).
+3. Copies the file to the stream in 64 KB chunks, calling the progress callback
+after every chunk (for a range, with what was sent of it and its length). Exactly
+Content-Length bytes: a file that grew meanwhile is cut off there, one that shrank
+is an UnexpectedEof error once it runs out.
+4. If the client goes away mid-transfer (BrokenPipe / ConnectionReset),
+logs the incomplete transfer and returns the error to the caller. */
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Called with (bytes_sent, total) after each chunk is written.
+pub type ProgressCallback = Box<dyn Fn(u64, u64) + Send + Sync + 'static>;
+
+pub struct FileDownloadHandler {
+    path: PathBuf,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl FileDownloadHandler {
+    pub fn new(path: impl Into<PathBuf>) -> FileDownloadHandler {
+        FileDownloadHandler {
+            path: path.into(),
+            on_progress: None,
+        }
+    }
+
+    // Register a callback for telemetry (progress bars, stalled-download detection).
+    pub fn on_progress<F>(mut self, callback: F) -> FileDownloadHandler
+        where
+            F: Fn(u64, u64) + Send + Sync + 'static
+    {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    // Answers `request` with the file; returns the number of body bytes sent (none for HEAD).
+    pub fn serve(&self, request: &Request, response: &mut Response) -> io::Result<u64> {
+        let mut file = File::open(&self.path)?;
+        let size = file.metadata()?.len();
+
+        let filename = self.path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("download");
+
+        let mut head = ResponseBuilder::new(StatusCode::Ok);
+        head.header("Content-Type", mime::from_path(&self.path))
+            .header("Content-Disposition", &content_disposition(filename))
+            .header("Accept-Ranges", "bytes");
+        let (start, total) = match RangeRequest::of(request, size) {
+            RangeRequest::Full => (0, size),
+            RangeRequest::Partial(range) => {
+                head.set_status(StatusCode::PartialContent).header("Content-Range", &range.content_range(size));
+                (range.start, range.length())
+            }
+            RangeRequest::Unsatisfiable => {
+                head.set_status(StatusCode::RangeNotSatisfiable).header("Content-Range", &unsatisfiable_content_range(size));
+                response.send(&mut head)?;
+                return Ok(0);
+            }
+        };
+        file.seek(SeekFrom::Start(start))?;
+        let mut body = response.start_body(head, total)?;
+        if request.method == Method::Head {
+            return Ok(0);
+        }
+
+        // never more than the Content-Length, even if the file grew since.
+        let mut file = file.take(total);
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut sent: u64 = 0;
+
+        loop {
+            let n = match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            if let Err(e) = body.write_all(&buffer[..n]) {
+                if is_disconnect(&e) {
+                    println!("Download of {} aborted by client after {} of {} bytes.",
+                        self.path.display(), sent, total);
+                }
+                return Err(e);
+            }
+            sent += n as u64;
+
+            if let Some(callback) = &self.on_progress {
+                callback(sent, total);
+            }
+        }
+
+        if sent < total {
+            // the client would wait for the rest, or read the next response as part of this
+            // one: dropping the writer closes the connection.
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while being sent ({} of {} bytes)", self.path.display(), sent, total),
+            ));
+        }
+        body.finish()?;
+        Ok(sent)
+    }
+}
+
+// attachment; filename="report.pdf"
+// attachment; filename="r_sum_.pdf"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf
+fn content_disposition(filename: &str) -> String {
+    // Quotes and backslashes would break out of the quoted-string, control characters out of the header.
+    let filename: String = filename
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"' && *c != '\\')
+        .collect();
+    let filename = if filename.is_empty() { "download".to_string() } else { filename };
+
+    if filename.is_ascii() {
+        return format!("attachment; filename=\"{}\"", filename);
+    }
+
+    let fallback: String = filename.chars().map(|c| if c.is_ascii() { c } else { '_' }).collect();
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        // RFC 5987's attr-char: everything else is %-encoded.
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{exchange, Middleware, Output, Router};
+    use std::sync::{Arc, Mutex};
+
+    // Answers `raw` with `download`, into memory: what serve returned, and what it sent.
+    fn serve(download: &FileDownloadHandler, raw: &str) -> (io::Result<u64>, String) {
+        let request = Request::parse(raw.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        let result = {
+            let mut response = Response::new(Output::Buffer { buffer: &mut out, peer: ([127, 0, 0, 1], 1).into() }, &request, None);
+            download.serve(&request, &mut response)
+        };
+        (result, String::from_utf8_lossy(&out).into_owned())
+    }
+
+    #[test]
+    fn ascii_names_are_quoted() {
+        assert_eq!(content_disposition("report.pdf"), "attachment; filename=\"report.pdf\"");
+    }
+
+    #[test]
+    fn control_characters_and_quotes_are_dropped() {
+        let header = content_disposition("evil\r\nSet-Cookie: a=\"b\\.txt");
+        assert_eq!(header, "attachment; filename=\"evilSet-Cookie: a=b.txt\"");
+    }
+
+    #[test]
+    fn non_ascii_names_get_filename_star() {
+        assert_eq!(
+            content_disposition("résumé 1.pdf"),
+            "attachment; filename=\"r_sum_ 1.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%201.pdf",
+        );
+    }
+
+    #[test]
+    fn serves_the_file_with_headers() {
+        let path = std::env::temp_dir().join(format!("surff-download-{}.txt", std::process::id()));
+        std::fs::write(&path, "hello").unwrap();
+
+        let (sent, out) = serve(&FileDownloadHandler::new(&path), "GET / HTTP/1.1\r\n\r\n");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(sent.unwrap(), 5);
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.contains("Content-Length: 5\r\n"));
+        assert!(out.contains("Content-Disposition: attachment; filename=\"surff-download-"));
+        assert!(out.contains("Accept-Ranges: bytes\r\n"));
+        assert!(out.ends_with("\r\n\r\nhello"));
+    }
+
+    struct Tagged;
+
+    impl Middleware for Tagged {
+        fn on_response(&self, _request: &Request, response: &mut ResponseBuilder) {
+            response.header("X-Tagged", "yes");
+        }
+    }
+
+    #[test]
+    fn the_head_goes_through_the_middleware_and_head_gets_no_body() {
+        let path = std::env::temp_dir().join(format!("surff-download-head-{}.txt", std::process::id()));
+        std::fs::write(&path, "hello").unwrap();
+        let download = FileDownloadHandler::new(&path).on_progress(|_, _| panic!("HEAD has no body to report on"));
+        let mut router = Router::new();
+        router.get("/file", move |request, response| download.serve(request, response).map(drop));
+        router.wrap(Tagged);
+
+        let response = exchange(&router, "HEAD /file HTTP/1.1\r\n\r\n");
+        std::fs::remove_file(&path).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nX-Tagged: yes\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nContent-Length: 5\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\n"), "{:?}", response);
+    }
+
+
+    #[test]
+    fn sends_exactly_the_content_length() {
+        let path = std::env::temp_dir().join(format!("surff-download-changing-{}.bin", std::process::id()));
+        std::fs::write(&path, vec![b'x'; 3 * CHUNK_SIZE]).unwrap();
+        let resize = |length: u64| {
+            let path = path.clone();
+            move |sent: u64, _| {
+                if sent == CHUNK_SIZE as u64 {
+                    std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(length).unwrap();
+                }
+            }
+        };
+
+        // grown after the first chunk: still 3 chunks' worth.
+        let (sent, out) = serve(&FileDownloadHandler::new(&path).on_progress(resize(5 * CHUNK_SIZE as u64)), "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(sent.unwrap(), 3 * CHUNK_SIZE as u64);
+        assert!(out.contains(&format!("Content-Length: {}\r\n", 3 * CHUNK_SIZE)));
+
+        // shrunk from 5 chunks' worth to 2.
+        let (sent, _) = serve(&FileDownloadHandler::new(&path).on_progress(resize(2 * CHUNK_SIZE as u64)), "GET / HTTP/1.1\r\n\r\n");
+        let err = sent.unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(err.to_string().ends_with(&format!("({} of {} bytes)", 2 * CHUNK_SIZE, 5 * CHUNK_SIZE)), "{}", err);
+    }
+
+
+    #[test]
+    fn ranges_get_206_and_past_the_end_416() {
+        let path = std::env::temp_dir().join(format!("surff-download-range-{}.txt", std::process::id()));
+        std::fs::write(&path, "0123456789").unwrap();
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&progress);
+        let download = FileDownloadHandler::new(&path).on_progress(move |sent, total| seen.lock().unwrap().push((sent, total)));
+
+        let (sent, out) = serve(&download, "GET / HTTP/1.1\r\nRange: bytes=3-5\r\n\r\n");
+        assert_eq!(sent.unwrap(), 3);
+        assert!(out.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{:?}", out);
+        assert!(out.contains("\r\nContent-Range: bytes 3-5/10\r\n"), "{:?}", out);
+        assert!(out.contains("\r\nContent-Length: 3\r\n"), "{:?}", out);
+        assert!(out.ends_with("\r\n\r\n345"), "{:?}", out);
+        assert_eq!(*progress.lock().unwrap(), [(3, 3)]);
+
+        let (_, out) = serve(&download, "GET / HTTP/1.1\r\nRange: bytes=-4\r\n\r\n");
+        assert!(out.ends_with("\r\nContent-Range: bytes 6-9/10\r\nContent-Length: 4\r\n\r\n6789"), "{:?}", out);
+
+        let (sent, out) = serve(&download, "GET / HTTP/1.1\r\nRange: bytes=10-\r\n\r\n");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sent.unwrap(), 0);
+        assert!(out.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"), "{:?}", out);
+        assert!(out.contains("\r\nContent-Range: bytes */10\r\n"), "{:?}", out);
+    }
+}
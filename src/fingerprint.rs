@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::TcpStream;
+
+use crate::crypto::{sha256, to_hex};
+use crate::http::{Request, ResponseBuilder, StatusCode};
+use crate::os::{self, TcpInfo};
+use crate::router::{Middleware, Response};
+
+/* # Fingerprints of connections, for telling bots apart!
+router.wrap(BotBlockerMiddleware::new().block("3b9f06c1d0e2a4f5b6c7d8e9f0a1b2c3"));
+router.wrap(FingerprintMiddleware::new());
+let fingerprint = request.extensions.get::<ConnectionFingerprint>();  // in a handler
+1. TCP: the options the client's SYN carried, read back from the kernel (os::tcp_info,
+getsockopt TCP_INFO on Linux): timestamps, SACK, its window scale, ECN and its MSS.
+Every operating system's stack has its own mix; a scanner crafting raw packets often
+has none of them.
+2. HTTP: the order of the request's header names, lowercased. Browsers and libraries
+each send theirs in an order of their own, whatever User-Agent says they are.
+3. Together they're a string, "tcp:ts,sack,ws7,mss1460;http:host,user-agent,accept",
+and its id is the first 32 hex digits of its SHA-256. A pipelined request (answered
+into a buffer, see server/pipeline.rs) has no socket to ask: its "tcp:" part is empty,
+as it is everywhere but on Linux.
+4. JA3 (the TLS ClientHello's version, cipher suites, extensions, curves and point
+formats, then their MD5) would go with these, but it needs the ClientHello of every
+connection, and surff doesn't terminate TLS (see tls.rs): until it does, there's no
+ja3_hash, and TLS is fingerprinted by whatever terminates it.
+5. FingerprintMiddleware puts a ConnectionFingerprint in the request's extensions, for
+the layers inside it and the handler. BotBlockerMiddleware answers a request whose
+fingerprint id it was told to block with a 403, and closes the connection; it takes
+the fingerprint from the extensions, or makes one if no FingerprintMiddleware did. */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionFingerprint {
+    // None when the kernel wasn't asked, or couldn't say.
+    pub tcp: Option<TcpInfo>,
+    pub header_order: Vec<String>,
+}
+
+impl ConnectionFingerprint {
+    pub fn new(stream: Option<&TcpStream>, request: &Request) -> ConnectionFingerprint {
+        ConnectionFingerprint {
+            tcp: stream.and_then(|stream| os::tcp_info(stream).ok()),
+            header_order: request.headers.iter().map(|(name, _)| name.to_ascii_lowercase()).collect(),
+        }
+    }
+
+    // The string the id is taken from (see 3. above).
+    pub fn text(&self) -> String {
+        let tcp = match &self.tcp {
+            Some(tcp) => {
+                let mut options = Vec::new();
+                if tcp.timestamps {
+                    options.push("ts".to_string());
+                }
+                if tcp.sack {
+                    options.push("sack".to_string());
+                }
+                if let Some(scale) = tcp.window_scale {
+                    options.push(format!("ws{}", scale));
+                }
+                if tcp.ecn {
+                    options.push("ecn".to_string());
+                }
+                options.push(format!("mss{}", tcp.mss));
+                options.join(",")
+            }
+            None => String::new(),
+        };
+        format!("tcp:{};http:{}", tcp, self.header_order.join(","))
+    }
+
+    pub fn id(&self) -> String {
+        to_hex(&sha256(self.text().as_bytes()))[..32].to_string()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FingerprintMiddleware;
+
+impl FingerprintMiddleware {
+    pub fn new() -> FingerprintMiddleware {
+        FingerprintMiddleware
+    }
+}
+
+impl Middleware for FingerprintMiddleware {
+    fn before(&self, request: &Request, response: &mut Response) -> io::Result<bool> {
+        request.extensions.insert(ConnectionFingerprint::new(response.stream(), request));
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BotBlockerMiddleware {
+    blocked: HashSet<String>,
+}
+
+impl BotBlockerMiddleware {
+    pub fn new() -> BotBlockerMiddleware {
+        BotBlockerMiddleware::default()
+    }
+
+    // A ConnectionFingerprint id, as logged for a bot's requests.
+    pub fn block(mut self, id: &str) -> BotBlockerMiddleware {
+        self.blocked.insert(id.to_ascii_lowercase());
+        self
+    }
+}
+
+impl Middleware for BotBlockerMiddleware {
+    fn before(&self, request: &Request, response: &mut Response) -> io::Result<bool> {
+        let id = match request.extensions.get::<ConnectionFingerprint>() {
+            Some(fingerprint) => fingerprint.id(),
+            None => ConnectionFingerprint::new(response.stream(), request).id(),
+        };
+        if !self.blocked.contains(&id) {
+            return Ok(true);
+        }
+        response.close();
+        response.send(&mut ResponseBuilder::new(StatusCode::Forbidden))?;
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{exchange, Router};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn fingerprints_have_tcp_options_and_header_order() {
+        let request = Request::parse(b"GET / HTTP/1.1\r\nHost: a\r\nUser-Agent: curl\r\nAccept: */*\r\n\r\n").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let fingerprint = ConnectionFingerprint::new(Some(&stream), &request);
+        assert_eq!(fingerprint.header_order, ["host", "user-agent", "accept"]);
+        if cfg!(target_os = "linux") {
+            // Linux itself on the other end: every option it has.
+            let tcp = fingerprint.tcp.unwrap();
+            assert!(tcp.timestamps && tcp.sack && tcp.window_scale.is_some() && tcp.mss > 0, "{:?}", tcp);
+            assert!(fingerprint.text().starts_with("tcp:ts,sack,ws"), "{}", fingerprint.text());
+        }
+
+        let without_tcp = ConnectionFingerprint::new(None, &request);
+        assert_eq!(without_tcp.text(), "tcp:;http:host,user-agent,accept");
+        assert_eq!(without_tcp.id().len(), 32);
+        let reordered = Request::parse(b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nHost: a\r\nAccept: */*\r\n\r\n").unwrap();
+        assert_ne!(ConnectionFingerprint::new(None, &reordered).id(), without_tcp.id());
+    }
+
+    #[test]
+    fn blocked_fingerprints_get_a_403() {
+        let seen = Arc::new(Mutex::new(None));
+        let noted = Arc::clone(&seen);
+        let mut router = Router::new();
+        router.get("/", move |request, response| {
+            *noted.lock().unwrap() = request.extensions.get::<ConnectionFingerprint>().map(|fingerprint| fingerprint.id());
+            response.send(&mut ResponseBuilder::new(StatusCode::Ok))
+        });
+        router.wrap(FingerprintMiddleware::new());
+        let response = exchange(&router, "GET / HTTP/1.1\r\nX-Bot: 1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let bot = seen.lock().unwrap().clone().unwrap();
+
+        router.wrap(BotBlockerMiddleware::new().block(&bot));
+        let response = exchange(&router, "GET / HTTP/1.1\r\nX-Bot: 1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n") && response.contains("Connection: close"), "{}", response);
+        // another header order: someone else.
+        let response = exchange(&router, "GET / HTTP/1.1\r\nX-Not-A-Bot: 1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+}
@@ -0,0 +1,500 @@
+use std::fmt;
+
+/* # gzip without a compression crate!
+gzip::decompress(&bytes)  => Ok(the original bytes), or why they aren't gzip
+gzip::compress(&bytes, 6) => the same, gzipped
+A gzip member (RFC 1952) is a 10-byte header (and some optional fields), DEFLATE data
+(RFC 1951), then the CRC-32 and length of what was compressed, both checked here.
+DEFLATE is a series of blocks: stored (copied as is), or Huffman-coded with either the
+fixed codes from the RFC or codes sent at the start of the block. Coded blocks are
+literals and <length, distance> pairs: "copy `length` bytes from `distance` back".
+Decompressed output is capped (see decompress's `limit`) so a few KB of zeros can't
+become gigabytes. Compression finds matches through a hash of the next 3 bytes and
+writes them with the fixed codes, in one block: no code table to send, a little bigger
+than zlib's output. `level` (0-9) is how many earlier matches are tried per byte;
+0 stores the bytes as they are. */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum GzipError {
+    NotGzip,
+    Truncated,
+    Corrupt(&'static str),
+    TooLarge,
+    ChecksumMismatch,
+}
+
+impl fmt::Display for GzipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GzipError::NotGzip => write!(f, "not gzip data"),
+            GzipError::Truncated => write!(f, "gzip data ends early"),
+            GzipError::Corrupt(reason) => write!(f, "corrupt gzip data: {}", reason),
+            GzipError::TooLarge => write!(f, "gzip data decompresses to more than allowed"),
+            GzipError::ChecksumMismatch => write!(f, "gzip checksum does not match"),
+        }
+    }
+}
+
+impl std::error::Error for GzipError {}
+
+// Header flags (FLG).
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+pub(crate) fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, GzipError> {
+    if data.len() < 18 || data[0] != 0x1f || data[1] != 0x8b {
+        return Err(GzipError::NotGzip);
+    }
+    if data[2] != 8 {
+        return Err(GzipError::Corrupt("compression method is not DEFLATE"));
+    }
+    let flags = data[3];
+    let mut at = 10;
+    if flags & FEXTRA != 0 {
+        let length = u16::from_le_bytes([*data.get(at).ok_or(GzipError::Truncated)?, *data.get(at + 1).ok_or(GzipError::Truncated)?]);
+        at += 2 + length as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data.get(at..).and_then(|rest| rest.iter().position(|&byte| byte == 0)).ok_or(GzipError::Truncated)?;
+            at += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        at += 2;
+    }
+    let deflated = data.get(at..).ok_or(GzipError::Truncated)?;
+
+    let (output, used) = inflate(deflated, limit)?;
+    let trailer = deflated.get(used..used + 8).ok_or(GzipError::Truncated)?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(&output) || size != output.len() as u32 {
+        return Err(GzipError::ChecksumMismatch);
+    }
+    Ok(output)
+}
+
+// CRC-32 as gzip (and zip, and PNG) use it: reflected, polynomial 0xedb88320.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// DEFLATE reads bits from the lowest of each byte up.
+struct Bits<'a> {
+    data: &'a [u8],
+    at: usize,
+    bit: u32,
+}
+
+impl Bits<'_> {
+    fn bit(&mut self) -> Result<u32, GzipError> {
+        let byte = *self.data.get(self.at).ok_or(GzipError::Truncated)?;
+        let bit = (byte >> self.bit) & 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.at += 1;
+        }
+        Ok(bit as u32)
+    }
+
+    // `count` bits, the first one read the lowest.
+    fn bits(&mut self, count: u32) -> Result<u32, GzipError> {
+        let mut value = 0;
+        for i in 0..count {
+            value |= self.bit()? << i;
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.at += 1;
+        }
+    }
+}
+
+// A canonical Huffman code, from the bit length of each symbol's code (0: not used).
+// Decoding walks the lengths in order: codes of the same length are consecutive numbers.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for length in 1..16 {
+            offsets[length] = offsets[length - 1] + counts[length - 1];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, GzipError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= bits.bit()? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(GzipError::Corrupt("invalid Huffman code"))
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// The order code length code lengths are sent in (most likely used first).
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// The inflated bytes, and how many of `data` they took.
+fn inflate(data: &[u8], limit: usize) -> Result<(Vec<u8>, usize), GzipError> {
+    let mut bits = Bits { data, at: 0, bit: 0 };
+    let mut output = Vec::new();
+    loop {
+        let last = bits.bit()? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = data.get(bits.at..bits.at + 4).ok_or(GzipError::Truncated)?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                if length != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(GzipError::Corrupt("stored block length check failed"));
+                }
+                let stored = data.get(bits.at + 4..bits.at + 4 + length as usize).ok_or(GzipError::Truncated)?;
+                if output.len() + stored.len() > limit {
+                    return Err(GzipError::TooLarge);
+                }
+                output.extend_from_slice(stored);
+                bits.at += 4 + length as usize;
+            }
+            1 => {
+                let mut lengths = [8u8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                codes(&mut bits, &mut output, &Huffman::new(&lengths), &Huffman::new(&[5; 30]), limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                codes(&mut bits, &mut output, &literals, &distances, limit)?;
+            }
+            _ => return Err(GzipError::Corrupt("invalid block type")),
+        }
+        if last {
+            bits.align();
+            return Ok((output, bits.at));
+        }
+    }
+}
+
+// The two codes a dynamic block starts with, themselves sent Huffman-coded.
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), GzipError> {
+    let literal_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let code_length_count = bits.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_length_code.decode(bits)? {
+            length @ 0..=15 => (length as u8, 1),
+            16 => (*lengths.last().ok_or(GzipError::Corrupt("repeat with nothing before it"))?, 3 + bits.bits(2)?),
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+    if lengths.len() != literal_count + distance_count {
+        return Err(GzipError::Corrupt("code lengths overrun"));
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn codes(bits: &mut Bits, output: &mut Vec<u8>, literals: &Huffman, distances: &Huffman, limit: usize) -> Result<(), GzipError> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        if symbol == 256 {
+            return Ok(());
+        }
+        if output.len() >= limit {
+            return Err(GzipError::TooLarge);
+        }
+        if symbol < 256 {
+            output.push(symbol as u8);
+            continue;
+        }
+
+        let index = symbol - 257;
+        if index >= LENGTH_BASE.len() {
+            return Err(GzipError::Corrupt("invalid length symbol"));
+        }
+        let length = LENGTH_BASE[index] as usize + bits.bits(LENGTH_EXTRA[index] as u32)? as usize;
+        let index = distances.decode(bits)? as usize;
+        if index >= DISTANCE_BASE.len() {
+            return Err(GzipError::Corrupt("invalid distance symbol"));
+        }
+        let distance = DISTANCE_BASE[index] as usize + bits.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+        if distance > output.len() {
+            return Err(GzipError::Corrupt("distance before the start"));
+        }
+        if output.len() + length > limit {
+            return Err(GzipError::TooLarge);
+        }
+        // One byte at a time: the copy may overlap what it's writing (distance < length).
+        let start = output.len() - distance;
+        for i in 0..length {
+            output.push(output[start + i]);
+        }
+    }
+}
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+const NO_POSITION: usize = usize::MAX;
+// Matches tried per byte, by level.
+const CHAIN_LENGTHS: [usize; 10] = [0, 4, 8, 16, 32, 64, 128, 256, 1024, 4096];
+
+pub(crate) fn compress(data: &[u8], level: u32) -> Vec<u8> {
+    // no modification time, no flags, "unknown" OS.
+    let mut output = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    let level = level.min(9) as usize;
+    if level == 0 {
+        stored(data, &mut output);
+    } else {
+        output.extend_from_slice(&deflate(data, CHAIN_LENGTHS[level]));
+    }
+    output.extend_from_slice(&crc32(data).to_le_bytes());
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output
+}
+
+fn stored(data: &[u8], output: &mut Vec<u8>) {
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+    if blocks.peek().is_none() {
+        // still one (empty, last) block.
+        output.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        output.push(u8::from(blocks.peek().is_none()));
+        output.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        output.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+}
+
+fn deflate(data: &[u8], chain_length: usize) -> Vec<u8> {
+    let mut out = BitWriter { out: Vec::new(), bits: 0, count: 0 };
+    // the last block, fixed codes.
+    out.write(1, 1);
+    out.write(1, 2);
+
+    let mut chains = Chains { head: vec![NO_POSITION; 1 << HASH_BITS], previous: vec![NO_POSITION; WINDOW] };
+    let mut at = 0;
+    while at < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if at + MIN_MATCH <= data.len() {
+            let longest = MAX_MATCH.min(data.len() - at);
+            let mut candidate = chains.head[hash(data, at)];
+            let mut tries = chain_length;
+            // positions on a chain only go down, and stop mattering past the window.
+            while candidate != NO_POSITION && at - candidate <= WINDOW && tries > 0 {
+                let length = data[candidate..].iter().zip(&data[at..at + longest]).take_while(|(a, b)| a == b).count();
+                if length > best_length {
+                    (best_length, best_distance) = (length, at - candidate);
+                    if length == longest {
+                        break;
+                    }
+                }
+                candidate = chains.previous[candidate % WINDOW];
+                tries -= 1;
+            }
+        }
+        if best_length >= MIN_MATCH {
+            out.length_and_distance(best_length, best_distance);
+            for position in at..at + best_length {
+                chains.insert(data, position);
+            }
+            at += best_length;
+        } else {
+            out.literal(data[at] as u32);
+            chains.insert(data, at);
+            at += 1;
+        }
+    }
+    out.literal(256);
+    out.finish()
+}
+
+// Earlier positions by the 3 bytes that start there. head: the latest position for each
+// hash; previous: the one before a position, with the same hash (or not: collisions).
+struct Chains {
+    head: Vec<usize>,
+    previous: Vec<usize>,
+}
+
+impl Chains {
+    fn insert(&mut self, data: &[u8], at: usize) {
+        if at + MIN_MATCH <= data.len() {
+            let hash = hash(data, at);
+            self.previous[at % WINDOW] = self.head[hash];
+            self.head[hash] = at;
+        }
+    }
+}
+
+fn hash(data: &[u8], at: usize) -> usize {
+    let next = u32::from_be_bytes([0, data[at], data[at + 1], data[at + 2]]);
+    (next.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+// The writing side of Bits: the lowest bit of each byte first.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are the one thing sent from their highest bit down.
+    fn code(&mut self, code: u32, length: u32) {
+        self.write(code.reverse_bits() >> (32 - length), length);
+    }
+
+    // A literal/length symbol in the fixed code (RFC 1951, 3.2.6).
+    fn literal(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length_and_distance(&mut self, length: usize, distance: usize) {
+        let index = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap_or(0);
+        self.literal(257 + index as u32);
+        self.write((length - LENGTH_BASE[index] as usize) as u32, LENGTH_EXTRA[index] as u32);
+        let index = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap_or(0);
+        self.code(index as u32, 5);
+        self.write((distance - DISTANCE_BASE[index] as usize) as u32, DISTANCE_EXTRA[index] as u32);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    // From Python's gzip.compress(data, compresslevel, mtime=0).
+    const STORED: &str = "1f8b0800000000000403010d00f2ff68656c6c6f2c2073746f726564f0ec5f7f0d000000";
+    const FIXED: &str = "1f8b0800000000000203cb48cdc9c957c84090008088f9e511000000";
+    const DYNAMIC: &str = "1f8b0800000000000203b5cbc70180201005d1567e05d4e2c10640490656b250bddb84e779b33a8d58fd764225\
+                           ea01865e1cf57e32a8e984c2f9927360272bb0fe8617c9ee1e508cba2f0ec637cd69ea80cbc74a895f9bc507128f\
+                           6f7aaf000000";
+
+    #[test]
+    fn every_block_type_inflates() {
+        assert_eq!(decompress(&unhex(STORED), 1024).unwrap(), b"hello, stored");
+        assert_eq!(decompress(&unhex(FIXED), 1024).unwrap(), b"hello hello hello");
+        let mut expected = b"The quick brown fox jumps over the lazy dog. ".repeat(3);
+        expected.extend_from_slice(b"Pack my box with five dozen liquor jugs.");
+        assert_eq!(decompress(&unhex(DYNAMIC), 1024).unwrap(), expected);
+    }
+
+    #[test]
+    fn bad_input_is_an_error() {
+        assert_eq!(decompress(b"hello hello hello hello", 1024), Err(GzipError::NotGzip));
+        let fixed = unhex(FIXED);
+        assert_eq!(decompress(&fixed[..fixed.len() - 4], 1024), Err(GzipError::Truncated));
+        let mut flipped = fixed.clone();
+        flipped[fixed.len() - 8] ^= 1;
+        assert_eq!(decompress(&flipped, 1024), Err(GzipError::ChecksumMismatch));
+        assert_eq!(decompress(&fixed, 10), Err(GzipError::TooLarge));
+    }
+
+
+    #[test]
+    fn compressed_data_inflates_back() {
+        let mut noise = Vec::new();
+        let mut state = 0x1234_5678u32;
+        for _ in 0..70_000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            noise.push(state as u8);
+        }
+        let html = b"<li><a href=\"/items/1\">Item</a></li>\n".repeat(2000);
+        let inputs: [&[u8]; 5] = [b"", b"a", b"hello hello hello", &html, &noise];
+        for input in inputs {
+            for level in [0, 1, 6, 9] {
+                let compressed = compress(input, level);
+                assert_eq!(decompress(&compressed, 1 << 20).as_deref(), Ok(input), "level {}, {} bytes", level, input.len());
+            }
+        }
+        assert!(compress(&html, 6).len() < html.len() / 20);
+        assert!(compress(&html, 6).len() <= compress(&html, 1).len());
+        // stored: just over the input.
+        assert_eq!(compress(&noise, 0).len(), noise.len() + 18 + 2 * 5);
+    }
+}
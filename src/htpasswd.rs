@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::base64;
+use crate::crypto;
+
+/* # Users and passwords in an htpasswd file, as Apache and nginx have them!
+# users.htpasswd
+alice:{SHA}qUqP5cyxm6YcTAhz05Hph5gvu9M=   # htpasswd -s: base64 of the SHA-1 of the password
+bob:hunter2                               # the password itself
+let users = HtpasswdFile::from_path(Path::new("users.htpasswd"))?;
+users.verify("alice", "test")  => true
+1. One user:hash per line; blank lines and lines starting with # are skipped.
+A user name can't be empty and can only be listed once.
+2. {SHA} entries and plain passwords are what this file knows. bcrypt ($2y$, the
+htpasswd default), $apr1$ (MD5) and crypt ($5$, $6$) hashes need code that surff
+doesn't have: any hash starting with $ is an error naming the line, rather than a
+user who can never log in. Anything else is taken as a plain password.
+3. verify compares digests with crypto::same_bytes, and does the same work for a user
+that isn't there, so the time it takes doesn't say which users exist. */
+
+#[derive(Clone, PartialEq, Eq)]
+enum Hash {
+    Sha1([u8; 20]),
+    Plain(String),
+}
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct HtpasswdFile {
+    users: HashMap<String, Hash>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HtpasswdError {
+    // from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for HtpasswdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for HtpasswdError {}
+
+impl HtpasswdFile {
+    // A mistake in the file is an InvalidData error naming the file and the line.
+    pub fn from_path(path: &Path) -> io::Result<HtpasswdFile> {
+        let text = fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        HtpasswdFile::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(text: &str) -> Result<HtpasswdFile, HtpasswdError> {
+        let mut users = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| HtpasswdError { line: index + 1, message };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line.split_once(':').ok_or_else(|| error("expected user:hash".to_string()))?;
+            if user.is_empty() {
+                return Err(error("empty user name".to_string()));
+            }
+            let hash = if let Some(encoded) = hash.strip_prefix("{SHA}") {
+                let digest = base64::decode(encoded.as_bytes()).and_then(|digest| <[u8; 20]>::try_from(digest).ok());
+                Hash::Sha1(digest.ok_or_else(|| error(format!("bad {{SHA}} hash for {:?}", user)))?)
+            } else if hash.starts_with("$2") {
+                return Err(error(format!("{:?} has a bcrypt hash, which is not supported: use {{SHA}} (htpasswd -s)", user)));
+            } else if hash.starts_with('$') {
+                return Err(error(format!("{:?} has an unsupported hash: use {{SHA}} (htpasswd -s)", user)));
+            } else {
+                Hash::Plain(hash.to_string())
+            };
+            if users.insert(user.to_string(), hash).is_some() {
+                return Err(error(format!("{:?} is listed twice", user)));
+            }
+        }
+        Ok(HtpasswdFile { users })
+    }
+
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(Hash::Sha1(digest)) => crypto::same_bytes(&crypto::sha1(password.as_bytes()), digest),
+            Some(Hash::Plain(plain)) => crypto::same_bytes(password.as_bytes(), plain.as_bytes()),
+            None => {
+                // as long as a {SHA} user would take.
+                std::hint::black_box(crypto::sha1(password.as_bytes()));
+                false
+            }
+        }
+    }
+
+    pub fn contains(&self, user: &str) -> bool {
+        self.users.contains_key(user)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+// The users, never their hashes.
+impl fmt::Debug for HtpasswdFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut users: Vec<&String> = self.users.keys().collect();
+        users.sort();
+        f.debug_struct("HtpasswdFile").field("users", &users).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_sha_and_plain_entries() {
+        let users = HtpasswdFile::parse("# users\nalice:{SHA}qUqP5cyxm6YcTAhz05Hph5gvu9M=\n\n bob:hunter2 \ncarol:pass:with:colons\n").unwrap();
+        assert_eq!(users.len(), 3);
+        assert!(users.verify("alice", "test"));
+        assert!(!users.verify("alice", "Test"));
+        assert!(users.verify("bob", "hunter2"));
+        assert!(!users.verify("bob", "hunter"));
+        assert!(users.verify("carol", "pass:with:colons"));
+        assert!(!users.verify("dave", "test"));
+        assert!(!users.verify("dave", ""));
+        assert_eq!(format!("{:?}", users), "HtpasswdFile { users: [\"alice\", \"bob\", \"carol\"] }");
+    }
+
+    #[test]
+    fn unsupported_hashes_are_errors_with_the_line() {
+        let cases = [
+            ("alice:$2y$05$abcdefghijklmnopqrstuv\n", 1, "\"alice\" has a bcrypt hash, which is not supported: use {SHA} (htpasswd -s)"),
+            ("# x\nbob:$apr1$salt$hash\n", 2, "\"bob\" has an unsupported hash: use {SHA} (htpasswd -s)"),
+            ("alice\n", 1, "expected user:hash"),
+            (":secret\n", 1, "empty user name"),
+            ("alice:{SHA}c2hvcnQ=\n", 1, "bad {SHA} hash for \"alice\""),
+            ("alice:a\nalice:b\n", 2, "\"alice\" is listed twice"),
+        ];
+        for (text, line, message) in cases {
+            assert_eq!(HtpasswdFile::parse(text), Err(HtpasswdError { line, message: message.to_string() }), "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::io::{self, prelude::*};
+
+use super::ResponseBuilder;
+
+/* # Streaming a response of unknown length!
+let mut body = ChunkedResponseWriter::start(&mut stream, ResponseBuilder::new(StatusCode::Ok))?;
+write!(body, "...")?;      => 3\r\n...\r\n
+body.finish()?;            => 0\r\n\r\n
+Transfer-Encoding: chunked replaces Content-Length: every write becomes one chunk,
+prefixed with its size in hex, and an empty chunk marks the end of the body.
+Forgetting .finish() leaves the client waiting for more, so it must always be called
+(dropping the writer doesn't send the last chunk: after an error, it can't be trusted to).
+HTTP/1.0 clients don't know chunked encoding; send those a Content-Length instead. */
+
+pub struct ChunkedResponseWriter<'a, W: Write> {
+    stream: &'a mut W,
+}
+
+impl<'a, W: Write> ChunkedResponseWriter<'a, W> {
+    // Writes the head of `response` (plus Transfer-Encoding: chunked); its body, if any, is ignored.
+    pub fn start(stream: &'a mut W, mut response: ResponseBuilder) -> io::Result<ChunkedResponseWriter<'a, W>> {
+        response.header("Transfer-Encoding", "chunked");
+        response.body_bytes(Vec::new());
+        response.write_to(stream)?;
+        Ok(ChunkedResponseWriter { stream })
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.stream.write_all(b"0\r\n\r\n")?;
+        self.stream.flush()
+    }
+}
+
+impl<W: Write> Write for ChunkedResponseWriter<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body.
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(b"\r\n");
+        self.stream.write_all(&chunk)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::StatusCode;
+
+    #[test]
+    fn every_write_is_a_chunk() {
+        let mut response = ResponseBuilder::new(StatusCode::Ok);
+        response.body_str("ignored");
+        let mut out = Vec::new();
+        let mut body = ChunkedResponseWriter::start(&mut out, response).unwrap();
+        body.write_all(b"hello").unwrap();
+        body.write_all(&[]).unwrap();
+        body.write_all(&[b'x'; 26]).unwrap();
+        body.finish().unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", head);
+        assert!(head.contains("\r\nTransfer-Encoding: chunked"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(body, format!("5\r\nhello\r\n1a\r\n{}\r\n0\r\n\r\n", "x".repeat(26)));
+    }
+}
@@ -0,0 +1,73 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/* # Request extensions: what middleware found out, for whoever comes after!
+request.extensions.insert(AuthenticatedUser { ... });   // in an auth middleware's before()
+let user = request.extensions.get::<AuthenticatedUser>();   // in a handler
+One value per type, so a middleware uses a type of its own as the key. Middleware only
+ever gets &Request, so insert takes &self too (there's a lock inside).
+A clone of the request shares its extensions with the original: it's still the same
+request, copied for a handler thread or a body transformer. For the same reason they
+aren't compared: two requests are equal if what the client sent is. */
+
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: Arc<Mutex<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>>,
+}
+
+impl Extensions {
+    // Replaces the value of the same type, if there was one.
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) {
+        self.values
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let value = self.values.lock().unwrap_or_else(PoisonError::into_inner).get(&TypeId::of::<T>()).cloned()?;
+        value.downcast::<T>().ok()
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.values.lock().unwrap_or_else(PoisonError::into_inner).contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.values.lock().unwrap_or_else(PoisonError::into_inner).len();
+        f.debug_struct("Extensions").field("count", &count).finish()
+    }
+}
+
+impl PartialEq for Extensions {
+    fn eq(&self, _other: &Extensions) -> bool {
+        true
+    }
+}
+
+impl Eq for Extensions {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_value_per_type_shared_by_clones() {
+        let extensions = Extensions::default();
+        assert_eq!(extensions.get::<u32>(), None);
+        extensions.insert(1u32);
+        extensions.insert(2u32);
+        extensions.insert("name");
+        assert_eq!(*extensions.get::<u32>().unwrap(), 2);
+        assert_eq!(*extensions.get::<&str>().unwrap(), "name");
+
+        let clone = extensions.clone();
+        clone.insert(3u32);
+        assert_eq!(*extensions.get::<u32>().unwrap(), 3);
+        assert!(!extensions.contains::<u64>());
+    }
+}
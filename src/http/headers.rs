@@ -0,0 +1,194 @@
+use super::ResponseBuilder;
+
+/* # One header, several values!
+With a few middleware layers each adding their own, a response can end up with
+Vary twice, or two Content-Types, and clients disagree on which one counts.
+ResponseBuilder::write_to sorts that out just before writing, header by header:
+1. Single: only one value makes sense (Content-Type, Content-Length, ETag, ...)
+=> the last one added wins.
+2. Combine: the value is a comma-separated list (Vary, Cache-Control, Link, ...)
+=> one header with every distinct value, in the order they were added.
+3. Multiple: anything else, and Set-Cookie in particular (which can't be combined:
+its dates have commas in them) => every value is sent, as added.
+The table follows the RFCs (9110 for most, 6265 for cookies, 8288 for Link);
+HeaderDeduplicator::policy adds or overrides entries for an application's own headers. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderPolicy {
+    Single,
+    Combine,
+    Multiple,
+}
+
+const SINGLE: &[&str] = &[
+    "Age",
+    "Access-Control-Allow-Origin",
+    "Access-Control-Max-Age",
+    "Content-Disposition",
+    "Content-Length",
+    "Content-Location",
+    "Content-Range",
+    "Content-Type",
+    "Date",
+    "ETag",
+    "Expires",
+    "Last-Modified",
+    "Location",
+    "Retry-After",
+    "Server",
+    "Strict-Transport-Security",
+    "X-Content-Type-Options",
+    "X-Frame-Options",
+];
+
+const COMBINE: &[&str] = &[
+    "Accept-Ranges",
+    "Access-Control-Allow-Headers",
+    "Access-Control-Allow-Methods",
+    "Access-Control-Expose-Headers",
+    "Allow",
+    "Cache-Control",
+    "Connection",
+    "Content-Encoding",
+    "Content-Language",
+    "Link",
+    "Transfer-Encoding",
+    "Vary",
+    "Via",
+    "X-Robots-Tag",
+];
+
+impl HeaderPolicy {
+    // From the built-in table; names it doesn't know are Multiple.
+    pub fn of(name: &str) -> HeaderPolicy {
+        if SINGLE.iter().any(|known| known.eq_ignore_ascii_case(name)) {
+            HeaderPolicy::Single
+        } else if COMBINE.iter().any(|known| known.eq_ignore_ascii_case(name)) {
+            HeaderPolicy::Combine
+        } else {
+            HeaderPolicy::Multiple
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HeaderDeduplicator {
+    overrides: Vec<(String, HeaderPolicy)>,
+}
+
+impl HeaderDeduplicator {
+    pub fn new() -> HeaderDeduplicator {
+        HeaderDeduplicator::default()
+    }
+
+    // e.g. .policy("X-Request-ID", HeaderPolicy::Single)
+    pub fn policy(mut self, name: &str, policy: HeaderPolicy) -> HeaderDeduplicator {
+        self.overrides.retain(|(known, _)| !known.eq_ignore_ascii_case(name));
+        self.overrides.push((name.to_string(), policy));
+        self
+    }
+
+    pub fn policy_for(&self, name: &str) -> HeaderPolicy {
+        self.overrides
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .map(|(_, policy)| *policy)
+            .unwrap_or_else(|| HeaderPolicy::of(name))
+    }
+
+    pub fn apply(&self, response: &mut ResponseBuilder) {
+        self.deduplicate(&mut response.headers);
+    }
+
+    pub(crate) fn deduplicate(&self, headers: &mut Vec<(String, String)>) {
+        let mut result: Vec<(String, String)> = Vec::with_capacity(headers.len());
+        for (name, value) in headers.drain(..) {
+            let existing = result.iter().position(|(known, _)| known.eq_ignore_ascii_case(&name));
+            match (self.policy_for(&name), existing) {
+                (HeaderPolicy::Single, Some(i)) => {
+                    // the last value wins, where the last one was added.
+                    result.remove(i);
+                    result.push((name, value));
+                }
+                (HeaderPolicy::Combine, Some(i)) => {
+                    let combined = &mut result[i].1;
+                    if !combined.split(", ").any(|known| known == value) {
+                        combined.push_str(", ");
+                        combined.push_str(&value);
+                    }
+                }
+                _ => result.push((name, value)),
+            }
+        }
+        *headers = result;
+    }
+}
+
+impl ResponseBuilder {
+    // With the built-in table; write_to does the same to what it sends.
+    pub fn deduplicate_headers(&mut self) -> &mut Self {
+        HeaderDeduplicator::new().apply(self);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::StatusCode;
+
+    fn written(response: &ResponseBuilder) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn single_value_headers_keep_the_last_value() {
+        let mut response = ResponseBuilder::new(StatusCode::Ok);
+        response
+            .header("Content-Type", "text/plain")
+            .header("ETag", "\"a\"")
+            .header("content-type", "text/html");
+
+        let out = written(&response);
+        assert!(out.contains("\r\ncontent-type: text/html\r\n"), "{:?}", out);
+        assert!(!out.contains("text/plain"));
+    }
+
+    #[test]
+    fn list_headers_are_combined() {
+        let mut response = ResponseBuilder::new(StatusCode::Ok);
+        response
+            .header("Vary", "Accept-Encoding")
+            .header("Cache-Control", "no-cache")
+            .header("Vary", "Origin")
+            .header("Vary", "Accept-Encoding");
+
+        assert!(written(&response).contains("\r\nVary: Accept-Encoding, Origin\r\nCache-Control: no-cache\r\n"));
+    }
+
+    #[test]
+    fn set_cookie_and_unknown_headers_are_all_kept() {
+        let mut response = ResponseBuilder::new(StatusCode::Ok);
+        response
+            .header("Set-Cookie", "a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT")
+            .header("Set-Cookie", "b=2")
+            .header("X-Request-ID", "1")
+            .header("X-Request-ID", "2");
+
+        let out = written(&response);
+        assert_eq!(out.matches("Set-Cookie:").count(), 2);
+        assert_eq!(out.matches("X-Request-ID:").count(), 2);
+    }
+
+    #[test]
+    fn policies_can_be_overridden() {
+        let mut response = ResponseBuilder::new(StatusCode::Ok);
+        response.header("X-Request-ID", "1").header("X-Request-ID", "2");
+
+        HeaderDeduplicator::new().policy("x-request-id", HeaderPolicy::Single).apply(&mut response);
+        assert_eq!(response.header_value("X-Request-ID"), Some("2"));
+        assert_eq!(written(&response).matches("X-Request-ID:").count(), 1);
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+use std::io::{self, prelude::*};
+
+/* # HTTP/2 frames, enough to say "HTTP/1.1, please"!
+A client with prior knowledge (h2c, RFC 9113, 3.3) skips Upgrade and opens with
+PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n  then frames: 9 bytes of header (length, type, flags,
+stream) and the payload. Read as HTTP/1.1 that's a request for "*" with a version we
+don't speak, and the 400 back is bytes the client can't make sense of.
+The server notices the preface instead (is_preface), and answers in HTTP/2: an empty
+SETTINGS, which must come first, then GOAWAY with HTTP_1_1_REQUIRED, which tells the
+client to try again over HTTP/1.1 (RFC 9113, 7). Then it closes the connection.
+Serving HTTP/2 itself (HPACK, streams, flow control) is the h2 crate's job, and surff
+doesn't depend on it; clients that negotiate HTTP/2 do it with TLS ALPN, which surff
+doesn't do either (see tls.rs), so they never get here. */
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+// Up to the end of what an HTTP/1.1 parser would take for a request line.
+const PREFACE_LINE: &[u8] = b"PRI * HTTP/2.0\r\n";
+
+pub const FRAME_SETTINGS: u8 = 0x4;
+pub const FRAME_GOAWAY: u8 = 0x7;
+pub const HTTP_1_1_REQUIRED: u32 = 0xd;
+const FRAME_HEADER: usize = 9;
+// SETTINGS_MAX_FRAME_SIZE's initial value: nobody may send more before SETTINGS say so.
+const MAX_PAYLOAD: usize = 16_384;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    // 31 bits; 0 is the connection itself.
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    Truncated,
+    TooLarge(usize),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated => write!(f, "HTTP/2 frame ends early"),
+            FrameError::TooLarge(length) => write!(f, "HTTP/2 frame of {} bytes", length),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = (self.payload.len() as u32).to_be_bytes()[1..].to_vec();
+        bytes.extend_from_slice(&[self.kind, self.flags]);
+        bytes.extend_from_slice(&(self.stream_id & 0x7fff_ffff).to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    // The frame at the start of `bytes`, and how many bytes it took.
+    pub fn parse(bytes: &[u8]) -> Result<(Frame, usize), FrameError> {
+        let header = bytes.get(..FRAME_HEADER).ok_or(FrameError::Truncated)?;
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if length > MAX_PAYLOAD {
+            return Err(FrameError::TooLarge(length));
+        }
+        let payload = bytes.get(FRAME_HEADER..FRAME_HEADER + length).ok_or(FrameError::Truncated)?;
+        let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+        Ok((Frame { kind: header[3], flags: header[4], stream_id, payload: payload.to_vec() }, FRAME_HEADER + length))
+    }
+
+    // The last stream this side will have processed (none: 0), why, and some text for people.
+    pub fn goaway(last_stream_id: u32, error_code: u32, debug: &str) -> Frame {
+        let mut payload = (last_stream_id & 0x7fff_ffff).to_be_bytes().to_vec();
+        payload.extend_from_slice(&error_code.to_be_bytes());
+        payload.extend_from_slice(debug.as_bytes());
+        Frame { kind: FRAME_GOAWAY, flags: 0, stream_id: 0, payload }
+    }
+}
+
+// Whether a connection's first bytes are HTTP/2's preface (as far as they've arrived).
+pub fn is_preface(first_bytes: &[u8]) -> bool {
+    first_bytes.len() >= PREFACE_LINE.len() && PREFACE.starts_with(&first_bytes[..first_bytes.len().min(PREFACE.len())])
+}
+
+// SETTINGS and GOAWAY(HTTP_1_1_REQUIRED): what a prior-knowledge client gets.
+pub fn refuse_http2(stream: &mut impl Write) -> io::Result<()> {
+    let settings = Frame { kind: FRAME_SETTINGS, flags: 0, stream_id: 0, payload: Vec::new() };
+    let mut bytes = settings.encode();
+    bytes.extend_from_slice(&Frame::goaway(0, HTTP_1_1_REQUIRED, "surff speaks HTTP/1.1").encode());
+    stream.write_all(&bytes)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let goaway = Frame::goaway(3, HTTP_1_1_REQUIRED, "bye");
+        let bytes = goaway.encode();
+        assert_eq!(bytes, [0, 0, 11, 7, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0xd, b'b', b'y', b'e']);
+        assert_eq!(Frame::parse(&bytes), Ok((goaway, bytes.len())));
+        assert_eq!(Frame::parse(&bytes[..bytes.len() - 1]), Err(FrameError::Truncated));
+        assert_eq!(Frame::parse(&[0, 0x40, 1, 0, 0, 0, 0, 0, 1]), Err(FrameError::TooLarge(16_385)));
+
+        assert!(is_preface(PREFACE) && is_preface(b"PRI * HTTP/2.0\r\n\r\n"));
+        let mut with_frames = PREFACE.to_vec();
+        with_frames.extend_from_slice(&bytes);
+        assert!(is_preface(&with_frames));
+        assert!(!is_preface(b"PRI * HTTP/2") && !is_preface(b"GET / HTTP/1.1\r\n\r\n"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+// # HTTP/1.x types shared by the server side.
+// request.rs turns the bytes of a request head into a Request (extensions.rs: what
+// middleware attaches to it);
+// read.rs reads those bytes (and the body) from the connection;
+// response.rs writes the answer, chunked.rs streams it when the length isn't known up front,
+// headers.rs decides what happens to a header that was added more than once,
+// pipeline.rs turns a request body into what a handler wants (verified, decompressed),
+// and http2.rs sends HTTP/2 clients back to HTTP/1.1.
+
+mod chunked;
+mod extensions;
+mod headers;
+mod http2;
+mod pipeline;
+mod range;
+mod read;
+mod request;
+mod response;
+
+pub use chunked::ChunkedResponseWriter;
+pub use extensions::Extensions;
+pub use headers::{HeaderDeduplicator, HeaderPolicy};
+pub use http2::{is_preface, refuse_http2, Frame, FrameError};
+pub use pipeline::{Base64Decoder, BodyPipeline, BodyProcessor, GzipDecompressor, HmacVerifier, PipelineError, ProcessError};
+pub use range::{unsatisfiable_content_range, ByteRange, RangeRequest};
+pub use read::{read_request, read_request_with_progress, read_until_headers, BodyProgress, BodyStream, ReadError, RequestLimits};
+pub use request::{ParseError, Request};
+pub use response::{ResponseBuilder, StatusCode};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    // Opens a tunnel (through a proxy); the target is host:port, not a path.
+    Connect,
+    // Anything else (TRACE, WebDAV's PROPFIND, ...), kept as sent.
+    Unknown(String),
+}
+
+impl Method {
+    // Method names are case-sensitive: "get" is not GET.
+    pub fn from_token(token: &str) -> Method {
+        match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            "CONNECT" => Method::Connect,
+            other => Method::Unknown(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Connect => "CONNECT",
+            Method::Unknown(token) => token,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+}
+
+impl HttpVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
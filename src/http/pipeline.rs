@@ -0,0 +1,202 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::{base64, crypto, gzip};
+use super::Request;
+
+/* # Preparing a body in steps!
+let pipeline = BodyPipeline::new()
+    .then(HmacVerifier::new(b"secret", "X-Signature"))
+    .then(GzipDecompressor::new(1024 * 1024))
+    .then(|body: Vec<u8>| Ok(body.to_ascii_uppercase()));
+let body = request.process_body(&pipeline)?;
+Each processor gets what the one before it returned (the first gets the request body),
+so the order is the order the sender applied them in, reversed: a client that
+compressed and then signed is checked, then decompressed. The first error stops it,
+and says which stage it came from (0 is the first processor added).
+A plain Fn(Vec<u8>) -> Result<Vec<u8>, _> is a processor too; the built-in ones
+below also look at the request's headers. */
+
+pub type ProcessError = Box<dyn Error + Send + Sync>;
+
+pub trait BodyProcessor: Send + Sync {
+    fn process(&self, request: &Request, body: Vec<u8>) -> Result<Vec<u8>, ProcessError>;
+}
+
+impl<F> BodyProcessor for F
+    where
+        F: Fn(Vec<u8>) -> Result<Vec<u8>, ProcessError> + Send + Sync
+{
+    fn process(&self, _request: &Request, body: Vec<u8>) -> Result<Vec<u8>, ProcessError> {
+        self(body)
+    }
+}
+
+#[derive(Default)]
+pub struct BodyPipeline {
+    processors: Vec<Box<dyn BodyProcessor>>,
+}
+
+impl BodyPipeline {
+    pub fn new() -> BodyPipeline {
+        BodyPipeline::default()
+    }
+
+    pub fn then(mut self, processor: impl BodyProcessor + 'static) -> BodyPipeline {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    pub fn run(&self, request: &Request) -> Result<Vec<u8>, PipelineError> {
+        let mut body = request.body.clone();
+        for (stage, processor) in self.processors.iter().enumerate() {
+            body = processor.process(request, body).map_err(|source| PipelineError { stage, source })?;
+        }
+        Ok(body)
+    }
+}
+
+impl fmt::Debug for BodyPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyPipeline").field("stages", &self.processors.len()).finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct PipelineError {
+    // Which processor failed, counting from 0.
+    pub stage: usize,
+    pub source: ProcessError,
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "body pipeline stage {} failed: {}", self.stage, self.source)
+    }
+}
+
+impl Error for PipelineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+// gzip => the bytes inside, or an error if there's more than `max_size` of them.
+// Bodies without Content-Encoding: gzip are passed on as they are.
+#[derive(Debug, Clone)]
+pub struct GzipDecompressor {
+    max_size: usize,
+}
+
+impl GzipDecompressor {
+    pub fn new(max_size: usize) -> GzipDecompressor {
+        GzipDecompressor { max_size }
+    }
+}
+
+impl BodyProcessor for GzipDecompressor {
+    fn process(&self, request: &Request, body: Vec<u8>) -> Result<Vec<u8>, ProcessError> {
+        let gzipped = request
+            .header("Content-Encoding")
+            .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("gzip"));
+        if !gzipped {
+            return Ok(body);
+        }
+        Ok(gzip::decompress(&body, self.max_size)?)
+    }
+}
+
+// Checks a `sha256=<hex>` HMAC of the body in `header_name`, and passes the body on
+// unchanged. (router::SignatureVerifier does the same for a route's body.)
+#[derive(Clone)]
+pub struct HmacVerifier {
+    key: Vec<u8>,
+    header_name: String,
+}
+
+impl HmacVerifier {
+    pub fn new(key: &[u8], header_name: &str) -> HmacVerifier {
+        HmacVerifier { key: key.to_vec(), header_name: header_name.to_string() }
+    }
+}
+
+// Not derived, to keep the key out of logs.
+impl fmt::Debug for HmacVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HmacVerifier").field("header_name", &self.header_name).finish_non_exhaustive()
+    }
+}
+
+impl BodyProcessor for HmacVerifier {
+    fn process(&self, request: &Request, body: Vec<u8>) -> Result<Vec<u8>, ProcessError> {
+        let signature = request
+            .header(&self.header_name)
+            .ok_or_else(|| format!("missing {} header", self.header_name))?;
+        if !crypto::signature_matches(&self.key, signature, &body) {
+            return Err("signature does not match the body".into());
+        }
+        Ok(body)
+    }
+}
+
+// Base64 text => the bytes it encodes. Trailing whitespace (a newline) is allowed.
+#[derive(Debug, Clone, Default)]
+pub struct Base64Decoder;
+
+impl Base64Decoder {
+    pub fn new() -> Base64Decoder {
+        Base64Decoder
+    }
+}
+
+impl BodyProcessor for Base64Decoder {
+    fn process(&self, _request: &Request, body: Vec<u8>) -> Result<Vec<u8>, ProcessError> {
+        let end = body.iter().rposition(|byte| !byte.is_ascii_whitespace()).map_or(0, |last| last + 1);
+        Ok(base64::decode(&body[..end]).ok_or("body is not valid base64")?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str, body: &[u8]) -> Request {
+        let mut request = Request::parse(format!("POST / HTTP/1.1\r\n{}\r\n", headers).as_bytes()).unwrap();
+        request.body = body.to_vec();
+        request
+    }
+
+    // gzip.compress(b"hello hello hello", mtime=0), base64-encoded.
+    const GZIPPED_BASE64: &[u8] = b"H4sIAAAAAAACA8tIzcnJV8hAkACAiPnlEQAAAA==\n";
+
+    #[test]
+    fn stages_run_in_order() {
+        let signature = format!("X-Signature: sha256={}\r\n", crypto::to_hex(&crypto::hmac_sha256(b"key", GZIPPED_BASE64)));
+        let pipeline = BodyPipeline::new()
+            .then(HmacVerifier::new(b"key", "X-Signature"))
+            .then(Base64Decoder::new())
+            .then(GzipDecompressor::new(1024))
+            .then(|body: Vec<u8>| -> Result<Vec<u8>, ProcessError> { Ok(body.to_ascii_uppercase()) });
+
+        let request = request(&format!("{}Content-Encoding: gzip\r\n", signature), GZIPPED_BASE64);
+        assert_eq!(request.process_body(&pipeline).unwrap(), b"HELLO HELLO HELLO");
+    }
+
+    #[test]
+    fn the_first_failing_stage_is_reported() {
+        let pipeline = BodyPipeline::new()
+            .then(HmacVerifier::new(b"key", "X-Signature"))
+            .then(Base64Decoder::new());
+        let error = request("", b"aGVsbG8=").process_body(&pipeline).unwrap_err();
+        assert_eq!(error.stage, 0);
+        assert_eq!(error.to_string(), "body pipeline stage 0 failed: missing X-Signature header");
+
+        let pipeline = BodyPipeline::new().then(Base64Decoder::new()).then(GzipDecompressor::new(1024));
+        assert_eq!(request("", b"aGVsbG8=\n").process_body(&pipeline).unwrap(), b"hello");
+        let error = request("Content-Encoding: gzip\r\n", b"aGVsbG8=").process_body(&pipeline).unwrap_err();
+        assert_eq!(error.stage, 1);
+        assert_eq!(error.source.to_string(), "not gzip data");
+        let error = request("", b"not base64").process_body(&pipeline).unwrap_err();
+        assert_eq!(error.stage, 0);
+    }
+}
@@ -0,0 +1,133 @@
+use super::{Method, Request};
+
+/* # Range requests: part of a file, for resumed downloads and seeking in videos!
+Range: bytes=0-499    => the first 500 bytes          => 206, Content-Range: bytes 0-499/1234
+Range: bytes=500-     => from byte 500 to the end
+Range: bytes=-500     => the last 500 bytes
+Range: bytes=5000-    => nothing of a 1234 byte file => 416, the Content-Range only has the length
+1. Only GET requests get a part: the header means nothing for the other methods.
+2. One range only. Several (bytes=0-1,5-6) would need a multipart/byteranges body;
+they get the whole file instead, as RFC 9110 allows, and so does anything that isn't a
+bytes range or doesn't parse: a server may always ignore Range.
+3. An end past the end of the file is cut to it, and so is a suffix longer than the file.
+A range that starts past the end (or any range of an empty file) can't be satisfied.
+Whoever answers says Accept-Ranges: bytes, so that clients know they may ask. */
+
+// From `start` to `end`, both included, like in the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    // Never 0: a range has at least its first byte.
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    // "bytes 0-499/1234", of a resource `total` bytes long.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeRequest {
+    // no Range header, or one that's ignored: a 200 with everything.
+    Full,
+    Partial(ByteRange),
+    // a 416, with unsatisfiable_content_range().
+    Unsatisfiable,
+}
+
+impl RangeRequest {
+    // What `request` asks for of a resource `length` bytes long.
+    pub fn of(request: &Request, length: u64) -> RangeRequest {
+        match request.header("Range") {
+            Some(value) if request.method == Method::Get => parse(value, length),
+            _ => RangeRequest::Full,
+        }
+    }
+}
+
+// "bytes */1234": what a 416 says the length is.
+pub fn unsatisfiable_content_range(length: u64) -> String {
+    format!("bytes */{}", length)
+}
+
+fn parse(value: &str, length: u64) -> RangeRequest {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let number = |digits: &str| (!digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())).then(|| digits.parse::<u64>().ok()).flatten();
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // a suffix: the last N bytes.
+        return match number(last) {
+            Some(0) => RangeRequest::Unsatisfiable,
+            Some(_) if length == 0 => RangeRequest::Unsatisfiable,
+            Some(suffix) => RangeRequest::Partial(ByteRange { start: length.saturating_sub(suffix), end: length - 1 }),
+            None => RangeRequest::Full,
+        };
+    }
+    let Some(start) = number(first) else {
+        return RangeRequest::Full;
+    };
+    let end = match last {
+        "" => u64::MAX,
+        last => match number(last) {
+            Some(end) if end >= start => end,
+            _ => return RangeRequest::Full,
+        },
+    };
+    if start >= length {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial(ByteRange { start, end: end.min(length - 1) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> RangeRequest {
+        RangeRequest::Partial(ByteRange { start, end })
+    }
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(parse("bytes=0-499", 1234), range(0, 499));
+        assert_eq!(parse("bytes=500-", 1234), range(500, 1233));
+        assert_eq!(parse("bytes=-500", 1234), range(734, 1233));
+        assert_eq!(parse("bytes=1000-5000", 1234), range(1000, 1233));
+        assert_eq!(parse("bytes=-5000", 1234), range(0, 1233));
+        assert_eq!(parse(" bytes= 7-7 ", 1234), range(7, 7));
+        assert_eq!(ByteRange { start: 0, end: 499 }.length(), 500);
+        assert_eq!(ByteRange { start: 0, end: 499 }.content_range(1234), "bytes 0-499/1234");
+
+        assert_eq!(parse("bytes=1234-", 1234), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 1234), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=-1", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(unsatisfiable_content_range(1234), "bytes */1234");
+
+        // ignored: the whole thing.
+        for value in ["bytes=0-1,5-6", "items=0-1", "bytes=5-1", "bytes=a-b", "bytes=-", "bytes=+1-2", "bytes 0-1", "bytes=99999999999999999999-"] {
+            assert_eq!(parse(value, 1234), RangeRequest::Full, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn only_gets_get_a_part() {
+        let request = |method: &str| Request::parse(format!("{} / HTTP/1.1\r\nRange: bytes=0-9\r\n\r\n", method).as_bytes()).unwrap();
+        assert_eq!(RangeRequest::of(&request("GET"), 100), range(0, 9));
+        assert_eq!(RangeRequest::of(&request("HEAD"), 100), RangeRequest::Full);
+        assert_eq!(RangeRequest::of(&request("POST"), 100), RangeRequest::Full);
+        assert_eq!(RangeRequest::of(&Request::parse(b"GET / HTTP/1.1\r\n\r\n").unwrap(), 100), RangeRequest::Full);
+    }
+}
@@ -0,0 +1,286 @@
+use std::fmt;
+use std::io::{self, prelude::*};
+
+use super::{ParseError, Request, StatusCode};
+
+/* # Reading a whole request off the wire!
+A single stream.read() returns whatever happens to have arrived: maybe half the
+headers, maybe the headers and part of the body. read_request instead:
+1. Reads line by line until the blank line that ends the head, giving up with
+HeadersTooLarge (=> 431) once the head is bigger than max_header_size.
+2. Parses the head with Request::parse.
+3. Reads exactly Content-Length more bytes as the body, refusing anything
+above max_body_size (=> 413) before reading a single byte of it.
+Chunked request bodies aren't supported (=> 501).
+4. The body is read through a BodyStream, which can report its progress as it goes:
+read_request_with_progress calls back after every read, with the bytes so far and the
+Content-Length (see server/upload_progress.rs).
+Reading the body in full keeps a persistent connection in step: the next read
+starts right at the next request. A client that closes the connection instead of
+sending another request gives ConnectionClosed. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    // 8 KiB of headers is what most servers (nginx, Apache) allow by default.
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_header_size: 8 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    // Closed cleanly before the first byte of a request.
+    ConnectionClosed,
+    Parse(ParseError),
+    HeadersTooLarge,
+    BodyTooLarge,
+    UnsupportedTransferEncoding,
+}
+
+impl ReadError {
+    // The response status to send back, or None when the connection itself failed.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ReadError::Io(_) | ReadError::ConnectionClosed => None,
+            ReadError::Parse(_) => Some(StatusCode::BadRequest),
+            ReadError::HeadersTooLarge => Some(StatusCode::RequestHeaderFieldsTooLarge),
+            ReadError::BodyTooLarge => Some(StatusCode::PayloadTooLarge),
+            ReadError::UnsupportedTransferEncoding => Some(StatusCode::NotImplemented),
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "I/O error: {}", e),
+            ReadError::ConnectionClosed => write!(f, "connection closed"),
+            ReadError::Parse(e) => write!(f, "{}", e),
+            ReadError::HeadersTooLarge => write!(f, "request headers too large"),
+            ReadError::BodyTooLarge => write!(f, "request body too large"),
+            ReadError::UnsupportedTransferEncoding => write!(f, "unsupported Transfer-Encoding"),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadError::Io(e) => Some(e),
+            ReadError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> ReadError {
+        ReadError::Io(e)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> ReadError {
+        ReadError::Parse(e)
+    }
+}
+
+pub fn read_request<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> Result<Request, ReadError> {
+    read_request_with_progress(reader, limits, None)
+}
+
+pub fn read_request_with_progress<R: BufRead>(reader: &mut R, limits: &RequestLimits, progress: Option<&BodyProgress>) -> Result<Request, ReadError> {
+    let head = read_until_headers(reader, limits.max_header_size)?;
+    let mut request = Request::parse(&head)?;
+
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(ReadError::UnsupportedTransferEncoding);
+    }
+
+    let length = content_length(&request)?.unwrap_or(0);
+    if length > limits.max_body_size {
+        return Err(ReadError::BodyTooLarge);
+    }
+
+    let mut body = vec![0; length];
+    let mut stream = BodyStream::new(reader, length as u64);
+    if let Some(progress) = progress {
+        let head = &request;
+        stream = stream.with_progress(move |read, total| progress(head, read, total));
+    }
+    stream.read_exact(&mut body)?;
+    drop(stream);
+    request.body = body;
+
+    Ok(request)
+}
+
+// What's told about a body as it comes in: the request (its head; the body is still
+// arriving), the bytes read so far, and the Content-Length. It runs on the thread
+// reading the request, between two reads: it has to be quick, and not block.
+pub type BodyProgress = dyn Fn(&Request, u64, Option<u64>) + Send + Sync;
+
+type ProgressCallback<'a> = Box<dyn FnMut(u64, Option<u64>) + 'a>;
+
+// # A request body, read off the connection!
+// At most `length` bytes, and an UnexpectedEof error if the connection ends first.
+// content_length is None for a body without one (chunked), which read_request
+// doesn't take yet.
+pub struct BodyStream<'a, R> {
+    reader: R,
+    remaining: u64,
+    read: u64,
+    content_length: Option<u64>,
+    progress: Option<ProgressCallback<'a>>,
+}
+
+impl<'a, R: Read> BodyStream<'a, R> {
+    pub fn new(reader: R, length: u64) -> BodyStream<'a, R> {
+        BodyStream { reader, remaining: length, read: 0, content_length: Some(length), progress: None }
+    }
+
+    // `progress` is called after each read from the network, with the bytes read so far.
+    pub fn with_progress(mut self, progress: impl FnMut(u64, Option<u64>) + 'a) -> BodyStream<'a, R> {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+}
+
+impl<R: Read> Read for BodyStream<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let allowed = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.reader.read(&mut buf[..allowed])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "request body ends early"));
+        }
+        self.remaining -= read as u64;
+        self.read += read as u64;
+        if let Some(progress) = &mut self.progress {
+            progress(self.read, self.content_length);
+        }
+        Ok(read)
+    }
+}
+
+// # Content-Length, strictly!
+// Only ASCII digits: "+3" or " 3" would get through str::parse, and a proxy in front
+// of us might read them differently. A request may repeat the header (or list the
+// value twice, "3, 3") as long as every copy agrees; two different lengths mean
+// two parties could disagree on where the body ends, so that's a 400.
+fn content_length(request: &Request) -> Result<Option<usize>, ParseError> {
+    let mut length = None;
+
+    for (name, value) in &request.headers {
+        if !name.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        let invalid = || ParseError::InvalidHeader(format!("{}: {}", name, value));
+        for item in value.split(',') {
+            let item = item.trim();
+            if item.is_empty() || !item.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            let parsed = item.parse::<usize>().map_err(|_| invalid())?;
+            match length {
+                Some(previous) if previous != parsed => return Err(invalid()),
+                _ => length = Some(parsed),
+            }
+        }
+    }
+
+    Ok(length)
+}
+
+// The request head, up to and including the blank line.
+pub fn read_until_headers<R: BufRead>(reader: &mut R, max_header_size: usize) -> Result<Vec<u8>, ReadError> {
+    let mut head = Vec::new();
+
+    while !head.ends_with(b"\r\n\r\n") {
+        // One byte over the limit is enough to know it's too large.
+        let allowed = (max_header_size + 1).saturating_sub(head.len()) as u64;
+        let n = reader.by_ref().take(allowed).read_until(b'\n', &mut head)?;
+        if head.len() > max_header_size {
+            return Err(ReadError::HeadersTooLarge);
+        }
+        if n == 0 && head.is_empty() {
+            return Err(ReadError::ConnectionClosed);
+        }
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(raw: &str) -> Result<Request, ReadError> {
+        read_request(&mut raw.as_bytes(), &RequestLimits::default())
+    }
+
+    #[test]
+    fn reads_the_body_by_content_length() {
+        let request = read("POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcdef").unwrap();
+        assert_eq!(request.body, b"abc");
+    }
+
+    #[test]
+    fn content_length_must_be_digits_only() {
+        for value in ["+3", "-3", " ", "3a", "0x3"] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nabc", value);
+            let err = read(&raw).unwrap_err();
+            assert_eq!(err.status(), Some(StatusCode::BadRequest), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn repeated_content_length_must_agree() {
+        let same = read("POST / HTTP/1.1\r\nContent-Length: 3\r\ncontent-length: 3, 3\r\n\r\nabc").unwrap();
+        assert_eq!(same.body, b"abc");
+
+        let differing = read("POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 5\r\n\r\nabcde").unwrap_err();
+        assert_eq!(differing.status(), Some(StatusCode::BadRequest));
+        let listed = read("POST / HTTP/1.1\r\nContent-Length: 3, 5\r\n\r\nabcde").unwrap_err();
+        assert_eq!(listed.status(), Some(StatusCode::BadRequest));
+    }
+
+
+    #[test]
+    fn bodies_report_their_progress_after_every_read() {
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = std::sync::Arc::clone(&calls);
+        let progress = move |request: &Request, read: u64, total: Option<u64>| recorded.lock().unwrap().push((request.path.clone(), read, total));
+        // 8 bytes at a time from the "network": the rest of the head first, then the body.
+        let raw: &[u8] = b"POST /up HTTP/1.1\r\nContent-Length: 12\r\n\r\nhello, world";
+        let mut reader = io::BufReader::with_capacity(8, raw);
+        let request = read_request_with_progress(&mut reader, &RequestLimits::default(), Some(&progress)).unwrap();
+        assert_eq!(request.body, b"hello, world");
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.last(), Some(&("/up".to_string(), 12, Some(12))));
+        assert!(calls.len() >= 2 && calls.windows(2).all(|pair| pair[0].1 < pair[1].1), "{:?}", calls);
+
+        let mut stream = BodyStream::new(&b"abc"[..], 5);
+        let mut body = Vec::new();
+        assert_eq!(stream.read_to_end(&mut body).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!((stream.bytes_read(), body.as_slice()), (3, &b"abc"[..]));
+    }
+}
@@ -0,0 +1,377 @@
+use std::fmt;
+
+use super::{BodyPipeline, Extensions, HttpVersion, Method, PipelineError};
+use crate::json::{JsonError, JsonMergePatch, JsonValue};
+
+/* # Parsing the request head!
+GET /hello%20world.html?lang=en HTTP/1.1\r\n      <= request line
+Host: 127.0.0.1:1998\r\n                          <= headers, one per line
+Accept: text/html\r\n
+\r\n                                              <= blank line: end of the head
+1. Everything up to the blank line must be in the buffer, otherwise it's Incomplete.
+2. The request line is "<method> <target> <version>", separated by single spaces.
+3. The target is split at the first '?': the path is percent-decoded
+(%20 => ' '), the query is kept as sent since its encoding is up to the handler.
+4. Each header is "<name>:<value>"; whitespace around the value is dropped. Names
+have no whitespace or control characters in them, values no CR, LF or NUL.
+Anything after the blank line is the body, which parse doesn't look at:
+read_request fills in .body. Extensions start out empty. */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: HttpVersion,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Set by middleware for the layers and handler after it (see extensions.rs).
+    pub extensions: Extensions,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    // No blank line yet: read more bytes and try again.
+    Incomplete,
+    InvalidRequestLine,
+    InvalidHeader(String),
+    UnsupportedVersion(String),
+    // A bad %-escape, or a path that isn't UTF-8 once decoded.
+    InvalidPath,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "incomplete request head"),
+            ParseError::InvalidRequestLine => write!(f, "malformed request line"),
+            ParseError::InvalidHeader(line) => write!(f, "malformed header: {}", line),
+            ParseError::UnsupportedVersion(version) => write!(f, "unsupported HTTP version: {}", version),
+            ParseError::InvalidPath => write!(f, "malformed request path"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Request {
+    pub fn parse(buf: &[u8]) -> Result<Request, ParseError> {
+        let head_end = buf
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or(ParseError::Incomplete)?;
+        // Header values may be Latin-1 in the wild; the lossy conversion keeps the rest readable.
+        let head = String::from_utf8_lossy(&buf[..head_end]);
+        let mut lines = head.split("\r\n");
+
+        let request_line = lines.next().unwrap_or("");
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) if !method.is_empty() && !target.is_empty() => {
+                (method, target, version)
+            },
+            _ => return Err(ParseError::InvalidRequestLine),
+        };
+
+        let version = match version {
+            "HTTP/1.1" => HttpVersion::Http11,
+            "HTTP/1.0" => HttpVersion::Http10,
+            other if other.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion(other.to_string())),
+            _ => return Err(ParseError::InvalidRequestLine),
+        };
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+        let path = percent_decode(path).ok_or(ParseError::InvalidPath)?;
+
+        let mut headers = Vec::new();
+        for line in lines {
+            let invalid = || ParseError::InvalidHeader(line.to_string());
+            // A leading space is obsolete line folding (RFC 7230 says reject it).
+            if line.starts_with([' ', '\t']) {
+                return Err(invalid());
+            }
+            let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+            if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                return Err(invalid());
+            }
+            // A bare CR or LF is a line break to some parsers and not to others.
+            if value.contains(['\r', '\n', '\0']) {
+                return Err(invalid());
+            }
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+
+        Ok(Request {
+            method: Method::from_token(method),
+            path,
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+            extensions: Extensions::default(),
+        })
+    }
+
+    // The body, run through each of the pipeline's processors in turn (see pipeline.rs).
+    pub fn process_body(&self, pipeline: &BodyPipeline) -> Result<Vec<u8>, PipelineError> {
+        pipeline.run(self)
+    }
+
+    // `base` with the body applied to it as a JSON Merge Patch (see json.rs), for PATCH handlers.
+    // A body that isn't UTF-8 JSON is an error; a 400 is the answer to it.
+    pub fn json_merge_patch(&self, base: &JsonValue) -> Result<JsonValue, JsonError> {
+        let text = std::str::from_utf8(&self.body).map_err(|e| JsonError { offset: e.valid_up_to(), message: "not UTF-8".to_string() })?;
+        let mut merged = base.clone();
+        JsonMergePatch::parse(text)?.apply_to(&mut merged);
+        Ok(merged)
+    }
+
+    // An application/x-www-form-urlencoded body (an HTML form's POST): name=value pairs
+    // split at &, with + for spaces and %-escapes decoded. None if that fails.
+    pub fn form(&self) -> Option<Vec<(String, String)>> {
+        let body = std::str::from_utf8(&self.body).ok()?;
+        let decode = |s: &str| percent_decode(&s.replace('+', " "));
+        body.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Some((decode(name)?, decode(value)?))
+            })
+            .collect()
+    }
+
+    // The value of a cookie the client sent (Cookie: a=1; b=2), from any Cookie header.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.trim_matches('"'))
+    }
+
+    // # Persistent connections!
+    // HTTP/1.1 keeps the connection open unless the client sends Connection: close.
+    // HTTP/1.0 clients only keep it open if both sides say Connection: keep-alive,
+    // and our handlers don't say it, so those connections are always closed.
+    pub fn keep_alive(&self) -> bool {
+        let close = self
+            .header("Connection")
+            .is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("close")));
+        self.version == HttpVersion::Http11 && !close
+    }
+
+    // Header names are case-insensitive; returns the first match.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// "%2Fa%20b" => "/a b". '+' is left alone: it only means a space in form-encoded queries.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A seeded generator (xorshift64*): a failure shows its seed, and the same seed
+    // gives the same case again.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+            choices[self.below(choices.len())]
+        }
+
+        fn chance(&mut self, one_in: usize) -> bool {
+            self.below(one_in) == 0
+        }
+    }
+
+    const METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "CONNECT", "PURGE", "M-SEARCH"];
+    const HEADER_NAMES: &[&str] = &["Host", "Accept", "Content-Length", "Cookie", "X-Forwarded-For", "x-custom", "Connection"];
+
+    // A request parse should accept, and the fields it should come back with.
+    fn valid_request(rng: &mut Rng) -> (Vec<u8>, Request) {
+        let method = rng.pick(METHODS);
+        let mut path = String::from("/");
+        let mut target = String::from("/");
+        for _ in 0..rng.below(40) {
+            let c = match rng.below(20) {
+                0 => ' ',
+                1 => 'é',
+                2 => '?',
+                3 => '%',
+                _ => char::from(b'!' + rng.below(94) as u8),
+            };
+            path.push(c);
+            // what can't go in the target as it is goes in %-encoded, and so does anything, sometimes.
+            if matches!(c, ' ' | '?' | '%') || !c.is_ascii() || rng.chance(10) {
+                let mut bytes = [0; 4];
+                for b in c.encode_utf8(&mut bytes).bytes() {
+                    target.push_str(&format!("%{:02X}", b));
+                }
+            } else {
+                target.push(c);
+            }
+        }
+        let query = rng.chance(3).then(|| (0..rng.below(30)).map(|_| char::from(b'!' + rng.below(94) as u8)).collect::<String>());
+        if let Some(query) = &query {
+            target.push('?');
+            target.push_str(query);
+        }
+        let version = if rng.chance(4) { HttpVersion::Http10 } else { HttpVersion::Http11 };
+
+        let mut headers = Vec::new();
+        for _ in 0..rng.below(8) {
+            // from a short list, so there are duplicates.
+            let name = rng.pick(HEADER_NAMES).to_string();
+            let length = if rng.chance(20) { 4000 } else { rng.below(40) };
+            let mut value: String = (0..length).map(|_| char::from(b' ' + rng.below(95) as u8)).collect();
+            value = value.trim().to_string();
+            headers.push((name, value));
+        }
+
+        let mut raw = format!("{} {} {}\r\n", method, target, version);
+        for (name, value) in &headers {
+            // the spaces around the value aren't part of it.
+            let padding = rng.pick(&["", " ", "\t", "  "]);
+            raw.push_str(&format!("{}:{}{}{}\r\n", name, padding, value, padding));
+        }
+        raw.push_str("\r\n");
+        let mut raw = raw.into_bytes();
+        // the body isn't parse's business.
+        raw.extend((0..rng.below(50)).map(|_| rng.next() as u8));
+
+        let request = Request {
+            method: Method::from_token(method),
+            path,
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+            extensions: Extensions::default(),
+        };
+        (raw, request)
+    }
+
+    // Something like a request, broken in some way: or anything at all.
+    fn mutated_request(rng: &mut Rng) -> Vec<u8> {
+        let (mut raw, _) = valid_request(rng);
+        for _ in 0..1 + rng.below(4) {
+            let at = rng.below(raw.len() + 1);
+            match rng.below(6) {
+                0 if at < raw.len() => raw[at] = rng.next() as u8,
+                1 if at < raw.len() => {
+                    raw.remove(at);
+                }
+                2 => raw.insert(at, *b"\r\n :%?\t\0".get(rng.below(8)).unwrap_or(&b'x')),
+                3 => raw.truncate(at),
+                4 => raw.splice(at..at, rng.pick(&["\r\n", "\r\n\r\n", "%", "%G0", "%C3", " HTTP/1.1", "\n"]).bytes()).for_each(drop),
+                _ => raw = (0..rng.below(100)).map(|_| rng.next() as u8).collect(),
+            }
+        }
+        raw
+    }
+
+    // What parse promises about anything it accepts.
+    fn assert_consistent(request: &Request, raw: &[u8]) {
+        let context = String::from_utf8_lossy(raw);
+        assert!(!request.method.as_str().is_empty(), "{:?}", context);
+        assert!(!request.method.as_str().contains(' '), "{:?}", context);
+        for (name, value) in &request.headers {
+            assert!(!name.is_empty() && !name.contains([' ', '\t', ':', '\r', '\n']), "{:?}", context);
+            assert_eq!(value.trim(), value, "{:?}", context);
+            assert!(!value.contains(['\r', '\n']), "{:?}", context);
+        }
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn valid_requests_round_trip() {
+        for seed in 1..=10_000u64 {
+            let mut rng = Rng(seed);
+            let (raw, expected) = valid_request(&mut rng);
+            let parsed = Request::parse(&raw);
+            assert_eq!(parsed.as_ref(), Ok(&expected), "seed {}: {:?}", seed, String::from_utf8_lossy(&raw));
+            // the head alone, up to any point before its end, is Incomplete.
+            let head_end = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            let cut = rng.below(head_end);
+            assert_eq!(Request::parse(&raw[..cut]), Err(ParseError::Incomplete), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn broken_requests_are_errors_not_panics() {
+        let mut accepted = 0;
+        for seed in 1..=10_000u64 {
+            let raw = mutated_request(&mut Rng(seed));
+            // a panic here fails the test, with the seed in the output above it.
+            match std::panic::catch_unwind(|| Request::parse(&raw)) {
+                Ok(Ok(request)) => {
+                    accepted += 1;
+                    assert_consistent(&request, &raw);
+                }
+                Ok(Err(error)) => assert!(!error.to_string().is_empty()),
+                Err(_) => panic!("seed {}: parse panicked on {:?}", seed, String::from_utf8_lossy(&raw)),
+            }
+        }
+        // some mutations leave the request valid (a changed header value, a cut body).
+        assert!(accepted > 0 && accepted < 10_000, "{}", accepted);
+    }
+
+
+    #[test]
+    fn reads_forms_and_cookies() {
+        let mut request = Request::parse(b"POST /login HTTP/1.1\r\nCookie: theme=dark; sid=\"abc\"\r\ncookie: lang=en\r\n\r\n").unwrap();
+        request.body = b"username=J%C3%B6rg+K&password=a%26b%3Dc&empty=&flag".to_vec();
+        let form = request.form().unwrap();
+        let pairs: Vec<(&str, &str)> = form.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        assert_eq!(pairs, [("username", "Jörg K"), ("password", "a&b=c"), ("empty", ""), ("flag", "")]);
+        request.body = b"a=%zz".to_vec();
+        assert_eq!(request.form(), None);
+
+        assert_eq!(request.cookie("theme"), Some("dark"));
+        assert_eq!(request.cookie("sid"), Some("abc"));
+        assert_eq!(request.cookie("lang"), Some("en"));
+        assert_eq!(request.cookie("them"), None);
+    }
+}
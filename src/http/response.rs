@@ -0,0 +1,327 @@
+use std::fmt;
+use std::io::{self, prelude::*};
+
+use super::HeaderDeduplicator;
+
+/* # Building responses!
+ResponseBuilder::new(StatusCode::Ok)
+    .header("Content-Type", "text/html; charset=utf-8")
+    .body_str("<h1>Hi!</h1>")
+    .write_to(&mut stream)
+=> HTTP/1.1 200 OK\r\n
+   Content-Type: text/html; charset=utf-8\r\n
+   Content-Length: 12\r\n
+   \r\n
+   <h1>Hi!</h1>
+Content-Length is added automatically (0 without a body), unless it was set by hand
+(e.g. when the body is streamed separately) or Transfer-Encoding is set:
+without it, clients can only tell where the body ends when the connection closes. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusCode {
+    SwitchingProtocols,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    Conflict,
+    Gone,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    UnprocessableContent,
+    UpgradeRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+}
+
+const ALL_STATUS_CODES: &[StatusCode] = &[
+    StatusCode::SwitchingProtocols,
+    StatusCode::Ok,
+    StatusCode::Created,
+    StatusCode::Accepted,
+    StatusCode::NoContent,
+    StatusCode::PartialContent,
+    StatusCode::MovedPermanently,
+    StatusCode::Found,
+    StatusCode::SeeOther,
+    StatusCode::NotModified,
+    StatusCode::TemporaryRedirect,
+    StatusCode::PermanentRedirect,
+    StatusCode::BadRequest,
+    StatusCode::Unauthorized,
+    StatusCode::Forbidden,
+    StatusCode::NotFound,
+    StatusCode::MethodNotAllowed,
+    StatusCode::NotAcceptable,
+    StatusCode::Conflict,
+    StatusCode::Gone,
+    StatusCode::PreconditionFailed,
+    StatusCode::PayloadTooLarge,
+    StatusCode::UnsupportedMediaType,
+    StatusCode::RangeNotSatisfiable,
+    StatusCode::UnprocessableContent,
+    StatusCode::UpgradeRequired,
+    StatusCode::TooManyRequests,
+    StatusCode::RequestHeaderFieldsTooLarge,
+    StatusCode::InternalServerError,
+    StatusCode::NotImplemented,
+    StatusCode::BadGateway,
+    StatusCode::ServiceUnavailable,
+    StatusCode::GatewayTimeout,
+];
+
+impl StatusCode {
+    // None for codes this enum doesn't have (yet).
+    pub fn from_code(code: u16) -> Option<StatusCode> {
+        ALL_STATUS_CODES.iter().copied().find(|status| status.code() == code)
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::Accepted => 202,
+            StatusCode::NoContent => 204,
+            StatusCode::PartialContent => 206,
+            StatusCode::MovedPermanently => 301,
+            StatusCode::Found => 302,
+            StatusCode::SeeOther => 303,
+            StatusCode::NotModified => 304,
+            StatusCode::TemporaryRedirect => 307,
+            StatusCode::PermanentRedirect => 308,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::NotAcceptable => 406,
+            StatusCode::Conflict => 409,
+            StatusCode::Gone => 410,
+            StatusCode::PreconditionFailed => 412,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::UnsupportedMediaType => 415,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::UnprocessableContent => 422,
+            StatusCode::UpgradeRequired => 426,
+            StatusCode::TooManyRequests => 429,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::BadGateway => 502,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::GatewayTimeout => 504,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::Accepted => "Accepted",
+            StatusCode::NoContent => "No Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
+            StatusCode::SeeOther => "See Other",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::NotAcceptable => "Not Acceptable",
+            StatusCode::Conflict => "Conflict",
+            StatusCode::Gone => "Gone",
+            StatusCode::PreconditionFailed => "Precondition Failed",
+            StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::UnprocessableContent => "Unprocessable Content",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::TooManyRequests => "Too Many Requests",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::BadGateway => "Bad Gateway",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+        }
+    }
+
+    // 1xx, 204 and 304 responses never have a body.
+    fn allows_body(&self) -> bool {
+        !matches!(self, StatusCode::SwitchingProtocols | StatusCode::NoContent | StatusCode::NotModified)
+    }
+}
+
+// "404 Not Found"
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResponseBuilder {
+    status: StatusCode,
+    pub(super) headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+    omit_body: bool,
+    reason: Option<String>,
+    content_length: bool,
+}
+
+impl ResponseBuilder {
+    pub fn new(status: StatusCode) -> ResponseBuilder {
+        ResponseBuilder {
+            status,
+            headers: Vec::new(),
+            body: None,
+            omit_body: false,
+            reason: None,
+            content_length: true,
+        }
+    }
+
+    // Headers are written in the order they were added. Adding a name twice sends it twice,
+    // unless it's one of the headers that can only be sent once, or combined (see headers.rs).
+    pub fn header(&mut self, key: &str, value: &str) -> &mut Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    // Replaces every earlier value of the header (any case) with this one.
+    pub fn set_header(&mut self, key: &str, value: &str) -> &mut Self {
+        self.remove_header(key);
+        self.header(key, value)
+    }
+
+    pub fn remove_header(&mut self, key: &str) -> &mut Self {
+        self.headers.retain(|(name, _)| !name.eq_ignore_ascii_case(key));
+        self
+    }
+
+    pub fn body_bytes(&mut self, data: Vec<u8>) -> &mut Self {
+        self.body = Some(data);
+        self
+    }
+
+    pub fn body_str(&mut self, s: &str) -> &mut Self {
+        self.body_bytes(s.as_bytes().to_vec())
+    }
+
+    // For HEAD requests: the same head a GET would get, Content-Length included,
+    // followed by zero bytes of body.
+    pub fn omit_body(&mut self, omit: bool) -> &mut Self {
+        self.omit_body = omit;
+        self
+    }
+
+    // Instead of the status code's usual reason phrase: 200 Connection Established.
+    // Control characters are dropped: a CR or LF would end the status line early.
+    pub fn reason(&mut self, reason: &str) -> &mut Self {
+        self.reason = Some(reason.chars().filter(|c| !c.is_control()).collect());
+        self
+    }
+
+    // No automatic Content-Length: for responses that must not have one, like the 200 that
+    // opens a CONNECT tunnel (everything after it belongs to the tunnel).
+    pub fn no_content_length(&mut self) -> &mut Self {
+        self.content_length = false;
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    // For middleware that turns a response into a different one (see middleware.rs).
+    pub fn set_status(&mut self, status: StatusCode) -> &mut Self {
+        self.status = status;
+        self.reason = None;
+        self
+    }
+
+    // None when no body was set: the body may still follow separately (send_file, chunks).
+    pub fn body(&self) -> Option<&[u8]> {
+        self.body.as_deref()
+    }
+
+    // Case-insensitive, like the header names themselves.
+    pub fn has_header(&self, key: &str) -> bool {
+        self.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case(key))
+    }
+
+    // The first value, if the header was added more than once.
+    pub fn header_value(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // In the order they were added, as they'd be written.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    // Every value, for headers that may be sent more than once.
+    pub fn header_values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn write_to(&self, stream: &mut impl Write) -> io::Result<()> {
+        // the last step before writing: one Content-Type, one Vary, ... (see headers.rs).
+        let mut headers = self.headers.clone();
+        HeaderDeduplicator::new().deduplicate(&mut headers);
+
+        let mut head = match &self.reason {
+            Some(reason) => format!("HTTP/1.1 {} {}\r\n", self.status.code(), reason),
+            None => format!("HTTP/1.1 {}\r\n", self.status),
+        };
+        for (key, value) in &headers {
+            head.push_str(&format!("{}: {}\r\n", key, value));
+        }
+
+        let body = self.body.as_deref().unwrap_or(&[]);
+        if self.content_length && self.status.allows_body() && !self.has_header("Content-Length") && !self.has_header("Transfer-Encoding") {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        head.push_str("\r\n");
+
+        // One write for small responses, rather than one for the head and one for the body.
+        let mut response = head.into_bytes();
+        if self.status.allows_body() && !self.omit_body {
+            response.extend_from_slice(body);
+        }
+        stream.write_all(&response)?;
+        stream.flush()
+    }
+}
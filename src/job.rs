@@ -0,0 +1,91 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+/* # Getting a result back from the pool!
+let handle = pool.execute_with_handle(|| 2 + 2)?;
+handle.wait() => Ok(4)
+The job is wrapped in a closure that runs it inside catch_unwind and sends
+the outcome down a one-shot channel; JobHandle holds the receiving end.
+A panicking job comes back as Err(payload), like JoinHandle::join,
+and doesn't take its worker down with it.
+
+# Deadlines: ThreadPool::execute_timeout!
+Threads can't be killed from the outside in safe Rust, so cancellation is cooperative:
+the job gets a CancellationToken, and a watchdog thread cancels it once the deadline
+has passed. A job that checks token.is_cancelled() now and then can stop early;
+either way, a job that was still running at the deadline reports TimedOut. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimedResult<T> {
+    Completed(T),
+    TimedOut,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // Every clone sees the cancellation.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+pub struct JobHandle<T> {
+    // None once the result has been handed out.
+    receiver: Option<mpsc::Receiver<thread::Result<T>>>,
+}
+
+impl<T> JobHandle<T> {
+    // Blocks until the job has run.
+    pub fn wait(mut self) -> thread::Result<T> {
+        match self.receiver.take() {
+            Some(receiver) => receiver.recv().unwrap_or_else(|_| Err(dropped())),
+            None => Err(dropped()),
+        }
+    }
+
+    // None while the job is still queued or running (and after the result was taken).
+    pub fn try_get(&mut self) -> Option<thread::Result<T>> {
+        let result = match self.receiver.as_ref()?.try_recv() {
+            Ok(result) => result,
+            Err(mpsc::TryRecvError::Empty) => return None,
+            Err(mpsc::TryRecvError::Disconnected) => Err(dropped()),
+        };
+        self.receiver = None;
+        Some(result)
+    }
+}
+
+// The closure to hand to ThreadPool::execute, and the handle for its result.
+pub(crate) fn with_handle<F, T>(f: F) -> (impl FnOnce() + Send + 'static, JobHandle<T>)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static
+{
+    let (sender, receiver) = mpsc::channel();
+
+    let job = move || {
+        // the handle may have been dropped already; nobody wants the result then.
+        let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(f)));
+    };
+
+    (job, JobHandle { receiver: Some(receiver) })
+}
+
+// The sender went away without a result: the job was dropped before it could run.
+fn dropped() -> Box<dyn std::any::Any + Send> {
+    Box::new("job was dropped before it ran")
+}
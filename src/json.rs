@@ -0,0 +1,581 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, prelude::*};
+
+use crate::http::ChunkedResponseWriter;
+
+/* # Streaming a JSON array, one item at a time!
+let mut head = ResponseBuilder::new(StatusCode::Ok);
+head.header("Content-Type", "application/json");
+let mut items = JsonArrayStream::new(response.start_chunked(head)?)?;   => [
+for row in export {
+    items.push_item(&row)?;                                              => {"id":1} then ,{"id":2}
+}
+items.finish()?;                                                         => ] and the last chunk
+An export of millions of rows never has to be one String in memory: every item is
+serialized on its own (ToJson) and goes out as a chunk right away, with the comma in
+front of all but the first. finish() closes the array and the chunked body; without
+it the client is left with a broken array, which it can tell from a finished one. */
+
+/* # JSON values, and JSON Merge Patch (RFC 7396)!
+PATCH /users/7 HTTP/1.1
+Content-Type: application/merge-patch+json
+{"name": "Alice", "age": null}
+let user = request.json_merge_patch(&stored)?;   {"name": "Bob", "age": 30} => {"name": "Alice"}
+1. JsonValue::parse reads any JSON text (RFC 8259), nested 128 deep at most; whatever
+isn't JSON is a JsonError saying where. to_json writes it back, objects sorted by key.
+2. Numbers are f64s, as in JavaScript: integers past 2^53 lose precision.
+3. The patch is applied as RFC 7396 says: an object is merged key by key into the
+target (which becomes an object if it wasn't one), null removes the key, and anything
+else (arrays too) replaces what was there. A patch that isn't an object replaces the
+whole target. */
+
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(BTreeMap<String, JsonValue>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonError {
+    // from 0.
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid JSON at byte {}: {}", self.offset, self.message)
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl JsonValue {
+    pub fn parse(text: &str) -> Result<JsonValue, JsonError> {
+        let mut parser = Parser { text: text.as_bytes(), at: 0 };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.at < text.len() {
+            return Err(parser.error("trailing characters after the value"));
+        }
+        Ok(value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(object) => object.get(key),
+            _ => None,
+        }
+    }
+}
+
+impl ToJson for JsonValue {
+    fn to_json(&self) -> String {
+        match self {
+            JsonValue::Null => "null".to_string(),
+            JsonValue::Bool(value) => value.to_json(),
+            JsonValue::Number(number) => number.to_json(),
+            JsonValue::String(value) => json_string(value),
+            JsonValue::Array(items) => items.to_json(),
+            JsonValue::Object(object) => {
+                let members: Vec<String> = object.iter().map(|(key, value)| format!("{}:{}", json_string(key), value.to_json())).collect();
+                format!("{{{}}}", members.join(","))
+            }
+        }
+    }
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_json())
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> JsonError {
+        JsonError { offset: self.at, message: message.to_string() }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.text.get(self.at), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.at += 1;
+        }
+    }
+
+    fn eat(&mut self, literal: &str) -> bool {
+        let matched = self.text[self.at..].starts_with(literal.as_bytes());
+        if matched {
+            self.at += literal.len();
+        }
+        matched
+    }
+
+    fn value(&mut self, depth: usize) -> Result<JsonValue, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nested too deep"));
+        }
+        self.skip_whitespace();
+        match self.text.get(self.at) {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => self.string().map(JsonValue::String),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ if self.eat("null") => Ok(JsonValue::Null),
+            _ if self.eat("true") => Ok(JsonValue::Bool(true)),
+            _ if self.eat("false") => Ok(JsonValue::Bool(false)),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<JsonValue, JsonError> {
+        self.at += 1;
+        let mut object = BTreeMap::new();
+        self.skip_whitespace();
+        if self.eat("}") {
+            return Ok(JsonValue::Object(object));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.text.get(self.at) != Some(&b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(":") {
+                return Err(self.error("expected :"));
+            }
+            // the last of a repeated key wins, as in most parsers.
+            object.insert(key, self.value(depth + 1)?);
+            self.skip_whitespace();
+            if self.eat("}") {
+                return Ok(JsonValue::Object(object));
+            }
+            if !self.eat(",") {
+                return Err(self.error("expected , or }"));
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<JsonValue, JsonError> {
+        self.at += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.eat("]") {
+            return Ok(JsonValue::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            if self.eat("]") {
+                return Ok(JsonValue::Array(items));
+            }
+            if !self.eat(",") {
+                return Err(self.error("expected , or ]"));
+            }
+        }
+    }
+
+    // -?(0|[1-9][0-9]*)(.[0-9]+)?([eE][+-]?[0-9]+)?
+    fn number(&mut self) -> Result<JsonValue, JsonError> {
+        let start = self.at;
+        let digits = |parser: &mut Parser| {
+            let from = parser.at;
+            while parser.text.get(parser.at).is_some_and(u8::is_ascii_digit) {
+                parser.at += 1;
+            }
+            parser.at - from
+        };
+        self.eat("-");
+        if self.eat("0") {
+            if self.text.get(self.at).is_some_and(u8::is_ascii_digit) {
+                return Err(self.error("leading zero in a number"));
+            }
+        } else if digits(self) == 0 {
+            return Err(self.error("expected a digit"));
+        }
+        if self.eat(".") && digits(self) == 0 {
+            return Err(self.error("expected a digit after ."));
+        }
+        if self.eat("e") || self.eat("E") {
+            let _ = self.eat("+") || self.eat("-");
+            if digits(self) == 0 {
+                return Err(self.error("expected a digit in the exponent"));
+            }
+        }
+        let literal = std::str::from_utf8(&self.text[start..self.at]).expect("ASCII");
+        let number: f64 = literal.parse().map_err(|_| JsonError { offset: start, message: "bad number".to_string() })?;
+        if !number.is_finite() {
+            return Err(JsonError { offset: start, message: "number out of range".to_string() });
+        }
+        Ok(JsonValue::Number(number))
+    }
+
+    // At the opening ".
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.at += 1;
+        let mut string = Vec::new();
+        loop {
+            match self.text.get(self.at) {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.at += 1;
+                    // the input was a &str, and escapes only add whole characters.
+                    return Ok(String::from_utf8(string).expect("UTF-8"));
+                }
+                Some(b'\\') => {
+                    let escape = self.text.get(self.at + 1).copied();
+                    self.at += 2;
+                    let escaped = match escape {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => {
+                            self.at -= 2;
+                            return Err(self.error("bad escape"));
+                        }
+                    };
+                    let mut utf8 = [0; 4];
+                    string.extend_from_slice(escaped.encode_utf8(&mut utf8).as_bytes());
+                }
+                Some(&byte) if byte < b' ' => return Err(self.error("control character in a string")),
+                Some(&byte) => {
+                    string.push(byte);
+                    self.at += 1;
+                }
+            }
+        }
+    }
+
+    // After \u: 4 hex digits, and a second \uXXXX when they're the first half of a surrogate pair.
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let first = self.hex4()?;
+        let code = match first {
+            0xD800..=0xDBFF => {
+                if !self.eat("\\u") {
+                    return Err(self.error("unpaired surrogate"));
+                }
+                let second = self.hex4()?;
+                if !(0xDC00..=0xDFFF).contains(&second) {
+                    return Err(self.error("unpaired surrogate"));
+                }
+                0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00)
+            }
+            0xDC00..=0xDFFF => return Err(self.error("unpaired surrogate")),
+            code => code,
+        };
+        char::from_u32(code).ok_or_else(|| self.error("bad \\u escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let hex = self.text.get(self.at..self.at + 4).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+        let hex = hex.ok_or_else(|| self.error("bad \\u escape"))?;
+        let code = u32::from_str_radix(std::str::from_utf8(hex).expect("ASCII"), 16).expect("hex digits");
+        self.at += 4;
+        Ok(code)
+    }
+}
+
+// A JSON Merge Patch document (RFC 7396), applied with apply_to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonMergePatch(pub JsonValue);
+
+impl JsonMergePatch {
+    pub fn parse(text: &str) -> Result<JsonMergePatch, JsonError> {
+        JsonValue::parse(text).map(JsonMergePatch)
+    }
+
+    pub fn apply_to(&self, target: &mut JsonValue) {
+        merge(target, &self.0);
+    }
+}
+
+// MergePatch(Target, Patch) from RFC 7396, section 2.
+fn merge(target: &mut JsonValue, patch: &JsonValue) {
+    let JsonValue::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !matches!(target, JsonValue::Object(_)) {
+        *target = JsonValue::Object(BTreeMap::new());
+    }
+    let JsonValue::Object(target) = target else {
+        unreachable!("made an object above");
+    };
+    for (key, value) in patch {
+        if *value == JsonValue::Null {
+            target.remove(key);
+        } else {
+            merge(target.entry(key.clone()).or_insert(JsonValue::Null), value);
+        }
+    }
+}
+
+// What JsonArrayStream can send. Implemented for strings, numbers, booleans, Options
+// (None is null) and slices; a struct writes its object with json_string for the keys.
+pub trait ToJson {
+    fn to_json(&self) -> String;
+}
+
+// `s` as a JSON string, quotes included.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl ToJson for str {
+    fn to_json(&self) -> String {
+        json_string(self)
+    }
+}
+
+impl ToJson for String {
+    fn to_json(&self) -> String {
+        json_string(self)
+    }
+}
+
+impl ToJson for bool {
+    fn to_json(&self) -> String {
+        self.to_string()
+    }
+}
+
+macro_rules! number_to_json {
+    ($($number:ty),*) => {
+        $(impl ToJson for $number {
+            fn to_json(&self) -> String {
+                self.to_string()
+            }
+        })*
+    };
+}
+
+number_to_json!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+// NaN and the infinities aren't JSON: null.
+impl ToJson for f64 {
+    fn to_json(&self) -> String {
+        if self.is_finite() { self.to_string() } else { "null".to_string() }
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self) -> String {
+        match self {
+            Some(value) => value.to_json(),
+            None => "null".to_string(),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self) -> String {
+        let items: Vec<String> = self.iter().map(ToJson::to_json).collect();
+        format!("[{}]", items.join(","))
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self) -> String {
+        self.as_slice().to_json()
+    }
+}
+
+impl<T: ToJson + ?Sized> ToJson for &T {
+    fn to_json(&self) -> String {
+        (**self).to_json()
+    }
+}
+
+pub struct JsonArrayStream<'a, W: Write> {
+    body: ChunkedResponseWriter<'a, W>,
+    first: bool,
+}
+
+impl<'a, W: Write> JsonArrayStream<'a, W> {
+    // Sends the opening [ down `body` (from Response::start_chunked or ChunkedResponseWriter::start).
+    pub fn new(mut body: ChunkedResponseWriter<'a, W>) -> io::Result<JsonArrayStream<'a, W>> {
+        body.write_all(b"[")?;
+        Ok(JsonArrayStream { body, first: true })
+    }
+
+    // One chunk per item, flushed, so the client gets it now and not when a buffer fills.
+    pub fn push_item<T: ToJson + ?Sized>(&mut self, item: &T) -> io::Result<()> {
+        let mut chunk = if self.first { String::new() } else { ",".to_string() };
+        chunk.push_str(&item.to_json());
+        self.first = false;
+        self.body.write_all(chunk.as_bytes())?;
+        self.body.flush()
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.body.write_all(b"]")?;
+        self.body.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{ResponseBuilder, StatusCode};
+
+    struct Row {
+        id: u32,
+        name: &'static str,
+        tags: Vec<String>,
+    }
+
+    impl ToJson for Row {
+        fn to_json(&self) -> String {
+            format!("{{\"id\":{},\"name\":{},\"tags\":{}}}", self.id, json_string(self.name), self.tags.to_json())
+        }
+    }
+
+    #[test]
+    fn values_become_json() {
+        assert_eq!(json_string("a \"quote\" \\ \n\u{1}é"), "\"a \\\"quote\\\" \\\\ \\n\\u0001é\"");
+        assert_eq!((-3i64).to_json(), "-3");
+        assert_eq!(true.to_json(), "true");
+        assert_eq!(Some("x").to_json(), "\"x\"");
+        assert_eq!(None::<u8>.to_json(), "null");
+        assert_eq!(vec![1, 2].to_json(), "[1,2]");
+        assert_eq!(Vec::<u8>::new().to_json(), "[]");
+    }
+
+    #[test]
+    fn every_item_is_a_chunk() {
+        let mut head = ResponseBuilder::new(StatusCode::Ok);
+        head.header("Content-Type", "application/json");
+        let mut out = Vec::new();
+        let mut items = JsonArrayStream::new(ChunkedResponseWriter::start(&mut out, head).unwrap()).unwrap();
+        items.push_item(&Row { id: 1, name: "a\"b", tags: vec!["x".to_string()] }).unwrap();
+        items.push_item(&Row { id: 2, name: "c", tags: Vec::new() }).unwrap();
+        items.finish().unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nTransfer-Encoding: chunked"), "{:?}", head);
+        assert!(head.contains("\r\nContent-Type: application/json"), "{:?}", head);
+        let first = "{\"id\":1,\"name\":\"a\\\"b\",\"tags\":[\"x\"]}";
+        let second = ",{\"id\":2,\"name\":\"c\",\"tags\":[]}";
+        let expected = format!("1\r\n[\r\n{:x}\r\n{}\r\n{:x}\r\n{}\r\n1\r\n]\r\n0\r\n\r\n", first.len(), first, second.len(), second);
+        assert_eq!(body, expected);
+
+        // nothing pushed: still an array.
+        let mut out = Vec::new();
+        JsonArrayStream::new(ChunkedResponseWriter::start(&mut out, ResponseBuilder::new(StatusCode::Ok)).unwrap()).unwrap().finish().unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with("\r\n\r\n1\r\n[\r\n1\r\n]\r\n0\r\n\r\n"));
+    }
+
+
+    fn json(text: &str) -> JsonValue {
+        JsonValue::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parses_and_writes_json() {
+        let value = json(" {\"b\": [1, -2.5, 3e2, true, null], \"a\": \"\\u00e9\\ud83d\\ude00\\n\\/\", \"c\": {}} ");
+        assert_eq!(value.get("a"), Some(&JsonValue::String("é😀\n/".to_string())));
+        assert_eq!(value.to_json(), "{\"a\":\"é😀\\n/\",\"b\":[1,-2.5,300,true,null],\"c\":{}}");
+        assert_eq!(json(&value.to_string()), value);
+
+        let cases = [
+            ("", 0, "unexpected end"),
+            ("[1,]", 3, "expected a value"),
+            ("{\"a\" 1}", 5, "expected :"),
+            ("{a: 1}", 1, "expected a key"),
+            ("[1 2]", 3, "expected , or ]"),
+            ("01", 1, "leading zero in a number"),
+            ("1.", 2, "expected a digit after ."),
+            ("-", 1, "expected a digit"),
+            ("1e999", 0, "number out of range"),
+            ("\"a", 2, "unterminated string"),
+            ("\"\\x\"", 1, "bad escape"),
+            ("\"\\ud83d\"", 7, "unpaired surrogate"),
+            ("\"\\u12\"", 3, "bad \\u escape"),
+            ("\"\t\"", 1, "control character in a string"),
+            ("true false", 5, "trailing characters after the value"),
+            ("nul", 0, "expected a value"),
+        ];
+        for (text, offset, message) in cases {
+            assert_eq!(JsonValue::parse(text), Err(JsonError { offset, message: message.to_string() }), "{:?}", text);
+        }
+        let deep = format!("{}{}", "[".repeat(200), "]".repeat(200));
+        assert_eq!(JsonValue::parse(&deep).unwrap_err().message, "nested too deep");
+        assert!(JsonValue::parse(&format!("{}{}", "[".repeat(100), "]".repeat(100))).is_ok());
+    }
+
+    #[test]
+    fn merge_patches_like_rfc_7396() {
+        // Appendix A: original, patch, result.
+        let examples = [
+            (r#"{"a":"b"}"#, r#"{"a":"c"}"#, r#"{"a":"c"}"#),
+            (r#"{"a":"b"}"#, r#"{"b":"c"}"#, r#"{"a":"b","b":"c"}"#),
+            (r#"{"a":"b"}"#, r#"{"a":null}"#, r#"{}"#),
+            (r#"{"a":"b","b":"c"}"#, r#"{"a":null}"#, r#"{"b":"c"}"#),
+            (r#"{"a":["b"]}"#, r#"{"a":"c"}"#, r#"{"a":"c"}"#),
+            (r#"{"a":"c"}"#, r#"{"a":["b"]}"#, r#"{"a":["b"]}"#),
+            (r#"{"a":{"b":"c"}}"#, r#"{"a":{"b":"d","c":null}}"#, r#"{"a":{"b":"d"}}"#),
+            (r#"{"a":[{"b":"c"}]}"#, r#"{"a":[1]}"#, r#"{"a":[1]}"#),
+            (r#"["a","b"]"#, r#"["c","d"]"#, r#"["c","d"]"#),
+            (r#"{"a":"b"}"#, r#"["c"]"#, r#"["c"]"#),
+            (r#"{"a":"foo"}"#, r#"null"#, r#"null"#),
+            (r#"{"a":"foo"}"#, r#""bar""#, r#""bar""#),
+            (r#"{"e":null}"#, r#"{"a":1}"#, r#"{"a":1,"e":null}"#),
+            (r#"[1,2]"#, r#"{"a":"b","c":null}"#, r#"{"a":"b"}"#),
+            (r#"{}"#, r#"{"a":{"bb":{"ccc":null}}}"#, r#"{"a":{"bb":{}}}"#),
+        ];
+        for (original, patch, result) in examples {
+            let mut target = json(original);
+            JsonMergePatch::parse(patch).unwrap().apply_to(&mut target);
+            assert_eq!(target, json(result), "{} + {}", original, patch);
+        }
+    }
+
+    #[test]
+    fn requests_patch_a_value() {
+        let mut request = crate::http::Request::parse(b"PATCH /users/7 HTTP/1.1\r\nContent-Type: application/merge-patch+json\r\n\r\n").unwrap();
+        request.body = br#"{"name": "Alice", "age": null}"#.to_vec();
+        let user = request.json_merge_patch(&json(r#"{"name": "Bob", "age": 30}"#)).unwrap();
+        assert_eq!(user.to_json(), r#"{"name":"Alice"}"#);
+
+        request.body = b"{\"name\": ".to_vec();
+        assert_eq!(request.json_merge_patch(&user).unwrap_err().to_string(), "invalid JSON at byte 9: unexpected end");
+        request.body = vec![b'"', 0xff, b'"'];
+        assert_eq!(request.json_merge_patch(&user).unwrap_err(), JsonError { offset: 1, message: "not UTF-8".to_string() });
+    }
+}
@@ -1,5 +1,246 @@
+#[cfg(not(target_arch = "wasm32"))]
 use std::thread; 
-use std::sync::{mpsc, Arc, Mutex}; 
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::{mpsc, Arc, Mutex, PoisonError}; 
+#[cfg(not(target_arch = "wasm32"))]
+use queue::Queues;
+#[cfg(not(target_arch = "wasm32"))]
+use restart::{Restart, RestartBreaker};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(target_arch = "wasm32"))]
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{Duration, Instant};
+#[cfg(not(target_arch = "wasm32"))]
+use std::cell::RefCell;
+use std::any::Any;
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::sync::OnceLock;
+use std::{fmt, io};
+
+pub mod admin;
+mod base64;
+pub mod cache;
+pub mod cache_control;
+pub mod cgi;
+pub mod client;
+pub mod client_ip;
+pub mod compression;
+pub mod config;
+mod crypto;
+pub mod download;
+pub mod fingerprint;
+mod gzip;
+pub mod htpasswd;
+pub mod http;
+pub mod job;
+pub mod json;
+pub mod middleware;
+pub mod mime;
+pub mod multipart;
+pub mod os;
+pub mod proxy;
+#[cfg(not(target_arch = "wasm32"))]
+mod queue;
+pub mod quic;
+pub mod range_cache;
+pub mod rate_limit;
+mod restart;
+pub mod router;
+pub mod scope;
+pub mod server;
+pub mod session;
+pub mod static_files;
+pub mod stats;
+pub mod template;
+pub mod testing;
+pub mod tls;
+mod toml;
+pub mod websocket;
+
+pub use job::{CancellationToken, JobHandle, TimedResult};
+pub use restart::WorkerRestartPolicy;
+pub use router::BLANK_FAVICON;
+pub use scope::Scope;
+pub use stats::{Histogram, PoolMetrics, PoolStats};
+
+// wasm32 has no std::thread: a single-threaded stand-in with the same API lives in wasm.rs,
+// and everything below is only compiled for targets with real threads.
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+#[cfg(target_arch = "wasm32")]
+pub use wasm::ThreadPool;
+
+// # Errors instead of panics!
+// ThreadPool::new used to assert!(size > 0); now the caller decides what to do.
+#[derive(Debug)]
+pub enum ThreadPoolError {
+    // A pool needs at least one worker.
+    ZeroSize,
+    // The OS refused to create a worker thread (e.g. out of memory or thread limit reached).
+    SpawnFailed(io::Error),
+}
+
+impl fmt::Display for ThreadPoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThreadPoolError::ZeroSize => write!(f, "thread pool size must be greater than zero"),
+            ThreadPoolError::SpawnFailed(e) => write!(f, "failed to spawn worker thread: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ThreadPoolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ThreadPoolError::ZeroSize => None,
+            ThreadPoolError::SpawnFailed(e) => Some(e),
+        }
+    }
+}
+
+// .execute fails instead of blocking when the job can't be queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    // The bounded queue (ThreadPoolBuilder::queue_capacity) is full: block, retry or shed load.
+    QueueFull,
+    // No worker will ever receive the job.
+    Disconnected,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::QueueFull => write!(f, "thread pool queue is full"),
+            ExecuteError::Disconnected => write!(f, "thread pool has no workers left"),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {}
+
+// # Configuring the pool!
+// ThreadPoolBuilder::new()
+//     .thread_name("surff-worker")      => threads show up as surff-worker-0, -1, ... in profilers
+//     .stack_size(4 * 1024 * 1024)
+//     .on_thread_start(|| ...)           => runs inside each worker before it takes any job
+//     .on_thread_stop(|| ...)            => runs inside each worker right before it exits
+//     .worker_init(|| Connection::open()) => per-worker state, handed to jobs sent with .execute_with_state
+//     .queue_capacity(64)                => at most 64 jobs waiting; .execute returns QueueFull beyond that
+//     .shutdown_timeout(Duration::from_secs(5)) => dropping the pool gives up on stuck workers after 5s
+//     .restart_policy(WorkerRestartPolicy::circuit_breaker()) => see restart.rs
+//     .build(4)
+// ThreadPool::new(size) is the same as ThreadPoolBuilder::new().build(size).
+
+// How often ThreadPool::drain looks at the queue.
+#[cfg(not(target_arch = "wasm32"))]
+const DRAIN_INTERVAL: Duration = Duration::from_millis(5);
+
+pub(crate) type Hook = std::sync::Arc<dyn Fn() + Send + Sync + 'static>;
+// worker_init's closure with its return type erased, so the builder doesn't need a type parameter.
+pub(crate) type StateInit = std::sync::Arc<dyn Fn() -> Box<dyn Any + Send> + Send + Sync + 'static>;
+
+#[derive(Clone, Default)]
+pub struct ThreadPoolBuilder {
+    thread_name: Option<String>,
+    stack_size: Option<usize>,
+    on_thread_start: Option<Hook>,
+    on_thread_stop: Option<Hook>,
+    worker_init: Option<StateInit>,
+    // None => unbounded queue.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    queue_capacity: Option<usize>,
+    // None => Drop waits for every worker, however long it takes.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    shutdown_timeout: Option<std::time::Duration>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    restart_policy: WorkerRestartPolicy,
+}
+
+impl ThreadPoolBuilder {
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
+    }
+
+    // Worker threads are named "<name>-<id>".
+    pub fn thread_name(mut self, name: impl Into<String>) -> ThreadPoolBuilder {
+        self.thread_name = Some(name.into());
+        self
+    }
+
+    pub fn stack_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(size);
+        self
+    }
+
+    pub fn on_thread_start<F>(mut self, f: F) -> ThreadPoolBuilder
+        where
+            F: Fn() + Send + Sync + 'static
+    {
+        self.on_thread_start = Some(std::sync::Arc::new(f));
+        self
+    }
+
+    pub fn on_thread_stop<F>(mut self, f: F) -> ThreadPoolBuilder
+        where
+            F: Fn() + Send + Sync + 'static
+    {
+        self.on_thread_stop = Some(std::sync::Arc::new(f));
+        self
+    }
+
+    // # Per-worker state!
+    // Every worker thread calls f once, after on_thread_start, and keeps the result
+    // until it exits (it's dropped before on_thread_stop runs). Jobs sent with
+    // .execute_with_state::<S> get a &mut to their worker's copy.
+    // Replacement workers (after a panic) and workers added by resize run f too.
+    pub fn worker_init<S, F>(mut self, f: F) -> ThreadPoolBuilder
+        where
+            F: Fn() -> S + Send + Sync + 'static,
+            S: Send + 'static
+    {
+        self.worker_init = Some(std::sync::Arc::new(move || Box::new(f()) as Box<dyn Any + Send>));
+        self
+    }
+
+    // Jobs waiting for a worker, not counting the ones running.
+    // 0 means a job is only accepted when a worker is ready to take it right away.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    // How long Drop waits for the workers to finish their jobs and exit.
+    // Workers still running after that are detached: they keep running until the process exits.
+    pub fn shutdown_timeout(mut self, timeout: std::time::Duration) -> ThreadPoolBuilder {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    // What the supervisor does when a job panics and takes its worker down.
+    pub fn restart_policy(mut self, policy: WorkerRestartPolicy) -> ThreadPoolBuilder {
+        self.restart_policy = policy;
+        self
+    }
+
+    pub fn build(self, size: usize) -> Result<ThreadPool, ThreadPoolError> {
+        ThreadPool::from_builder(self, size)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn thread_builder(&self, id: usize) -> thread::Builder {
+        let mut builder = thread::Builder::new();
+        if let Some(name) = &self.thread_name {
+            builder = builder.name(format!("{}-{}", name, id));
+        }
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+        builder
+    }
+}
 
 /* The std lib provides thread::spawn 
 that expects to get some code the thread should run as soon as the thread is created.
@@ -21,100 +262,455 @@ that holds the type of closure that .execute receives.
 4. The .execute method sends the job it wants to execute 
 down the sending side of the channel. 
 5. In its thread, the Worker loops over its receiving side of the channel
-and executes the closures of any jobs received. */
+and executes the closures of any jobs received. 
+(The channel has since made way for a queue per worker, with work stealing: see queue.rs.)
+
+# Surviving panicking jobs: 
+1. The Worker runs each job inside catch_unwind, so a panic can't silently kill it
+(and no queue lock is ever held while a job runs, so none can be poisoned either).
+2. After a panic the Worker reports its id on the events channel and exits;
+its thread-locals may have been left half-updated, so it's replaced by a fresh thread.
+3. A supervisor thread owns the receiving side of the events channel and spawns 
+a replacement Worker with the same id, which takes over the same queue. 
+4. When, or whether at all, is up to the WorkerRestartPolicy (see restart.rs). 
 
+# Resizing: 
+Growing spawns more Workers, each with its own queue. Shrinking picks the newest Workers, 
+closes their queues and puts a Retire message at the front of each; 
+every Worker that takes one replies with its id and exits, and is joined and removed. 
+Terminate goes to the back of every Worker's queue, only used by Drop. */
+
+#[cfg(not(target_arch = "wasm32"))]
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    workers: Arc<Mutex<Vec<Worker>>>,
+    // shared with the supervisor, which swaps in replacement workers. 
+    stats: PoolStats,
+    context: WorkerContext,
+    supervisor: Option<Supervisor>,
+    // one resize at a time; they take &self, so the pool can be resized through an Arc.
+    resizing: Mutex<()>,
 } 
 
+#[cfg(not(target_arch = "wasm32"))]
 struct Worker {
     id: usize, 
     thread: Option<thread::JoinHandle<()>>,
 }
 
+// Everything a worker thread needs; cloned into every Worker (and replacement).
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone)]
+struct WorkerContext {
+    queues: Arc<Queues>,
+    stats: PoolStats,
+    config: ThreadPoolBuilder,
+    events: mpsc::Sender<WorkerEvent>,
+}
+
+// What workers (and the pool itself) tell the supervisor.
+#[cfg(not(target_arch = "wasm32"))]
+enum WorkerEvent {
+    Panicked(usize),
+    Shutdown,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+struct Supervisor {
+    events: mpsc::Sender<WorkerEvent>,
+    thread: thread::JoinHandle<()>,
+}
+
 // Make threads listen for either a Job to run or a signal to stop listening.
+#[cfg(not(target_arch = "wasm32"))]
 enum Message {
     NewJob(Job),
+    // Exit after sending your id back (used by resize).
+    Retire(mpsc::Sender<usize>),
     Terminate, 
 }
 
-// Job type alias is a Box of anything that implements the FnBox trait, etc 
-// => it's a trait object: 
-type Job = Box<dyn FnBox + Send + 'static>; 
-    
-// # Trick to take ownership of the value inside a Box<T> using self: Box<Self>.
-trait FnBox {
-    fn call_box(self: Box<Self>);       
-}
-    
-// Implement the FnBox trait for any type F that implements the FnOnce() trait.
-impl<F: FnOnce()> FnBox for F {
-    fn call_box(self: Box<F>) {
-        (*self)()    
-        // to take ownership of self and move the value out of the Box.
-        // any FnOnce() closure can use .call_box() to move the closure out of the Box 
-        // and call the closure.
-    }
-}
+// Job type alias is a Box of any closure that .execute accepts
+// => it's a trait object. Box<dyn FnOnce()> can be called directly: job() moves the closure out of the Box.
+#[cfg(not(target_arch = "wasm32"))]
+type Job = Box<dyn FnOnce() + Send + 'static>; 
 
+#[cfg(not(target_arch = "wasm32"))]
 impl ThreadPool {      
     // # Create a new ThreadPool!
-    pub fn new (size: usize) -> ThreadPool {        
+    pub fn new (size: usize) -> Result<ThreadPool, ThreadPoolError> {        
     // size is the number of the threads in the pool.
+        ThreadPoolBuilder::new().build(size)
+    }
+
+    // Same as ThreadPoolBuilder::new().queue_capacity(capacity).build(size).
+    pub fn with_queue(size: usize, capacity: usize) -> Result<ThreadPool, ThreadPoolError> {
+        ThreadPoolBuilder::new().queue_capacity(capacity).build(size)
+    }
+
+    fn from_builder(config: ThreadPoolBuilder, size: usize) -> Result<ThreadPool, ThreadPoolError> {
+        if size == 0 {
+            return Err(ThreadPoolError::ZeroSize);
+        }
         
-        assert!(size > 0);
-        // panics if the size is zero. 
-        
-        let (sender, receiver) = mpsc::channel(); 
+        let queues = Arc::new(Queues::new(config.queue_capacity)); 
 
-        let receiver = Arc::new(Mutex::new(receiver)); 
+        let stats = PoolStats::new(size);
 
-        let mut workers = Vec::with_capacity(size); 
+        let (events, events_receiver) = mpsc::channel();
+
+        let context = WorkerContext {
+            queues,
+            stats: stats.clone(),
+            config,
+            events: events.clone(),
+        };
+
+        let mut pool = ThreadPool {
+            workers: Arc::new(Mutex::new(Vec::with_capacity(size))),
+            stats,
+            context: context.clone(),
+            supervisor: None,
+            resizing: Mutex::new(()),
+        };
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-            // the workers can share ownership of the receiving end.
+            context.queues.open(id);
+            let worker = Worker::new(id, context.config.thread_builder(id), context.clone())
+                .map_err(ThreadPoolError::SpawnFailed)?;
+            // If spawning fails halfway, returning drops `pool`, 
+            // which shuts down the workers that did start.
+            pool.workers.lock().unwrap().push(worker);
         }
 
-        ThreadPool {
-            workers,
-            sender,
+        let workers = Arc::clone(&pool.workers);
+        let mut supervisor_builder = thread::Builder::new();
+        if let Some(name) = &context.config.thread_name {
+            supervisor_builder = supervisor_builder.name(format!("{}-supervisor", name));
         }
+        let thread = supervisor_builder
+            .spawn(move || supervise(events_receiver, workers, context))
+            .map_err(ThreadPoolError::SpawnFailed)?;
+        pool.supervisor = Some(Supervisor { events, thread });
+
+        Ok(pool)
     }
 
     // Using the thread::spawn impl as a reference: 
-    pub fn execute<F>(&self, f: F)
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
         where
             F: FnOnce() + Send + 'static        
             // () after FnOnce because the closure takes no parameters.
     {
         let job = Box::new(f);
 
-        self.sender.send(Message::NewJob(job)).unwrap();    
+        self.stats.job_queued();
+        let result = self.context.queues.push_job(Message::NewJob(job));
+        if result.is_err() {
+            // the job (and everything it captured) has been dropped.
+            self.stats.job_rejected();
+        }
+        result
     }            
+
+    // Like .execute, but the job's return value (or panic) can be collected through the handle.
+    pub fn execute_with_handle<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
+        where
+            F: FnOnce() -> T + Send + 'static,
+            T: Send + 'static
+    {
+        let (job, handle) = job::with_handle(f);
+        self.execute(job)?;
+        Ok(handle)
+    }
+
+    // # Jobs with a deadline!
+    // The clock starts when a worker picks the job up, not when it's queued.
+    pub fn execute_timeout<F>(&self, f: F, timeout: Duration) -> Result<JobHandle<TimedResult<()>>, ExecuteError>
+        where
+            F: FnOnce(CancellationToken) + Send + 'static
+    {
+        self.execute_with_handle(move || {
+            let token = CancellationToken::new();
+            let finished = Arc::new(AtomicBool::new(false));
+
+            let watchdog = {
+                let token = token.clone();
+                let finished = Arc::clone(&finished);
+                let deadline = Instant::now() + timeout;
+                thread::spawn(move || {
+                    // park_timeout can wake up early (or be woken by the job finishing), so loop.
+                    while !finished.load(Ordering::Relaxed) {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        if left.is_zero() {
+                            token.cancel();
+                            break;
+                        }
+                        thread::park_timeout(left);
+                    }
+                })
+            };
+
+            // Stop the watchdog even if f panics (the panic still reaches the JobHandle).
+            let _stop = StopWatchdog {
+                finished,
+                watchdog: watchdog.thread().clone(),
+            };
+
+            f(token.clone());
+
+            if token.is_cancelled() {
+                TimedResult::TimedOut
+            } else {
+                TimedResult::Completed(())
+            }
+        })
+    }
+
+    // Like .execute, with the state worker_init created for the worker that runs the job.
+    // The job panics (and its worker is replaced) if there's no state of type S:
+    // worker_init wasn't set, or was set with a different type.
+    pub fn execute_with_state<S, F>(&self, f: F) -> Result<(), ExecuteError>
+        where
+            S: Send + 'static,
+            F: FnOnce(&mut S) + Send + 'static
+    {
+        self.execute(move || {
+            WORKER_STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                match state.as_mut().and_then(|state| state.downcast_mut::<S>()) {
+                    Some(state) => f(state),
+                    None => panic!("execute_with_state: no worker state of type {}", std::any::type_name::<S>()),
+                }
+            })
+        })
+    }
+
+    // The old .execute: panics instead of returning an error.
+    pub fn execute_or_panic<F>(&self, f: F)
+        where
+            F: FnOnce() + Send + 'static
+    {
+        if let Err(e) = self.execute(f) {
+            panic!("ThreadPool::execute failed: {}", e);
+        }
+    }
+
+    // # Grow or shrink the pool!
+    // Shrinking waits for the retiring workers to finish the job they're running, if any.
+    pub fn resize(&self, new_size: usize) -> Result<(), ThreadPoolError> {
+        if new_size == 0 {
+            return Err(ThreadPoolError::ZeroSize);
+        }
+        let _resizing = self.resizing.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        // Slots the supervisor couldn't refill don't count: there's no thread to retire.
+        workers.retain(|worker| {
+            if worker.thread.is_none() {
+                self.context.queues.close(worker.id);
+                self.stats.remove_worker(worker.id);
+            }
+            worker.thread.is_some()
+        });
+
+        while workers.len() < new_size {
+            let id = self.stats.add_worker();
+            self.context.queues.open(id);
+            match Worker::new(id, self.context.config.thread_builder(id), self.context.clone()) {
+                Ok(worker) => workers.push(worker),
+                Err(e) => {
+                    self.context.queues.close(id);
+                    self.stats.remove_worker(id);
+                    return Err(ThreadPoolError::SpawnFailed(e));
+                },
+            }
+        }
+
+        let retiring = workers.len() - new_size;
+        if retiring == 0 {
+            return Ok(());
+        }
+        // the newest workers go first; their queued jobs get stolen by the others.
+        let (retired, retired_ids) = mpsc::channel();
+        for worker in &workers[new_size..] {
+            self.context.queues.close(worker.id);
+            self.context.queues.push_control(worker.id, Message::Retire(retired.clone()));
+        }
+        // Don't hold the lock while waiting: the supervisor may need it to replace a panicked worker.
+        drop(workers);
+
+        let retired_ids: Vec<usize> = retired_ids.iter().take(retiring).collect();
+
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        for id in retired_ids {
+            if let Some(position) = workers.iter().position(|worker| worker.id == id) {
+                let mut worker = workers.remove(position);
+                if let Some(thread) = worker.thread.take() {
+                    let _ = thread.join();
+                }
+            }
+            self.stats.remove_worker(id);
+        }
+
+        Ok(())
+    }
+
+    // # Draining: waiting for the jobs, without shutting down!
+    // Returns once every job queued so far has finished (true), or after `timeout`
+    // (false). The pool keeps taking new jobs all the while; stop sending them first
+    // if there should be an end to it (Server::drain_connections).
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.stats.queue_depth() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(DRAIN_INTERVAL);
+        }
+        true
+    }
+
+    // # Shutting down with a deadline!
+    // Like dropping the pool, but waits at most `timeout` (whatever the builder said)
+    // and returns the ids of the workers that were still running and got detached.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Vec<usize> {
+        self.shutdown(Some(timeout))
+    }
+
+    // JoinHandle has no timed join: with a timeout, a helper thread per worker
+    // does the join and reports back, and we wait for those reports until the deadline.
+    fn shutdown(&mut self, timeout: Option<Duration>) -> Vec<usize> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        // Stop the supervisor first, so no replacement worker is spawned mid-shutdown.
+        if let Some(supervisor) = self.supervisor.take() {
+            let _ = supervisor.events.send(WorkerEvent::Shutdown);
+            let _ = supervisor.thread.join();
+        }
+
+        // The supervisor has exited, so nothing else holds the lock now.
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap_or_else(PoisonError::into_inner));
+        if workers.is_empty() {
+            return Vec::new();
+        }
+
+        println!("Sending terminate message to all workers.");
+        for worker in workers.iter() {
+            // control messages don't count against queue_capacity, so this never blocks.
+            self.context.queues.close(worker.id);
+            self.context.queues.push_control(worker.id, Message::Terminate);
+        }
+
+        println!("Shutting down all workers.");
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => {
+                for worker in workers {
+                    worker.join();
+                }
+                return Vec::new();
+            },
+        };
+
+        let (joined, joined_ids) = mpsc::channel();
+        let mut running: Vec<usize> = workers.iter().map(|worker| worker.id).collect();
+        for worker in workers {
+            let joined = joined.clone();
+            thread::spawn(move || {
+                let id = worker.id;
+                worker.join();
+                let _ = joined.send(id);
+            });
+        }
+
+        while !running.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match joined_ids.recv_timeout(left) {
+                Ok(id) => running.retain(|&running_id| running_id != id),
+                Err(_) => break,
+            }
+        }
+
+        for id in &running {
+            // its joiner thread (and the JoinHandle with it) is simply left behind.
+            eprintln!("Worker {} did not stop in time; detaching it.", id);
+        }
+        running
+    }
+
+    // A handle to the pool's counters that can outlive a borrow of the pool.
+    pub fn stats(&self) -> PoolStats {
+        self.stats.clone()
+    }
+
+    // Idle and active workers and jobs waiting for one, right now.
+    pub fn metrics(&self) -> PoolMetrics {
+        self.stats.metrics()
+    }
+
+    // Busy workers / total workers, between 0.0 and 1.0.
+    pub fn utilization(&self) -> f64 {
+        self.stats.utilization()
+    }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-    // Mutex<T> ensures that only one Worker thread at a time is trying to request a job.
+    fn join(mut self) {
+        println!("Shutting down worker {}", self.id); 
+
+        if let Some(thread) = self.thread.take() {     
+        // .take() on Option to move thread out of worker. 
+            if let Err(payload) = thread.join() {
+            // .join() takes ownership / consumes the thread. 
+            // Err means the worker panicked: unwrapping here would panic inside drop
+            // (a double panic aborts the process), so just report it.
+                eprintln!("Worker {} had panicked: {}", self.id, panic_message(&*payload));
+            }
+        }
+    }
+
+    fn new(id: usize, builder: thread::Builder, context: WorkerContext) -> io::Result<Worker> {
+    // Each queue's Mutex ensures its jobs are taken (by the owner or a thief) one at a time.
         
-        let thread = thread::spawn(move|| {
-        // closure loops forever,
-        // asking the receiving end of the channel for a job and running it.
+        // thread::Builder::spawn returns an error where thread::spawn would panic.
+        let thread = builder.spawn(move|| {
+                let WorkerContext { queues, stats, config, events } = context;
+                let own = queues.local(id);
+
+                if let Some(on_start) = &config.on_thread_start {
+                    on_start();
+                }
+                if let Some(init) = &config.worker_init {
+                    WORKER_STATE.with(|state| *state.borrow_mut() = Some(init()));
+                }
+
+        // closure loops until it's told to stop,
+        // taking the next message from its own queue (or stealing jobs) and handling it.
                 loop {
-                    let message = receiver.lock().unwrap().recv().unwrap();  
-                    // .lock() on the receiver to acquire the mutex.
-                    //  (can fail if the mutex is in a poisoned state 
-                    // (other thread panicked while holding the lock).
-                    // .recv() to receive a Job from the channel 
-                    // (blocks if there's no job yet, and waits. It can fail if thread 
-                    // holding the sending side has shut down, idem viceversa).
+                    let message = queues.next_message(id, &own);
+                    // blocks while there's nothing to run or steal anywhere. 
                     
                     match message {
                         Message::NewJob(job) => {
                             println! ("Worker {} got a job; executing.", id); 
-                            job.call_box(); 
+                            let busy = stats.job_started(id);
+                            let started = Instant::now();
+                            let result = panic::catch_unwind(AssertUnwindSafe(job)); 
+                            stats.job_finished(id, started.elapsed());
+                            drop(busy);
+
+                            if let Err(payload) = result {
+                                eprintln!("Worker {} panicked while running a job: {}", id, panic_message(&*payload));
+                                // ask the supervisor for a replacement and get out of the way.
+                                let _ = events.send(WorkerEvent::Panicked(id));
+                                break;
+                            }
+                        },
+                        Message::Retire(retired) => {
+                            println!("Worker {} was retired.", id);
+                            let _ = retired.send(id);
+                            break;
                         },
                         Message::Terminate => {
                             println!("Worker {} was told to terminate.", id);
@@ -122,12 +718,19 @@ impl Worker {
                         },
                     }
                 }
-        });
 
-        Worker {
-            id: id, 
+                // drop the worker's state now rather than whenever thread-locals get destroyed.
+                WORKER_STATE.with(|state| drop(state.borrow_mut().take()));
+
+                if let Some(on_stop) = &config.on_thread_stop {
+                    on_stop();
+                }
+        })?;
+
+        Ok(Worker {
+            id, 
             thread: Some(thread), 
-        }
+        })
     }
 }
 
@@ -135,22 +738,466 @@ impl Worker {
 // Impl Drop trait to call .join() on each thread in the pool and clean it up. 
 // Threads can finish the requests they're working on before closing.
 
+#[cfg(not(target_arch = "wasm32"))]
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
-        for _ in &mut self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+        // Does nothing if .shutdown_timeout already shut the pool down.
+        self.shutdown(self.context.config.shutdown_timeout);
+    }
+}
+
+// # Shedding load: a 503 instead of a job nobody would run soon!
+// try_execute_or_close is execute for jobs that serve a connection. If the job can't be
+// queued (QueueFull, or no workers left), the client gets a 503 with Retry-After
+// right away, written by the calling thread (e.g. the accept thread) without waiting
+// for the pool, and the connection is closed. `stream` is a clone of the connection
+// the job owns: dropping the job alone would close it without a word.
+// The 503 is always the same, so it's serialized only once (service_unavailable).
+pub(crate) const RETRY_AFTER_SECS: u32 = 5;
+
+impl ThreadPool {
+    pub fn try_execute_or_close<F>(&self, stream: &TcpStream, job: F) -> Result<(), ExecuteError>
+        where
+            F: FnOnce() + Send + 'static
+    {
+        let result = self.execute(job);
+        if result.is_err() {
+            let mut stream = stream;
+            let _ = stream.write_all(service_unavailable());
+            let _ = stream.shutdown(Shutdown::Both);
         }
+        result
+    }
+}
 
-        println!("Shutting down all workers.");
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id); 
+pub(crate) fn service_unavailable() -> &'static [u8] {
+    static RESPONSE: OnceLock<Vec<u8>> = OnceLock::new();
+    RESPONSE.get_or_init(|| {
+        let mut bytes = Vec::new();
+        http::ResponseBuilder::new(http::StatusCode::ServiceUnavailable)
+            .header("Retry-After", &RETRY_AFTER_SECS.to_string())
+            .header("Connection", "close")
+            .write_to(&mut bytes)
+            .expect("writing to a Vec can't fail");
+        bytes
+    })
+}
+
+// The result of ThreadPoolBuilder::worker_init, one per worker thread.
+#[cfg(not(target_arch = "wasm32"))]
+thread_local! {
+    static WORKER_STATE: RefCell<Option<Box<dyn Any + Send>>> = const { RefCell::new(None) };
+}
+
+// Wakes execute_timeout's watchdog thread so it exits as soon as the job is done.
+#[cfg(not(target_arch = "wasm32"))]
+struct StopWatchdog {
+    finished: Arc<AtomicBool>,
+    watchdog: thread::Thread,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for StopWatchdog {
+    fn drop(&mut self) {
+        self.finished.store(true, Ordering::Relaxed);
+        self.watchdog.unpark();
+    }
+}
+
+// # The supervisor!
+// Runs until the pool is dropped, replacing the workers that report a panic
+// (as the restart policy says).
+#[cfg(not(target_arch = "wasm32"))]
+fn supervise(
+    events: mpsc::Receiver<WorkerEvent>,
+    workers: Arc<Mutex<Vec<Worker>>>,
+    context: WorkerContext,
+) {
+    let mut breaker = RestartBreaker::new(context.config.restart_policy);
+    // workers waiting out the breaker's backoff: (id, when to restart it)
+    let mut waiting: Vec<(usize, Instant)> = Vec::new();
+
+    loop {
+        let event = match waiting.iter().map(|(_, due)| *due).min() {
+            Some(due) => match events.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                Ok(event) => Some(event),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match events.recv() {
+                Ok(event) => Some(event),
+                Err(_) => break,
+            },
+        };
+
+        match event {
+            Some(WorkerEvent::Shutdown) => break,
+            Some(WorkerEvent::Panicked(id)) => {
+                let mut workers = workers.lock().unwrap_or_else(PoisonError::into_inner);
+                let pool_size = workers.len();
+                let position = match workers.iter().position(|worker| worker.id == id) {
+                    Some(position) => position,
+                    None => continue,
+                };
+                // The old thread has already left its loop, so this join returns right away.
+                if let Some(thread) = workers[position].thread.take() {
+                    let _ = thread.join();
+                }
+
+                match breaker.on_death(Instant::now(), pool_size) {
+                    Restart::Now => restart(&mut workers[position], &context),
+                    Restart::After(delay) => {
+                        eprintln!("Too many workers died; restarting worker {} in {:?}.", id, delay);
+                        context.queues.close(id);
+                        waiting.push((id, Instant::now() + delay));
+                    },
+                    Restart::Never => {
+                        println!("Worker {} is not restarted (WorkerRestartPolicy::Never).", id);
+                        context.queues.close(id);
+                        context.stats.remove_worker(id);
+                        workers.remove(position);
+                    },
+                }
+            },
+            None => {},
+        }
 
-            if let Some(thread) = worker.thread.take() {     
-            // .take() on Option to move thread out of worker. 
-                thread.join().unwrap();     
-                // .join() takes ownership / consumes the thread. 
+        let now = Instant::now();
+        if waiting.iter().any(|(_, due)| *due <= now) {
+            let mut workers = workers.lock().unwrap_or_else(PoisonError::into_inner);
+            waiting.retain(|&(id, due)| {
+                if due > now {
+                    return true;
+                }
+                // resize may have dropped the slot meanwhile, or given its id to a new worker.
+                if let Some(worker) = workers.iter_mut().find(|worker| worker.id == id && worker.thread.is_none()) {
+                    context.queues.open(id);
+                    restart(worker, &context);
+                }
+                false
+            });
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn restart(worker: &mut Worker, context: &WorkerContext) {
+    let id = worker.id;
+    match Worker::new(id, context.config.thread_builder(id), context.clone()) {
+        Ok(replacement) => {
+            println!("Worker {} restarted.", id);
+            context.stats.worker_restarted();
+            *worker = replacement;
+        },
+        // Keep the empty slot; the pool runs one worker short rather than panicking here.
+        // Closing its queue sends new jobs elsewhere; the ones already in it get stolen.
+        Err(e) => {
+            eprintln!("Failed to restart worker {}: {}", id, e);
+            context.queues.close(id);
+        },
+    }
+}
+
+// Panic payloads are usually a &str (panic!("literal")) or a String (panic!("{}", x)).
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use std::hint::black_box;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    #[test]
+    fn drain_waits_for_the_jobs() {
+        let pool = ThreadPool::new(2).unwrap();
+        let finished = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        for _ in 0..4 {
+            let finished = Arc::clone(&finished);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(50));
+                finished.fetch_add(1, Ordering::SeqCst);
+            }).unwrap();
+        }
+        assert!(pool.drain(Duration::from_secs(2)));
+        assert_eq!(finished.load(Ordering::SeqCst), 4);
+
+        pool.execute(|| thread::sleep(Duration::from_millis(300))).unwrap();
+        assert!(!pool.drain(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn a_full_queue_turns_the_connection_away() {
+        let pool = ThreadPool::with_queue(1, 1).unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = blocked.recv();
+        }).unwrap();
+        // once the worker holds that job, one more fills the queue.
+        while pool.stats().busy_workers() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        pool.execute(|| {}).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let owned = server.try_clone().unwrap();
+        let result = pool.try_execute_or_close(&server, move || drop(owned));
+        assert_eq!(result, Err(ExecuteError::QueueFull));
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\nRetry-After: 5\r\n"), "{:?}", response);
+        release.send(()).unwrap();
+    }
+
+    // Waits at most 2s for `done`.
+    fn eventually(done: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !done() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        done()
+    }
+
+    #[test]
+    fn the_circuit_breaker_delays_restarts() {
+        let backoff = Duration::from_millis(300);
+        let policy = WorkerRestartPolicy::CircuitBreaker { window: Duration::from_secs(10), threshold: 0.5, backoff };
+        let pool = ThreadPoolBuilder::new().restart_policy(policy).build(2).unwrap();
+        let stats = pool.stats();
+        let started = Instant::now();
+        for _ in 0..2 {
+            pool.execute(|| panic!("boom")).unwrap();
+        }
+
+        // the first of the two is restarted right away, the second one (more than half) waits.
+        assert!(eventually(|| stats.workers_restarted_total() == 1));
+        assert!(eventually(|| stats.workers_restarted_total() == 2));
+        assert!(started.elapsed() >= backoff, "{:?}", started.elapsed());
+        assert_eq!(pool.execute_with_handle(|| 7).unwrap().wait().unwrap(), 7);
+    }
+
+    #[test]
+    fn never_restarting_shrinks_the_pool() {
+        let pool = ThreadPoolBuilder::new().restart_policy(WorkerRestartPolicy::Never).build(2).unwrap();
+        let stats = pool.stats();
+        pool.execute(|| panic!("boom")).unwrap();
+
+        assert!(eventually(|| stats.worker_count() == 1));
+        assert_eq!(pool.execute_with_handle(|| 7).unwrap().wait().unwrap(), 7);
+        assert_eq!(stats.workers_restarted_total(), 0);
+    }
+
+    #[test]
+    fn builder_options_are_applied() {
+        let started = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let stopped = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let pool = {
+            let (started, stopped) = (Arc::clone(&started), Arc::clone(&stopped));
+            ThreadPoolBuilder::new()
+                .thread_name("builder-test")
+                .stack_size(256 * 1024)
+                .on_thread_start(move || {
+                    started.fetch_add(1, Ordering::SeqCst);
+                })
+                .on_thread_stop(move || {
+                    stopped.fetch_add(1, Ordering::SeqCst);
+                })
+                .build(2)
+                .unwrap()
+        };
+
+        let name = pool.execute_with_handle(|| thread::current().name().map(String::from)).unwrap().wait().unwrap();
+        assert!(matches!(name.as_deref(), Some("builder-test-0") | Some("builder-test-1")), "{:?}", name);
+        // 64 KB on the stack fits in 256 KB.
+        let sum = pool.execute_with_handle(|| black_box([1u8; 64 * 1024]).iter().map(|&b| b as usize).sum::<usize>());
+        assert_eq!(sum.unwrap().wait().unwrap(), 64 * 1024);
+        assert!(eventually(|| started.load(Ordering::SeqCst) == 2));
+
+        drop(pool);
+        assert_eq!(stopped.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn a_panicking_job_gets_its_worker_replaced() {
+        let pool = ThreadPool::new(2).unwrap();
+        let stats = pool.stats();
+        pool.execute(|| panic!("boom")).unwrap();
+
+        assert!(eventually(|| stats.workers_restarted_total() == 1));
+        assert_eq!(stats.worker_count(), 2);
+        // both workers still take jobs: two that wait for each other can only finish together.
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                pool.execute_with_handle(move || barrier.wait().is_leader()).unwrap()
+            })
+            .collect();
+        let leaders = handles.into_iter().map(|handle| handle.wait().unwrap()).filter(|&leader| leader);
+        assert_eq!(leaders.count(), 1);
+    }
+
+    #[test]
+    fn resizing_loses_no_jobs() {
+        let pool = ThreadPool::new(4).unwrap();
+        let finished = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let queue = |jobs: usize| {
+            for _ in 0..jobs {
+                let finished = Arc::clone(&finished);
+                pool.execute(move || {
+                    thread::sleep(Duration::from_millis(2));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                }).unwrap();
+            }
+        };
+
+        // the retired workers' queued jobs go to the ones that are left.
+        queue(50);
+        pool.resize(1).unwrap();
+        assert_eq!(pool.stats().worker_count(), 1);
+        queue(50);
+        pool.resize(6).unwrap();
+        assert_eq!(pool.stats().worker_count(), 6);
+        queue(50);
+
+        assert!(pool.drain(Duration::from_secs(5)));
+        assert_eq!(finished.load(Ordering::SeqCst), 150);
+        assert!(matches!(pool.resize(0), Err(ThreadPoolError::ZeroSize)));
+    }
+
+    #[test]
+    fn jobs_own_what_they_capture() {
+        let pool = ThreadPool::new(2).unwrap();
+        // moved in, not copied: a String and a Vec the job consumes.
+        let greeting = String::from("hello");
+        let words = vec![String::from("from"), String::from("the"), String::from("pool")];
+        let handle = pool.execute_with_handle(move || {
+            let mut sentence = greeting;
+            for word in words {
+                sentence.push(' ');
+                sentence.push_str(&word);
+            }
+            sentence
+        });
+        assert_eq!(handle.unwrap().wait().unwrap(), "hello from the pool");
+
+        // FnOnce: the job may consume its captures, here the last other Arc.
+        let shared = Arc::new(());
+        let captured = Arc::clone(&shared);
+        pool.execute_with_handle(move || drop(captured)).unwrap().wait().unwrap();
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    fn execute_says_when_the_queue_is_full() {
+        let pool = ThreadPoolBuilder::new().queue_capacity(2).build(1).unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = blocked.recv();
+        }).unwrap();
+        while pool.stats().busy_workers() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let ran = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let job = || {
+            let ran = Arc::clone(&ran);
+            move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        pool.execute(job()).unwrap();
+        pool.execute(job()).unwrap();
+        assert_eq!(pool.execute(job()), Err(ExecuteError::QueueFull));
+
+        // once the worker is free the queue empties, and there's room again.
+        release.send(()).unwrap();
+        assert!(pool.drain(Duration::from_secs(2)));
+        pool.execute(job()).unwrap();
+        assert!(pool.drain(Duration::from_secs(2)));
+        assert_eq!(ran.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn handles_return_the_value_or_the_panic() {
+        let pool = ThreadPool::new(1).unwrap();
+        assert_eq!(pool.execute_with_handle(|| 2 + 2).unwrap().wait().unwrap(), 4);
+
+        let payload = pool.execute_with_handle(|| -> u32 { panic!("boom") }).unwrap().wait().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+
+        // try_get: nothing while the job runs, the result once, then nothing again.
+        let (release, blocked) = mpsc::channel::<()>();
+        let mut handle = pool.execute_with_handle(move || blocked.recv().is_ok()).unwrap();
+        assert!(handle.try_get().is_none());
+        release.send(()).unwrap();
+        let result = loop {
+            if let Some(result) = handle.try_get() {
+                break result;
             }
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert!(result.unwrap());
+        assert!(handle.try_get().is_none());
+    }
+
+    #[test]
+    fn dropping_the_pool_after_a_panic_is_fine() {
+        // with a replacement, and without one (the dead worker is joined on drop).
+        for policy in [WorkerRestartPolicy::AlwaysRestart, WorkerRestartPolicy::Never] {
+            let pool = ThreadPoolBuilder::new().restart_policy(policy).build(2).unwrap();
+            let stats = pool.stats();
+            pool.execute(|| panic!("boom")).unwrap();
+            assert!(eventually(|| stats.total_completed() == 1));
+            // Drop joins every worker: unwrapping the panicked one's join here used to abort.
+            drop(pool);
+        }
+    }
+
+    #[test]
+    fn worker_init_runs_once_per_worker() {
+        let inits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let pool = {
+            let inits = Arc::clone(&inits);
+            ThreadPoolBuilder::new()
+                .worker_init(move || {
+                    inits.fetch_add(1, Ordering::SeqCst);
+                    0usize
+                })
+                .build(3)
+                .unwrap()
+        };
+
+        // each job counts on its worker's state: the counts add up, and nobody's was reset.
+        let (ran, counts) = mpsc::channel();
+        for _ in 0..30 {
+            let ran = ran.clone();
+            pool.execute_with_state(move |jobs: &mut usize| {
+                *jobs += 1;
+                let _ = ran.send((thread::current().id(), *jobs));
+            }).unwrap();
         }
+        drop(ran);
+        let mut last = std::collections::HashMap::new();
+        for (worker, jobs) in counts.iter().take(30) {
+            let previous = last.insert(worker, jobs).unwrap_or(0);
+            assert_eq!(jobs, previous + 1);
+        }
+        assert_eq!(last.values().sum::<usize>(), 30);
+        // a worker that got no job may still be starting up.
+        assert!(eventually(|| inits.load(Ordering::SeqCst) == 3));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(inits.load(Ordering::SeqCst), 3);
     }
-}
\ No newline at end of file
+}
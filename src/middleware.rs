@@ -0,0 +1,1668 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::client::ClientResponse;
+use crate::client_ip::{ClientIpExtractor, IpNet};
+use crate::base64;
+use crate::crypto;
+use crate::htpasswd::HtpasswdFile;
+use crate::http::{Method, Request, ResponseBuilder, StatusCode};
+use crate::router::{self, Middleware, Response};
+
+/* # Middleware that comes with surff!
+router.wrap(ServerHeaderMiddleware::default());     // Server: surff/0.1.0
+router.wrap(XRobotsTagMiddleware::for_prefix("/admin"));   // X-Robots-Tag: noindex, nofollow
+router.wrap(CorsMiddleware::new().allow_origin("https://app.example").allow_methods(&[Method::Put]));
+router.wrap(HttpsRedirectMiddleware::new(443).with_hsts());  // plain HTTP => 301 to https://
+router.wrap(SurrogateMiddleware::new(3600).key_prefix("/users", &["users"]));  // CDN caching
+router.wrap(TarpitMiddleware::new(blocklist, Duration::from_secs(10)));  // known-bad IPs wait
+router.wrap(SecurityMiddleware::new().enable_content_type_confusion_protection(true));
+router.wrap(ResponseSigningMiddleware::new(b"secret"));  // X-Signature: sha256=...
+router.wrap(BasicAuthMiddleware::new(Path::new("users.htpasswd"))?);  // 401 without a password
+router.wrap(GzipMiddleware::new());  // Content-Encoding: gzip (see compression.rs)
+router.get("/admin", handler).middleware(RequireRole::new(&["admin"]));  // one route only
+router.wrap(PreloadMiddleware::new(vec![PreloadHint::new("/app.css", "style")]));  // Link: rel=preload
+router.post("/payments", idempotency.wrap(pay));  // Idempotency-Key: retries get the first answer
+router.get("/popular", coalescer.wrap(popular));  // concurrent identical GETs: one handler call
+See router/mod.rs for how layers are ordered: wrap these last, so they're outermost
+and nothing inside can undo them. */
+
+// # Server: tells clients (and whoever debugs them) what answered.
+// The outermost layer decides: a Server header set further in is replaced, or removed
+// with suppress(), for deployments that would rather not say what they run.
+#[derive(Debug, Clone)]
+pub struct ServerHeaderMiddleware {
+    name: Option<String>,
+}
+
+impl ServerHeaderMiddleware {
+    pub fn new(name: String) -> ServerHeaderMiddleware {
+        ServerHeaderMiddleware { name: Some(name) }
+    }
+
+    pub fn suppress() -> ServerHeaderMiddleware {
+        ServerHeaderMiddleware { name: None }
+    }
+}
+
+impl Default for ServerHeaderMiddleware {
+    fn default() -> ServerHeaderMiddleware {
+        ServerHeaderMiddleware::new(format!("surff/{}", env!("CARGO_PKG_VERSION")))
+    }
+}
+
+impl Middleware for ServerHeaderMiddleware {
+    fn on_response(&self, _request: &Request, response: &mut ResponseBuilder) {
+        match &self.name {
+            Some(name) => response.set_header("Server", name),
+            None => response.remove_header("Server"),
+        };
+    }
+}
+
+// # X-Robots-Tag: keeps search engines away from parts of a site.
+// Unlike robots.txt, it also covers URLs nobody links to (drafts, generated pages).
+// Every response gets the header, or only those below a prefix (on a segment boundary,
+// like routes: /admin covers /admin/users, not /administrator).
+// For a staging environment, wrap the whole router in it there and nowhere else.
+const ROBOTS_DIRECTIVES: &[&str] = &["all", "none", "noindex", "nofollow", "noarchive", "nosnippet", "notranslate", "noimageindex"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XRobotsTagError {
+    NoDirectives,
+    UnknownDirective(String),
+}
+
+impl fmt::Display for XRobotsTagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XRobotsTagError::NoDirectives => write!(f, "X-Robots-Tag needs at least one directive"),
+            XRobotsTagError::UnknownDirective(tag) => write!(f, "unknown X-Robots-Tag directive: {}", tag),
+        }
+    }
+}
+
+impl std::error::Error for XRobotsTagError {}
+
+#[derive(Debug, Clone)]
+pub struct XRobotsTagMiddleware {
+    value: String,
+    prefix: Option<String>,
+}
+
+impl XRobotsTagMiddleware {
+    // e.g. &["noindex", "noarchive"]; directives are case-insensitive, and sent in lowercase.
+    pub fn new(tags: &[&str]) -> Result<XRobotsTagMiddleware, XRobotsTagError> {
+        if tags.is_empty() {
+            return Err(XRobotsTagError::NoDirectives);
+        }
+        let mut directives = Vec::with_capacity(tags.len());
+        for tag in tags {
+            let tag = tag.trim().to_ascii_lowercase();
+            if !ROBOTS_DIRECTIVES.contains(&tag.as_str()) {
+                return Err(XRobotsTagError::UnknownDirective(tag));
+            }
+            directives.push(tag);
+        }
+        Ok(XRobotsTagMiddleware { value: directives.join(", "), prefix: None })
+    }
+
+    // noindex, nofollow for everything below `prefix`.
+    pub fn for_prefix(prefix: &str) -> XRobotsTagMiddleware {
+        XRobotsTagMiddleware {
+            value: "noindex, nofollow".to_string(),
+            prefix: None,
+        }
+        .prefix(prefix)
+    }
+
+    // Limits other directives to a prefix: XRobotsTagMiddleware::new(&[...])?.prefix("/drafts")
+    pub fn prefix(mut self, prefix: &str) -> XRobotsTagMiddleware {
+        self.prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    fn applies_to(&self, path: &str) -> bool {
+        match &self.prefix {
+            None => true,
+            Some(prefix) => prefix.is_empty() || path == prefix || router::is_prefix(prefix, path),
+        }
+    }
+}
+
+impl Middleware for XRobotsTagMiddleware {
+    fn on_response(&self, request: &Request, response: &mut ResponseBuilder) {
+        if self.applies_to(&request.path) {
+            response.header("X-Robots-Tag", &self.value);
+        }
+    }
+}
+
+// # CORS: which other origins' scripts may read our responses.
+// 1. A preflight (OPTIONS with Origin and Access-Control-Request-Method) from an allowed
+// origin is answered right here with a 204 and the Access-Control-Allow-* headers; the
+// routes never see it. From any other origin it's routed like any OPTIONS request, and
+// without the headers the browser won't send the real request.
+// 2. Every other response to an allowed origin gets Access-Control-Allow-Origin (and
+// Vary: Origin, since the answer depends on it).
+// Without allow_origin, every origin is allowed (Access-Control-Allow-Origin: *).
+// Methods: GET, HEAD and POST, plus those added with allow_methods. Headers: those added
+// with allow_headers or, if there are none, whichever the preflight asks for.
+const CORS_DEFAULT_METHODS: &[Method] = &[Method::Get, Method::Head, Method::Post];
+const CORS_DEFAULT_MAX_AGE: u64 = 600;
+
+#[derive(Debug, Clone)]
+pub struct CorsMiddleware {
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    max_age: u64,
+    credentials: bool,
+}
+
+impl Default for CorsMiddleware {
+    fn default() -> CorsMiddleware {
+        CorsMiddleware {
+            origins: Vec::new(),
+            methods: CORS_DEFAULT_METHODS.to_vec(),
+            headers: Vec::new(),
+            max_age: CORS_DEFAULT_MAX_AGE,
+            credentials: false,
+        }
+    }
+}
+
+impl CorsMiddleware {
+    pub fn new() -> CorsMiddleware {
+        CorsMiddleware::default()
+    }
+
+    // e.g. "https://app.example": scheme, host and port, exactly as browsers send it.
+    pub fn allow_origin(mut self, origin: &str) -> CorsMiddleware {
+        self.origins.push(origin.to_string());
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: &[Method]) -> CorsMiddleware {
+        for method in methods {
+            if !self.methods.contains(method) {
+                self.methods.push(method.clone());
+            }
+        }
+        self
+    }
+
+    // Request headers scripts may send (beyond the CORS-safelisted ones), e.g. "Content-Type".
+    pub fn allow_headers(mut self, headers: &[&str]) -> CorsMiddleware {
+        self.headers.extend(headers.iter().map(|header| header.to_string()));
+        self
+    }
+
+    // How long browsers may cache a preflight answer.
+    pub fn max_age(mut self, seconds: u64) -> CorsMiddleware {
+        self.max_age = seconds;
+        self
+    }
+
+    // Lets cookies through; the response then names the origin instead of *.
+    pub fn allow_credentials(mut self) -> CorsMiddleware {
+        self.credentials = true;
+        self
+    }
+
+    // The Access-Control-Allow-Origin value for a request, or None if its origin isn't allowed.
+    fn allowed_origin<'r>(&self, request: &'r Request) -> Option<&'r str> {
+        let origin = request.header("Origin")?;
+        if self.origins.is_empty() || self.origins.iter().any(|allowed| allowed == origin) {
+            Some(origin)
+        } else {
+            None
+        }
+    }
+
+    fn allow_origin_header(&self, origin: &str, response: &mut ResponseBuilder) {
+        if self.origins.is_empty() && !self.credentials {
+            response.set_header("Access-Control-Allow-Origin", "*");
+        } else {
+            response.set_header("Access-Control-Allow-Origin", origin).header("Vary", "Origin");
+        }
+        if self.credentials {
+            response.set_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+}
+
+impl Middleware for CorsMiddleware {
+    fn before(&self, request: &Request, response: &mut Response) -> io::Result<bool> {
+        let is_preflight = request.method == Method::Options && request.header("Access-Control-Request-Method").is_some();
+        if !is_preflight || self.allowed_origin(request).is_none() {
+            return Ok(true);
+        }
+
+        // on_response adds Access-Control-Allow-Origin, as for every other response.
+        let methods: Vec<&str> = self.methods.iter().map(|method| method.as_str()).collect();
+        let mut preflight = ResponseBuilder::new(StatusCode::NoContent);
+        preflight
+            .header("Access-Control-Allow-Methods", &methods.join(", "))
+            .header("Access-Control-Max-Age", &self.max_age.to_string());
+        let headers = match request.header("Access-Control-Request-Headers") {
+            // none configured: whatever the script asked for.
+            Some(requested) if self.headers.is_empty() => requested.to_string(),
+            _ => self.headers.join(", "),
+        };
+        if !headers.is_empty() {
+            preflight.header("Access-Control-Allow-Headers", &headers);
+        }
+        response.send(&mut preflight)?;
+        Ok(false)
+    }
+
+    fn on_response(&self, request: &Request, response: &mut ResponseBuilder) {
+        if let Some(origin) = self.allowed_origin(request) {
+            self.allow_origin_header(origin, response);
+        }
+    }
+}
+
+// # HTTPS redirect: plain HTTP requests get a 301 to the same URL on https://.
+// surff doesn't speak TLS itself: a frontend proxy terminates it on `https_port` and
+// passes requests on with X-Forwarded-Proto: https, which lets those through to the
+// routes. Everything else is answered right here:
+// GET http://example.com:8080/a%20b?x=1 => 301, Location: https://example.com:443/a%20b?x=1
+// with_hsts() adds Strict-Transport-Security to the responses that went out over HTTPS,
+// telling browsers to skip the plain-HTTP round trip next time (over plain HTTP they'd
+// ignore it, since anyone in between could have added it).
+// Only put this behind a proxy that sets X-Forwarded-Proto itself: a client could send it.
+const HSTS: &str = "max-age=31536000; includeSubDomains";
+
+#[derive(Debug, Clone)]
+pub struct HttpsRedirectMiddleware {
+    https_port: u16,
+    hsts: bool,
+}
+
+impl HttpsRedirectMiddleware {
+    pub fn new(https_port: u16) -> HttpsRedirectMiddleware {
+        HttpsRedirectMiddleware { https_port, hsts: false }
+    }
+
+    pub fn with_hsts(mut self) -> HttpsRedirectMiddleware {
+        self.hsts = true;
+        self
+    }
+
+    // None without a usable Host header: there's nowhere to redirect to then.
+    fn location(&self, request: &Request) -> Option<String> {
+        let host = host_without_port(request.header("Host")?)?;
+        let mut location = format!("https://{}:{}{}", host, self.https_port, encode_path(&request.path));
+        if let Some(query) = &request.query {
+            location.push('?');
+            location.push_str(query);
+        }
+        Some(location)
+    }
+}
+
+fn is_https(request: &Request) -> bool {
+    request
+        .header("X-Forwarded-Proto")
+        .is_some_and(|proto| proto.split(',').next().is_some_and(|first| first.trim().eq_ignore_ascii_case("https")))
+}
+
+// "example.com:8080" => "example.com", "[::1]:8080" => "[::1]". Anything that could
+// break out of the URL's authority (a '/', '@', a space, ...) makes it unusable.
+fn host_without_port(host: &str) -> Option<&str> {
+    let host = if host.starts_with('[') {
+        &host[..=host.find(']')?]
+    } else {
+        host.split(':').next()?
+    };
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '[' | ']' | ':');
+    if host.is_empty() || !host.chars().all(allowed) {
+        return None;
+    }
+    Some(host)
+}
+
+// The path was percent-decoded by the parser; a Location header needs it encoded again.
+pub(crate) fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b'!' | b'$' | b'&'
+            | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' | b':' | b'@' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl Middleware for HttpsRedirectMiddleware {
+    fn before(&self, request: &Request, response: &mut Response) -> io::Result<bool> {
+        if is_https(request) {
+            return Ok(true);
+        }
+        match self.location(request) {
+            Some(location) => response.send(ResponseBuilder::new(StatusCode::MovedPermanently).header("Location", &location))?,
+            None => response.send(&mut ResponseBuilder::new(StatusCode::BadRequest))?,
+        }
+        Ok(false)
+    }
+
+    fn on_response(&self, request: &Request, response: &mut ResponseBuilder) {
+        if self.hsts && is_https(request) {
+            response.set_header("Strict-Transport-Security", HSTS);
+        }
+    }
+}
+
+// # Surrogate-Control and Surrogate-Key: caching instructions for the CDN only.
+// Cache-Control is for browsers (and everything in between); a CDN like Fastly reads
+// Surrogate-Control instead, and drops it before the response goes on to the client.
+// Surrogate-Key tags a response so that everything with a tag can be purged from the
+// CDN at once ("users" after any user changed, "user-42" after that one did).
+// Cacheable responses (2xx, to GET or HEAD, without Cache-Control: no-store or private) get
+// Surrogate-Control: max-age=N and the keys of every key_prefix the path is below.
+// Handlers can tag their own responses with .header("Surrogate-Key", "user-42"):
+// those are merged with the prefix keys into one header. Purging itself is a call to
+// the CDN's own API, which isn't something every response should wait for.
+#[derive(Debug, Clone)]
+pub struct SurrogateMiddleware {
+    max_age: u64,
+    // (path prefix, keys), on a segment boundary like routes.
+    prefixes: Vec<(String, Vec<String>)>,
+}
+
+impl SurrogateMiddleware {
+    // How long the CDN may keep cacheable responses, in seconds.
+    pub fn new(max_age: u64) -> SurrogateMiddleware {
+        SurrogateMiddleware { max_age, prefixes: Vec::new() }
+    }
+
+    // Tags every cacheable response below `prefix` with `keys`; "/" tags all of them.
+    pub fn key_prefix(mut self, prefix: &str, keys: &[&str]) -> SurrogateMiddleware {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        self.prefixes.push((prefix.trim_end_matches('/').to_string(), keys));
+        self
+    }
+}
+
+fn is_cacheable(request: &Request, response: &ResponseBuilder) -> bool {
+    let private = response.header_value("Cache-Control").is_some_and(|value| {
+        value
+            .split(',')
+            .any(|directive| ["no-store", "private"].contains(&directive.trim().to_ascii_lowercase().as_str()))
+    });
+    matches!(request.method, Method::Get | Method::Head) && (200..300).contains(&response.status().code()) && !private
+}
+
+impl Middleware for SurrogateMiddleware {
+    fn on_response(&self, request: &Request, response: &mut ResponseBuilder) {
+        if !is_cacheable(request, response) {
+            // a tag on an uncacheable response would only confuse a purge.
+            response.remove_header("Surrogate-Key");
+            return;
+        }
+        response.set_header("Surrogate-Control", &format!("max-age={}", self.max_age));
+
+        // the handler's own keys first, then the prefixes', each one once.
+        let mut keys: Vec<String> = Vec::new();
+        let prefix_keys = self
+            .prefixes
+            .iter()
+            .filter(|(prefix, _)| prefix.is_empty() || request.path == *prefix || router::is_prefix(prefix, &request.path))
+            .flat_map(|(_, keys)| keys.iter());
+        let handler_keys = response.header_values("Surrogate-Key").flat_map(str::split_whitespace);
+        for key in handler_keys.chain(prefix_keys.map(String::as_str)) {
+            if !keys.iter().any(|seen| seen == key) {
+                keys.push(key.to_string());
+            }
+        }
+        response.remove_header("Surrogate-Key");
+        if !keys.is_empty() {
+            response.header("Surrogate-Key", &keys.join(" "));
+        }
+    }
+}
+
+// # Tarpit: known-bad clients get their answer, eventually.
+// Refusing a scanner outright lets it move on to the next target at once. Instead, a
+// request from an address on the blocklist is read like any other, then the answer
+// (an empty 200, so nothing looks out of the ordinary) waits for `delay`, tying up one
+// of the scanner's connections for that long. Everyone else goes straight through.
+// The wait holds one of our workers too, so at most max_tarpitted (DEFAULT_MAX_TARPITTED
+// unless set) requests are held at a time; beyond that they're closed right away with a
+// 403, so a flood from the blocklist can't take the whole pool.
+// Behind proxies, give it the server's ClientIpExtractor (see client_ip.rs), or every
+// request would look like it came from the proxy.
+pub const DEFAULT_MAX_TARPITTED: usize = 2;
+
+pub struct TarpitMiddleware {
+    blocklist: Vec<IpNet>,
+    delay: Duration,
+    max_tarpitted: usize,
+    client_ips: ClientIpExtractor,
+    tarpitted: Arc<AtomicUsize>,
+}
+
+impl TarpitMiddleware {
+    pub fn new(blocklist: Vec<IpNet>, delay: Duration) -> TarpitMiddleware {
+        TarpitMiddleware {
+            blocklist,
+            delay,
+            max_tarpitted: DEFAULT_MAX_TARPITTED,
+            client_ips: ClientIpExtractor::default(),
+            tarpitted: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn max_tarpitted(mut self, max: usize) -> TarpitMiddleware {
+        self.max_tarpitted = max;
+        self
+    }
+
+    pub fn client_ips(mut self, client_ips: ClientIpExtractor) -> TarpitMiddleware {
+        self.client_ips = client_ips;
+        self
+    }
+
+    fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.blocklist.iter().any(|net| net.contains(ip))
+    }
+}
+
+// Gives the tarpit slot back however the request ends.
+struct Tarpitted<'a>(&'a AtomicUsize);
+
+impl Drop for Tarpitted<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Middleware for TarpitMiddleware {
+    fn before(&self, request: &Request, response: &mut Response) -> io::Result<bool> {
+        let client = self.client_ips.client_ip_for(response.peer_addr()?.ip(), request);
+        if !self.is_blocked(client) {
+            return Ok(true);
+        }
+
+        response.close();
+        if self.tarpitted.fetch_add(1, Ordering::SeqCst) >= self.max_tarpitted {
+            self.tarpitted.fetch_sub(1, Ordering::SeqCst);
+            response.send(&mut ResponseBuilder::new(StatusCode::Forbidden))?;
+            return Ok(false);
+        }
+        let _slot = Tarpitted(&self.tarpitted);
+        thread::sleep(self.delay);
+        response.send(&mut ResponseBuilder::new(StatusCode::Ok))?;
+        Ok(false)
+    }
+}
+
+// # Content-type confusion: scripts hidden in text.
+// Old browsers (Internet Explorer) would run a text/plain or text/html response as a
+// script when a page loaded it with <script src>, whatever its Content-Type said. So a
+// page that echoes its input (a search that says "no results for ...") could be used as
+// a script by anyone. X-Content-Type-Options: nosniff is the real fix, and
+// SecurityMiddleware sends it on every response.
+// With enable_content_type_confusion_protection(true) it also looks at the body of
+// text/plain and text/html responses to no-cors fetches (Sec-Fetch-Mode: no-cors: what
+// a <script src> sends, unlike a navigation), and turns those with something
+// script-like in them (<script, javascript:, eval() into a 400, with a warning in the log.
+// It's a heuristic, and only sees bodies that were set on the ResponseBuilder: not files
+// (send_file) or chunks.
+const SCRIPT_SIGNATURES: &[&str] = &["<script", "javascript:", "eval("];
+
+#[derive(Debug, Clone, Default)]
+pub struct SecurityMiddleware {
+    content_type_confusion: bool,
+}
+
+impl SecurityMiddleware {
+    pub fn new() -> SecurityMiddleware {
+        SecurityMiddleware::default()
+    }
+
+    pub fn enable_content_type_confusion_protection(mut self, enable: bool) -> SecurityMiddleware {
+        self.content_type_confusion = enable;
+        self
+    }
+}
+
+// The first signature in `body`, ignoring case.
+fn script_signature(body: &[u8]) -> Option<&'static str> {
+    let body = String::from_utf8_lossy(body).to_ascii_lowercase();
+    SCRIPT_SIGNATURES.iter().copied().find(|signature| body.contains(signature))
+}
+
+impl Middleware for SecurityMiddleware {
+    fn on_response(&self, request: &Request, response: &mut ResponseBuilder) {
+        response.set_header("X-Content-Type-Options", "nosniff");
+        if !self.content_type_confusion {
+            return;
+        }
+
+        let no_cors = request.header("Sec-Fetch-Mode").is_some_and(|mode| mode.eq_ignore_ascii_case("no-cors"));
+        let textual = response.header_value("Content-Type").is_some_and(|content_type| {
+            let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+            mime == "text/plain" || mime == "text/html"
+        });
+        if !no_cors || !textual {
+            return;
+        }
+        if let Some(signature) = response.body().and_then(script_signature) {
+            eprintln!(
+                "Security: blocked a {} response to a no-cors {} {} (found {:?})",
+                response.header_value("Content-Type").unwrap_or(""), request.method, request.path, signature,
+            );
+            response
+                .set_status(StatusCode::BadRequest)
+                .set_header("Content-Type", "text/plain")
+                .body_str("Blocked: script-like content in a text response.\n");
+        }
+    }
+}
+
+// # Signed responses: X-Signature: sha256=<hex HMAC of the response>.
+// A client that shares the secret can tell the response came from us and wasn't changed
+// on the way (by a cache or a proxy, say). What's signed is the status code, the
+// Content-Type and the body, each on its own line:
+//     200\ntext/html\n<body bytes>
+// Other headers aren't: proxies add and rewrite those all the time. As with
+// SecurityMiddleware, only a body set on the ResponseBuilder is seen; files (send_file)
+// and chunked responses are signed as if their body was empty.
+// The secret is kept in an Arc so clones of the middleware don't copy it around.
+#[derive(Clone)]
+pub struct ResponseSigningMiddleware {
+    secret: Arc<[u8]>,
+}
+
+impl ResponseSigningMiddleware {
+    pub fn new(secret: &[u8]) -> ResponseSigningMiddleware {
+        ResponseSigningMiddleware { secret: Arc::from(secret) }
+    }
+}
+
+// Not derived: Debug output ends up in logs, and the secret shouldn't.
+impl fmt::Debug for ResponseSigningMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseSigningMiddleware").finish_non_exhaustive()
+    }
+}
+
+// The X-Signature value for a response.
+fn response_signature(secret: &[u8], status: u16, content_type: &str, body: &[u8]) -> String {
+    let mut message = format!("{}\n{}\n", status, content_type).into_bytes();
+    message.extend_from_slice(body);
+    format!("sha256={}", crypto::to_hex(&crypto::hmac_sha256(secret, &message)))
+}
+
+impl Middleware for ResponseSigningMiddleware {
+    fn on_response(&self, _request: &Request, response: &mut ResponseBuilder) {
+        let signature = response_signature(
+            &self.secret,
+            response.status().code(),
+            response.header_value("Content-Type").unwrap_or(""),
+            response.body().unwrap_or(&[]),
+        );
+        response.set_header("X-Signature", &signature);
+    }
+}
+
+// The client's half: checks a response against the secret it shares with the server.
+//     let verifier = ResponseVerifier::new(b"secret");
+//     let response = HttpClient::new().get("http://localhost:7878/")?;
+//     if !verifier.verify(&response) { /* don't trust it */ }
+#[derive(Clone)]
+pub struct ResponseVerifier {
+    secret: Arc<[u8]>,
+}
+
+impl ResponseVerifier {
+    pub fn new(secret: &[u8]) -> ResponseVerifier {
+        ResponseVerifier { secret: Arc::from(secret) }
+    }
+
+    // False when the signature is missing or doesn't match. The comparison takes as long
+    // wherever the first wrong byte is, so it can't be used to guess a signature bit by bit.
+    pub fn verify(&self, response: &ClientResponse) -> bool {
+        let Some(signature) = response.header("X-Signature") else {
+            return false;
+        };
+        let expected = response_signature(
+            &self.secret,
+            response.status,
+            response.header("Content-Type").unwrap_or(""),
+            &response.body,
+        );
+        crypto::same_bytes(signature.trim().as_bytes(), expected.as_bytes())
+    }
+}
+
+impl fmt::Debug for ResponseVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseVerifier").finish_non_exhaustive()
+    }
+}
+
+// # Roles: which authenticated users may use a route.
+// An auth middleware (BasicAuthMiddleware, a session check, ...) that knows who's asking
+// says so with request.extensions.insert(AuthenticatedUser { .. }); RequireRole, on the
+// routes that need it, then lets through users with at least one of its roles:
+//     router.get("/admin", handler).middleware(RequireRole::new(&["admin"]));
+//     router.get("/dashboard", handler).middleware(RequireRole::new(&["user", "admin"]));
+// No AuthenticatedUser at all is a 401 (whose WWW-Authenticate, if any, is the auth
+// middleware's to send); one without any of the roles is a 403.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedUser {
+    pub id: String,
+    pub roles: Vec<String>,
+}
+
+impl AuthenticatedUser {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|has| has == role)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequireRole {
+    roles: Vec<String>,
+}
+
+impl RequireRole {
+    pub fn new(roles: &[&str]) -> RequireRole {
+        RequireRole { roles: roles.iter().map(|role| role.to_string()).collect() }
+    }
+}
+
+impl Middleware for RequireRole {
+    fn before(&self, request: &Request, response: &mut Response) -> io::Result<bool> {
+        let status = match request.extensions.get::<AuthenticatedUser>() {
+            None => StatusCode::Unauthorized,
+            Some(user) if !self.roles.iter().any(|role| user.has_role(role)) => StatusCode::Forbidden,
+            Some(_) => return Ok(true),
+        };
+        response.send(&mut ResponseBuilder::new(status))?;
+        Ok(false)
+    }
+}
+
+// # Basic auth: a user and password from an htpasswd file (see htpasswd.rs).
+// Authorization: Basic base64(user:password) that the file verifies => the request goes
+// on, with an AuthenticatedUser (the user's name, no roles) in its extensions; anything
+// else gets a 401 with WWW-Authenticate, which makes browsers ask for a password.
+// The file is checked for changes (its mtime and length) at most once per check_every,
+// 1s unless changed, and read again when it has: users are added and removed while the
+// server runs. A file that no longer parses is reported and the users already loaded stay.
+// Basic auth sends the password with every request, so it's for HTTPS only.
+const HTPASSWD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Htpasswd {
+    users: Arc<HtpasswdFile>,
+    // what the loaded file looked like: (mtime, length).
+    stamp: Option<(SystemTime, u64)>,
+    checked: Instant,
+}
+
+impl fmt::Debug for Htpasswd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Htpasswd").field("users", &self.users).field("stamp", &self.stamp).finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub struct BasicAuthMiddleware {
+    path: PathBuf,
+    realm: String,
+    check_every: Duration,
+    htpasswd: RwLock<Htpasswd>,
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+impl BasicAuthMiddleware {
+    // Fails if the file can't be read or parsed: better at startup than a 401 for everyone.
+    pub fn new(path: &Path) -> io::Result<BasicAuthMiddleware> {
+        let stamp = stamp(path);
+        let users = Arc::new(HtpasswdFile::from_path(path)?);
+        Ok(BasicAuthMiddleware {
+            path: path.to_path_buf(),
+            realm: "surff".to_string(),
+            check_every: HTPASSWD_CHECK_INTERVAL,
+            htpasswd: RwLock::new(Htpasswd { users, stamp, checked: Instant::now() }),
+        })
+    }
+
+    // What the browser shows when it asks for the password.
+    pub fn realm(mut self, realm: &str) -> BasicAuthMiddleware {
+        self.realm = realm.to_string();
+        self
+    }
+
+    pub fn check_every(mut self, interval: Duration) -> BasicAuthMiddleware {
+        self.check_every = interval;
+        self
+    }
+
+    // The users, read again first if it's time to look and the file has changed.
+    // Requests only wait for the write lock while the file is being read.
+    pub fn users(&self) -> Arc<HtpasswdFile> {
+        {
+            let htpasswd = self.htpasswd.read().unwrap_or_else(PoisonError::into_inner);
+            if htpasswd.checked.elapsed() < self.check_every {
+                return Arc::clone(&htpasswd.users);
+            }
+        }
+        let mut htpasswd = self.htpasswd.write().unwrap_or_else(PoisonError::into_inner);
+        // another request may have looked while this one waited.
+        if htpasswd.checked.elapsed() >= self.check_every {
+            htpasswd.checked = Instant::now();
+            let stamp = stamp(&self.path);
+            if stamp != htpasswd.stamp {
+                htpasswd.stamp = stamp;
+                match HtpasswdFile::from_path(&self.path) {
+                    Ok(users) => htpasswd.users = Arc::new(users),
+                    Err(e) => eprintln!("Kept the users loaded before: {}", e),
+                }
+            }
+        }
+        Arc::clone(&htpasswd.users)
+    }
+
+    // The user, if the Authorization header's password is right.
+    fn authenticate(&self, request: &Request) -> Option<String> {
+        let (scheme, credentials) = request.header("Authorization")?.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let credentials = String::from_utf8(base64::decode(credentials.trim().as_bytes())?).ok()?;
+        let (user, password) = credentials.split_once(':')?;
+        self.users().verify(user, password).then(|| user.to_string())
+    }
+}
+
+impl Middleware for BasicAuthMiddleware {
+    fn before(&self, request: &Request, response: &mut Response) -> io::Result<bool> {
+        if let Some(id) = self.authenticate(request) {
+            request.extensions.insert(AuthenticatedUser { id, roles: Vec::new() });
+            return Ok(true);
+        }
+        let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm.replace(['\\', '"'], ""));
+        response.send(ResponseBuilder::new(StatusCode::Unauthorized).header("WWW-Authenticate", &challenge))?;
+        Ok(false)
+    }
+}
+
+// # Preload hints: Link: </style.css>; rel=preload; as=style, on HTML pages.
+// The browser can start fetching what a page needs before it has parsed far enough to
+// find it. Every text/html response gets a Link header for each hint, and with
+// scan_body(true) also for the stylesheets (<link rel="stylesheet" href>) and scripts
+// (<script src>) in its body, found by looking for those tags, not by parsing the HTML.
+// Like the other body checks, that only sees bodies set on the ResponseBuilder.
+// HTTP/2 server push and 103 Early Hints (hints sent before the handler even runs) are
+// out of scope: surff speaks HTTP/1.1, where only the final response's head has them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreloadHint {
+    pub uri: String,
+    // "style", "script", "font", "image", ...
+    pub as_type: String,
+    // Fonts, and anything else fetched in CORS mode, need it to match the real request.
+    pub crossorigin: bool,
+}
+
+impl PreloadHint {
+    pub fn new(uri: &str, as_type: &str) -> PreloadHint {
+        PreloadHint { uri: uri.to_string(), as_type: as_type.to_string(), crossorigin: false }
+    }
+
+    pub fn crossorigin(mut self) -> PreloadHint {
+        self.crossorigin = true;
+        self
+    }
+
+    fn link(&self) -> String {
+        let mut link = format!("<{}>; rel=preload; as={}", self.uri, self.as_type);
+        if self.crossorigin {
+            link.push_str("; crossorigin");
+        }
+        link
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PreloadMiddleware {
+    hints: Vec<PreloadHint>,
+    scan_body: bool,
+}
+
+impl PreloadMiddleware {
+    pub fn new(hints: Vec<PreloadHint>) -> PreloadMiddleware {
+        PreloadMiddleware { hints, scan_body: false }
+    }
+
+    pub fn scan_body(mut self, scan: bool) -> PreloadMiddleware {
+        self.scan_body = scan;
+        self
+    }
+}
+
+// The value of `name="..."` (or '...') in a tag, as written.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lowercase = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(found) = lowercase[from..].find(name) {
+        let start = from + found;
+        from = start + name.len();
+        // a whole attribute name, not the end of another one (data-src).
+        if start > 0 && !lowercase.as_bytes()[start - 1].is_ascii_whitespace() {
+            continue;
+        }
+        let rest = tag[from..].trim_start();
+        let Some(rest) = rest.strip_prefix('=') else {
+            continue;
+        };
+        let rest = rest.trim_start();
+        let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        return rest[1..].find(quote).map(|end| &rest[1..1 + end]);
+    }
+    None
+}
+
+// The stylesheets and scripts an HTML page loads, in the order it loads them.
+fn scan_hints(html: &str) -> Vec<PreloadHint> {
+    let lowercase = html.to_ascii_lowercase();
+    let mut hints = Vec::new();
+    let mut from = 0;
+    while let Some(found) = lowercase[from..].find('<') {
+        let start = from + found;
+        let Some(length) = lowercase[start..].find('>') else {
+            break;
+        };
+        let tag = &html[start..start + length];
+        from = start + length;
+        let name = lowercase[start + 1..start + length].split_ascii_whitespace().next().unwrap_or("");
+        let hint = match name {
+            "link" if attribute(tag, "rel").is_some_and(|rel| rel.eq_ignore_ascii_case("stylesheet")) => {
+                attribute(tag, "href").map(|href| PreloadHint::new(href, "style"))
+            }
+            "script" => attribute(tag, "src").map(|src| PreloadHint::new(src, "script")),
+            _ => None,
+        };
+        hints.extend(hint.filter(|hint| !hint.uri.is_empty()));
+    }
+    hints
+}
+
+impl Middleware for PreloadMiddleware {
+    fn on_response(&self, _request: &Request, response: &mut ResponseBuilder) {
+        let html = response.header_value("Content-Type").is_some_and(|content_type| {
+            content_type.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("text/html")
+        });
+        if !html {
+            return;
+        }
+
+        let mut hints = self.hints.clone();
+        if self.scan_body {
+            let scanned = response.body().map(|body| scan_hints(&String::from_utf8_lossy(body))).unwrap_or_default();
+            for hint in scanned {
+                if !hints.iter().any(|known| known.uri == hint.uri) {
+                    hints.push(hint);
+                }
+            }
+        }
+        for hint in hints {
+            response.header("Link", &hint.link());
+        }
+    }
+}
+
+// # Idempotency keys: a retried POST gets the first one's answer instead of running again.
+// let idempotency = Arc::new(IdempotencyMiddleware::new(Duration::from_secs(24 * 60 * 60)));
+// router.post("/payments", idempotency.wrap(pay));
+// A client that timed out can't tell whether its payment went through; if it sent
+// Idempotency-Key: <uuid>, it can send the same request again to find out.
+// 1. Requests without the header are passed on, every time.
+// 2. The first with a key runs the handler, into memory (router::capture), and what it
+// sent is kept for `ttl` and sent again, head and body, for every repeat of the key. A
+// handler that fails (Err, or no response) leaves nothing behind: the next one runs it.
+// 3. A repeat that arrives while the first still runs waits for it (on a Condvar).
+// 4. Keys belong to the request's path, or to the longest prefix(..) it's below, so two
+// endpoints (or an endpoint and what's below it, with a prefix) don't share them.
+// The key is trusted to mean the same request: the bodies aren't compared. Unlike
+// middleware, this wraps the handler, which is the only thing that sees the whole body go out.
+#[derive(Debug)]
+pub struct IdempotencyMiddleware {
+    ttl: Duration,
+    prefixes: Vec<String>,
+    entries: Mutex<HashMap<(String, String), Idempotent>>,
+    finished: Condvar,
+}
+
+#[derive(Debug)]
+enum Idempotent {
+    Running,
+    Done { at: Instant, response: ResponseBuilder },
+}
+
+// Takes a Running entry out again if its handler never finished (an Err, a panic).
+struct Claimed<'a> {
+    middleware: &'a IdempotencyMiddleware,
+    key: Option<(String, String)>,
+}
+
+impl Drop for Claimed<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.middleware.entries.lock().unwrap_or_else(PoisonError::into_inner).remove(&key);
+            self.middleware.finished.notify_all();
+        }
+    }
+}
+
+impl IdempotencyMiddleware {
+    pub fn new(ttl: Duration) -> IdempotencyMiddleware {
+        IdempotencyMiddleware { ttl, prefixes: Vec::new(), entries: Mutex::new(HashMap::new()), finished: Condvar::new() }
+    }
+
+    // Paths below `prefix` (on a segment boundary) share their keys.
+    pub fn prefix(mut self, prefix: &str) -> IdempotencyMiddleware {
+        self.prefixes.push(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    pub fn wrap<F>(self: &Arc<Self>, handler: F) -> impl Fn(&Request, &mut Response) -> io::Result<()> + Send + Sync + 'static
+        where
+            F: Fn(&Request, &mut Response) -> io::Result<()> + Send + Sync + 'static
+    {
+        let middleware = Arc::clone(self);
+        move |request, response| middleware.respond(request, response, &handler)
+    }
+
+    fn respond<F>(&self, request: &Request, response: &mut Response, handler: &F) -> io::Result<()>
+        where
+            F: Fn(&Request, &mut Response) -> io::Result<()>
+    {
+        let Some(key) = request.header("Idempotency-Key").filter(|key| !key.is_empty()) else {
+            return handler(request, response);
+        };
+        let scope = self
+            .prefixes
+            .iter()
+            .filter(|prefix| *prefix == &request.path || router::is_prefix(prefix, &request.path))
+            .max_by_key(|prefix| prefix.len())
+            .unwrap_or(&request.path);
+        let key = (scope.clone(), key.to_string());
+
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let now = Instant::now();
+            entries.retain(|_, entry| !matches!(entry, Idempotent::Done { at, .. } if now.duration_since(*at) >= self.ttl));
+            match entries.get(&key) {
+                Some(Idempotent::Done { response: cached, .. }) => {
+                    let mut cached = cached.clone();
+                    drop(entries);
+                    return response.send(&mut cached);
+                }
+                Some(Idempotent::Running) => entries = self.finished.wait(entries).unwrap_or_else(PoisonError::into_inner),
+                None => break,
+            }
+        }
+        entries.insert(key.clone(), Idempotent::Running);
+        drop(entries);
+
+        let mut claimed = Claimed { middleware: self, key: Some(key) };
+        let mut captured = router::capture(request, response, handler)?;
+        let key = claimed.key.take().expect("claimed above");
+        let done = Idempotent::Done { at: Instant::now(), response: captured.clone() };
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).insert(key, done);
+        self.finished.notify_all();
+        response.send(&mut captured)
+    }
+}
+
+// # Coalescing: a hundred identical GETs at once, answered by one handler call.
+// let coalescer = Arc::new(RequestCoalescer::default());
+// router.get("/popular", coalescer.wrap(popular));
+// The first GET (or HEAD) for a URI runs the handler, into memory (router::capture);
+// the same requests arriving while it runs wait for it (each on a channel of its own)
+// and all get a copy of its response, shared behind an Arc until then. Varnish calls it
+// request collapsing. Requests that aren't the same for everyone aren't coalesced:
+// 1. those with Authorization or Cookie, or Cache-Control: no-store (the client wants
+// a fresh answer), go straight to the handler;
+// 2. a response with Set-Cookie or Cache-Control: private or no-store isn't shared,
+// and neither is a failure: the waiters then run the handler themselves.
+// Where a waiter hears about the response: None if it isn't shared.
+type Waiter = Sender<Option<Arc<ResponseBuilder>>>;
+
+#[derive(Debug, Default)]
+pub struct RequestCoalescer {
+    in_flight: Mutex<HashMap<(Method, String), Vec<Waiter>>>,
+}
+
+// The request that's running; whatever happens to it, its waiters hear about it.
+struct Leading<'a> {
+    coalescer: &'a RequestCoalescer,
+    key: Option<(Method, String)>,
+}
+
+impl Leading<'_> {
+    fn finish(&mut self, response: Option<Arc<ResponseBuilder>>) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let waiters = self.coalescer.in_flight.lock().unwrap_or_else(PoisonError::into_inner).remove(&key);
+        for waiter in waiters.unwrap_or_default() {
+            let _ = waiter.send(response.clone());
+        }
+    }
+}
+
+impl Drop for Leading<'_> {
+    fn drop(&mut self) {
+        self.finish(None);
+    }
+}
+
+impl RequestCoalescer {
+    pub fn wrap<F>(self: &Arc<Self>, handler: F) -> impl Fn(&Request, &mut Response) -> io::Result<()> + Send + Sync + 'static
+        where
+            F: Fn(&Request, &mut Response) -> io::Result<()> + Send + Sync + 'static
+    {
+        let coalescer = Arc::clone(self);
+        move |request, response| coalescer.respond(request, response, &handler)
+    }
+
+    fn respond<F>(&self, request: &Request, response: &mut Response, handler: &F) -> io::Result<()>
+        where
+            F: Fn(&Request, &mut Response) -> io::Result<()>
+    {
+        let personal = request.header("Authorization").is_some() || request.header("Cookie").is_some();
+        if !matches!(request.method, Method::Get | Method::Head) || personal || has_directive(request.header("Cache-Control"), &["no-store"]) {
+            return handler(request, response);
+        }
+        let uri = match &request.query {
+            Some(query) => format!("{}?{}", request.path, query),
+            None => request.path.clone(),
+        };
+        let key = (request.method.clone(), uri);
+
+        let waiting = {
+            let mut in_flight = self.in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+            match in_flight.get_mut(&key) {
+                Some(waiters) => {
+                    let (sender, receiver) = mpsc::channel();
+                    waiters.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    in_flight.insert(key.clone(), Vec::new());
+                    None
+                }
+            }
+        };
+        if let Some(receiver) = waiting {
+            return match receiver.recv() {
+                Ok(Some(shared)) => response.send(&mut ResponseBuilder::clone(&shared)),
+                _ => handler(request, response),
+            };
+        }
+
+        let mut leading = Leading { coalescer: self, key: Some(key) };
+        let mut captured = router::capture(request, response, handler)?;
+        let private = captured.has_header("Set-Cookie")
+            || captured.header_values("Cache-Control").any(|value| has_directive(Some(value), &["private", "no-store"]));
+        leading.finish((!private).then(|| Arc::new(captured.clone())));
+        response.send(&mut captured)
+    }
+}
+
+// Whether a Cache-Control value has one of `directives` (private="Set-Cookie" included).
+fn has_directive(value: Option<&str>, directives: &[&str]) -> bool {
+    value.is_some_and(|value| {
+        value.split(',').any(|directive| {
+            let name = directive.split('=').next().unwrap_or("").trim();
+            directives.iter().any(|wanted| name.eq_ignore_ascii_case(wanted))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method, StatusCode};
+    use crate::router::{exchange, Output, Router};
+    use std::time::Instant;
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |_, response| {
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Server", "inner/1.0"))
+        });
+        router
+    }
+
+    #[test]
+    fn robots_tags_are_checked() {
+        assert_eq!(XRobotsTagMiddleware::new(&[]).unwrap_err(), XRobotsTagError::NoDirectives);
+        assert_eq!(
+            XRobotsTagMiddleware::new(&["noindex", "nofolow"]).unwrap_err(),
+            XRobotsTagError::UnknownDirective("nofolow".to_string())
+        );
+        assert_eq!(XRobotsTagMiddleware::new(&["NoIndex", "noarchive"]).unwrap().value, "noindex, noarchive");
+    }
+
+    #[test]
+    fn robots_tag_only_below_its_prefix() {
+        let mut router = Router::new();
+        for path in ["/admin", "/administrator"] {
+            router.route(Method::Get, path, |_, response| response.send(&mut ResponseBuilder::new(StatusCode::Ok)));
+        }
+        router.wrap(XRobotsTagMiddleware::for_prefix("/admin"));
+
+        for path in ["/admin", "/admin/users"] {
+            let response = exchange(&router, &format!("GET {} HTTP/1.1\r\n\r\n", path));
+            assert!(response.contains("\r\nX-Robots-Tag: noindex, nofollow\r\n"), "{}: {:?}", path, response);
+        }
+        let response = exchange(&router, "GET /administrator HTTP/1.1\r\n\r\n");
+        assert!(!response.contains("X-Robots-Tag"), "{:?}", response);
+    }
+
+    fn api() -> Router {
+        let mut router = Router::new();
+        router.get("/api/users", |_, response| response.send(ResponseBuilder::new(StatusCode::Ok).body_str("[]")));
+        router.post("/api/users", |_, response| response.send(&mut ResponseBuilder::new(StatusCode::Created)));
+        router
+    }
+
+    #[test]
+    fn options_lists_the_methods_of_the_path() {
+        let response = exchange(&api(), "OPTIONS /api/users HTTP/1.1\r\n\r\n");
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nAllow: GET, POST, HEAD, OPTIONS\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn options_routes_take_precedence() {
+        let mut router = api();
+        router.options("/api/users", |_, response| response.send(&mut ResponseBuilder::new(StatusCode::NoContent)));
+
+        let response = exchange(&router, "OPTIONS /api/users HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{:?}", response);
+    }
+
+    #[test]
+    fn cors_answers_preflights_from_allowed_origins() {
+        let mut router = api();
+        router.wrap(CorsMiddleware::new().allow_origin("https://app.example").allow_methods(&[Method::Delete]));
+
+        let preflight = "OPTIONS /api/users HTTP/1.1\r\nOrigin: https://app.example\r\n\
+                         Access-Control-Request-Method: DELETE\r\nAccess-Control-Request-Headers: content-type\r\n\r\n";
+        let response = exchange(&router, preflight);
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nAccess-Control-Allow-Methods: GET, HEAD, POST, DELETE\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Headers: content-type\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: https://app.example\r\n"));
+        assert!(!response.contains("Allow: GET, POST"));
+
+        // another origin: an ordinary OPTIONS answer, without any CORS headers.
+        let response = exchange(&router, &preflight.replace("app.example", "evil.example"));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nAllow: "), "{:?}", response);
+        assert!(!response.contains("Access-Control"));
+    }
+
+    #[test]
+    fn cors_marks_ordinary_responses() {
+        let mut router = api();
+        router.wrap(CorsMiddleware::new());
+
+        let response = exchange(&router, "GET /api/users HTTP/1.1\r\nOrigin: https://app.example\r\n\r\n");
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: *\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\n[]"));
+
+        // no Origin => not a cross-origin request.
+        let response = exchange(&router, "GET /api/users HTTP/1.1\r\n\r\n");
+        assert!(!response.contains("Access-Control"));
+    }
+
+    #[test]
+    fn default_names_the_crate_version() {
+        let mut router = Router::new();
+        router.wrap(ServerHeaderMiddleware::default());
+
+        // the default 404 goes through the layers too.
+        let response = exchange(&router, "GET /missing HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(response.contains(&format!("\r\nServer: surff/{}\r\n", env!("CARGO_PKG_VERSION"))));
+    }
+
+    #[test]
+    fn replaces_the_header_of_inner_handlers() {
+        let mut router = router();
+        router.wrap(ServerHeaderMiddleware::new("edge".to_string()));
+
+        let response = exchange(&router, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nServer: edge\r\n"), "{:?}", response);
+        assert_eq!(response.matches("Server:").count(), 1);
+    }
+
+    #[test]
+    fn suppress_removes_it() {
+        let mut router = router();
+        router.wrap(ServerHeaderMiddleware::suppress());
+
+        let response = exchange(&router, "GET / HTTP/1.1\r\n\r\n");
+        assert!(!response.contains("Server:"), "{:?}", response);
+    }
+
+    #[test]
+    fn plain_http_is_redirected_with_the_whole_uri() {
+        let mut router = router();
+        router.wrap(HttpsRedirectMiddleware::new(8443));
+
+        let response = exchange(&router, "GET /a%20b/c?x=1&y=%2F HTTP/1.1\r\nHost: example.com:8080\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nLocation: https://example.com:8443/a%20b/c?x=1&y=%2F\r\n"), "{:?}", response);
+
+        let response = exchange(&router, "GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n");
+        assert!(response.contains("\r\nLocation: https://[::1]:8443/\r\n"), "{:?}", response);
+        // no Host, or one that isn't a host: nowhere to send the client.
+        for host in ["", "Host: evil.example/@x\r\n"] {
+            let response = exchange(&router, &format!("GET / HTTP/1.1\r\n{}\r\n", host));
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", response);
+        }
+    }
+
+    #[test]
+    fn https_requests_pass_through_with_hsts() {
+        let mut router = router();
+        router.wrap(HttpsRedirectMiddleware::new(443).with_hsts());
+
+        let response = exchange(&router, "GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-Proto: https\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nStrict-Transport-Security: max-age=31536000; includeSubDomains\r\n"));
+
+        // the redirect itself goes out over plain HTTP, where HSTS means nothing.
+        let response = exchange(&router, "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 301 "), "{:?}", response);
+        assert!(!response.contains("Strict-Transport-Security"));
+    }
+
+    #[test]
+    fn cacheable_responses_get_surrogate_headers() {
+        let mut router = Router::new();
+        router.get("/users", |_, response| {
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Surrogate-Key", "user-42 users"))
+        });
+        router.get("/private", |_, response| {
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Cache-Control", "no-cache, private").header("Surrogate-Key", "users"))
+        });
+        router.post("/users", |_, response| response.send(&mut ResponseBuilder::new(StatusCode::Created)));
+        router.wrap(SurrogateMiddleware::new(600).key_prefix("/", &["site"]).key_prefix("/users", &["users"]));
+
+        for method in ["GET", "HEAD"] {
+            let response = exchange(&router, &format!("{} /users/42 HTTP/1.1\r\n\r\n", method));
+            assert!(response.contains("\r\nSurrogate-Control: max-age=600\r\n"), "{:?}", response);
+            assert!(response.contains("\r\nSurrogate-Key: user-42 users site\r\n"), "{:?}", response);
+            assert_eq!(response.matches("Surrogate-Key").count(), 1);
+        }
+
+        // not cacheable: a POST, a private response, a 404.
+        for request in ["POST /users HTTP/1.1\r\n\r\n", "GET /private HTTP/1.1\r\n\r\n", "GET /nothing HTTP/1.1\r\n\r\n"] {
+            let response = exchange(&router, request);
+            assert!(!response.contains("Surrogate-"), "{:?}", response);
+        }
+    }
+
+    fn tarpitted(blocklist: &str, max: usize) -> Router {
+        let mut router = router();
+        router.wrap(TarpitMiddleware::new(vec![blocklist.parse().unwrap()], Duration::from_millis(200)).max_tarpitted(max));
+        router
+    }
+
+    #[test]
+    fn blocked_addresses_wait_for_an_empty_answer() {
+        let started = Instant::now();
+        let response = exchange(&tarpitted("127.0.0.0/8", 2), "GET / HTTP/1.1\r\n\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(200), "{:?}", started.elapsed());
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+
+        // everyone else: straight to the handler.
+        let started = Instant::now();
+        let response = exchange(&tarpitted("192.0.2.0/24", 2), "GET / HTTP/1.1\r\n\r\n");
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert!(response.contains("\r\nServer: inner/1.0\r\n"), "{:?}", response);
+    }
+
+    #[test]
+    fn the_tarpit_holds_only_so_many() {
+        let router = tarpitted("127.0.0.1", 1);
+        let (first, second) = thread::scope(|scope| {
+            let first = scope.spawn(|| exchange(&router, "GET / HTTP/1.1\r\n\r\n"));
+            thread::sleep(Duration::from_millis(50));
+            let started = Instant::now();
+            let second = exchange(&router, "GET / HTTP/1.1\r\n\r\n");
+            assert!(started.elapsed() < Duration::from_millis(150), "{:?}", started.elapsed());
+            (first.join().unwrap(), second)
+        });
+        assert!(first.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", first);
+        assert!(second.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{:?}", second);
+
+        // the slot is free again.
+        assert!(exchange(&router, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    fn echo() -> Router {
+        let mut router = Router::new();
+        router.get("/search", |request, response| {
+            let query = request.query.clone().unwrap_or_default();
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Content-Type", "text/html; charset=utf-8").body_str(&query))
+        });
+        router.get("/app.js", |_, response| {
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Content-Type", "text/javascript").body_str("eval(x)"))
+        });
+        router.wrap(SecurityMiddleware::new().enable_content_type_confusion_protection(true));
+        router
+    }
+
+    #[test]
+    fn scripts_in_text_for_no_cors_fetches_are_blocked() {
+        for query in ["<SCRIPT>alert(1)</SCRIPT>", "a=javascript:x", "eval(document.cookie)"] {
+            let request = format!("GET /search?{} HTTP/1.1\r\nSec-Fetch-Mode: no-cors\r\n\r\n", query);
+            let response = exchange(&echo(), &request);
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}: {:?}", query, response);
+            assert!(!response.contains(query));
+            assert!(response.contains("\r\nContent-Type: text/plain\r\n"));
+        }
+    }
+
+    #[test]
+    fn navigations_scripts_and_harmless_text_pass() {
+        // a navigation, an actual script, and text without anything script-like.
+        let requests = [
+            "GET /search?<script> HTTP/1.1\r\nSec-Fetch-Mode: navigate\r\n\r\n",
+            "GET /app.js HTTP/1.1\r\nSec-Fetch-Mode: no-cors\r\n\r\n",
+            "GET /search?scripture HTTP/1.1\r\nSec-Fetch-Mode: no-cors\r\n\r\n",
+        ];
+        for request in requests {
+            let response = exchange(&echo(), request);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+            assert!(response.contains("\r\nX-Content-Type-Options: nosniff\r\n"));
+        }
+    }
+
+    #[test]
+    fn signed_responses_verify() {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |_, response| {
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Content-Type", "text/plain").body_str("hello"))
+        });
+        router.wrap(ResponseSigningMiddleware::new(b"secret"));
+
+        let raw = exchange(&router, "GET / HTTP/1.1\r\n\r\n");
+        let response = crate::client::parse_response(raw.as_bytes(), false).unwrap();
+        let signature = response.header("X-Signature").unwrap();
+        // 200\ntext/plain\nhello, by hand:
+        assert_eq!(signature, format!("sha256={}", crypto::to_hex(&crypto::hmac_sha256(b"secret", b"200\ntext/plain\nhello"))));
+        assert!(ResponseVerifier::new(b"secret").verify(&response));
+        assert!(!ResponseVerifier::new(b"other").verify(&response));
+
+        let mut changed = response.clone();
+        changed.body = b"hellO".to_vec();
+        assert!(!ResponseVerifier::new(b"secret").verify(&changed));
+        let mut unsigned = response;
+        unsigned.headers.retain(|(name, _)| name != "X-Signature");
+        assert!(!ResponseVerifier::new(b"secret").verify(&unsigned));
+    }
+
+    // "Authorization: <id> <role>,<role>", for the tests only.
+    struct TestAuth;
+
+    impl Middleware for TestAuth {
+        fn before(&self, request: &Request, _response: &mut Response) -> io::Result<bool> {
+            if let Some((id, roles)) = request.header("Authorization").and_then(|value| value.split_once(' ')) {
+                let roles = roles.split(',').map(str::to_string).collect();
+                request.extensions.insert(AuthenticatedUser { id: id.to_string(), roles });
+            }
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn routes_require_one_of_their_roles() {
+        let mut router = Router::new();
+        router
+            .get("/admin", |_, response| response.send(&mut ResponseBuilder::new(StatusCode::Ok)))
+            .middleware(RequireRole::new(&["admin"]));
+        router
+            .get("/dashboard", |request, response| {
+                let user = request.extensions.get::<AuthenticatedUser>().unwrap();
+                response.send(ResponseBuilder::new(StatusCode::Ok).body_str(&user.id))
+            })
+            .middleware(RequireRole::new(&["user", "admin"]));
+        router.get("/", |_, response| response.send(&mut ResponseBuilder::new(StatusCode::Ok)));
+        router.wrap(TestAuth);
+
+        let status = |path: &str, authorization: &str| {
+            let response = exchange(&router, &format!("GET {} HTTP/1.1\r\n{}\r\n", path, authorization));
+            response.split(' ').nth(1).unwrap().to_string()
+        };
+        assert_eq!(status("/admin", ""), "401");
+        assert_eq!(status("/admin", "Authorization: ann user\r\n"), "403");
+        assert_eq!(status("/admin", "Authorization: bob user,admin\r\n"), "200");
+        assert_eq!(status("/dashboard", ""), "401");
+        assert_eq!(status("/dashboard", "Authorization: ann guest\r\n"), "403");
+        assert_eq!(status("/dashboard", "Authorization: ann user\r\n"), "200");
+        // routes without RequireRole don't care.
+        assert_eq!(status("/", ""), "200");
+
+        let response = exchange(&router, "GET /dashboard HTTP/1.1\r\nAuthorization: ann user\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\nann"), "{:?}", response);
+    }
+
+    #[test]
+    fn html_responses_get_preload_links() {
+        let page = "<html><head><LINK href='/site.css' REL=\"stylesheet\"><link rel=\"icon\" href=\"/icon.png\">\
+                    <script data-src=\"/lazy.js\"></script><script src=\"/app.js\" defer></script></head></html>";
+        let mut router = Router::new();
+        router.get("/", move |_, response| {
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Content-Type", "text/html; charset=utf-8").body_str(page))
+        });
+        router.get("/data", |_, response| {
+            response.send(ResponseBuilder::new(StatusCode::Ok).header("Content-Type", "application/json").body_str(page))
+        });
+        let font = PreloadHint::new("/font.woff2", "font").crossorigin();
+        router.wrap(PreloadMiddleware::new(vec![font, PreloadHint::new("/app.js", "script")]).scan_body(true));
+
+        let response = exchange(&router, "GET / HTTP/1.1\r\n\r\n");
+        // the hints first, then what the page loads that they don't already cover.
+        let links = "\r\nLink: </font.woff2>; rel=preload; as=font; crossorigin, \
+                     </app.js>; rel=preload; as=script, </site.css>; rel=preload; as=style\r\n";
+        assert!(response.contains(links), "{:?}", response);
+        assert!(!exchange(&router, "GET /data HTTP/1.1\r\n\r\n").contains("Link:"));
+    }
+
+
+    // POST /payments/... answers with how many payments it has made, slowly.
+    fn payments(idempotency: &Arc<IdempotencyMiddleware>) -> (Router, Arc<AtomicUsize>) {
+        let made = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&made);
+        let mut router = Router::new();
+        router.post(
+            "/payments",
+            idempotency.wrap(move |request, response| {
+                if request.header("X-Fail").is_some() {
+                    return Err(io::Error::other("card declined"));
+                }
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                thread::sleep(Duration::from_millis(50));
+                response.send(ResponseBuilder::new(StatusCode::Created).header("X-Payment", &n.to_string()).body_str(&format!("payment {}", n)))
+            }),
+        );
+        (router, made)
+    }
+
+    #[test]
+    fn repeated_keys_get_the_first_response() {
+        let idempotency = Arc::new(IdempotencyMiddleware::new(Duration::from_secs(60)).prefix("/payments/eu"));
+        let (router, made) = payments(&idempotency);
+        let post = |path: &str, headers: &str| exchange(&router, &format!("POST {} HTTP/1.1\r\n{}\r\n", path, headers));
+
+        let first = post("/payments", "Idempotency-Key: a\r\n");
+        assert!(first.starts_with("HTTP/1.1 201 Created\r\n"), "{:?}", first);
+        assert!(first.ends_with("\r\n\r\npayment 1"), "{:?}", first);
+        assert_eq!(post("/payments", "Idempotency-Key: a\r\n"), first);
+        assert_eq!(made.load(Ordering::SeqCst), 1);
+
+        // another key, another path, no key: run again.
+        assert!(post("/payments", "Idempotency-Key: b\r\n").ends_with("payment 2"));
+        assert!(post("/payments/us", "Idempotency-Key: a\r\n").ends_with("payment 3"));
+        assert!(post("/payments", "").ends_with("payment 4"));
+        assert!(post("/payments", "").ends_with("payment 5"));
+        // below the prefix, the paths share their keys.
+        assert!(post("/payments/eu/1", "Idempotency-Key: a\r\n").ends_with("payment 6"));
+        assert!(post("/payments/eu/2", "Idempotency-Key: a\r\n").ends_with("payment 6"));
+
+        // a failure isn't kept.
+        let failing = Request::parse(b"POST /payments HTTP/1.1\r\nIdempotency-Key: c\r\nX-Fail: yes\r\n\r\n").unwrap();
+        let mut buffer = Vec::new();
+        let mut response = Response::new(Output::Buffer { buffer: &mut buffer, peer: ([127, 0, 0, 1], 1).into() }, &failing, None);
+        assert!(router.respond(&failing, &mut response).is_err());
+        assert!(post("/payments", "Idempotency-Key: c\r\n").ends_with("payment 7"));
+    }
+
+    #[test]
+    fn concurrent_repeats_wait_for_the_first_and_keys_expire() {
+        let idempotency = Arc::new(IdempotencyMiddleware::new(Duration::from_millis(300)));
+        let (router, made) = payments(&idempotency);
+        let raw = "POST /payments HTTP/1.1\r\nIdempotency-Key: retry\r\n\r\n";
+
+        let responses: Vec<String> = thread::scope(|scope| {
+            let threads: Vec<_> = (0..4).map(|_| scope.spawn(|| exchange(&router, raw))).collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+        });
+        assert_eq!(made.load(Ordering::SeqCst), 1);
+        assert!(responses.iter().all(|response| response.ends_with("\r\n\r\npayment 1")), "{:?}", responses);
+
+        thread::sleep(Duration::from_millis(400));
+        assert!(exchange(&router, raw).ends_with("payment 2"));
+    }
+
+
+    // GET /popular (and /session, which sets a cookie) count their calls, slowly.
+    fn popular(coalescer: &Arc<RequestCoalescer>) -> (Router, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut router = Router::new();
+        for (path, cookie) in [("/popular", false), ("/session", true)] {
+            let calls = Arc::clone(&calls);
+            router.get(
+                path,
+                coalescer.wrap(move |_, response| {
+                    let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                    thread::sleep(Duration::from_millis(200));
+                    let mut answer = ResponseBuilder::new(StatusCode::Ok);
+                    if cookie {
+                        answer.header("Set-Cookie", &format!("id={}", n));
+                    }
+                    response.send(answer.body_str(&format!("call {}", n)))
+                }),
+            );
+        }
+        (router, calls)
+    }
+
+    // `raw`, from `n` clients at once.
+    fn at_once(router: &Router, n: usize, raw: &str) -> Vec<String> {
+        let barrier = std::sync::Barrier::new(n);
+        thread::scope(|scope| {
+            let threads: Vec<_> = (0..n)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        exchange(router, raw)
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+        })
+    }
+
+    #[test]
+    fn identical_gets_share_one_call() {
+        let coalescer = Arc::new(RequestCoalescer::default());
+        let (router, calls) = popular(&coalescer);
+
+        let responses = at_once(&router, 8, "GET /popular?page=1 HTTP/1.1\r\n\r\n");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(responses.iter().all(|response| response.ends_with("\r\n\r\ncall 1")), "{:?}", responses);
+        assert!(responses.iter().all(|response| response.starts_with("HTTP/1.1 200 OK\r\n")));
+        // it's not a cache: once it's answered, the next one runs again.
+        assert!(exchange(&router, "GET /popular?page=1 HTTP/1.1\r\n\r\n").ends_with("call 2"));
+    }
+
+    #[test]
+    fn personal_requests_and_private_responses_arent_shared() {
+        let coalescer = Arc::new(RequestCoalescer::default());
+        let (router, calls) = popular(&coalescer);
+
+        at_once(&router, 3, "GET /popular HTTP/1.1\r\nAuthorization: Bearer x\r\n\r\n");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        at_once(&router, 3, "GET /popular HTTP/1.1\r\nCache-Control: no-store\r\n\r\n");
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+
+        let responses = at_once(&router, 3, "GET /session HTTP/1.1\r\n\r\n");
+        assert_eq!(calls.load(Ordering::SeqCst), 9);
+        let mut cookies: Vec<&str> = responses.iter().filter_map(|response| response.lines().find(|line| line.starts_with("Set-Cookie: "))).collect();
+        cookies.sort();
+        cookies.dedup();
+        assert_eq!(cookies.len(), 3, "{:?}", responses);
+    }
+
+
+    #[test]
+    fn basic_auth_checks_the_htpasswd_file_and_reloads_it() {
+        let path = std::env::temp_dir().join(format!("surff-htpasswd-{}", std::process::id()));
+        std::fs::write(&path, "alice:{SHA}qUqP5cyxm6YcTAhz05Hph5gvu9M=\n").unwrap();
+        let auth = BasicAuthMiddleware::new(&path).unwrap().realm("staff").check_every(Duration::ZERO);
+        let mut router = Router::new();
+        router.get("/", |request, response| {
+            let user = request.extensions.get::<AuthenticatedUser>().unwrap();
+            response.send(ResponseBuilder::new(StatusCode::Ok).body_str(&user.id))
+        });
+        router.wrap(auth);
+        let get = |credentials: &str| exchange(&router, &format!("GET / HTTP/1.1\r\nAuthorization: Basic {}\r\n\r\n", credentials));
+
+        assert!(get("YWxpY2U6dGVzdA==").ends_with("\r\n\r\nalice"));
+        for rejected in [get("YWxpY2U6bm9wZQ=="), get("ZGF2ZTp0ZXN0"), get("not base64"), exchange(&router, "GET / HTTP/1.1\r\n\r\n")] {
+            assert!(rejected.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{:?}", rejected);
+            assert!(rejected.contains("\r\nWWW-Authenticate: Basic realm=\"staff\", charset=\"UTF-8\"\r\n"), "{:?}", rejected);
+        }
+
+        // bob in, alice out, without a restart.
+        let later = SystemTime::now() + Duration::from_secs(10);
+        std::fs::write(&path, "bob:hunter2\n").unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert!(get("Ym9iOmh1bnRlcjI=").ends_with("\r\n\r\nbob"));
+        assert!(get("YWxpY2U6dGVzdA==").starts_with("HTTP/1.1 401 "));
+
+        // a broken file: bob stays.
+        std::fs::write(&path, "bob:$2y$05$hash\n").unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(later + Duration::from_secs(10)).unwrap();
+        assert!(get("Ym9iOmh1bnRlcjI=").ends_with("\r\n\r\nbob"));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(BasicAuthMiddleware::new(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
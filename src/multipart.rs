@@ -0,0 +1,319 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/* # multipart/form-data bodies for outbound requests!
+Each part is written as
+--<boundary>\r\n
+Content-Disposition: form-data; name="<name>"[; filename="<filename>"]\r\n
+[Content-Type: <mime>\r\n]
+\r\n
+<data>\r\n
+and the body ends with --<boundary>--\r\n
+The boundary is ----FormBoundary + 16 random bytes in hex (like browsers do),
+so it won't show up inside the data by accident.
+Send the body with Content-Type: multipart/form-data; boundary=<boundary>.
+Names, filenames and content types end up inside header lines, so build()
+refuses any of them with a control character in it (a CR/LF would start a new
+header, or a new part). Quotes in names and filenames are sent as %22, like
+browsers do.
+parse() reads such a body back into FormParts, e.g. for tests or for handlers
+that receive uploads: find the boundary with boundary_from_content_type. */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultipartError {
+    // Which field ("name", "filename" or "content type") and its value.
+    ControlCharacter(&'static str, String),
+    Malformed(&'static str),
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultipartError::ControlCharacter(field, value) => write!(f, "control character in {}: {:?}", field, value),
+            MultipartError::Malformed(reason) => write!(f, "malformed multipart body: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+// One part of a parsed body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormPart {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+pub struct MultipartFormBuilder {
+    parts: Vec<Part>,
+}
+
+struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    data: Vec<u8>,
+}
+
+impl MultipartFormBuilder {
+    pub fn new() -> MultipartFormBuilder {
+        MultipartFormBuilder { parts: Vec::new() }
+    }
+
+    pub fn add_text_field(&mut self, name: &str, value: &str) -> &mut Self {
+        self.parts.push(Part {
+            name: name.to_string(),
+            filename: None,
+            content_type: None,
+            data: value.as_bytes().to_vec(),
+        });
+        self
+    }
+
+    pub fn add_file_field(&mut self, name: &str, filename: &str, mime_type: &str, data: &[u8]) -> &mut Self {
+        self.parts.push(Part {
+            name: name.to_string(),
+            filename: Some(filename.to_string()),
+            content_type: Some(mime_type.to_string()),
+            data: data.to_vec(),
+        });
+        self
+    }
+
+    // Returns the serialized body and the boundary for the Content-Type header.
+    pub fn build(&self) -> Result<(Vec<u8>, String), MultipartError> {
+        for part in &self.parts {
+            check("name", &part.name)?;
+            if let Some(filename) = &part.filename {
+                check("filename", filename)?;
+            }
+            if let Some(content_type) = &part.content_type {
+                check("content type", content_type)?;
+            }
+        }
+
+        let boundary = random_boundary();
+        let mut body = Vec::new();
+
+        for part in &self.parts {
+            body.extend_from_slice(b"--");
+            body.extend_from_slice(boundary.as_bytes());
+            body.extend_from_slice(b"\r\n");
+
+            body.extend_from_slice(b"Content-Disposition: form-data; name=\"");
+            body.extend_from_slice(quote(&part.name).as_bytes());
+            body.push(b'"');
+            if let Some(filename) = &part.filename {
+                body.extend_from_slice(b"; filename=\"");
+                body.extend_from_slice(quote(filename).as_bytes());
+                body.push(b'"');
+            }
+            body.extend_from_slice(b"\r\n");
+
+            if let Some(content_type) = &part.content_type {
+                body.extend_from_slice(b"Content-Type: ");
+                body.extend_from_slice(content_type.as_bytes());
+                body.extend_from_slice(b"\r\n");
+            }
+
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(&part.data);
+            body.extend_from_slice(b"\r\n");
+        }
+
+        body.extend_from_slice(b"--");
+        body.extend_from_slice(boundary.as_bytes());
+        body.extend_from_slice(b"--\r\n");
+
+        Ok((body, boundary))
+    }
+}
+
+impl Default for MultipartFormBuilder {
+    fn default() -> MultipartFormBuilder {
+        MultipartFormBuilder::new()
+    }
+}
+
+fn check(field: &'static str, value: &str) -> Result<(), MultipartError> {
+    if value.chars().any(char::is_control) {
+        return Err(MultipartError::ControlCharacter(field, value.to_string()));
+    }
+    Ok(())
+}
+
+// Browsers percent-encode quotes in names and filenames so they can't break out of the quoted-string.
+fn quote(value: &str) -> String {
+    value.replace('"', "%22")
+}
+
+// "multipart/form-data; boundary=----FormBoundary1234" => "----FormBoundary1234"
+pub fn boundary_from_content_type(content_type: &str) -> Option<&str> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .split(';')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty())
+}
+
+// # Reading a body back!
+// Anything before the first delimiter (the "preamble") is skipped, then each part is
+// headers, a blank line, and data up to the CRLF in front of the next delimiter.
+// "--<boundary>--" ends the body. Names and filenames are returned as sent (%22 stays %22).
+pub fn parse(body: &[u8], boundary: &str) -> Result<Vec<FormPart>, MultipartError> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let next_delimiter = [b"\r\n".as_slice(), &delimiter].concat();
+
+    let start = find(body, &delimiter).ok_or(MultipartError::Malformed("no boundary"))?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut parts = Vec::new();
+
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or(MultipartError::Malformed("no line break after the boundary"))?;
+
+        let head_end = find(rest, b"\r\n\r\n").ok_or(MultipartError::Malformed("unterminated part headers"))?;
+        let head = std::str::from_utf8(&rest[..head_end]).map_err(|_| MultipartError::Malformed("part headers are not UTF-8"))?;
+        rest = &rest[head_end + 4..];
+
+        let data_end = find(rest, &next_delimiter).ok_or(MultipartError::Malformed("no closing boundary"))?;
+        let mut part = parse_part_head(head)?;
+        part.data = rest[..data_end].to_vec();
+        parts.push(part);
+        rest = &rest[data_end + next_delimiter.len()..];
+    }
+}
+
+fn parse_part_head(head: &str) -> Result<FormPart, MultipartError> {
+    let mut part = FormPart { name: String::new(), filename: None, content_type: None, data: Vec::new() };
+    let mut has_name = false;
+
+    for line in head.split("\r\n") {
+        let (key, value) = line.split_once(':').ok_or(MultipartError::Malformed("malformed part header"))?;
+        let value = value.trim();
+        if key.eq_ignore_ascii_case("Content-Type") {
+            part.content_type = Some(value.to_string());
+        } else if key.eq_ignore_ascii_case("Content-Disposition") {
+            // form-data; name="..."[; filename="..."]; the quoted values can't contain '"' (see quote).
+            for param in value.split(';').skip(1) {
+                match param.split_once('=') {
+                    Some((key, value)) if key.trim() == "name" => {
+                        part.name = value.trim().trim_matches('"').to_string();
+                        has_name = true;
+                    },
+                    Some((key, value)) if key.trim() == "filename" => {
+                        part.filename = Some(value.trim().trim_matches('"').to_string());
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+
+    if !has_name {
+        return Err(MultipartError::Malformed("part without a name"));
+    }
+    Ok(part)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// No rand crate: RandomState is seeded from the OS, which is plenty for a boundary.
+fn random_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+
+    let mut boundary = String::from("----FormBoundary");
+    for i in 0..2u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_u64(i);
+        boundary.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    boundary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_then_parse_round_trips() {
+        let mut form = MultipartFormBuilder::new();
+        form.add_text_field("title", "Hello, world")
+            .add_file_field("upload", "notes.txt", "text/plain", b"line 1\r\n--not a boundary\r\n")
+            .add_file_field("image", "pixel.png", "image/png", &[0x89, b'P', b'N', b'G', 0, 0xff]);
+        let (body, boundary) = form.build().unwrap();
+
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        assert_eq!(boundary_from_content_type(&content_type), Some(boundary.as_str()));
+
+        let parts = parse(&body, &boundary).unwrap();
+        assert_eq!(parts, vec![
+            FormPart { name: "title".into(), filename: None, content_type: None, data: b"Hello, world".to_vec() },
+            FormPart {
+                name: "upload".into(),
+                filename: Some("notes.txt".into()),
+                content_type: Some("text/plain".into()),
+                data: b"line 1\r\n--not a boundary\r\n".to_vec(),
+            },
+            FormPart {
+                name: "image".into(),
+                filename: Some("pixel.png".into()),
+                content_type: Some("image/png".into()),
+                data: vec![0x89, b'P', b'N', b'G', 0, 0xff],
+            },
+        ]);
+    }
+
+    #[test]
+    fn quotes_are_percent_encoded() {
+        let mut form = MultipartFormBuilder::new();
+        form.add_file_field("a\"b", "x\".txt", "text/plain", b"");
+        let (body, boundary) = form.build().unwrap();
+        let parts = parse(&body, &boundary).unwrap();
+        assert_eq!(parts[0].name, "a%22b");
+        assert_eq!(parts[0].filename.as_deref(), Some("x%22.txt"));
+    }
+
+    #[test]
+    fn control_characters_are_rejected() {
+        let mut form = MultipartFormBuilder::new();
+        form.add_file_field("file", "a.txt", "text/plain\r\nX-Injected: 1", b"");
+        assert!(matches!(form.build(), Err(MultipartError::ControlCharacter("content type", _))));
+
+        let mut form = MultipartFormBuilder::new();
+        form.add_text_field("na\nme", "value");
+        assert!(matches!(form.build(), Err(MultipartError::ControlCharacter("name", _))));
+
+        let mut form = MultipartFormBuilder::new();
+        form.add_file_field("file", "a\0.txt", "text/plain", b"");
+        assert!(matches!(form.build(), Err(MultipartError::ControlCharacter("filename", _))));
+    }
+
+    #[test]
+    fn truncated_bodies_are_malformed() {
+        let mut form = MultipartFormBuilder::new();
+        form.add_text_field("title", "Hello");
+        let (body, boundary) = form.build().unwrap();
+        let truncated = &body[..body.len() - boundary.len() - 8];
+        assert!(matches!(parse(truncated, &boundary), Err(MultipartError::Malformed(_))));
+    }
+}
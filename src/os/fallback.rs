@@ -0,0 +1,32 @@
+use std::fs::File;
+use std::io::{self, prelude::*, SeekFrom};
+use std::net::TcpStream;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// The portable read/write loop: file => buffer => socket.
+pub fn send_file(file: &File, socket: &TcpStream, offset: u64, count: u64) -> io::Result<u64> {
+    // &File and &TcpStream implement Read/Write/Seek, so we don't need ownership.
+    let mut file = file;
+    let mut socket = socket;
+
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut sent: u64 = 0;
+
+    while sent < count {
+        let want = (count - sent).min(BUFFER_SIZE as u64) as usize;
+        let n = match file.read(&mut buffer[..want]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        socket.write_all(&buffer[..n])?;
+        sent += n as u64;
+    }
+
+    Ok(sent)
+}
@@ -0,0 +1,256 @@
+use std::fs::File;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::os::raw::{c_int, c_long, c_short, c_ulong, c_void};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use super::TcpInfo;
+
+// # Zero-copy file serving with sendfile(2)!
+// read + write copies every byte twice through user space (kernel => buffer => kernel).
+// sendfile moves the bytes from the page cache straight into the socket.
+// std already links against libc, so we only need to declare the function.
+
+// off_t matches the width of `long` for the plain (non-LFS) sendfile symbol.
+#[allow(non_camel_case_types)]
+type off_t = c_long;
+
+extern "C" {
+    fn sendfile(out_fd: c_int, in_fd: c_int, offset: *mut off_t, count: usize) -> isize;
+    fn listen(sockfd: c_int, backlog: c_int) -> c_int;
+    fn setsockopt(sockfd: c_int, level: c_int, name: c_int, value: *const c_void, len: u32) -> c_int;
+    fn getsockopt(sockfd: c_int, level: c_int, name: c_int, value: *mut c_void, len: *mut u32) -> c_int;
+    fn poll(fds: *mut pollfd, nfds: c_ulong, timeout: c_int) -> c_int;
+    fn kill(pid: c_int, sig: c_int) -> c_int;
+    fn signal(signum: c_int, handler: usize) -> usize;
+    fn geteuid() -> u32;
+}
+
+// struct pollfd from <poll.h>.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct pollfd {
+    fd: c_int,
+    events: c_short,
+    revents: c_short,
+}
+
+const POLLIN: c_short = 0x001;
+
+// From <sys/socket.h> and <netinet/tcp.h>; the same on every Linux architecture.
+const SOL_SOCKET: c_int = 1;
+const SO_KEEPALIVE: c_int = 9;
+const IPPROTO_TCP: c_int = 6;
+const TCP_KEEPIDLE: c_int = 4;
+const TCP_KEEPINTVL: c_int = 5;
+const TCP_KEEPCNT: c_int = 6;
+const TCP_INFO: c_int = 11;
+
+// From <signal.h>.
+const SIGKILL: c_int = 9;
+const SIGTERM: c_int = 15;
+const SIG_ERR: usize = usize::MAX;
+
+// The kernel caps a single sendfile call at a bit under 2 GiB anyway.
+const MAX_CHUNK: u64 = 0x7fff_f000;
+
+pub fn send_file(file: &File, socket: &TcpStream, offset: u64, count: u64) -> io::Result<u64> {
+    let mut off = off_t::try_from(offset)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offset too large for sendfile"))?;
+    let mut sent: u64 = 0;
+
+    while sent < count {
+        let chunk = (count - sent).min(MAX_CHUNK) as usize;
+
+        // SAFETY: both descriptors are kept open by the borrowed File and TcpStream
+        // for the duration of the call, and `off` is a valid, exclusively borrowed off_t.
+        let n = unsafe { sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut off, chunk) };
+
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if n == 0 {
+            // End of file before `count` bytes.
+            break;
+        }
+        sent += n as u64;
+    }
+
+    Ok(sent)
+}
+
+// # A bigger accept backlog!
+// TcpListener::bind already called listen(fd, 128); calling it again on the
+// listening socket just changes the queue length. The kernel silently caps it
+// at net.core.somaxconn.
+pub fn set_backlog(listener: &TcpListener, backlog: u32) -> io::Result<()> {
+    let backlog = c_int::try_from(backlog).unwrap_or(c_int::MAX);
+
+    // SAFETY: the descriptor is kept open by the borrowed TcpListener.
+    if unsafe { listen(listener.as_raw_fd(), backlog) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// # TCP keepalive probes!
+// A client whose network vanishes never sends a FIN, so without probes the
+// connection looks open until some read or write times out. With SO_KEEPALIVE the
+// kernel sends a probe after `idle` without traffic, then every `interval`, and
+// resets the connection after `count` unanswered ones. Both times are whole seconds.
+pub fn set_tcp_keepalive(stream: &TcpStream, idle: Duration, interval: Duration, count: u32) -> io::Result<()> {
+    let seconds = |d: Duration| c_int::try_from(d.as_secs().max(1)).unwrap_or(c_int::MAX);
+    let count = c_int::try_from(count.max(1)).unwrap_or(c_int::MAX);
+
+    set_int(stream, SOL_SOCKET, SO_KEEPALIVE, 1)?;
+    set_int(stream, IPPROTO_TCP, TCP_KEEPIDLE, seconds(idle))?;
+    set_int(stream, IPPROTO_TCP, TCP_KEEPINTVL, seconds(interval))?;
+    set_int(stream, IPPROTO_TCP, TCP_KEEPCNT, count)
+}
+
+fn set_int(stream: &TcpStream, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    // SAFETY: the descriptor is kept open by the borrowed TcpStream, and the
+    // pointer and length describe `value`, which outlives the call.
+    let result = unsafe {
+        setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const c_int as *const c_void,
+            std::mem::size_of::<c_int>() as u32,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// # What the kernel knows about a connection: getsockopt(TCP_INFO)!
+// struct tcp_info from <linux/tcp.h>, as far as surff reads it; the kernel copies
+// no more than the length it's given, and newer kernels only add fields at the end.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Default)]
+struct tcp_info {
+    state: u8,
+    ca_state: u8,
+    retransmits: u8,
+    probes: u8,
+    backoff: u8,
+    options: u8,
+    // snd_wscale in the low 4 bits, rcv_wscale in the high ones.
+    wscale: u8,
+    flags: u8,
+    rto: u32,
+    ato: u32,
+    snd_mss: u32,
+    rcv_mss: u32,
+    unacked: u32,
+    sacked: u32,
+    lost: u32,
+    retrans: u32,
+    fackets: u32,
+    last_data_sent: u32,
+    last_ack_sent: u32,
+    last_data_recv: u32,
+    last_ack_recv: u32,
+    pmtu: u32,
+    rcv_ssthresh: u32,
+    rtt: u32,
+    rttvar: u32,
+}
+
+// tcpi_options bits.
+const TCPI_OPT_TIMESTAMPS: u8 = 1;
+const TCPI_OPT_SACK: u8 = 2;
+const TCPI_OPT_WSCALE: u8 = 4;
+const TCPI_OPT_ECN: u8 = 8;
+
+// The options are those the client's SYN asked for (and the kernel agreed to).
+pub fn tcp_info(stream: &TcpStream) -> io::Result<TcpInfo> {
+    let mut info = tcp_info::default();
+    let mut length = std::mem::size_of::<tcp_info>() as u32;
+    // SAFETY: the descriptor is kept open by the borrowed TcpStream, and the pointer
+    // and length describe `info`, which the kernel writes at most `length` bytes of.
+    let result = unsafe { getsockopt(stream.as_raw_fd(), IPPROTO_TCP, TCP_INFO, &mut info as *mut tcp_info as *mut c_void, &mut length) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(TcpInfo {
+        timestamps: info.options & TCPI_OPT_TIMESTAMPS != 0,
+        sack: info.options & TCPI_OPT_SACK != 0,
+        // the scale the client announced is the one applied to what we send it.
+        window_scale: (info.options & TCPI_OPT_WSCALE != 0).then_some(info.wscale & 0x0f),
+        ecn: info.options & TCPI_OPT_ECN != 0,
+        mss: info.snd_mss,
+        rtt: Duration::from_micros(info.rtt as u64),
+    })
+}
+
+// # Waiting on many sockets at once with poll(2)!
+// Returns, for each descriptor, whether reading it would not block: there's data,
+// the peer closed its side, or an error is pending (POLLHUP/POLLERR are reported
+// even though we only ask for POLLIN). All false when the timeout passes first.
+pub fn poll_readable(fds: &[RawFd], timeout: Duration) -> io::Result<Vec<bool>> {
+    let mut pollfds: Vec<pollfd> = fds.iter().map(|&fd| pollfd { fd, events: POLLIN, revents: 0 }).collect();
+    // Rounded up: a 0.3 ms timeout must not turn into a busy loop of poll(.., 0).
+    let millis = timeout.as_nanos().div_ceil(1_000_000);
+    let millis = c_int::try_from(millis).unwrap_or(c_int::MAX);
+
+    loop {
+        // SAFETY: the pointer and length describe `pollfds`, which outlives the call.
+        let n = unsafe { poll(pollfds.as_mut_ptr(), pollfds.len() as c_ulong, millis) };
+        if n >= 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    Ok(pollfds.iter().map(|p| p.revents != 0).collect())
+}
+
+// A negative pid means the whole process group: the program and everything it started.
+pub fn kill_process_group(group: u32) -> io::Result<()> {
+    let group = c_int::try_from(group).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not a process group id"))?;
+    // SAFETY: kill takes no pointers; the worst a wrong id does is fail with ESRCH.
+    if unsafe { kill(-group, SIGKILL) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// # SIGTERM: noted in a flag, for a thread to look at.
+// A signal handler may interrupt any code, so all it does is the one thing that's
+// safe there: a store to an atomic.
+static SIGTERM_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn note_sigterm(_signum: c_int) {
+    SIGTERM_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+pub fn watch_sigterm() -> io::Result<()> {
+    // SAFETY: note_sigterm has the signature of a sighandler_t and is async-signal-safe.
+    if unsafe { signal(SIGTERM, note_sigterm as extern "C" fn(c_int) as usize) } == SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn sigterm_received() -> bool {
+    SIGTERM_RECEIVED.load(Ordering::SeqCst)
+}
+
+pub fn running_as_root() -> bool {
+    // SAFETY: geteuid has no arguments and can't fail.
+    unsafe { geteuid() == 0 }
+}
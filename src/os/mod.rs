@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+// # Platform-specific fast paths.
+// send_file copies `count` bytes of `file`, starting at `offset`, to the socket
+// and returns how many bytes were sent (fewer than `count` if the file is shorter).
+// On Linux the kernel does the copy with sendfile(2); everywhere else we fall back
+// to a plain read/write loop through a user-space buffer.
+// set_backlog changes how many not-yet-accepted connections the kernel queues for
+// a listener (std always asks for 128). set_tcp_keepalive turns on TCP keepalive probes
+// for a connection. Both are only handled on Linux; elsewhere they're no-ops.
+// kill_process_group kills a process group (see cgi.rs) on Linux; elsewhere it
+// does nothing and only the process itself gets killed.
+// watch_sigterm makes SIGTERM set a flag (sigterm_received) instead of ending the
+// process, so it can shut down in its own time; elsewhere the flag never gets set.
+// running_as_root says whether the effective user is root on Linux; elsewhere
+// there's no telling (None).
+// tcp_info reads what the kernel knows about a connection (getsockopt TCP_INFO) on
+// Linux: the TCP options the client's SYN carried, its MSS, the round trip time.
+// Elsewhere it's an Unsupported error.
+
+#[cfg(target_os = "linux")]
+pub mod linux;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpInfo {
+    pub timestamps: bool,
+    pub sack: bool,
+    // None if the client didn't offer window scaling.
+    pub window_scale: Option<u8>,
+    pub ecn: bool,
+    pub mss: u32,
+    pub rtt: Duration,
+}
+
+mod fallback;
+
+#[cfg(target_os = "linux")]
+pub fn send_file(file: &File, socket: &TcpStream, offset: u64, count: u64) -> io::Result<u64> {
+    linux::send_file(file, socket, offset, count)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_file(file: &File, socket: &TcpStream, offset: u64, count: u64) -> io::Result<u64> {
+    fallback::send_file(file, socket, offset, count)
+}
+
+// Always available, e.g. for comparing against the zero-copy path.
+pub use fallback::send_file as send_file_buffered;
+
+#[cfg(target_os = "linux")]
+pub fn set_backlog(listener: &TcpListener, backlog: u32) -> io::Result<()> {
+    linux::set_backlog(listener, backlog)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_backlog(_listener: &TcpListener, _backlog: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_tcp_keepalive(stream: &TcpStream, idle: Duration, interval: Duration, count: u32) -> io::Result<()> {
+    linux::set_tcp_keepalive(stream, idle, interval, count)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_keepalive(_stream: &TcpStream, _idle: Duration, _interval: Duration, _count: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn kill_process_group(group: u32) -> io::Result<()> {
+    linux::kill_process_group(group)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn kill_process_group(_group: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn watch_sigterm() -> io::Result<()> {
+    linux::watch_sigterm()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn watch_sigterm() -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn sigterm_received() -> bool {
+    linux::sigterm_received()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sigterm_received() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+pub fn running_as_root() -> Option<bool> {
+    Some(linux::running_as_root())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn running_as_root() -> Option<bool> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+pub fn tcp_info(stream: &TcpStream) -> io::Result<TcpInfo> {
+    linux::tcp_info(stream)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_info(_stream: &TcpStream) -> io::Result<TcpInfo> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "TCP_INFO is only read on Linux"))
+}
@@ -0,0 +1,646 @@
+use std::io::{self, prelude::*, BufReader};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::{HttpClient, StreamingResponse};
+use crate::http::{HttpVersion, Method, Request, ResponseBuilder, StatusCode};
+use crate::middleware::encode_path;
+use crate::router::{Response, UpgradeHandler};
+use crate::server::JsonLinesLogger;
+use crate::websocket::{self, Opcode, WsClient, WsFrame};
+
+/* # A reverse proxy: requests passed on to an upstream server, its answers passed back!
+let proxy = ProxyHandler::new("127.0.0.1:9000".parse()?).strip_prefix("/api");
+router.route(Method::Get, "/api", move |request, response| proxy.handle(request, response));
+GET /api/users?page=2 => GET /users?page=2 on 127.0.0.1:9000, and its answer back.
+1. The request goes on as it came (method, path, query, headers and body), without the
+hop-by-hop headers (Connection and the headers it names, Keep-Alive, TE, Trailer,
+Transfer-Encoding, Upgrade, Proxy-*), and with X-Forwarded-For (the client appended),
+X-Forwarded-Host and X-Forwarded-Proto.
+2. The answer is streamed through, never held whole (see HttpClient::open): a body with a
+Content-Length keeps it, and is copied 8 KiB at a time; one without goes out chunked.
+HTTP/1.0 clients can't take chunks, so theirs is read whole first (up to the limit).
+3. max_response_body_bytes (100 MiB unless changed) caps what an upstream may send:
+a Content-Length over it is a 502 before anything is sent. A body without a length that
+goes past it midway is cut off: its head has gone out by then, so the connection is
+closed without the last chunk, which tells the client the body is incomplete.
+4. An upstream that can't be reached, answers with something that isn't HTTP, or with a
+status surff doesn't know, is a 502 too.
+5. With more than one upstream, ProxyHandler::with_pool takes them turn about (see
+UpstreamPool below), and skips the ones that have been failing.
+6. A ProxyHandler is an UpgradeHandler too, for WebSockets:
+router.on_upgrade("websocket", proxy.clone());
+The client gets its 101 from surff, then WsClient (see websocket.rs) opens the same path
+on the upstream, and two threads pass frames along, one each way, unmasked from the client
+and masked again for the upstream (servers must never mask, clients always). A Close, or
+either side going away, ends both: after a Close, the answering Close has 5 seconds. An upstream that won't take the WebSocket can only
+be answered by closing the connection: the 101 has gone out by then. So the handshake
+doesn't pass Sec-WebSocket-Extensions on, nor answer with one: the frames go through as
+they are, and nothing in between could compress them. */
+
+const DEFAULT_MAX_RESPONSE_BODY_BYTES: u64 = 100 * 1024 * 1024;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const COPY_BLOCK_SIZE: usize = 8 * 1024;
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const HOP_BY_HOP: &[&str] = &["connection", "keep-alive", "te", "trailer", "transfer-encoding", "upgrade"];
+
+#[derive(Debug, Clone)]
+enum Upstreams {
+    One(SocketAddr),
+    Pool(Arc<UpstreamPool>),
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyHandler {
+    upstreams: Upstreams,
+    prefix: Option<String>,
+    timeout: Duration,
+    max_response_body_bytes: u64,
+}
+
+// Whether a header stays between us and one side, rather than going on to the other.
+// `connection`: the Connection header's value, which can name more of them.
+fn is_hop_by_hop(name: &str, connection: Option<&str>) -> bool {
+    let name = name.to_ascii_lowercase();
+    HOP_BY_HOP.contains(&name.as_str())
+        || name.starts_with("proxy-")
+        || name == "host"
+        || name == "content-length"
+        || connection.is_some_and(|connection| connection.split(',').any(|token| token.trim().eq_ignore_ascii_case(&name)))
+}
+
+fn bad_gateway(response: &mut Response, reason: &str) -> io::Result<()> {
+    eprintln!("Proxy: {}", reason);
+    response.send(
+        ResponseBuilder::new(StatusCode::BadGateway)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body_str(&format!("{}\n", StatusCode::BadGateway)),
+    )
+}
+
+impl ProxyHandler {
+    pub fn new(upstream: SocketAddr) -> ProxyHandler {
+        ProxyHandler::with_upstreams(Upstreams::One(upstream))
+    }
+
+    // Each request to the upstream the pool selects, and how it went reported back.
+    pub fn with_pool(pool: Arc<UpstreamPool>) -> ProxyHandler {
+        ProxyHandler::with_upstreams(Upstreams::Pool(pool))
+    }
+
+    fn with_upstreams(upstreams: Upstreams) -> ProxyHandler {
+        ProxyHandler {
+            upstreams,
+            prefix: None,
+            timeout: DEFAULT_TIMEOUT,
+            max_response_body_bytes: DEFAULT_MAX_RESPONSE_BODY_BYTES,
+        }
+    }
+
+    // The part of the request path that belongs to the route, not to the upstream.
+    pub fn strip_prefix(mut self, prefix: &str) -> ProxyHandler {
+        self.prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    // For connecting, and for each read or write on the upstream connection.
+    pub fn timeout(mut self, timeout: Duration) -> ProxyHandler {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn max_response_body_bytes(mut self, bytes: u64) -> ProxyHandler {
+        self.max_response_body_bytes = bytes;
+        self
+    }
+
+    // GET /api/users?page=2 => http://127.0.0.1:9000/users?page=2
+    fn upstream_url(&self, upstream: SocketAddr, request: &Request) -> String {
+        let path = match &self.prefix {
+            Some(prefix) => request.path.strip_prefix(prefix.as_str()).unwrap_or(&request.path),
+            None => &request.path,
+        };
+        let path = if path.starts_with('/') { encode_path(path) } else { format!("/{}", encode_path(path)) };
+        match &request.query {
+            Some(query) => format!("http://{}{}?{}", upstream, path, query),
+            None => format!("http://{}{}", upstream, path),
+        }
+    }
+
+    fn upstream_headers(request: &Request, client: Option<SocketAddr>) -> Vec<(String, String)> {
+        let connection = request.header("Connection");
+        let mut headers: Vec<(String, String)> = request
+            .headers
+            .iter()
+            .filter(|(name, _)| !is_hop_by_hop(name, connection) && !name.eq_ignore_ascii_case("X-Forwarded-For"))
+            .cloned()
+            .collect();
+        let mut forwarded_for: Vec<String> = request
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("X-Forwarded-For"))
+            .map(|(_, value)| value.clone())
+            .collect();
+        if let Some(client) = client {
+            forwarded_for.push(client.ip().to_string());
+        }
+        if !forwarded_for.is_empty() {
+            headers.push(("X-Forwarded-For".to_string(), forwarded_for.join(", ")));
+        }
+        if let Some(host) = request.header("Host") {
+            headers.push(("X-Forwarded-Host".to_string(), host.to_string()));
+        }
+        headers.push(("X-Forwarded-Proto".to_string(), "http".to_string()));
+        headers
+    }
+
+    pub fn handle(&self, request: &Request, response: &mut Response) -> io::Result<()> {
+        let addr = match &self.upstreams {
+            Upstreams::One(addr) => *addr,
+            Upstreams::Pool(pool) => match pool.select() {
+                Some(addr) => addr,
+                None => return bad_gateway(response, "no healthy upstream"),
+            },
+        };
+        let report = |failed: bool| {
+            if let Upstreams::Pool(pool) = &self.upstreams {
+                if failed {
+                    pool.report_failure(addr);
+                } else {
+                    pool.report_success(addr);
+                }
+            }
+        };
+        let client = HttpClient::new()
+            .connect_timeout(self.timeout)
+            .timeout(self.timeout)
+            .max_response_size(usize::try_from(self.max_response_body_bytes).unwrap_or(usize::MAX));
+        let headers = ProxyHandler::upstream_headers(request, response.peer_addr().ok());
+        let mut upstream = match client.open(request.method.as_str(), &self.upstream_url(addr, request), &headers, &request.body) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                report(true);
+                return bad_gateway(response, &format!("{}: {}", addr, e));
+            }
+        };
+        report(upstream.status >= 500);
+        let Some(status) = StatusCode::from_code(upstream.status) else {
+            return bad_gateway(response, &format!("{} answered with status {}", addr, upstream.status));
+        };
+        if upstream.content_length.is_some_and(|length| length > self.max_response_body_bytes) {
+            return bad_gateway(response, &format!("{} sent more than {} bytes", addr, self.max_response_body_bytes));
+        }
+
+        let mut head = ResponseBuilder::new(status);
+        let connection = upstream.header("Connection").map(str::to_string);
+        for (name, value) in &upstream.headers {
+            if !is_hop_by_hop(name, connection.as_deref()) {
+                head.header(name, value);
+            }
+        }
+
+        let no_body = request.method == Method::Head || matches!(upstream.status, 204 | 304);
+        match upstream.content_length {
+            _ if no_body => {
+                // for HEAD, the length a GET would have had.
+                if let Some(length) = upstream.header("Content-Length").map(str::to_string) {
+                    head.set_header("Content-Length", &length);
+                }
+                response.send(&mut head)
+            }
+            Some(length) => {
+                let mut body = response.start_body(head, length)?;
+                copy(&mut upstream, &mut body)?;
+                body.finish()
+            }
+            None if request.version == HttpVersion::Http11 => {
+                let mut body = response.start_chunked(head)?;
+                copy(&mut upstream, &mut body)?;
+                body.finish()
+            }
+            None => {
+                let mut whole = Vec::new();
+                if let Err(e) = upstream.read_to_end(&mut whole) {
+                    return bad_gateway(response, &format!("{}: {}", addr, e));
+                }
+                response.send(head.body_bytes(whole))
+            }
+        }
+    }
+}
+
+impl UpgradeHandler for ProxyHandler {
+    fn response_headers(&self, request: &Request) -> Vec<(String, String)> {
+        match request.header("Sec-WebSocket-Key") {
+            Some(key) => vec![("Sec-WebSocket-Accept".to_string(), websocket::accept_key(key))],
+            None => Vec::new(),
+        }
+    }
+
+    fn on_upgrade(&self, request: &Request, stream: TcpStream) -> io::Result<()> {
+        let addr = match &self.upstreams {
+            Upstreams::One(addr) => *addr,
+            Upstreams::Pool(pool) => pool.select().ok_or_else(|| io::Error::other("proxy: no healthy upstream"))?,
+        };
+        let headers: Vec<(String, String)> = ProxyHandler::upstream_headers(request, stream.peer_addr().ok())
+            .into_iter()
+            .filter(|(name, _)| !name.to_ascii_lowercase().starts_with("sec-websocket-") || name.eq_ignore_ascii_case("Sec-WebSocket-Protocol"))
+            .collect();
+        let url = self.upstream_url(addr, request).replacen("http://", "ws://", 1);
+        let upstream = WsClient::connect(&url, &headers);
+        if let Upstreams::Pool(pool) = &self.upstreams {
+            if upstream.is_ok() {
+                pool.report_success(addr);
+            } else {
+                pool.report_failure(addr);
+            }
+        }
+        let upstream = upstream.map_err(|e| io::Error::other(format!("proxy: {}: {}", addr, e)))?;
+
+        let mut from_client = BufReader::new(stream.try_clone()?);
+        let mut to_client = stream.try_clone()?;
+        // After a Close one way, the other way's Close in answer, if it comes soon.
+        // After the end of a stream, nothing more: the other side is shut down at once.
+        thread::scope(|scope| {
+            let upstream = &upstream;
+            scope.spawn(move || {
+                let closed = forward(|| upstream.recv(), |frame| frame.write_to(&mut to_client, None));
+                match closed {
+                    Ok(true) => to_client.set_read_timeout(Some(CLOSE_TIMEOUT)),
+                    _ => to_client.shutdown(Shutdown::Both),
+                }
+            });
+            let closed = forward(|| WsFrame::read_from(&mut from_client, true), |frame| upstream.send(frame));
+            let _ = match closed {
+                Ok(true) => upstream.set_read_timeout(Some(CLOSE_TIMEOUT)),
+                _ => upstream.shutdown(),
+            };
+            closed.map(|_| ())
+        })?;
+        let _ = stream.shutdown(Shutdown::Both);
+        Ok(())
+    }
+}
+
+// Frames from `recv` to `send`: Ok(true) once a Close has gone, Ok(false) at the end
+// of the stream (or when it's been quiet past a timeout).
+fn forward(mut recv: impl FnMut() -> io::Result<WsFrame>, mut send: impl FnMut(&WsFrame) -> io::Result<()>) -> io::Result<bool> {
+    loop {
+        let frame = match recv() {
+            Ok(frame) => frame,
+            Err(e) if matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::NotConnected | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) => {
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+        send(&frame)?;
+        if frame.opcode == Opcode::Close {
+            return Ok(true);
+        }
+    }
+}
+
+// Errors reading from the upstream are errors of the response: the head is out already,
+// and all that's left to do is to close the connection.
+fn copy(upstream: &mut StreamingResponse, to: &mut impl Write) -> io::Result<()> {
+    let mut block = vec![0; COPY_BLOCK_SIZE];
+    loop {
+        let read = match upstream.read(&mut block) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(io::Error::new(e.kind(), format!("upstream: {}", e))),
+        };
+        to.write_all(&block[..read])?;
+    }
+}
+
+/* # Several upstreams, and which of them to use!
+let pool = UpstreamPool::new(vec![a, b, c]).failure_threshold(3).quarantine(Duration::from_secs(10));
+let proxy = ProxyHandler::with_pool(Arc::new(pool));
+1. select takes the upstreams in turn (round-robin), skipping the unhealthy ones, and is
+None (a 502) when they're all unhealthy.
+2. The health checks are passive, as nginx's and HAProxy's are: nothing is sent just to
+see. failure_threshold (3) failures in a row, a connection that failed or a 5xx answer
+(report_failure), make an upstream Unhealthy, and select leaves it out for the quarantine
+(10 seconds). A success (report_success) in between starts the count again.
+3. After the quarantine, select hands out the upstream once, as a probe: a real request,
+whose success makes it Healthy again, and whose failure starts another quarantine.
+Until one of those is reported, the next probe waits a quarantine too.
+4. Each change of health is logged at warn level: to log_to's JsonLinesLogger if there
+is one, to stderr if not. */
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+const DEFAULT_QUARANTINE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamHealth {
+    Healthy,
+    Unhealthy,
+}
+
+#[derive(Debug, Default)]
+struct UpstreamState {
+    consecutive_failures: u32,
+    // Some while Unhealthy: when select may hand it out next, as a probe.
+    unhealthy_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    addr: SocketAddr,
+    state: Mutex<UpstreamState>,
+}
+
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    failure_threshold: u32,
+    quarantine: Duration,
+    log: Option<Arc<JsonLinesLogger>>,
+}
+
+impl UpstreamPool {
+    pub fn new(addrs: Vec<SocketAddr>) -> UpstreamPool {
+        UpstreamPool {
+            upstreams: addrs.into_iter().map(|addr| Upstream { addr, state: Mutex::default() }).collect(),
+            next: AtomicUsize::new(0),
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            quarantine: DEFAULT_QUARANTINE,
+            log: None,
+        }
+    }
+
+    // Failures in a row that make an upstream Unhealthy (at least 1).
+    pub fn failure_threshold(mut self, failures: u32) -> UpstreamPool {
+        self.failure_threshold = failures.max(1);
+        self
+    }
+
+    pub fn quarantine(mut self, quarantine: Duration) -> UpstreamPool {
+        self.quarantine = quarantine;
+        self
+    }
+
+    // Changes of health go to `logger` instead of stderr.
+    pub fn log_to(mut self, logger: Arc<JsonLinesLogger>) -> UpstreamPool {
+        self.log = Some(logger);
+        self
+    }
+
+    pub fn select(&self) -> Option<SocketAddr> {
+        let now = Instant::now();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..self.upstreams.len() {
+            let upstream = &self.upstreams[(start + offset) % self.upstreams.len()];
+            let mut state = upstream.lock();
+            match state.unhealthy_until {
+                None => return Some(upstream.addr),
+                Some(until) if now >= until => {
+                    state.unhealthy_until = Some(now + self.quarantine);
+                    return Some(upstream.addr);
+                }
+                Some(_) => {}
+            }
+        }
+        None
+    }
+
+    pub fn report_success(&self, addr: SocketAddr) {
+        let Some(upstream) = self.find(addr) else { return };
+        let mut state = upstream.lock();
+        state.consecutive_failures = 0;
+        if state.unhealthy_until.take().is_some() {
+            self.warn(addr, UpstreamHealth::Healthy, "upstream is healthy again");
+        }
+    }
+
+    pub fn report_failure(&self, addr: SocketAddr) {
+        let Some(upstream) = self.find(addr) else { return };
+        let mut state = upstream.lock();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.unhealthy_until.is_some() {
+            // a probe that failed: still Unhealthy, for another quarantine.
+            state.unhealthy_until = Some(Instant::now() + self.quarantine);
+        } else if state.consecutive_failures >= self.failure_threshold {
+            state.unhealthy_until = Some(Instant::now() + self.quarantine);
+            let message = format!("upstream is unhealthy after {} failures in a row", state.consecutive_failures);
+            self.warn(addr, UpstreamHealth::Unhealthy, &message);
+        }
+    }
+
+    // None for an address that isn't in the pool.
+    pub fn health(&self, addr: SocketAddr) -> Option<UpstreamHealth> {
+        let upstream = self.find(addr)?;
+        Some(if upstream.lock().unhealthy_until.is_some() { UpstreamHealth::Unhealthy } else { UpstreamHealth::Healthy })
+    }
+
+    fn find(&self, addr: SocketAddr) -> Option<&Upstream> {
+        self.upstreams.iter().find(|upstream| upstream.addr == addr)
+    }
+
+    fn warn(&self, addr: SocketAddr, health: UpstreamHealth, message: &str) {
+        let health = format!("{:?}", health);
+        let logged = match &self.log {
+            Some(logger) => logger.log("warn", "surff::proxy", message, &[("upstream", &addr.to_string()), ("health", &health)]),
+            None => Err(io::Error::other("no log file")),
+        };
+        if logged.is_err() {
+            eprintln!("Warning: {} ({}): {}", addr, health, message);
+        }
+    }
+}
+
+impl Upstream {
+    fn lock(&self) -> std::sync::MutexGuard<'_, UpstreamState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{self, Router};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // An upstream that answers one connection with `answer`, and sends back what it was asked.
+    fn upstream(answer: &'static [u8]) -> (SocketAddr, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let read = stream.read(&mut buf).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            let _ = stream.write_all(answer);
+            String::from_utf8_lossy(&request).into_owned()
+        });
+        (addr, handle)
+    }
+
+    fn proxy_router(proxy: ProxyHandler) -> Router {
+        let mut router = Router::new();
+        router.route(Method::Get, "/api", move |request, response| proxy.handle(request, response));
+        router
+    }
+
+    #[test]
+    fn passes_requests_on_and_answers_back() {
+        let (addr, asked) = upstream(b"HTTP/1.1 201 Created\r\nContent-Length: 5\r\nX-Upstream: yes\r\nConnection: close\r\n\r\nhello");
+        let router = proxy_router(ProxyHandler::new(addr).strip_prefix("/api"));
+        let response = router::exchange(
+            &router,
+            "GET /api/a%20b?page=2 HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, X-Secret\r\nX-Secret: 1\r\nAccept: text/plain\r\nX-Forwarded-For: 192.0.2.1\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert!(response.contains("\r\nX-Upstream: yes\r\n") && !response.contains("Connection: close"), "{}", response);
+        assert!(response.ends_with("\r\nContent-Length: 5\r\n\r\nhello"), "{}", response);
+
+        let asked = asked.join().unwrap();
+        assert!(asked.starts_with(&format!("GET /a%20b?page=2 HTTP/1.1\r\nHost: {}\r\n", addr)), "{}", asked);
+        assert!(asked.contains("\r\nAccept: text/plain\r\n"), "{}", asked);
+        assert!(asked.contains("\r\nX-Forwarded-For: 192.0.2.1, 127.0.0.1\r\n"), "{}", asked);
+        assert!(asked.contains("\r\nX-Forwarded-Host: example.com\r\n"), "{}", asked);
+        assert!(!asked.contains("X-Secret") && !asked.contains("keep-alive"), "{}", asked);
+    }
+
+    #[test]
+    fn streams_chunked_answers_and_caps_their_size() {
+        let (addr, _) = upstream(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n");
+        let response = router::exchange(&proxy_router(ProxyHandler::new(addr)), "GET /api HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nTransfer-Encoding: chunked\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"), "{}", response);
+
+        // a Content-Length over the limit: a 502 before anything else.
+        let (addr, _) = upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n");
+        let response = router::exchange(&proxy_router(ProxyHandler::new(addr).max_response_body_bytes(10)), "GET /api HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", response);
+
+        // no length, and too much of it: cut off, without the last chunk.
+        let (addr, _) = upstream(b"HTTP/1.1 200 OK\r\n\r\n0123456789abcdef");
+        let router = proxy_router(ProxyHandler::new(addr).max_response_body_bytes(10));
+        let request = Request::parse(b"GET /api HTTP/1.1\r\n\r\n").unwrap();
+        let mut out = Vec::new();
+        let mut response = Response::new(router::Output::Buffer { buffer: &mut out, peer: ([127, 0, 0, 1], 1).into() }, &request, None);
+        let error = router.respond(&request, &mut response).unwrap_err();
+        assert!(error.to_string().contains("response too large"), "{}", error);
+        drop(response);
+        assert!(!String::from_utf8_lossy(&out).ends_with("0\r\n\r\n"));
+    }
+
+    #[test]
+    fn unreachable_upstreams_are_a_502() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let response = router::exchange(&proxy_router(ProxyHandler::new(closed)), "GET /api HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", response);
+        let (addr, _) = upstream(b"SSH-2.0-OpenSSH\r\n\r\n");
+        let response = router::exchange(&proxy_router(ProxyHandler::new(addr)), "GET /api HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn head_requests_keep_the_upstream_length() {
+        let (addr, _) = upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n");
+        let response = router::exchange(&proxy_router(ProxyHandler::new(addr)), "HEAD /api HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\nContent-Length: 1234\r\n\r\n"), "{}", response);
+    }
+
+
+    #[test]
+    fn pools_skip_failing_upstreams_until_a_probe_succeeds() {
+        let (a, b): (SocketAddr, SocketAddr) = ("127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap());
+        let pool = UpstreamPool::new(vec![a, b]).failure_threshold(2).quarantine(Duration::from_millis(50));
+        assert_eq!([pool.select(), pool.select(), pool.select()], [Some(a), Some(b), Some(a)]);
+
+        // a success in between starts the count again.
+        pool.report_failure(a);
+        pool.report_success(a);
+        pool.report_failure(a);
+        assert_eq!(pool.health(a), Some(UpstreamHealth::Healthy));
+        pool.report_failure(a);
+        assert_eq!(pool.health(a), Some(UpstreamHealth::Unhealthy));
+        assert_eq!([pool.select(), pool.select()], [Some(b), Some(b)]);
+
+        pool.report_failure(b);
+        pool.report_failure(b);
+        assert_eq!(pool.select(), None);
+
+        // after the quarantine, one probe each; a's fails, b's succeeds.
+        thread::sleep(Duration::from_millis(60));
+        let mut probes = [pool.select().unwrap(), pool.select().unwrap()];
+        probes.sort();
+        assert_eq!((probes, pool.select()), ([a, b], None));
+        pool.report_failure(a);
+        pool.report_success(b);
+        assert_eq!((pool.health(a), pool.health(b)), (Some(UpstreamHealth::Unhealthy), Some(UpstreamHealth::Healthy)));
+        assert_eq!([pool.select(), pool.select()], [Some(b), Some(b)]);
+        assert_eq!(pool.health("127.0.0.1:3".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn proxies_report_to_their_pool() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (failing, _) = upstream(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+        let pool = Arc::new(UpstreamPool::new(vec![closed, failing]).failure_threshold(1).quarantine(Duration::from_secs(60)));
+        let router = proxy_router(ProxyHandler::with_pool(pool.clone()));
+        let response = router::exchange(&router, "GET /api HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", response);
+        let response = router::exchange(&router, "GET /api HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+        assert_eq!((pool.health(closed), pool.health(failing)), (Some(UpstreamHealth::Unhealthy), Some(UpstreamHealth::Unhealthy)));
+
+        // nothing left to ask.
+        let response = router::exchange(&router, "GET /api HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", response);
+    }
+
+
+    #[test]
+    fn websockets_are_passed_both_ways() {
+        let mut upstream = Router::new();
+        upstream.on_upgrade("websocket", websocket::WsEcho);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the proxy side of a connection, then the upstream's.
+        let serve = |listener: TcpListener, router: Router| {
+            thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let head = crate::client::read_head(&mut BufReader::new(stream.try_clone().unwrap())).unwrap();
+                let _ = router.dispatch(&Request::parse(&head).unwrap(), &mut stream);
+            })
+        };
+        serve(listener, upstream);
+
+        let mut proxy = Router::new();
+        proxy.on_upgrade("websocket", ProxyHandler::new(addr).strip_prefix("/api"));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/api/chat", listener.local_addr().unwrap());
+        let served = serve(listener, proxy);
+
+        let client = WsClient::connect(&url, &[]).unwrap();
+        client.send(&WsFrame::text("through the proxy")).unwrap();
+        assert_eq!(client.recv().unwrap(), WsFrame::text("through the proxy"));
+        client.send(&WsFrame { fin: false, opcode: Opcode::Binary, payload: vec![1; 70_000] }).unwrap();
+        client.send(&WsFrame { fin: true, opcode: Opcode::Continuation, payload: vec![2] }).unwrap();
+        assert_eq!(client.recv().unwrap().payload.len(), 70_000);
+        assert_eq!(client.recv().unwrap(), WsFrame { fin: true, opcode: Opcode::Continuation, payload: vec![2] });
+        client.send(&WsFrame::close(1000, "")).unwrap();
+        assert_eq!(client.recv().unwrap(), WsFrame::close(1000, ""));
+        served.join().unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
+
+use crate::{ExecuteError, Message};
+
+/* # Work stealing: a queue per worker!
+With one Mutex<Receiver> shared by every worker, each job sent and each job taken
+goes through the same lock, and under a high submission rate the workers spend
+their time waiting for it. Instead every worker gets its own VecDeque:
+1. .execute pushes the job onto the shortest queue of a live worker
+(when several are equally short, the one after the last pick, round-robin style).
+2. A worker takes jobs from the front of its own queue.
+3. When its own queue is empty, it looks at every other queue's length (no locking)
+and steals half the jobs from the busiest one, moving them to its own queue.
+4. With nothing to run or steal, it sleeps on a Condvar until a job is pushed.
+Retire and Terminate go to a specific worker's queue, and are never stolen.
+A queue belongs to a worker id, not a thread: a replacement worker (after a panic)
+picks up where its predecessor left off, and the queue of a retired worker is kept
+(closed, so .execute skips it) for the next worker resize adds with that id.
+Jobs left in a closed queue are stolen by the others. */
+
+pub(crate) struct Queues {
+    // Indexed by worker id; only written when a new id shows up.
+    locals: RwLock<Vec<Arc<LocalQueue>>>,
+    // NewJobs waiting in all of the queues together.
+    jobs: AtomicUsize,
+    // Workers waiting in wait_for_work.
+    idle: AtomicUsize,
+    sleep: Mutex<()>,
+    wakeup: Condvar,
+    // Where the next search for the shortest queue starts.
+    next: AtomicUsize,
+    // None => unbounded.
+    capacity: Option<usize>,
+}
+
+pub(crate) struct LocalQueue {
+    messages: Mutex<VecDeque<Message>>,
+    // Mirrors of what's in `messages` (all of it, and only the NewJobs),
+    // so other threads can compare queues without taking their locks.
+    len: AtomicUsize,
+    jobs: AtomicUsize,
+    // Whether .execute may push onto this queue.
+    open: AtomicBool,
+}
+
+impl LocalQueue {
+    fn new() -> LocalQueue {
+        LocalQueue {
+            messages: Mutex::new(VecDeque::new()),
+            len: AtomicUsize::new(0),
+            jobs: AtomicUsize::new(0),
+            open: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Message>> {
+        self.messages.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push(&self, message: Message, front: bool) {
+        let mut messages = self.lock();
+        if matches!(message, Message::NewJob(_)) {
+            self.jobs.fetch_add(1, Ordering::SeqCst);
+        }
+        if front {
+            messages.push_front(message);
+        } else {
+            messages.push_back(message);
+        }
+        self.len.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn pop(&self) -> Option<Message> {
+        if self.len.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        let mut messages = self.lock();
+        let message = messages.pop_front()?;
+        if matches!(message, Message::NewJob(_)) {
+            self.jobs.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        Some(message)
+    }
+
+    // Takes half of the NewJobs (rounded up), oldest first; control messages stay put.
+    fn steal_half(&self) -> Vec<Message> {
+        let mut messages = self.lock();
+        let wanted = self.jobs.load(Ordering::SeqCst).div_ceil(2);
+        let mut stolen = Vec::with_capacity(wanted);
+        let mut index = 0;
+        while stolen.len() < wanted && index < messages.len() {
+            if matches!(messages[index], Message::NewJob(_)) {
+                stolen.extend(messages.remove(index));
+            } else {
+                index += 1;
+            }
+        }
+        self.jobs.fetch_sub(stolen.len(), Ordering::SeqCst);
+        self.len.fetch_sub(stolen.len(), Ordering::SeqCst);
+        stolen
+    }
+}
+
+impl Queues {
+    pub(crate) fn new(capacity: Option<usize>) -> Queues {
+        Queues {
+            locals: RwLock::new(Vec::new()),
+            jobs: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wakeup: Condvar::new(),
+            next: AtomicUsize::new(0),
+            capacity,
+        }
+    }
+
+    // The queue for worker `id`, created (closed) if that id is new.
+    pub(crate) fn local(&self, id: usize) -> Arc<LocalQueue> {
+        if let Some(local) = self.locals.read().unwrap_or_else(PoisonError::into_inner).get(id) {
+            return Arc::clone(local);
+        }
+        let mut locals = self.locals.write().unwrap_or_else(PoisonError::into_inner);
+        while locals.len() <= id {
+            locals.push(Arc::new(LocalQueue::new()));
+        }
+        Arc::clone(&locals[id])
+    }
+
+    pub(crate) fn open(&self, id: usize) {
+        self.local(id).open.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn close(&self, id: usize) {
+        self.local(id).open.store(false, Ordering::SeqCst);
+    }
+
+    // # Queueing a job (never blocks)!
+    // A bounded pool counts every job waiting in any queue against its capacity.
+    // Idle workers are about to take a job, so they add to it: like a zero-capacity
+    // sync_channel, capacity 0 accepts a job only when a worker is ready for it.
+    pub(crate) fn push_job(&self, message: Message) -> Result<(), ExecuteError> {
+        let locals = self.locals.read().unwrap_or_else(PoisonError::into_inner);
+        let target = self.shortest_open(&locals).ok_or(ExecuteError::Disconnected)?;
+
+        match self.capacity {
+            None => {
+                self.jobs.fetch_add(1, Ordering::SeqCst);
+            },
+            Some(capacity) => {
+                let limit = capacity + self.idle.load(Ordering::SeqCst);
+                self.jobs
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |jobs| (jobs < limit).then_some(jobs + 1))
+                    .map_err(|_| ExecuteError::QueueFull)?;
+            },
+        }
+        locals[target].push(message, false);
+        drop(locals);
+
+        self.wake(false);
+        Ok(())
+    }
+
+    // Control messages skip the capacity check, and Retire jumps the queue
+    // (the retiring worker's jobs are left for the others to steal).
+    pub(crate) fn push_control(&self, id: usize, message: Message) {
+        let front = matches!(message, Message::Retire(_));
+        self.local(id).push(message, front);
+        // whoever wakes up first might not be worker `id`, so wake them all.
+        self.wake(true);
+    }
+
+    fn shortest_open(&self, locals: &[Arc<LocalQueue>]) -> Option<usize> {
+        let count = locals.len();
+        if count == 0 {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        (start..count)
+            .chain(0..start)
+            .filter(|&id| locals[id].open.load(Ordering::SeqCst))
+            .min_by_key(|&id| locals[id].len.load(Ordering::SeqCst))
+    }
+
+    // # Finding the next message for worker `id` (blocks until there is one)!
+    pub(crate) fn next_message(&self, id: usize, own: &LocalQueue) -> Message {
+        loop {
+            if let Some(message) = own.pop() {
+                if matches!(message, Message::NewJob(_)) {
+                    self.jobs.fetch_sub(1, Ordering::SeqCst);
+                }
+                return message;
+            }
+            if self.steal(id, own) {
+                continue;
+            }
+            self.wait_for_work(own);
+        }
+    }
+
+    // Moves half of the busiest other queue's jobs onto our own; false if there were none.
+    fn steal(&self, id: usize, own: &LocalQueue) -> bool {
+        if self.jobs.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        let victim = {
+            let locals = self.locals.read().unwrap_or_else(PoisonError::into_inner);
+            let busiest = (0..locals.len())
+                .filter(|&other| other != id)
+                .max_by_key(|&other| locals[other].jobs.load(Ordering::SeqCst));
+            match busiest {
+                Some(other) if locals[other].jobs.load(Ordering::SeqCst) > 0 => Arc::clone(&locals[other]),
+                _ => return false,
+            }
+        };
+
+        // One lock at a time: two workers stealing from each other mustn't deadlock.
+        let stolen = victim.steal_half();
+        if stolen.is_empty() {
+            return false;
+        }
+        for message in stolen {
+            own.push(message, false);
+        }
+        true
+    }
+
+    // The counters are checked with the sleep lock held, and pushers take that lock
+    // before notifying, so a job pushed after the check always finds us waiting.
+    fn wait_for_work(&self, own: &LocalQueue) {
+        let sleep = self.sleep.lock().unwrap_or_else(PoisonError::into_inner);
+        self.idle.fetch_add(1, Ordering::SeqCst);
+        let sleep = if own.len.load(Ordering::SeqCst) == 0 && self.jobs.load(Ordering::SeqCst) == 0 {
+            self.wakeup.wait(sleep).unwrap_or_else(PoisonError::into_inner)
+        } else {
+            sleep
+        };
+        self.idle.fetch_sub(1, Ordering::SeqCst);
+        drop(sleep);
+    }
+
+    fn wake(&self, all: bool) {
+        if self.idle.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let _sleep = self.sleep.lock().unwrap_or_else(PoisonError::into_inner);
+        if all {
+            self.wakeup.notify_all();
+        } else {
+            self.wakeup.notify_one();
+        }
+    }
+}
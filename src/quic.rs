@@ -0,0 +1,202 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/* # QUIC packets, as far as surff reads them!
+let listener = QuicListener::bind("0.0.0.0:443".parse()?)?;
+let (initial, client) = listener.accept_initial()?;  // a client opening a connection
+1. Every QUIC version starts its long-header packets the same way (RFC 8999, 5.1): a
+first byte with the top bit set, a 32-bit version, then the destination and source
+connection IDs, each after its length. parse_long_header reads that, and for QUIC v1
+(RFC 9000, 17.2), where the IDs are at most 20 bytes, the packet type too.
+2. accept_initial waits for a v1 Initial in a datagram of at least 1200 bytes (smaller
+ones must be dropped: RFC 9000, 14.1). A long header of a version other than v1 gets
+a Version Negotiation packet back (RFC 9000, 17.2.1) offering v1; short headers, and
+anything that isn't QUIC, are dropped: they'd be for connections that don't exist.
+3. What comes after the connection IDs is encrypted with keys from the handshake, which
+is TLS 1.3 inside QUIC (RFC 9001): quinn and rustls would do that, and surff has neither
+as a dependency. So a connection is accepted this far and no further; HTTP/3 (HEADERS
+and DATA frames, QPACK) would sit on top of the streams it opens. */
+
+pub const QUIC_V1: u32 = 1;
+// Version 0 marks a Version Negotiation packet.
+const VERSION_NEGOTIATION: u32 = 0;
+const MAX_V1_CONNECTION_ID: usize = 20;
+const MIN_INITIAL_DATAGRAM: usize = 1200;
+// The most a UDP datagram can carry (QUIC's max_udp_payload_size default).
+const MAX_DATAGRAM: usize = 65_527;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    Retry,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LongHeader {
+    pub version: u32,
+    pub destination: Vec<u8>,
+    pub source: Vec<u8>,
+    // None for versions other than v1: what the rest of the first byte means is theirs.
+    pub packet_type: Option<PacketType>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuicError {
+    // A short header, or not QUIC at all.
+    NotLongHeader,
+    Truncated,
+    ConnectionIdTooLong(usize),
+}
+
+impl fmt::Display for QuicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuicError::NotLongHeader => write!(f, "not a QUIC long header packet"),
+            QuicError::Truncated => write!(f, "QUIC packet ends early"),
+            QuicError::ConnectionIdTooLong(length) => write!(f, "QUIC connection ID of {} bytes", length),
+        }
+    }
+}
+
+impl std::error::Error for QuicError {}
+
+pub fn parse_long_header(datagram: &[u8]) -> Result<LongHeader, QuicError> {
+    let first = *datagram.first().ok_or(QuicError::Truncated)?;
+    if first & 0x80 == 0 {
+        return Err(QuicError::NotLongHeader);
+    }
+    let version = datagram.get(1..5).ok_or(QuicError::Truncated)?;
+    let version = u32::from_be_bytes([version[0], version[1], version[2], version[3]]);
+    let mut at = 5;
+    let mut connection_id = || {
+        let length = *datagram.get(at).ok_or(QuicError::Truncated)? as usize;
+        if version == QUIC_V1 && length > MAX_V1_CONNECTION_ID {
+            return Err(QuicError::ConnectionIdTooLong(length));
+        }
+        let id = datagram.get(at + 1..at + 1 + length).ok_or(QuicError::Truncated)?;
+        at += 1 + length;
+        Ok(id.to_vec())
+    };
+    let destination = connection_id()?;
+    let source = connection_id()?;
+    let packet_type = match (first >> 4) & 0b11 {
+        _ if version != QUIC_V1 => None,
+        0 => Some(PacketType::Initial),
+        1 => Some(PacketType::ZeroRtt),
+        2 => Some(PacketType::Handshake),
+        _ => Some(PacketType::Retry),
+    };
+    Ok(LongHeader { version, destination, source, packet_type })
+}
+
+// The answer to `header`, offering `versions`: its connection IDs swapped, as they'll be
+// when the client reads them.
+pub fn version_negotiation(header: &LongHeader, versions: &[u32]) -> Vec<u8> {
+    // the rest of the first byte is unused, and should be random: this will do.
+    let mut packet = vec![0x80 | (header.destination.first().copied().unwrap_or(0) & 0x7f)];
+    packet.extend_from_slice(&VERSION_NEGOTIATION.to_be_bytes());
+    for id in [&header.source, &header.destination] {
+        packet.push(id.len() as u8);
+        packet.extend_from_slice(id);
+    }
+    for version in versions {
+        packet.extend_from_slice(&version.to_be_bytes());
+    }
+    packet
+}
+
+#[derive(Debug)]
+pub struct QuicListener {
+    socket: UdpSocket,
+}
+
+impl QuicListener {
+    pub fn bind(addr: SocketAddr) -> io::Result<QuicListener> {
+        Ok(QuicListener { socket: UdpSocket::bind(addr)? })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // The next v1 Initial; everything else that arrives meanwhile is answered or dropped.
+    pub fn accept_initial(&self) -> io::Result<(LongHeader, SocketAddr)> {
+        let mut datagram = vec![0; MAX_DATAGRAM];
+        loop {
+            let (length, from) = self.socket.recv_from(&mut datagram)?;
+            let Ok(header) = parse_long_header(&datagram[..length]) else {
+                continue;
+            };
+            match header.version {
+                QUIC_V1 if header.packet_type == Some(PacketType::Initial) && length >= MIN_INITIAL_DATAGRAM => {
+                    return Ok((header, from));
+                }
+                // never answered, or two servers could negotiate with each other forever.
+                QUIC_V1 | VERSION_NEGOTIATION => {}
+                _ => {
+                    self.socket.send_to(&version_negotiation(&header, &[QUIC_V1]), from)?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // A long header packet, padded to `size` bytes.
+    fn packet(first: u8, version: u32, destination: &[u8], source: &[u8], size: usize) -> Vec<u8> {
+        let mut packet = vec![first];
+        packet.extend_from_slice(&version.to_be_bytes());
+        for id in [destination, source] {
+            packet.push(id.len() as u8);
+            packet.extend_from_slice(id);
+        }
+        packet.resize(size.max(packet.len()), 0);
+        packet
+    }
+
+    #[test]
+    fn parses_long_headers() {
+        let header = parse_long_header(&packet(0xc3, QUIC_V1, &[1, 2, 3, 4, 5, 6, 7, 8], &[9], 1200)).unwrap();
+        assert_eq!(header, LongHeader { version: QUIC_V1, destination: vec![1, 2, 3, 4, 5, 6, 7, 8], source: vec![9], packet_type: Some(PacketType::Initial) });
+        assert_eq!(parse_long_header(&packet(0xe0, QUIC_V1, &[], &[], 0)).unwrap().packet_type, Some(PacketType::Handshake));
+        // another version may have longer IDs, and its own packet types.
+        let other = parse_long_header(&packet(0xc0, 0x1a2a_3a4a, &[7; 40], &[], 0)).unwrap();
+        assert_eq!((other.destination.len(), other.packet_type), (40, None));
+
+        assert_eq!(parse_long_header(&packet(0xc0, QUIC_V1, &[7; 21], &[], 0)), Err(QuicError::ConnectionIdTooLong(21)));
+        assert_eq!(parse_long_header(&[0x40, 1, 2, 3]), Err(QuicError::NotLongHeader));
+        assert_eq!(parse_long_header(&packet(0xc0, QUIC_V1, &[1, 2], &[3], 0)[..8]), Err(QuicError::Truncated));
+    }
+
+    #[test]
+    fn accepts_v1_initials_and_negotiates_the_rest() {
+        let listener = QuicListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let server = listener.local_addr().unwrap();
+
+        // dropped: too small for an Initial, then not QUIC.
+        client.send_to(&packet(0xc0, QUIC_V1, &[1], &[2], 100), server).unwrap();
+        client.send_to(b"GET / HTTP/1.1\r\n\r\n", server).unwrap();
+        // answered: a version the listener doesn't speak.
+        client.send_to(&packet(0xc0, 0x1a2a_3a4a, &[1, 2, 3], &[4, 5], 1200), server).unwrap();
+        client.send_to(&packet(0xc0, QUIC_V1, &[8; 8], &[9; 4], 1200), server).unwrap();
+
+        let (initial, from) = listener.accept_initial().unwrap();
+        assert_eq!((initial.destination, initial.source), (vec![8; 8], vec![9; 4]));
+        assert_eq!(from, client.local_addr().unwrap());
+
+        let mut reply = [0; 64];
+        let (length, _) = client.recv_from(&mut reply).unwrap();
+        let negotiation = parse_long_header(&reply[..length]).unwrap();
+        assert_eq!((negotiation.version, negotiation.destination, negotiation.source), (0, vec![4, 5], vec![1, 2, 3]));
+        assert_eq!(&reply[length - 4..length], &QUIC_V1.to_be_bytes());
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+
+use crate::http::ByteRange;
+
+/* # Keeping the byte ranges of big files in memory!
+let cache = Arc::new(RangeCache::new(64 * 1024 * 1024).max_ranges_per_file(16));
+let files = StaticFileHandler::new("videos")?.range_cache(Arc::clone(&cache));
+A video player asks for the same segments of a file again and again (Range: bytes=...),
+and every one of those would be read from disk. With a RangeCache they're read once:
+1. The key is (path, start, end): the same range, not a part of one that's cached.
+2. An entry remembers the file's mtime. Once that changes, all of the file's entries
+are dropped, and the range is read again.
+3. At most `max_bytes` in all: the least recently used range goes first (LRU). A range
+bigger than that isn't kept at all. And at most max_ranges_per_file (8 unless changed)
+per file, so one file can't push out all the others: its own oldest goes first.
+4. stats() counts hits, misses and the bytes kept, for /metrics (to_prometheus). */
+
+const DEFAULT_MAX_RANGES_PER_FILE: usize = 8;
+
+// (path, first byte, last byte)
+type Key = (PathBuf, u64, u64);
+
+struct Entry {
+    bytes: Arc<[u8]>,
+    modified: Option<SystemTime>,
+    // its place in Entries::by_use.
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<Key, Entry>,
+    // least recently used first.
+    by_use: BTreeMap<u64, Key>,
+    next_use: u64,
+    bytes: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &Key) -> Option<Arc<[u8]>> {
+        let entry = self.entries.get_mut(key)?;
+        self.by_use.remove(&entry.used);
+        entry.used = self.next_use;
+        self.by_use.insert(self.next_use, key.clone());
+        self.next_use += 1;
+        Some(Arc::clone(&entry.bytes))
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.by_use.remove(&entry.used);
+            self.bytes -= entry.bytes.len() as u64;
+        }
+    }
+
+    fn remove_file(&mut self, path: &Path) {
+        let keys: Vec<Key> = self.entries.keys().filter(|key| key.0 == path).cloned().collect();
+        for key in &keys {
+            self.remove(key);
+        }
+    }
+}
+
+// What stats() returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RangeCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub bytes_cached: u64,
+}
+
+pub struct RangeCache {
+    max_bytes: u64,
+    max_ranges_per_file: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RangeCache {
+    pub fn new(max_bytes: u64) -> RangeCache {
+        RangeCache {
+            max_bytes,
+            max_ranges_per_file: DEFAULT_MAX_RANGES_PER_FILE,
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn max_ranges_per_file(mut self, ranges: usize) -> RangeCache {
+        self.max_ranges_per_file = ranges.max(1);
+        self
+    }
+
+    // `range` of `file`, opened from `path`: from memory when it's there and the file
+    // hasn't changed since, otherwise from the file (and kept). A file shorter than the
+    // range is an UnexpectedEof.
+    pub fn read(&self, path: &Path, file: &File, range: ByteRange) -> io::Result<Arc<[u8]>> {
+        let modified = file.metadata()?.modified().ok();
+        let key = (path.to_path_buf(), range.start, range.end);
+        {
+            let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+            match entries.entries.get(&key).map(|entry| entry.modified == modified) {
+                Some(true) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(entries.touch(&key).expect("found above"));
+                }
+                Some(false) => entries.remove_file(path),
+                None => {}
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // read without the lock: the disk is what's slow.
+        let mut bytes = vec![0; usize::try_from(range.length()).map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?];
+        let mut file = file;
+        file.seek(SeekFrom::Start(range.start))?;
+        file.read_exact(&mut bytes)?;
+        let bytes: Arc<[u8]> = bytes.into();
+        if range.length() <= self.max_bytes {
+            self.insert(key, Arc::clone(&bytes), modified);
+        }
+        Ok(bytes)
+    }
+
+    fn insert(&self, key: Key, bytes: Arc<[u8]>, modified: Option<SystemTime>) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        // another thread may have read it at the same time.
+        entries.remove(&key);
+        loop {
+            let of_file = entries.entries.keys().filter(|other| other.0 == key.0).count();
+            if of_file < self.max_ranges_per_file {
+                break;
+            }
+            let oldest = entries.by_use.values().find(|other| other.0 == key.0).cloned().expect("counted above");
+            entries.remove(&oldest);
+        }
+        while entries.bytes + bytes.len() as u64 > self.max_bytes {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+
+        entries.bytes += bytes.len() as u64;
+        let used = entries.next_use;
+        entries.next_use += 1;
+        entries.by_use.insert(used, key.clone());
+        entries.entries.insert(key, Entry { bytes, modified, used });
+    }
+
+    pub fn stats(&self) -> RangeCacheStats {
+        RangeCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes_cached: self.entries.lock().unwrap_or_else(PoisonError::into_inner).bytes,
+        }
+    }
+
+    pub fn to_prometheus(&self) -> String {
+        let stats = self.stats();
+        let mut text = String::new();
+        let metrics = [
+            ("surff_range_cache_hits_total", "counter", "Range requests answered from memory.", stats.hits),
+            ("surff_range_cache_misses_total", "counter", "Range requests read from disk.", stats.misses),
+            ("surff_range_cache_bytes", "gauge", "Bytes of byte ranges kept in memory.", stats.bytes_cached),
+        ];
+        for (name, kind, help, value) in metrics {
+            let _ = write!(text, "# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value);
+        }
+        text
+    }
+}
+
+// Not the bytes themselves.
+impl fmt::Debug for RangeCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RangeCache")
+            .field("max_bytes", &self.max_bytes)
+            .field("max_ranges_per_file", &self.max_ranges_per_file)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    fn file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("surff-range-cache-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn read(cache: &RangeCache, path: &Path, start: u64, end: u64) -> String {
+        let bytes = cache.read(path, &File::open(path).unwrap(), ByteRange { start, end }).unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn hits_misses_and_a_changed_file() {
+        let cache = RangeCache::new(1024);
+        let path = file("changed", "0123456789");
+        assert_eq!(read(&cache, &path, 2, 4), "234");
+        assert_eq!(read(&cache, &path, 2, 4), "234");
+        assert_eq!(read(&cache, &path, 0, 0), "0");
+        assert_eq!(cache.stats(), RangeCacheStats { hits: 1, misses: 2, bytes_cached: 4 });
+
+        fs::write(&path, "abcdefghij").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        assert_eq!(read(&cache, &path, 2, 4), "cde");
+        // the other range of the file went too.
+        assert_eq!(cache.stats(), RangeCacheStats { hits: 1, misses: 3, bytes_cached: 3 });
+
+        let err = cache.read(&path, &File::open(&path).unwrap(), ByteRange { start: 8, end: 20 }).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(cache.to_prometheus().contains("\nsurff_range_cache_misses_total 4\n"), "{}", cache.to_prometheus());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_ranges_go_first() {
+        let cache = RangeCache::new(6).max_ranges_per_file(2);
+        let (a, b) = (file("a", "aaaaaaaaaa"), file("b", "bbbbbbbbbb"));
+        read(&cache, &a, 0, 1);
+        read(&cache, &b, 0, 1);
+        read(&cache, &a, 0, 1);
+        // over max_bytes: b's is the oldest.
+        read(&cache, &a, 2, 5);
+        assert_eq!(cache.stats().bytes_cached, 6);
+        read(&cache, &a, 0, 1);
+        read(&cache, &b, 0, 1);
+        assert_eq!(cache.stats().hits, 2);
+
+        // a third range of a: its own oldest goes, (2, 5) (and then some for the bytes).
+        read(&cache, &a, 6, 6);
+        read(&cache, &a, 0, 1);
+        assert_eq!(cache.stats().hits, 3);
+        read(&cache, &a, 2, 5);
+        assert_eq!(cache.stats().hits, 3);
+
+        // too big to keep.
+        let before = cache.stats().bytes_cached;
+        assert_eq!(read(&cache, &a, 0, 9), "aaaaaaaaaa");
+        assert_eq!(cache.stats().bytes_cached, before);
+        fs::remove_file(a).unwrap();
+        fs::remove_file(b).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::server::Counter;
+
+/* # Per-IP rate limiting with token buckets!
+Every client IP gets a bucket that holds up to `capacity` tokens and refills at
+`rate` tokens per second. Each request takes one token; an empty bucket means 429.
+So a client can send `capacity` requests at once, and `rate` per second after that.
+Buckets are refilled lazily, from the time elapsed since the last request.
+A bucket that has filled up again carries no information (it's the same as a new one),
+so check_and_consume drops those now and then to keep the map from growing forever.
+Cloning a RateLimiter is cheap and the clones share the buckets.
+
+# Burst allowance: an overdraft for page loads!
+RateLimiter::builder(10.0, 20).burst(30.0).build()
+A browser loading a page fetches its images, scripts and styles all at once. With
+.burst(30.0) an empty bucket can go down to -30 tokens before requests are refused,
+and refills at the same `rate` from there: a client that bursts now and then is
+let through, one that keeps sending faster than `rate` runs out all the same, just
+a little later. The rejections are counted in two kinds:
+- burst_rejections: the bucket was full less than (capacity + burst) / rate ago, so
+the client blew through all of that in one go: a burst too big for the allowance.
+- sustained_rejections: the bucket hasn't been full for longer than that, so the
+client has been sending faster than `rate` all along.
+Lots of burst rejections say the allowance is too small for real clients; lots of
+sustained ones are what the limiter is for. */
+
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    // below 0 while the client is in its overdraft.
+    tokens: f64,
+    last_refill: Instant,
+    last_full: Instant,
+}
+
+struct Buckets {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    last_eviction: Instant,
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    state: Arc<Mutex<Buckets>>,
+    rate: f64,
+    capacity: f64,
+    // how far below zero a bucket may go.
+    burst_capacity: f64,
+    burst_rejections: Arc<AtomicU64>,
+    sustained_rejections: Arc<AtomicU64>,
+}
+
+pub struct RateLimiterBuilder {
+    rate: f64,
+    capacity: u32,
+    burst_capacity: f64,
+}
+
+impl RateLimiterBuilder {
+    // Requests a client may take on credit once its bucket is empty (0 by default).
+    pub fn burst(mut self, capacity: f64) -> RateLimiterBuilder {
+        self.burst_capacity = capacity;
+        self
+    }
+
+    pub fn build(self) -> RateLimiter {
+        RateLimiter {
+            state: Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_eviction: Instant::now(),
+            })),
+            rate: self.rate.max(0.0),
+            capacity: self.capacity.max(1) as f64,
+            burst_capacity: self.burst_capacity.max(0.0),
+            burst_rejections: Arc::new(AtomicU64::new(0)),
+            sustained_rejections: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl RateLimiter {
+    // `rate` tokens per second, at most `capacity` at once (at least 1), no overdraft.
+    pub fn new(rate: f64, capacity: u32) -> RateLimiter {
+        RateLimiter::builder(rate, capacity).build()
+    }
+
+    pub fn builder(rate: f64, capacity: u32) -> RateLimiterBuilder {
+        RateLimiterBuilder { rate, capacity, burst_capacity: 0.0 }
+    }
+
+    // true => allowed (and a token was taken); false => answer 429.
+    pub fn check_and_consume(&self, addr: IpAddr) -> bool {
+        self.check_at(addr, Instant::now())
+    }
+
+    fn check_at(&self, addr: IpAddr, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if now.duration_since(state.last_eviction) >= EVICTION_INTERVAL {
+            state.buckets.retain(|_, bucket| self.refilled(bucket, now) < self.capacity);
+            state.last_eviction = now;
+        }
+
+        let bucket = state.buckets.entry(addr).or_insert(TokenBucket {
+            tokens: self.capacity,
+            last_refill: now,
+            last_full: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.last_refill = now;
+        if bucket.tokens >= self.capacity {
+            bucket.last_full = now;
+        }
+
+        if bucket.tokens - 1.0 >= -self.burst_capacity {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+        // forever, with a rate of 0.
+        let headroom = Duration::try_from_secs_f64((self.capacity + self.burst_capacity) / self.rate).unwrap_or(Duration::MAX);
+        if now.duration_since(bucket.last_full) < headroom {
+            self.burst_rejections.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.sustained_rejections.fetch_add(1, Ordering::Relaxed);
+        }
+        false
+    }
+
+    // Number of addresses with a (not yet full) bucket.
+    pub fn tracked_addresses(&self) -> usize {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).buckets.len()
+    }
+
+    // Like the server's counters, handles that keep counting (e.g. for /metrics).
+    pub fn burst_rejections(&self) -> Counter {
+        Counter::new(Arc::clone(&self.burst_rejections))
+    }
+
+    pub fn sustained_rejections(&self) -> Counter {
+        Counter::new(Arc::clone(&self.sustained_rejections))
+    }
+
+    fn refilled(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn allowed(limiter: &RateLimiter, now: Instant, requests: usize) -> usize {
+        (0..requests).filter(|_| limiter.check_at(CLIENT, now)).count()
+    }
+
+    #[test]
+    fn without_a_burst_the_bucket_is_the_limit() {
+        let limiter = RateLimiter::new(10.0, 5);
+        let now = Instant::now();
+        assert_eq!(allowed(&limiter, now, 8), 5);
+        // a tenth of a second buys one more.
+        assert_eq!(allowed(&limiter, now + Duration::from_millis(100), 3), 1);
+    }
+
+    #[test]
+    fn the_burst_is_an_overdraft() {
+        let limiter = RateLimiter::builder(10.0, 5).burst(3.0).build();
+        let now = Instant::now();
+        assert_eq!(allowed(&limiter, now, 10), 8);
+        // paid back at the usual rate: at -3, a tenth of a second is still in the red.
+        assert_eq!(allowed(&limiter, now + Duration::from_millis(100), 3), 1);
+        assert_eq!(limiter.burst_rejections().get(), 4);
+        assert_eq!(limiter.sustained_rejections().get(), 0);
+    }
+
+    #[test]
+    fn rejections_long_after_the_bucket_was_full_are_sustained() {
+        let limiter = RateLimiter::builder(10.0, 5).burst(5.0).build();
+        let start = Instant::now();
+        // a client at twice the rate: 20 requests a second, for two seconds.
+        for tick in 0..40 {
+            allowed(&limiter, start + Duration::from_millis(50 * tick), 1);
+        }
+        // the headroom is a second's worth: the rejections after that are sustained.
+        assert!(limiter.sustained_rejections().get() > 0);
+        assert!(limiter.burst_rejections().get() < limiter.sustained_rejections().get());
+    }
+
+    #[test]
+    fn a_rate_of_nothing_doesnt_panic() {
+        let limiter = RateLimiter::builder(0.0, 1).burst(1.0).build();
+        assert_eq!(allowed(&limiter, Instant::now(), 3), 2);
+        assert_eq!(limiter.burst_rejections().get(), 1);
+    }
+}
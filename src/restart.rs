@@ -0,0 +1,136 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/* # Restarting workers whose job panicked!
+ThreadPoolBuilder::new().restart_policy(WorkerRestartPolicy::circuit_breaker())
+- AlwaysRestart (the default): a replacement right away, every time.
+- Never: the pool runs a worker short from then on.
+- CircuitBreaker { window, threshold, backoff }: right away, unless more than
+`threshold` (a share of the pool's size) of the workers died within `window`.
+A bug that makes every job panic would otherwise have the supervisor spawning
+threads in a tight loop. The breaker opens then: replacements wait for `backoff`,
+which doubles every time it trips again, and it closes again once a death doesn't
+trip it.
+While a worker waits for its replacement, its queue is closed: new jobs go to the
+others (or, with no worker left, .execute returns Disconnected) and the jobs that
+were already in it get stolen, or run by the replacement. */
+
+// backoff * 2^6 at most: a minute, for the default second.
+const MAX_BACKOFF_DOUBLINGS: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WorkerRestartPolicy {
+    #[default]
+    AlwaysRestart,
+    CircuitBreaker { window: Duration, threshold: f64, backoff: Duration },
+    Never,
+}
+
+impl WorkerRestartPolicy {
+    // More than half of the workers within 10 s opens it, for 1 s at first.
+    pub fn circuit_breaker() -> WorkerRestartPolicy {
+        WorkerRestartPolicy::CircuitBreaker {
+            window: Duration::from_secs(10),
+            threshold: 0.5,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Restart {
+    Now,
+    After(Duration),
+    Never,
+}
+
+// The supervisor's memory of recent deaths.
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub(crate) struct RestartBreaker {
+    policy: WorkerRestartPolicy,
+    deaths: VecDeque<Instant>,
+    open_until: Option<Instant>,
+    trips: u32,
+}
+
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+impl RestartBreaker {
+    pub(crate) fn new(policy: WorkerRestartPolicy) -> RestartBreaker {
+        RestartBreaker { policy, deaths: VecDeque::new(), open_until: None, trips: 0 }
+    }
+
+    // A worker died at `now`, in a pool of `pool_size` workers (counting it).
+    pub(crate) fn on_death(&mut self, now: Instant, pool_size: usize) -> Restart {
+        let (window, threshold, backoff) = match self.policy {
+            WorkerRestartPolicy::AlwaysRestart => return Restart::Now,
+            WorkerRestartPolicy::Never => return Restart::Never,
+            WorkerRestartPolicy::CircuitBreaker { window, threshold, backoff } => (window, threshold, backoff),
+        };
+
+        self.deaths.push_back(now);
+        while self.deaths.front().is_some_and(|death| now.duration_since(*death) > window) {
+            self.deaths.pop_front();
+        }
+        // still open: this one waits along with the others.
+        if let Some(open_until) = self.open_until.filter(|open_until| *open_until > now) {
+            return Restart::After(open_until - now);
+        }
+        if self.deaths.len() as f64 <= threshold * pool_size as f64 {
+            self.trips = 0;
+            return Restart::Now;
+        }
+
+        let delay = backoff * 2u32.pow(self.trips.min(MAX_BACKOFF_DOUBLINGS));
+        self.trips += 1;
+        self.open_until = Some(now + delay);
+        Restart::After(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn breaker() -> RestartBreaker {
+        RestartBreaker::new(WorkerRestartPolicy::circuit_breaker())
+    }
+
+    #[test]
+    fn the_simple_policies_dont_count() {
+        let now = Instant::now();
+        let mut always = RestartBreaker::new(WorkerRestartPolicy::AlwaysRestart);
+        let mut never = RestartBreaker::new(WorkerRestartPolicy::Never);
+        for _ in 0..10 {
+            assert_eq!(always.on_death(now, 2), Restart::Now);
+            assert_eq!(never.on_death(now, 2), Restart::Never);
+        }
+    }
+
+    #[test]
+    fn more_than_half_the_pool_opens_the_breaker() {
+        let start = Instant::now();
+        let mut breaker = breaker();
+        assert_eq!(breaker.on_death(start, 4), Restart::Now);
+        assert_eq!(breaker.on_death(start + SECOND, 4), Restart::Now);
+        assert_eq!(breaker.on_death(start + 2 * SECOND, 4), Restart::After(SECOND));
+        // the others that die while it's open wait for the same moment.
+        assert_eq!(breaker.on_death(start + 2 * SECOND + SECOND / 2, 4), Restart::After(SECOND / 2));
+    }
+
+    #[test]
+    fn the_backoff_doubles_until_things_calm_down() {
+        let start = Instant::now();
+        let mut breaker = breaker();
+        breaker.on_death(start, 2);
+        assert_eq!(breaker.on_death(start, 2), Restart::After(SECOND));
+        assert_eq!(breaker.on_death(start + SECOND, 2), Restart::After(2 * SECOND));
+        assert_eq!(breaker.on_death(start + 3 * SECOND, 2), Restart::After(4 * SECOND));
+
+        // a window later, the old deaths no longer count.
+        assert_eq!(breaker.on_death(start + 20 * SECOND, 2), Restart::Now);
+        breaker.on_death(start + 20 * SECOND, 2);
+        assert_eq!(breaker.on_death(start + 20 * SECOND, 2), Restart::After(SECOND));
+    }
+}
@@ -0,0 +1,148 @@
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use crate::http::Request;
+
+/* # CONNECT: tunnels through the server!
+router.connect_tunnel("*.example.com", |request, client| {
+    let upstream = TcpStream::connect(&request.path)?;    // "api.example.com:443"
+    tunnel(client, upstream)
+});
+1. CONNECT api.example.com:443 goes to the first tunnel whose pattern matches the
+host: exactly ("api.example.com"), or as a wildcard for its subdomains ("*.example.com"
+matches api.example.com, not example.com itself). A pattern with a port ("db.internal:5432")
+must match the port too. Case doesn't matter.
+2. A match gets "HTTP/1.1 200 Connection Established", and the handler gets the
+connection itself: from then on the bytes are the tunnel's, not HTTP.
+3. No match => 403 Forbidden, and no tunnel.
+The handler runs on the worker that read the request, for as long as the tunnel is
+open; tunnel() copies both ways until both sides are done. */
+
+pub(super) type TunnelHandler = Arc<dyn Fn(&Request, TcpStream) -> io::Result<()> + Send + Sync + 'static>;
+
+#[derive(Clone)]
+pub(super) struct Tunnel {
+    pub(super) pattern: String,
+    pub(super) handler: TunnelHandler,
+}
+
+impl Tunnel {
+    // `target` is the request target of a CONNECT: host:port.
+    pub(super) fn matches(&self, target: &str) -> bool {
+        let pattern = self.pattern.to_ascii_lowercase();
+        let target = target.to_ascii_lowercase();
+        let (host, port) = split_authority(&target);
+        let (pattern_host, pattern_port) = split_authority(&pattern);
+
+        if pattern_port.is_some() && pattern_port != port {
+            return false;
+        }
+        match pattern_host.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => host == pattern_host,
+        }
+    }
+}
+
+// "api.example.com:443" => ("api.example.com", Some("443")); "[::1]:22" => ("[::1]", Some("22")).
+fn split_authority(authority: &str) -> (&str, Option<&str>) {
+    match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => (&authority[..i], Some(&authority[i + 1..])),
+        _ => (authority, None),
+    }
+}
+
+// Copies client => upstream on a second thread and upstream => client on this one. When one
+// side stops sending, the other is told so (a half close), and it returns once both have.
+pub fn tunnel(client: TcpStream, upstream: TcpStream) -> io::Result<()> {
+    let mut client_reader = client.try_clone()?;
+    let mut upstream_writer = upstream.try_clone()?;
+    let outgoing = thread::spawn(move || {
+        let copied = io::copy(&mut client_reader, &mut upstream_writer);
+        let _ = upstream_writer.shutdown(Shutdown::Write);
+        copied
+    });
+
+    let (mut upstream_reader, mut client_writer) = (upstream, client);
+    let incoming = io::copy(&mut upstream_reader, &mut client_writer);
+    let _ = client_writer.shutdown(Shutdown::Write);
+
+    let outgoing = outgoing.join().unwrap_or_else(|_| Err(io::Error::other("tunnel thread panicked")));
+    incoming.and(outgoing).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method, ResponseBuilder, StatusCode};
+    use crate::router::{exchange, Router};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn tunnel_to(pattern: &str) -> Tunnel {
+        Tunnel { pattern: pattern.to_string(), handler: Arc::new(|_, _| Ok(())) }
+    }
+
+    #[test]
+    fn host_patterns() {
+        assert!(tunnel_to("api.example.com").matches("API.example.com:443"));
+        assert!(!tunnel_to("api.example.com").matches("api.example.com.evil:443"));
+        assert!(tunnel_to("*.example.com").matches("a.b.example.com:443"));
+        assert!(!tunnel_to("*.example.com").matches("example.com:443"));
+        assert!(!tunnel_to("*.example.com").matches("badexample.com:443"));
+        assert!(tunnel_to("db.internal:5432").matches("db.internal:5432"));
+        assert!(!tunnel_to("db.internal:5432").matches("db.internal:22"));
+    }
+
+    #[test]
+    fn disallowed_hosts_are_forbidden() {
+        let mut router = Router::new();
+        router.connect_tunnel("*.example.com", |_, _| Ok(()));
+        router.get("/", |_, response| response.send(&mut ResponseBuilder::new(StatusCode::Ok)));
+
+        let response = exchange(&router, "CONNECT evil.test:443 HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{:?}", response);
+        assert_eq!(Method::from_token("CONNECT"), Method::Connect);
+    }
+
+    #[test]
+    fn tunnels_bytes_both_ways() {
+        // an upstream that answers "pong" to "ping".
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut ping = [0; 4];
+            stream.read_exact(&mut ping).unwrap();
+            assert_eq!(&ping, b"ping");
+            stream.write_all(b"pong").unwrap();
+        });
+
+        let mut router = Router::new();
+        router.connect_tunnel("localhost", move |_, client| tunnel(client, TcpStream::connect(upstream_addr)?));
+        let router = Arc::new(router);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let proxy = thread::spawn(move || {
+            let request = Request::parse(b"CONNECT localhost:443 HTTP/1.1\r\n\r\n").unwrap();
+            router.dispatch(&request, &mut server).unwrap()
+        });
+
+        let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+        let mut head = vec![0; established.len()];
+        client.read_exact(&mut head).unwrap();
+        assert_eq!(head, established);
+        client.write_all(b"ping").unwrap();
+        let mut pong = String::new();
+        client.read_to_string(&mut pong).unwrap();
+        assert_eq!(pong, "pong");
+
+        client.shutdown(Shutdown::Write).unwrap();
+        // the connection was taken over: not to be used for another request.
+        assert!(!proxy.join().unwrap());
+    }
+}
@@ -0,0 +1,78 @@
+use crate::http::{ResponseBuilder, StatusCode};
+
+/* # /favicon.ico without the 404s!
+router.serve_favicon(BLANK_FAVICON);                      // or your own bytes
+router.serve_favicon_from_file(Path::new("favicon.ico"))?;  // read once, at startup
+Browsers ask every site for /favicon.ico; answering with an icon (image/x-icon,
+cached for a day) keeps those requests from filling the log with 404s. */
+
+pub(super) const FAVICON_PATH: &str = "/favicon.ico";
+const FAVICON_CACHE_CONTROL: &str = "public, max-age=86400";
+
+// A 1x1 transparent ICO: the directory (6 bytes), one entry (16), then the image itself:
+// a BITMAPINFOHEADER (40; twice the height, for the two masks), one BGRA pixel (4)
+// and the AND mask's single row, padded to 32 bits (4).
+pub const BLANK_FAVICON: &[u8] = &[
+    // ICONDIR: reserved, type 1 (icon), 1 image
+    0x00, 0x00, 0x01, 0x00, 0x01, 0x00,
+    // ICONDIRENTRY: 1x1, no palette, reserved, 1 plane, 32 bits per pixel, 48 bytes at offset 22
+    0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x20, 0x00, 0x30, 0x00, 0x00, 0x00, 0x16, 0x00, 0x00, 0x00,
+    // BITMAPINFOHEADER: 40 bytes, 1 wide, 2 high, 1 plane, 32 bits, uncompressed, the rest unset
+    0x28, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x20, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // the pixel: fully transparent
+    0x00, 0x00, 0x00, 0x00,
+    // AND mask: transparent too, for readers that ignore the alpha channel
+    0x80, 0x00, 0x00, 0x00,
+];
+
+pub(super) fn favicon_response(icon: &[u8]) -> ResponseBuilder {
+    let mut response = ResponseBuilder::new(StatusCode::Ok);
+    response
+        .header("Content-Type", "image/x-icon")
+        .header("Cache-Control", FAVICON_CACHE_CONTROL)
+        .body_bytes(icon.to_vec());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{exchange, exchange_bytes, Router};
+    use std::fs;
+
+    #[test]
+    fn blank_favicon_is_a_well_formed_ico() {
+        assert_eq!(BLANK_FAVICON.len(), 70);
+        assert_eq!(&BLANK_FAVICON[..6], &[0, 0, 1, 0, 1, 0]);
+        // size and offset in the entry add up to the whole file.
+        let size = u32::from_le_bytes(BLANK_FAVICON[14..18].try_into().unwrap()) as usize;
+        let offset = u32::from_le_bytes(BLANK_FAVICON[18..22].try_into().unwrap()) as usize;
+        assert_eq!(offset + size, BLANK_FAVICON.len());
+    }
+
+    #[test]
+    fn serves_the_icon() {
+        let mut router = Router::new();
+        router.serve_favicon(BLANK_FAVICON);
+
+        let response = exchange_bytes(&router, "GET /favicon.ico HTTP/1.1\r\n\r\n");
+        let head = b"HTTP/1.1 200 OK\r\nContent-Type: image/x-icon\r\nCache-Control: public, max-age=86400\r\nContent-Length: 70\r\n\r\n";
+        assert_eq!(&response[..head.len()], head);
+        assert_eq!(&response[head.len()..], BLANK_FAVICON);
+    }
+
+    #[test]
+    fn serves_the_icon_from_a_file() {
+        let path = std::env::temp_dir().join(format!("surff-favicon-{}.ico", std::process::id()));
+        fs::write(&path, b"icon").unwrap();
+        let mut router = Router::new();
+        router.serve_favicon_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // read at startup: the file is gone, the icon isn't.
+        assert!(exchange(&router, "GET /favicon.ico HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nicon"));
+        assert!(Router::new().serve_favicon_from_file(&path).is_err());
+    }
+}
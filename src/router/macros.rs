@@ -0,0 +1,80 @@
+use crate::http::Method;
+
+/* # A routing table at a glance!
+let router = surff_router! {
+    GET "/" => home,
+    post "/login" => login,
+    DELETE "/users" => delete_user,
+};
+=> Router::new(), then router.route(Method::Get, "/", home) and so on, in that order.
+Method names are case-insensitive. Anything else (GTE, PUSH, ...) doesn't compile:
+the name is turned into a Method in a const, and the const panics on names it doesn't know. */
+
+#[macro_export]
+macro_rules! surff_router {
+    ($($method:ident $path:literal => $handler:expr),* $(,)?) => {{
+        let mut router = $crate::router::Router::new();
+        $(
+            {
+                const METHOD: $crate::http::Method = $crate::router::method_named(stringify!($method));
+                router.route(METHOD, $path, $handler);
+            }
+        )*
+        router
+    }};
+}
+
+// For surff_router!: only ever called in a const, where the panic is a compile error.
+#[doc(hidden)]
+pub const fn method_named(name: &str) -> Method {
+    if name.eq_ignore_ascii_case("GET") {
+        Method::Get
+    } else if name.eq_ignore_ascii_case("HEAD") {
+        Method::Head
+    } else if name.eq_ignore_ascii_case("POST") {
+        Method::Post
+    } else if name.eq_ignore_ascii_case("PUT") {
+        Method::Put
+    } else if name.eq_ignore_ascii_case("DELETE") {
+        Method::Delete
+    } else if name.eq_ignore_ascii_case("PATCH") {
+        Method::Patch
+    } else if name.eq_ignore_ascii_case("OPTIONS") {
+        Method::Options
+    } else if name.eq_ignore_ascii_case("CONNECT") {
+        Method::Connect
+    } else {
+        panic!("surff_router!: unknown HTTP method (expected GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS or CONNECT)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Request, ResponseBuilder, StatusCode};
+    use crate::router::{exchange, Response};
+    use std::io;
+
+    fn home(_: &Request, response: &mut Response) -> io::Result<()> {
+        response.send(ResponseBuilder::new(StatusCode::Ok).body_str("home"))
+    }
+
+    #[test]
+    fn method_names_are_case_insensitive() {
+        assert_eq!(method_named("get"), Method::Get);
+        assert_eq!(method_named("Delete"), Method::Delete);
+        assert_eq!(method_named("OPTIONS"), Method::Options);
+    }
+
+    #[test]
+    fn macro_builds_the_table() {
+        let router = surff_router! {
+            GET "/" => home,
+            post "/login" => |_, response| response.send(&mut ResponseBuilder::new(StatusCode::Created)),
+        };
+
+        assert!(exchange(&router, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nhome"));
+        assert!(exchange(&router, "POST /login HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 201 Created"));
+        assert!(exchange(&router, "PUT /login HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 Method Not Allowed"));
+    }
+}
@@ -0,0 +1,476 @@
+use std::fs;
+use std::io;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::http::{Method, Request, ResponseBuilder, StatusCode};
+use connect::Tunnel;
+use upgrade::Upgrade;
+
+mod connect;
+mod favicon;
+mod macros;
+mod plugin;
+mod response;
+mod routes;
+mod stream;
+mod transform;
+mod upgrade;
+mod version;
+
+#[doc(hidden)]
+pub use macros::method_named;
+pub use connect::tunnel;
+pub use favicon::BLANK_FAVICON;
+pub use plugin::{Plugin, PluginRegistry};
+pub use response::{BodyWriter, Output, Response};
+pub use routes::{FileRoute, RouteTarget, Routes};
+pub(crate) use response::{capture, HeadGate};
+pub use transform::{BodyTransformer, SignatureVerifier, TransformError};
+pub use upgrade::UpgradeHandler;
+pub use version::VersionRouter;
+
+/* # Routing requests to handlers!
+let mut router = Router::new();
+router.route(Method::Get, "/", |request, response| { ... });
+router.post("/login", login);         // = router.route(Method::Post, "/login", login)
+router.post("/hook", hook).body_transformer(SignatureVerifier::new(b"secret"));  // see transform.rs
+router.wrap(ServerHeaderMiddleware::default());
+router.dispatch(&request, &mut stream)
+1. A route whose method and path match the request exactly wins.
+2. Otherwise the first route (in the order they were added) whose path is a
+prefix of the request path, on a segment boundary: /static matches /static/app.js,
+but not /staticfiles. "/" only ever matches "/" exactly, or it would match everything.
+Routes read from a file (Routes::from_toml, see routes.rs) are only looked at, the same
+way, once 1. and 2. found no other route: routes added in code win, even later ones.
+3. No route for HEAD => the GET route, as if it were a GET. The handler still sees
+Method::Head; Response leaves the body out.
+4. The path matches routes, but none for this method => OPTIONS gets a 200 (without a body),
+anything else a 405 Method Not Allowed. Both list the path's methods in an Allow header,
+with HEAD (if there's a GET) and OPTIONS added. Routes for HEAD or OPTIONS themselves
+take precedence over these defaults.
+5. Nothing matched at all => the not-found handler, which writes a bare 404 unless replaced.
+CONNECT requests don't go to routes at all, but to tunnels (connect_tunnel, see connect.rs),
+and neither do requests to switch protocols once there are upgrade handlers (see upgrade.rs).
+Handlers are kept behind Arc, so cloning a Router is cheap.
+
+# Middleware!
+Every request goes through the layers added with wrap() before it's routed, and every
+response head on its way out, the default 404/405s included. The last layer wrapped is the
+outermost: its before() runs first and its on_response() last, so it sees the head exactly
+as it's sent. A route can have layers of its own too (router.get(..).middleware(..)),
+which run inside the router's, once the route has been found. Plugins (see plugin.rs)
+are a simpler kind of layer, outside all of them. */
+
+type Handler = Arc<dyn Fn(&Request, &mut Response) -> io::Result<()> + Send + Sync + 'static>;
+
+pub trait Middleware: Send + Sync {
+    // Ok(false): the middleware has answered the request itself (through `response`),
+    // and neither the inner layers nor the handler get to see it.
+    fn before(&self, _request: &Request, _response: &mut Response) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    // Every response head, whoever sent it, just before it's written.
+    fn on_response(&self, _request: &Request, _response: &mut ResponseBuilder) {}
+}
+
+#[derive(Clone)]
+struct Route {
+    method: Method,
+    path: String,
+    handler: Handler,
+    transformers: Vec<Arc<dyn BodyTransformer>>,
+    // like the router's, but only for this route, and inside them.
+    layers: Vec<Arc<dyn Middleware>>,
+    // only tried once no other route matches (routes from a file, see routes.rs).
+    fallback: bool,
+}
+
+// What route() and the shortcuts return, for setting up the route they just added.
+pub struct RouteOptions<'a> {
+    route: &'a mut Route,
+}
+
+impl RouteOptions<'_> {
+    // Rewrites the body before the handler is called; see transform.rs.
+    pub fn body_transformer(self, transformer: impl BodyTransformer + 'static) -> Self {
+        self.route.transformers.push(Arc::new(transformer));
+        self
+    }
+
+    // Only for this route: runs after the router's layers (and the transformers after it),
+    // e.g. RequireRole for the routes that need one.
+    pub fn middleware(self, middleware: impl Middleware + 'static) -> Self {
+        self.route.layers.push(Arc::new(middleware));
+        self
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Option<Handler>,
+    tunnels: Vec<Tunnel>,
+    upgrades: Vec<Upgrade>,
+    layers: Vec<Arc<dyn Middleware>>,
+    plugins: Arc<PluginRegistry>,
+}
+
+macro_rules! method_shortcut {
+    ($name:ident, $method:ident) => {
+        pub fn $name<F>(&mut self, path: &str, handler: F) -> RouteOptions<'_>
+            where
+                F: Fn(&Request, &mut Response) -> io::Result<()> + Send + Sync + 'static
+        {
+            self.route(Method::$method, path, handler)
+        }
+    };
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    pub fn route<F>(&mut self, method: Method, path: &str, handler: F) -> RouteOptions<'_>
+        where
+            F: Fn(&Request, &mut Response) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.routes.push(Route {
+            method,
+            path: path.to_string(),
+            handler: Arc::new(handler),
+            transformers: Vec::new(),
+            layers: Vec::new(),
+            fallback: false,
+        });
+        let route = self.routes.last_mut().expect("a route was just added");
+        RouteOptions { route }
+    }
+
+    // A route the others win over, whenever they were added.
+    fn fallback_route<F>(&mut self, method: Method, path: &str, handler: F)
+        where
+            F: Fn(&Request, &mut Response) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.route(method, path, handler).route.fallback = true;
+    }
+
+    // router.get("/", handler) is router.route(Method::Get, "/", handler), and so on.
+    method_shortcut!(get, Get);
+    method_shortcut!(post, Post);
+    method_shortcut!(put, Put);
+    method_shortcut!(delete, Delete);
+    method_shortcut!(patch, Patch);
+    method_shortcut!(head, Head);
+    // Takes precedence over the automatic OPTIONS answer.
+    method_shortcut!(options, Options);
+
+    // CONNECT requests for hosts matching `host_pattern` ("api.example.com", "*.example.com").
+    pub fn connect_tunnel<F>(&mut self, host_pattern: &str, handler: F)
+        where
+            F: Fn(&Request, TcpStream) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.tunnels.push(Tunnel {
+            pattern: host_pattern.to_string(),
+            handler: Arc::new(handler),
+        });
+    }
+
+    // Requests with Upgrade: <protocol>, e.g. "websocket" or "h2c".
+    pub fn on_upgrade(&mut self, protocol: &str, handler: impl UpgradeHandler + 'static) {
+        self.upgrades.push(Upgrade {
+            protocol: protocol.to_ascii_lowercase(),
+            handler: Arc::new(handler),
+        });
+    }
+
+    // GET /favicon.ico => `icon`, e.g. surff::BLANK_FAVICON (see favicon.rs).
+    pub fn serve_favicon(&mut self, icon: &'static [u8]) {
+        self.get(favicon::FAVICON_PATH, move |_, response| response.send(&mut favicon::favicon_response(icon)));
+    }
+
+    // Reads the icon now, so a missing file fails at startup rather than on every request.
+    pub fn serve_favicon_from_file(&mut self, path: &Path) -> io::Result<()> {
+        let icon = fs::read(path)?;
+        self.get(favicon::FAVICON_PATH, move |_, response| response.send(&mut favicon::favicon_response(&icon)));
+        Ok(())
+    }
+
+    // GET `path` => the body is whatever the iterator yields, chunk by chunk (see stream.rs).
+    pub fn stream<F, I>(&mut self, path: &str, handler: F)
+        where
+            F: Fn(&Request) -> I + Send + Sync + 'static,
+            I: Iterator<Item = Vec<u8>>
+    {
+        self.get(path, move |request, response| stream::respond(request, response, || handler(request)));
+    }
+
+    // Replaces the default 404 response.
+    pub fn not_found<F>(&mut self, handler: F)
+        where
+            F: Fn(&Request, &mut Response) -> io::Result<()> + Send + Sync + 'static
+    {
+        self.not_found = Some(Arc::new(handler));
+    }
+
+    pub fn wrap(&mut self, middleware: impl Middleware + 'static) {
+        self.layers.push(Arc::new(middleware));
+    }
+
+    // router.plugins().register(Box::new(plugin)); see plugin.rs.
+    pub fn plugins(&mut self) -> &mut PluginRegistry {
+        Arc::make_mut(&mut self.plugins)
+    }
+
+    // Ok(false) when the connection can't carry another request: the handler took it over,
+    // or the response asked for it to be closed.
+    pub fn dispatch(&self, request: &Request, stream: &mut TcpStream) -> io::Result<bool> {
+        self.dispatch_to(request, Output::Stream(stream), false, None)
+    }
+
+    // dispatch, to a buffer when the request is pipelined (see server/pipeline.rs), with
+    // the server's say on whether the head may still go out, and Connection: close
+    // from the start if `close` (the server is draining).
+    pub(crate) fn dispatch_to(&self, request: &Request, output: Output, close: bool, gate: Option<HeadGate>) -> io::Result<bool> {
+        let mut response = Response::new(output, request, gate);
+        if close {
+            response.close();
+        }
+        self.respond(request, &mut response)?;
+
+        if !response.head_sent() && !response.taken_over() {
+            // a handler that returned without answering would leave the client waiting.
+            response.close();
+            response.send(&mut ResponseBuilder::new(StatusCode::InternalServerError))?;
+        }
+        Ok(response.keep_alive())
+    }
+
+    // dispatch without the bookkeeping, for routers inside routers (VersionRouter).
+    pub fn respond(&self, request: &Request, response: &mut Response) -> io::Result<()> {
+        if self.plugins.is_empty() {
+            return self.respond_layered(request, response);
+        }
+        // added first, so the router's own layers go inside it.
+        response.add_layers(&[Arc::clone(&self.plugins) as Arc<dyn Middleware>]);
+        let mut request = request.clone();
+        if let Some(mut answer) = self.plugins.on_request(&mut request) {
+            return response.send(&mut answer);
+        }
+        match self.respond_layered(&request, response) {
+            Err(e) if !response.head_sent() => match self.plugins.on_error(&request, &e) {
+                Some(mut answer) => response.send(&mut answer),
+                None => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    fn respond_layered(&self, request: &Request, response: &mut Response) -> io::Result<()> {
+        response.add_layers(&self.layers);
+        for layer in self.layers.iter().rev() {
+            if !layer.before(request, response)? {
+                return Ok(());
+            }
+        }
+
+        if request.method == Method::Connect {
+            return self.open_tunnel(request, response);
+        }
+        if !self.upgrades.is_empty() && request.header("Upgrade").is_some() {
+            return self.switch_protocols(request, response);
+        }
+
+        let route = self.find(&request.method, &request.path).or_else(|| match request.method {
+            Method::Head => self.find(&Method::Get, &request.path),
+            _ => None,
+        });
+        if let Some(route) = route {
+            return call(route, request, response);
+        }
+
+        let allowed = self.allowed_methods(&request.path);
+        if allowed.is_empty() {
+            return match &self.not_found {
+                Some(not_found) => not_found(request, response),
+                None => response.send(&mut ResponseBuilder::new(StatusCode::NotFound)),
+            };
+        }
+
+        let status = match request.method {
+            Method::Options => StatusCode::Ok,
+            _ => StatusCode::MethodNotAllowed,
+        };
+        response.send(ResponseBuilder::new(status).header("Allow", &allowed.join(", ")))
+    }
+
+    fn open_tunnel(&self, request: &Request, response: &mut Response) -> io::Result<()> {
+        let tunnel = match self.tunnels.iter().find(|tunnel| tunnel.matches(&request.path)) {
+            Some(tunnel) => tunnel,
+            None => return response.send(&mut ResponseBuilder::new(StatusCode::Forbidden)),
+        };
+        response.send(
+            ResponseBuilder::new(StatusCode::Ok)
+                .reason("Connection Established")
+                .no_content_length(),
+        )?;
+        let stream = response.take_over()?;
+        (tunnel.handler)(request, stream)
+    }
+
+    fn switch_protocols(&self, request: &Request, response: &mut Response) -> io::Result<()> {
+        let requested = upgrade::requested_protocols(request);
+        let upgrade = self
+            .upgrades
+            .iter()
+            .find(|upgrade| requested.iter().any(|protocol| protocol.eq_ignore_ascii_case(&upgrade.protocol)));
+        let upgrade = match upgrade {
+            Some(upgrade) => upgrade,
+            None => {
+                let supported: Vec<&str> = self.upgrades.iter().map(|upgrade| upgrade.protocol.as_str()).collect();
+                return response.send(
+                    ResponseBuilder::new(StatusCode::UpgradeRequired)
+                        .header("Upgrade", &supported.join(", "))
+                        .header("Connection", "Upgrade"),
+                );
+            }
+        };
+
+        let mut switching = ResponseBuilder::new(StatusCode::SwitchingProtocols);
+        switching.header("Upgrade", &upgrade.protocol).header("Connection", "Upgrade");
+        for (name, value) in upgrade.handler.response_headers(request) {
+            switching.header(&name, &value);
+        }
+        response.send(&mut switching)?;
+        let stream = response.take_over()?;
+        upgrade.handler.on_upgrade(request, stream)
+    }
+
+    fn find(&self, method: &Method, path: &str) -> Option<&Route> {
+        [false, true].into_iter().find_map(|fallback| {
+            let candidates = || self.routes.iter().filter(|route| route.method == *method && route.fallback == fallback);
+            candidates()
+                .find(|route| route.path == path)
+                .or_else(|| candidates().find(|route| is_prefix(&route.path, path)))
+        })
+    }
+
+    // Methods with a route for `path`, in the order they were added; empty if there are none.
+    fn allowed_methods(&self, path: &str) -> Vec<&str> {
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in &self.routes {
+            if (route.path == path || is_prefix(&route.path, path)) && !allowed.contains(&route.method.as_str()) {
+                allowed.push(route.method.as_str());
+            }
+        }
+        if allowed.is_empty() {
+            return allowed;
+        }
+
+        if allowed.contains(&Method::Get.as_str()) && !allowed.contains(&Method::Head.as_str()) {
+            allowed.push(Method::Head.as_str());
+        }
+        if !allowed.contains(&Method::Options.as_str()) {
+            allowed.push(Method::Options.as_str());
+        }
+        allowed
+    }
+}
+
+// The route's own layers, its transformers, then its handler.
+fn call(route: &Route, request: &Request, response: &mut Response) -> io::Result<()> {
+    response.add_layers(&route.layers);
+    for layer in route.layers.iter().rev() {
+        if !layer.before(request, response)? {
+            return Ok(());
+        }
+    }
+
+    if route.transformers.is_empty() {
+        return (route.handler)(request, response);
+    }
+    match transform::apply(&route.transformers, request) {
+        Ok(transformed) => (route.handler)(&transformed, response),
+        Err(e) => response.send(
+            ResponseBuilder::new(StatusCode::BadRequest)
+                .header("Content-Type", "text/plain")
+                .body_str(&format!("{}\n", e)),
+        ),
+    }
+}
+
+// On a segment boundary: /static is a prefix of /static/app.js, not of /staticfiles.
+pub(crate) fn is_prefix(route: &str, path: &str) -> bool {
+    let route = route.trim_end_matches('/');
+    !route.is_empty()
+        && path.starts_with(route)
+        && path[route.len()..].starts_with('/')
+}
+
+// Dispatches `raw` over a real socket pair and returns everything that was written back.
+#[cfg(test)]
+pub(crate) fn exchange(router: &Router, raw: &str) -> String {
+    String::from_utf8_lossy(&exchange_bytes(router, raw)).into_owned()
+}
+
+#[cfg(test)]
+pub(crate) fn exchange_bytes(router: &Router, raw: &str) -> Vec<u8> {
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    // The router only writes; sending it bytes it never reads would make the close a reset.
+    // Whatever follows the head is the body, as the server would have read it.
+    let mut request = Request::parse(raw.as_bytes()).unwrap();
+    if let Some((_, body)) = raw.split_once("\r\n\r\n") {
+        request.body = body.as_bytes().to_vec();
+    }
+    router.dispatch(&request, &mut server).unwrap();
+    drop(server);
+
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/", |_, response| response.send(ResponseBuilder::new(StatusCode::Ok).body_str("hello")));
+        router.post("/", |_, response| response.send(&mut ResponseBuilder::new(StatusCode::NoContent)));
+        router
+    }
+
+    #[test]
+    fn head_is_get_without_the_body() {
+        let get = exchange(&router(), "GET / HTTP/1.1\r\n\r\n");
+        let head = exchange(&router(), "HEAD / HTTP/1.1\r\n\r\n");
+        assert!(get.ends_with("\r\n\r\nhello"), "{:?}", get);
+        // the same head, Content-Length included.
+        assert_eq!(head, get.trim_end_matches("hello"));
+        assert!(head.contains("\r\nContent-Length: 5\r\n"));
+    }
+
+    #[test]
+    fn options_and_other_methods_get_the_allowed_ones() {
+        let response = exchange(&router(), "OPTIONS / HTTP/1.1\r\n\r\n");
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nAllow: GET, POST, HEAD, OPTIONS\r\nContent-Length: 0\r\n\r\n");
+
+        let response = exchange(&router(), "DELETE / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nAllow: GET, POST, HEAD, OPTIONS\r\n"));
+
+        // a path nobody routes is still a 404, whatever the method.
+        let response = exchange(&router(), "DELETE /nothing HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{:?}", response);
+    }
+}
@@ -0,0 +1,183 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use super::Middleware;
+use crate::http::{Request, ResponseBuilder};
+
+/* # Plugins: middleware without the Response plumbing!
+struct ApiVersion;
+impl Plugin for ApiVersion {
+    fn name(&self) -> &str { "api-version" }
+    fn on_response(&self, _: &Request, response: &mut ResponseBuilder) {
+        response.header("X-Api-Version", "3");
+    }
+}
+router.plugins().register(Box::new(ApiVersion));
+A plugin works on the request and the ResponseBuilder only; everything but name()
+has a default that does nothing. They run in the order they were registered:
+1. on_request, before anything else the router does, may change the request (the
+layers and the handler get the changed one) or answer it: the first Some(response)
+is sent, and nothing after it runs, plugins included.
+2. on_response, for every head, after the router's layers (the plugins are its
+outermost layer). It sees the request as it arrived, before on_request changed it.
+3. on_error, when the handler fails before its head went out: the first
+Some(response) is sent instead of the error, which is otherwise passed on. */
+
+pub trait Plugin {
+    fn name(&self) -> &str;
+
+    fn on_request(&self, _request: &mut Request) -> Option<ResponseBuilder> {
+        None
+    }
+
+    fn on_response(&self, _request: &Request, _response: &mut ResponseBuilder) {}
+
+    fn on_error(&self, _request: &Request, _error: &dyn Error) -> Option<ResponseBuilder> {
+        None
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct PluginRegistry {
+    plugins: Vec<Arc<dyn Plugin + Send + Sync>>,
+}
+
+impl PluginRegistry {
+    pub fn register(&mut self, plugin: Box<dyn Plugin + Send + Sync>) {
+        self.plugins.push(Arc::from(plugin));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.plugins.iter().map(|plugin| plugin.name()).collect()
+    }
+
+    pub(super) fn on_request(&self, request: &mut Request) -> Option<ResponseBuilder> {
+        self.plugins.iter().find_map(|plugin| plugin.on_request(request))
+    }
+
+    pub(super) fn on_error(&self, request: &Request, error: &dyn Error) -> Option<ResponseBuilder> {
+        self.plugins.iter().find_map(|plugin| plugin.on_error(request, error))
+    }
+}
+
+impl Middleware for PluginRegistry {
+    fn on_response(&self, request: &Request, response: &mut ResponseBuilder) {
+        for plugin in &self.plugins {
+            plugin.on_response(request, response);
+        }
+    }
+}
+
+impl fmt::Debug for PluginRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PluginRegistry").field("plugins", &self.names()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::StatusCode;
+    use crate::router::{exchange, Response, Router};
+    use std::io;
+
+    // Adds its name to X-Seen on the way in, and to X-Plugins on the way out.
+    struct Tag(&'static str);
+
+    impl Plugin for Tag {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn on_request(&self, request: &mut Request) -> Option<ResponseBuilder> {
+            let seen = request.header("X-Seen").map_or(self.0.to_string(), |seen| format!("{},{}", seen, self.0));
+            request.headers.retain(|(name, _)| name != "X-Seen");
+            request.headers.push(("X-Seen".to_string(), seen));
+            None
+        }
+
+        fn on_response(&self, _request: &Request, response: &mut ResponseBuilder) {
+            let plugins = response.header_value("X-Plugins").map_or(self.0.to_string(), |seen| format!("{},{}", seen, self.0));
+            response.set_header("X-Plugins", &plugins);
+        }
+    }
+
+    struct DenyAll;
+
+    impl Plugin for DenyAll {
+        fn name(&self) -> &str {
+            "deny"
+        }
+
+        fn on_request(&self, request: &mut Request) -> Option<ResponseBuilder> {
+            request.header("Authorization").is_none().then(|| ResponseBuilder::new(StatusCode::Unauthorized))
+        }
+    }
+
+    struct Apologize;
+
+    impl Plugin for Apologize {
+        fn name(&self) -> &str {
+            "apologize"
+        }
+
+        fn on_error(&self, _request: &Request, error: &dyn Error) -> Option<ResponseBuilder> {
+            let mut response = ResponseBuilder::new(StatusCode::InternalServerError);
+            response.body_str(&format!("sorry: {}", error));
+            Some(response)
+        }
+    }
+
+    struct Layer;
+
+    impl Middleware for Layer {
+        fn on_response(&self, _request: &Request, response: &mut ResponseBuilder) {
+            response.set_header("X-Plugins", "layer");
+        }
+    }
+
+    fn echo_seen(request: &Request, response: &mut Response) -> io::Result<()> {
+        response.send(ResponseBuilder::new(StatusCode::Ok).body_str(request.header("X-Seen").unwrap_or("")))
+    }
+
+    #[test]
+    fn plugins_run_in_registration_order_outside_the_layers() {
+        let mut router = Router::new();
+        router.get("/", echo_seen);
+        router.wrap(Layer);
+        router.plugins().register(Box::new(Tag("a")));
+        router.plugins().register(Box::new(Tag("b")));
+        assert_eq!(router.plugins().names(), ["a", "b"]);
+
+        let response = exchange(&router, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nX-Plugins: layer,a,b\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\na,b"), "{:?}", response);
+    }
+
+    #[test]
+    fn on_request_can_answer_and_on_error_can_replace_the_error() {
+        let mut router = Router::new();
+        router.get("/", echo_seen);
+        router.get("/fails", |_, _| Err(io::Error::other("the disk is gone")));
+        router.plugins().register(Box::new(DenyAll));
+        router.plugins().register(Box::new(Tag("after")));
+        router.plugins().register(Box::new(Apologize));
+
+        let response = exchange(&router, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{:?}", response);
+        // on_response still runs; the later on_requests don't.
+        assert!(response.contains("\r\nX-Plugins: after\r\n"), "{:?}", response);
+
+        let response = exchange(&router, "GET / HTTP/1.1\r\nAuthorization: yes\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\nafter"), "{:?}", response);
+
+        let response = exchange(&router, "GET /fails HTTP/1.1\r\nAuthorization: yes\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\nsorry: the disk is gone"), "{:?}", response);
+    }
+}
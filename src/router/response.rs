@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{self, prelude::*, SeekFrom};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+
+use super::Middleware;
+use crate::http::{ByteRange, ChunkedResponseWriter, Method, Request, ResponseBuilder, StatusCode};
+use crate::os;
+
+/* # Answering a request!
+router.route(Method::Get, "/", |request, response| {
+    response.send(ResponseBuilder::new(StatusCode::Ok).body_str("Hi!"))
+});
+Handlers don't write to the TcpStream themselves: they hand their ResponseBuilder
+to the Response, which lets every middleware (Router::wrap) look at the head
+first, and leaves the body out for HEAD requests.
+1. send: a whole response, body included.
+2. start_chunked: the head now, the body in chunks after (ChunkedResponseWriter).
+3. send_file: the head with the file's length, then the file itself (os::send_file);
+send_file_range: a 206 with only part of it, for Range requests (see http/range.rs).
+4. start_body: the head with a length of the handler's choosing, then the body as it
+writes it (BodyWriter), e.g. to report progress (see download.rs).
+5. take_over: the connection itself, for tunnels and upgraded protocols; the
+server forgets about it once the handler returns.
+There's one response per request: sending a second head is an error.
+A pipelined request (see server/pipeline.rs) is answered into a buffer instead of
+the connection (Output::Buffer), which goes out once the responses before it have:
+everything works the same, except take_over, which is an error then. */
+
+// Where a response is written to.
+pub enum Output<'a> {
+    Stream(&'a mut TcpStream),
+    Buffer { buffer: &'a mut Vec<u8>, peer: SocketAddr },
+}
+
+impl Write for Output<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stream(stream) => stream.write(buf),
+            Output::Buffer { buffer, .. } => buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stream(stream) => stream.flush(),
+            Output::Buffer { .. } => Ok(()),
+        }
+    }
+}
+
+// Asked right before a head goes out, with its status; an Err stops it (see the handler
+// timeout in server/mod.rs), Ok(true) closes the connection after it (a drain that
+// started while the handler ran).
+pub(crate) type HeadGate<'a> = &'a (dyn Fn(StatusCode) -> io::Result<bool> + Sync);
+
+pub struct Response<'a> {
+    output: Output<'a>,
+    gate: Option<HeadGate<'a>>,
+    request: &'a Request,
+    // innermost first: a mounted router's layers go in front of those of the router it's in.
+    layers: Vec<Arc<dyn Middleware>>,
+    head_sent: bool,
+    close: bool,
+    taken_over: bool,
+}
+
+impl<'a> Response<'a> {
+    pub(crate) fn new(output: Output<'a>, request: &'a Request, gate: Option<HeadGate<'a>>) -> Response<'a> {
+        Response {
+            output,
+            gate,
+            request,
+            layers: Vec::new(),
+            head_sent: false,
+            close: false,
+            taken_over: false,
+        }
+    }
+
+    pub fn send(&mut self, response: &mut ResponseBuilder) -> io::Result<()> {
+        self.prepare(response)?;
+        response.write_to(&mut self.output)
+    }
+
+    // Not for HEAD requests: there'd be chunks after a head that promises no body.
+    pub fn start_chunked(&mut self, mut response: ResponseBuilder) -> io::Result<ChunkedResponseWriter<'_, Output<'a>>> {
+        // prepare first, so the middleware can't take Transfer-Encoding away again.
+        self.prepare(&mut response)?;
+        ChunkedResponseWriter::start(&mut self.output, response)
+    }
+
+    // Content-Length is `length`, and the head goes out now; `response`'s body, if any, is ignored.
+    // Writing more than `length` is an error, and so is finishing (or dropping) the writer
+    // before all of it was written, which also closes the connection. HEAD requests get
+    // the same head, and what's written is dropped.
+    pub fn start_body(&mut self, mut response: ResponseBuilder, length: u64) -> io::Result<BodyWriter<'_, 'a>> {
+        response.set_header("Content-Length", &length.to_string());
+        response.body_bytes(Vec::new());
+        self.send(&mut response)?;
+        let discard = self.request.method == Method::Head;
+        Ok(BodyWriter { response: self, remaining: length, discard })
+    }
+
+    // Content-Length is `length`; a file that turns out shorter is an error (and closes the
+    // connection), since the client would otherwise read the next response as part of this one.
+    pub fn send_file(&mut self, response: &mut ResponseBuilder, file: &File, length: u64) -> io::Result<()> {
+        self.send_file_part(response, file, 0, length)
+    }
+
+    // `range` of a file `total` bytes long, as a 206 Partial Content with its Content-Range;
+    // the range must be within the file (RangeRequest::of makes sure of it).
+    pub fn send_file_range(&mut self, response: &mut ResponseBuilder, file: &File, total: u64, range: ByteRange) -> io::Result<()> {
+        response
+            .set_status(StatusCode::PartialContent)
+            .set_header("Content-Range", &range.content_range(total));
+        self.send_file_part(response, file, range.start, range.length())
+    }
+
+    fn send_file_part(&mut self, response: &mut ResponseBuilder, file: &File, offset: u64, length: u64) -> io::Result<()> {
+        response.set_header("Content-Length", &length.to_string());
+        self.send(response)?;
+        if self.request.method == Method::Head {
+            return Ok(());
+        }
+
+        let sent = match &mut self.output {
+            Output::Stream(stream) => os::send_file(file, stream, offset, length)?,
+            Output::Buffer { buffer, .. } => {
+                let mut file = file;
+                file.seek(SeekFrom::Start(offset))?;
+                file.take(length).read_to_end(buffer)? as u64
+            }
+        };
+        if sent < length {
+            self.close = true;
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("file shrank while being sent ({} of {} bytes)", sent, length),
+            ));
+        }
+        Ok(())
+    }
+
+    // The connection, for the handler to keep once it returns (a clone of the same socket).
+    // The server's read timeout is lifted: how long to wait is up to the new owner.
+    pub fn take_over(&mut self) -> io::Result<TcpStream> {
+        let Output::Stream(stream) = &self.output else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "a pipelined request can't take over the connection"));
+        };
+        let stream = stream.try_clone()?;
+        stream.set_read_timeout(None)?;
+        self.taken_over = true;
+        Ok(stream)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match &self.output {
+            Output::Stream(stream) => stream.peer_addr(),
+            Output::Buffer { peer, .. } => Ok(*peer),
+        }
+    }
+
+    // None for a pipelined request, answered into a buffer.
+    pub fn stream(&self) -> Option<&TcpStream> {
+        match &self.output {
+            Output::Stream(stream) => Some(stream),
+            Output::Buffer { .. } => None,
+        }
+    }
+
+    pub fn head_sent(&self) -> bool {
+        self.head_sent
+    }
+
+    // Sends Connection: close with the response (if it hasn't gone out already) and closes
+    // the connection after it.
+    pub fn close(&mut self) {
+        self.close = true;
+    }
+
+    // Router::respond adds its own layers when the request reaches it.
+    pub(crate) fn add_layers(&mut self, layers: &[Arc<dyn Middleware>]) {
+        self.layers.splice(0..0, layers.iter().cloned());
+    }
+
+    pub(crate) fn taken_over(&self) -> bool {
+        self.taken_over
+    }
+
+    // Whether the connection can carry another request once this one is answered.
+    pub(crate) fn keep_alive(&self) -> bool {
+        !self.close && !self.taken_over
+    }
+
+    // The last look at a head before it's written. The layers were wrapped innermost first,
+    // so the outermost one goes last and has the final say.
+    fn prepare(&mut self, response: &mut ResponseBuilder) -> io::Result<()> {
+        if self.head_sent {
+            return Err(io::Error::other("a response was already sent for this request"));
+        }
+        for layer in &self.layers {
+            layer.on_response(self.request, response);
+        }
+        if let Some(gate) = self.gate {
+            if gate(response.status())? {
+                self.close = true;
+            }
+        }
+
+        if response.header_value("Connection").is_some_and(|value| value.eq_ignore_ascii_case("close")) {
+            self.close = true;
+        } else if self.close {
+            response.set_header("Connection", "close");
+        }
+        if self.request.method == Method::Head {
+            response.omit_body(true);
+        }
+        self.head_sent = true;
+        Ok(())
+    }
+}
+
+pub struct BodyWriter<'r, 'a> {
+    response: &'r mut Response<'a>,
+    remaining: u64,
+    discard: bool,
+}
+
+impl BodyWriter<'_, '_> {
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        if self.remaining > 0 && !self.discard {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("the body ended {} bytes short of its Content-Length", self.remaining),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Write for BodyWriter<'_, '_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.len() as u64 > self.remaining {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "more body than its Content-Length"));
+        }
+        let written = if self.discard { data.len() } else { self.response.output.write(data)? };
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.response.output.flush()
+    }
+}
+
+impl Drop for BodyWriter<'_, '_> {
+    // the client is still waiting for the rest: the connection can't carry another response.
+    fn drop(&mut self) {
+        if self.remaining > 0 && !self.discard {
+            self.response.close = true;
+        }
+    }
+}
+
+// Runs `handler` into memory and reads back what it sent, as a ResponseBuilder that can
+// be sent again (or kept): the body is whole, however it went out (file, chunks).
+// `response`'s layers don't see the handler's head, only the copy, once it's sent.
+// take_over is an error in there, like it is for a pipelined request.
+pub(crate) fn capture<F>(request: &Request, response: &Response, handler: F) -> io::Result<ResponseBuilder>
+    where
+        F: FnOnce(&Request, &mut Response) -> io::Result<()>
+{
+    let mut buffer = Vec::new();
+    let peer = response.peer_addr()?;
+    {
+        let mut inner = Response::new(Output::Buffer { buffer: &mut buffer, peer }, request, None);
+        handler(request, &mut inner)?;
+        if !inner.head_sent() {
+            return Err(io::Error::other("the handler sent no response"));
+        }
+    }
+
+    let invalid = |e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let sent = crate::client::parse_response(&buffer, request.method == Method::Head).map_err(|e| invalid(&e))?;
+    let status = StatusCode::from_code(sent.status).ok_or_else(|| invalid(&sent.status))?;
+    let mut captured = ResponseBuilder::new(status);
+    // framing: ResponseBuilder works it out again for the copy (a HEAD's length is kept,
+    // there's no body to count).
+    let framing = |name: &str| {
+        name.eq_ignore_ascii_case("Transfer-Encoding") || (name.eq_ignore_ascii_case("Content-Length") && request.method != Method::Head)
+    };
+    for (name, value) in &sent.headers {
+        if !framing(name) {
+            captured.header(name, value);
+        }
+    }
+    captured.body_bytes(sent.body);
+    Ok(captured)
+}
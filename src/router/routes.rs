@@ -0,0 +1,230 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{Response, Router};
+use crate::http::{Method, ResponseBuilder, StatusCode};
+use crate::mime;
+use crate::static_files::StaticFileHandler;
+use crate::toml::{self, Section};
+
+/* # Routes from a file: a static site without writing any Rust!
+# routes.toml
+[[route]]
+path = "/"
+file = "index.html"          # GET / => index.html
+[[route]]
+method = "GET"               # the default
+path = "/assets"
+dir = "public/assets"        # GET /assets/app.css => public/assets/app.css
+let router = Routes::from_toml(Path::new("routes.toml"))?.into_router();
+surff --routes routes.toml   // the same, from the command line
+1. Every [[route]] has a path and exactly one of file or dir; method is optional.
+file and dir are relative to the directory the TOML file is in, and must exist when
+it's loaded: a typo fails at startup, not with a 404 later.
+2. A file is opened on every request, so it can be edited while the server runs
+(a file that's gone by then is a 404). A dir is a StaticFileHandler (see static_files.rs)
+with the route's path as its prefix; "/" only ever matches itself, so it can't be one.
+3. These routes are looked up after all the others (see router/mod.rs): a handler
+registered in code for a path wins over the file's, whichever was added first.
+4. A mistake in the file (an unknown key, a missing path, TOML that toml.rs doesn't
+know) is an InvalidData error naming the file and the line. */
+
+#[derive(Debug, Clone)]
+pub enum RouteTarget {
+    File(PathBuf),
+    Dir(StaticFileHandler),
+}
+
+#[derive(Debug, Clone)]
+pub struct FileRoute {
+    pub method: Method,
+    pub path: String,
+    pub target: RouteTarget,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    pub routes: Vec<FileRoute>,
+}
+
+impl Routes {
+    pub fn from_toml(path: &Path) -> io::Result<Routes> {
+        let text = fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        read(&text, base).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    // See the top of this file (3.) for how they rank against the router's other routes.
+    pub fn register(self, router: &mut Router) {
+        for route in self.routes {
+            match route.target {
+                RouteTarget::File(file) => {
+                    router.fallback_route(route.method, &route.path, move |_, response| serve_file(&file, response))
+                }
+                RouteTarget::Dir(files) => {
+                    router.fallback_route(route.method, &route.path, move |request, response| files.handle(request, response))
+                }
+            }
+        }
+    }
+
+    pub fn into_router(self) -> Router {
+        let mut router = Router::new();
+        self.register(&mut router);
+        router
+    }
+}
+
+fn read(text: &str, base: &Path) -> Result<Routes, String> {
+    let document = toml::parse(text).map_err(|e| e.to_string())?;
+    if let Some(key) = document.root.keys().next() {
+        return Err(format!("{:?} is outside of a [[route]]", key));
+    }
+    let mut routes = Vec::new();
+    for (name, sections) in &document.arrays {
+        if name != "route" {
+            return Err(format!("line {}: unknown table [[{}]]", sections[0].line, name));
+        }
+        for section in sections {
+            routes.push(route(section, base).map_err(|e| format!("line {}: {}", section.line, e))?);
+        }
+    }
+    Ok(Routes { routes })
+}
+
+fn route(section: &Section, base: &Path) -> Result<FileRoute, String> {
+    let mut method = Method::Get;
+    let (mut path, mut file, mut dir) = (None, None, None);
+    for (key, value) in &section.keys {
+        let value = value.as_str().ok_or_else(|| format!("{} must be a string", key))?;
+        match key.as_str() {
+            "method" => {
+                method = Method::from_token(value);
+                if matches!(method, Method::Connect | Method::Unknown(_)) {
+                    return Err(format!("can't route method {:?}", value));
+                }
+            }
+            "path" => path = Some(value),
+            "file" => file = Some(value),
+            "dir" => dir = Some(value),
+            _ => return Err(format!("unknown key {:?}", key)),
+        }
+    }
+
+    let path = path.ok_or("a route needs a path")?;
+    if !path.starts_with('/') {
+        return Err(format!("path {:?} doesn't start with /", path));
+    }
+    let target = match (file, dir) {
+        (Some(file), None) => {
+            let file = base.join(file);
+            if !file.is_file() {
+                return Err(format!("{} is not a file", file.display()));
+            }
+            RouteTarget::File(file)
+        }
+        (None, Some(_)) if path == "/" => return Err("a dir can't be served at \"/\", which only matches itself".to_string()),
+        (None, Some(dir)) => {
+            let dir = base.join(dir);
+            let files = StaticFileHandler::new(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+            RouteTarget::Dir(files.strip_prefix(path))
+        }
+        _ => return Err("a route needs one of file or dir".to_string()),
+    };
+    Ok(FileRoute { method, path: path.to_string(), target })
+}
+
+fn serve_file(path: &Path, response: &mut Response) -> io::Result<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return response.send(
+                ResponseBuilder::new(StatusCode::NotFound)
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body_str(&format!("{}\n", StatusCode::NotFound)),
+            );
+        }
+        Err(e) => return Err(e),
+    };
+    let length = file.metadata()?.len();
+    let mut head = ResponseBuilder::new(StatusCode::Ok);
+    head.header("Content-Type", mime::from_path(path));
+    response.send_file(&mut head, &file, length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::exchange;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A directory with routes.toml (holding `routes`), index.html and assets/app.css.
+    fn site(routes: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("surff-routes-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("index.html"), "home").unwrap();
+        fs::write(dir.join("about.html"), "about").unwrap();
+        fs::write(dir.join("assets").join("app.css"), "body {}").unwrap();
+        fs::write(dir.join("routes.toml"), routes).unwrap();
+        dir
+    }
+
+    #[test]
+    fn serves_the_files_and_code_routes_win() {
+        let dir = site(
+            "[[route]]\npath = \"/\"\nfile = \"index.html\"\n\n\
+             [[route]]\nmethod = \"GET\"\npath = \"/assets\"\ndir = \"assets\"\n\n\
+             [[route]]\npath = \"/about\"\nfile = \"about.html\"\n",
+        );
+        let routes = Routes::from_toml(&dir.join("routes.toml")).unwrap();
+        assert_eq!(routes.routes.len(), 3);
+        let mut router = routes.into_router();
+        // added after, and still first.
+        router.get("/about", |_, response| response.send(ResponseBuilder::new(StatusCode::Ok).body_str("from code")));
+
+        let response = exchange(&router, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nContent-Type: text/html"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\nhome"), "{:?}", response);
+        assert!(exchange(&router, "GET /assets/app.css HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nbody {}"));
+        assert!(exchange(&router, "GET /about HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nfrom code"));
+        assert!(exchange(&router, "POST / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 "));
+
+        fs::remove_file(dir.join("index.html")).unwrap();
+        assert!(exchange(&router, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mistakes_are_errors_with_the_line() {
+        let cases = [
+            ("[[route]]\nfile = \"index.html\"\n", "line 1: a route needs a path"),
+            ("\n[[route]]\npath = \"/\"\n", "line 2: a route needs one of file or dir"),
+            ("[[route]]\npath = \"/\"\nfile = \"index.html\"\ndir = \"assets\"\n", "line 1: a route needs one of file or dir"),
+            ("[[route]]\npath = \"/\"\nfile = \"index.html\"\nfiel = \"x\"\n", "line 1: unknown key \"fiel\""),
+            ("[[route]]\nmethod = \"FETCH\"\npath = \"/\"\nfile = \"index.html\"\n", "line 1: can't route method \"FETCH\""),
+            ("[[route]]\npath = 1\n", "line 1: path must be a string"),
+            ("[[route]]\npath = \"about\"\nfile = \"about.html\"\n", "line 1: path \"about\" doesn't start with /"),
+            ("[[route]]\npath = \"/\"\ndir = \"assets\"\n", "line 1: a dir can't be served at \"/\", which only matches itself"),
+            ("[[page]]\npath = \"/\"\n", "line 1: unknown table [[page]]"),
+            ("root = \"/srv\"\n", "\"root\" is outside of a [[route]]"),
+            ("[[route]]\npath = \"/\"\nfile = \"index.html\"\n[route]\n", "line 4: only [[array]] tables are supported"),
+        ];
+        for (text, message) in cases {
+            let dir = site(text);
+            let file = dir.join("routes.toml");
+            let err = Routes::from_toml(&file).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), format!("{}: {}", file.display(), message), "{:?}", text);
+            fs::remove_dir_all(dir).unwrap();
+        }
+
+        let dir = site("[[route]]\npath = \"/old\"\nfile = \"old.html\"\n");
+        let err = Routes::from_toml(&dir.join("routes.toml")).unwrap_err().to_string();
+        assert!(err.ends_with(&format!("line 1: {} is not a file", dir.join("old.html").display())), "{}", err);
+        assert_eq!(Routes::from_toml(&dir.join("missing.toml")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
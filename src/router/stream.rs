@@ -0,0 +1,112 @@
+use std::any::Any;
+use std::io::{self, prelude::*};
+use std::panic::{self, AssertUnwindSafe};
+
+use super::Response;
+use crate::http::{HttpVersion, Method, Request, ResponseBuilder, StatusCode};
+
+/* # Streaming a body from an iterator!
+router.stream("/rows", |request| rows.iter().map(|row| format!("{}\n", row).into_bytes()));
+Each item the iterator yields goes out as one chunk (chunked.rs) as soon as it's there,
+so the client sees the first rows while the rest are still being produced.
+1. The iterator panics halfway => the head is long gone, so there's no answering with
+a 500; the connection is closed without the last (empty) chunk instead, so the client
+can tell the body was cut short. An empty chunk from the iterator is simply skipped.
+2. HTTP/1.0 clients don't know chunked encoding: for them the whole iterator is
+collected first and sent with a Content-Length (or a 500, if it panics).
+3. HEAD gets the head only; the iterator isn't even created. */
+
+pub(super) fn respond<I>(request: &Request, response: &mut Response, chunks: impl FnOnce() -> I) -> io::Result<()>
+    where
+        I: Iterator<Item = Vec<u8>>
+{
+    if request.method == Method::Head {
+        return response.send(ResponseBuilder::new(StatusCode::Ok).header("Transfer-Encoding", "chunked"));
+    }
+
+    if request.version == HttpVersion::Http10 {
+        let body = panic::catch_unwind(AssertUnwindSafe(|| chunks().flatten().collect::<Vec<u8>>()));
+        return match body {
+            Ok(body) => response.send(ResponseBuilder::new(StatusCode::Ok).body_bytes(body)),
+            Err(payload) => internal_error(response, &*payload),
+        };
+    }
+
+    // a handler that panics before the first chunk can still get a proper 500.
+    let mut chunks = match panic::catch_unwind(AssertUnwindSafe(chunks)) {
+        Ok(chunks) => chunks,
+        Err(payload) => return internal_error(response, &*payload),
+    };
+    let mut body = response.start_chunked(ResponseBuilder::new(StatusCode::Ok))?;
+    loop {
+        let chunk = match panic::catch_unwind(AssertUnwindSafe(|| chunks.next())) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => return body.finish(),
+            // the Err closes the connection, without the final chunk.
+            Err(payload) => {
+                let message = crate::panic_message(&*payload);
+                return Err(io::Error::other(format!("stream panicked: {}", message)));
+            }
+        };
+        body.write_all(&chunk)?;
+        body.flush()?;
+    }
+}
+
+fn internal_error(response: &mut Response, payload: &(dyn Any + Send)) -> io::Result<()> {
+    eprintln!("Stream panicked: {}", crate::panic_message(payload));
+    response.close();
+    response.send(&mut ResponseBuilder::new(StatusCode::InternalServerError))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::router::{exchange, Router};
+    use std::io::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.stream("/count", |_| (1..=3).map(|n| n.to_string().into_bytes()));
+        router.stream("/broken", |_| {
+            (1..=3).map(|n| {
+                if n == 2 {
+                    panic!("row 2 is broken");
+                }
+                n.to_string().into_bytes()
+            })
+        });
+        router
+    }
+
+    #[test]
+    fn every_item_is_a_chunk() {
+        let response = exchange(&router(), "GET /count HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\n1\r\n1\r\n1\r\n2\r\n1\r\n3\r\n0\r\n\r\n"), "{:?}", response);
+    }
+
+    #[test]
+    fn a_panic_cuts_the_body_short() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        let request = crate::http::Request::parse(b"GET /broken HTTP/1.1\r\n\r\n").unwrap();
+        assert!(router().dispatch(&request, &mut server).is_err());
+        drop(server);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\n1\r\n1\r\n"), "{:?}", response);
+    }
+
+    #[test]
+    fn http_1_0_gets_a_content_length() {
+        let response = exchange(&router(), "GET /count HTTP/1.0\r\n\r\n");
+        assert!(response.ends_with("\r\nContent-Length: 3\r\n\r\n123"), "{:?}", response);
+
+        let response = exchange(&router(), "GET /broken HTTP/1.0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{:?}", response);
+    }
+}
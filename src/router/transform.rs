@@ -0,0 +1,157 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::crypto;
+use crate::http::Request;
+
+/* # Rewriting a request body before the handler sees it!
+router.post("/webhook", handler)
+    .body_transformer(SignatureVerifier::new(b"secret"))
+    .body_transformer(MyDecryptor::new(key));
+Transformers run in the order they were added, each on what the last one returned, once
+the whole body has been read and just before the route's handler is called. The handler
+gets the request with the last body in it. The first error stops the chain: the client
+gets a 400 Bad Request with the error's message, and the handler isn't called.
+Only the route they're registered on runs them; middleware still sees the body as sent. */
+
+pub trait BodyTransformer: Send + Sync {
+    // `request` is for reading headers (a signature, a Content-Encoding); its body is
+    // the one from before this transformer, and so is `body`.
+    fn transform(&self, request: &Request, body: Vec<u8>) -> Result<Vec<u8>, TransformError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransformError {
+    message: String,
+}
+
+impl TransformError {
+    pub fn new(message: &str) -> TransformError {
+        TransformError { message: message.to_string() }
+    }
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+// The request as the handler should see it: with every transformer applied to its body.
+pub(super) fn apply(transformers: &[Arc<dyn BodyTransformer>], request: &Request) -> Result<Request, TransformError> {
+    let mut request = request.clone();
+    for transformer in transformers {
+        let body = std::mem::take(&mut request.body);
+        request.body = transformer.transform(&request, body)?;
+    }
+    Ok(request)
+}
+
+// # Webhooks: X-Signature: sha256=<hex HMAC-SHA256 of the body>.
+// The body is passed on unchanged when the signature matches, and rejected when it's
+// missing or wrong. Senders that use another header (GitHub's X-Hub-Signature-256, say)
+// can be checked with header(). Put it first, so the signature is checked against the
+// body as it was sent.
+#[derive(Clone)]
+pub struct SignatureVerifier {
+    secret: Arc<[u8]>,
+    header: String,
+}
+
+impl SignatureVerifier {
+    pub fn new(secret: &[u8]) -> SignatureVerifier {
+        SignatureVerifier { secret: Arc::from(secret), header: "X-Signature".to_string() }
+    }
+
+    pub fn header(mut self, name: &str) -> SignatureVerifier {
+        self.header = name.to_string();
+        self
+    }
+}
+
+// Not derived, to keep the secret out of logs.
+impl fmt::Debug for SignatureVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignatureVerifier").field("header", &self.header).finish_non_exhaustive()
+    }
+}
+
+impl BodyTransformer for SignatureVerifier {
+    fn transform(&self, request: &Request, body: Vec<u8>) -> Result<Vec<u8>, TransformError> {
+        let signature = request
+            .header(&self.header)
+            .ok_or_else(|| TransformError::new(&format!("missing {} header", self.header)))?;
+        if !crypto::signature_matches(&self.secret, signature, &body) {
+            return Err(TransformError::new("signature does not match the body"));
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{ResponseBuilder, StatusCode};
+    use crate::router::{exchange, Router};
+
+    struct Uppercase;
+
+    impl BodyTransformer for Uppercase {
+        fn transform(&self, _request: &Request, body: Vec<u8>) -> Result<Vec<u8>, TransformError> {
+            Ok(body.to_ascii_uppercase())
+        }
+    }
+
+    struct Reverse;
+
+    impl BodyTransformer for Reverse {
+        fn transform(&self, _request: &Request, mut body: Vec<u8>) -> Result<Vec<u8>, TransformError> {
+            if body.is_empty() {
+                return Err(TransformError::new("nothing to reverse"));
+            }
+            body.reverse();
+            Ok(body)
+        }
+    }
+
+    fn echo(request: &Request, response: &mut crate::router::Response) -> std::io::Result<()> {
+        response.send(ResponseBuilder::new(StatusCode::Ok).body_bytes(request.body.clone()))
+    }
+
+    fn post(path: &str, headers: &str, body: &str) -> String {
+        format!("POST {} HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}", path, headers, body.len(), body)
+    }
+
+    #[test]
+    fn transformers_run_in_order_and_stop_at_the_first_error() {
+        let mut router = Router::new();
+        router.post("/echo", echo).body_transformer(Uppercase).body_transformer(Reverse);
+        router.post("/plain", echo);
+
+        let response = exchange(&router, &post("/echo", "", "abc"));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\n\r\nCBA"), "{:?}", response);
+        // only the route they were added to.
+        let response = exchange(&router, &post("/plain", "", "abc"));
+        assert!(response.ends_with("\r\n\r\nabc"), "{:?}", response);
+
+        let response = exchange(&router, &post("/echo", "", ""));
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", response);
+        assert!(response.ends_with("\r\n\r\nnothing to reverse\n"), "{:?}", response);
+    }
+
+    #[test]
+    fn signatures_are_checked_against_the_body() {
+        let mut router = Router::new();
+        router.post("/hook", echo).body_transformer(SignatureVerifier::new(b"secret"));
+        let signature = format!("X-Signature: sha256={}\r\n", crypto::to_hex(&crypto::hmac_sha256(b"secret", b"{\"ok\":1}")));
+
+        let response = exchange(&router, &post("/hook", &signature, "{\"ok\":1}"));
+        assert!(response.ends_with("\r\n\r\n{\"ok\":1}"), "{:?}", response);
+        for (headers, body) in [(signature.as_str(), "{\"ok\":2}"), ("", "{\"ok\":1}"), ("X-Signature: md5=00\r\n", "{\"ok\":1}")] {
+            let response = exchange(&router, &post("/hook", headers, body));
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", response);
+        }
+    }
+}
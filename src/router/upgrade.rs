@@ -0,0 +1,108 @@
+use std::io;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use crate::http::Request;
+
+/* # Switching protocols: WebSocket, h2c, ...!
+router.on_upgrade("websocket", |request: &Request, stream: TcpStream| { ... });
+1. A request with an Upgrade header (Upgrade: websocket) goes to the first upgrade
+handler registered for one of the protocols it lists, instead of to the routes.
+Protocol names are case-insensitive; a version after a slash (websocket/13) is ignored.
+2. The handler gets "101 Switching Protocols" (with Upgrade: <protocol>, Connection: Upgrade,
+and whatever headers response_headers adds, like Sec-WebSocket-Accept) written for it,
+then the connection itself: from then on it speaks the new protocol.
+3. A protocol nobody registered => 426 Upgrade Required, with an Upgrade header listing
+those that are. Without any upgrade handlers, the Upgrade header is ignored, as RFC 9110
+allows, and the request is routed as usual. */
+
+pub trait UpgradeHandler: Send + Sync {
+    // Extra headers for the 101 response.
+    fn response_headers(&self, _request: &Request) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    fn on_upgrade(&self, request: &Request, stream: TcpStream) -> io::Result<()>;
+}
+
+// A plain closure is an UpgradeHandler without extra headers.
+impl<F> UpgradeHandler for F
+    where
+        F: Fn(&Request, TcpStream) -> io::Result<()> + Send + Sync
+{
+    fn on_upgrade(&self, request: &Request, stream: TcpStream) -> io::Result<()> {
+        self(request, stream)
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct Upgrade {
+    pub(super) protocol: String,
+    pub(super) handler: Arc<dyn UpgradeHandler>,
+}
+
+// The protocols a request asks to switch to, in its order of preference.
+pub(super) fn requested_protocols(request: &Request) -> Vec<&str> {
+    match request.header("Upgrade") {
+        Some(value) => value
+            .split(',')
+            .map(|protocol| protocol.split('/').next().unwrap_or("").trim())
+            .filter(|protocol| !protocol.is_empty())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method, ResponseBuilder, StatusCode};
+    use crate::router::{exchange, Router};
+    use std::io::Write;
+
+    struct Echo;
+
+    impl UpgradeHandler for Echo {
+        fn response_headers(&self, _request: &Request) -> Vec<(String, String)> {
+            vec![("Sec-WebSocket-Accept".to_string(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string())]
+        }
+
+        fn on_upgrade(&self, _request: &Request, mut stream: TcpStream) -> io::Result<()> {
+            stream.write_all(b"now speaking websocket")
+        }
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/", |_, response| response.send(ResponseBuilder::new(StatusCode::Ok).body_str("http")));
+        router.on_upgrade("websocket", Echo);
+        router.on_upgrade("h2c", |_: &Request, _: TcpStream| Ok(()));
+        router
+    }
+
+    #[test]
+    fn upgrades_switch_protocols() {
+        let response = exchange(&router(), "GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: WebSocket/13\r\n\r\n");
+        assert_eq!(
+            response,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\nnow speaking websocket"
+        );
+    }
+
+    #[test]
+    fn unknown_protocols_are_refused() {
+        let response = exchange(&router(), "GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: spdy/3\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"), "{:?}", response);
+        assert!(response.contains("\r\nUpgrade: websocket, h2c\r\n"));
+    }
+
+    #[test]
+    fn upgrade_is_ignored_without_handlers() {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |_, response| response.send(ResponseBuilder::new(StatusCode::Ok).body_str("http")));
+
+        let response = exchange(&router, "GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("http"), "{:?}", response);
+    }
+}
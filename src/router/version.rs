@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use super::{Middleware, Response, Router};
+use crate::http::{Request, ResponseBuilder, StatusCode};
+
+/* # One API, several versions!
+router.wrap(VersionRouter::new().version("v1", v1).version("v2", v2).default("v2"));
+Instead of /v1/users next to /v2/users, the client says which version it wants,
+and the request is answered by that version's Router:
+1. API-Version: v1                                  (the default, or .from_header(name))
+2. Accept: application/vnd.myapi.v1+json            (.from_accept("myapi"))
+A request that doesn't ask for a version goes to the default version, or, without
+one, on to the router the VersionRouter wraps. Asking for a version that isn't
+there gets a 406 Not Acceptable listing those that are. */
+
+const DEFAULT_VERSION_HEADER: &str = "API-Version";
+
+#[derive(Debug, Clone)]
+enum VersionSource {
+    Header(String),
+    // The vendor in application/vnd.<vendor>.<version>+json
+    Accept(String),
+}
+
+#[derive(Clone)]
+pub struct VersionRouter {
+    versions: HashMap<String, Arc<Router>>,
+    default: Option<String>,
+    source: VersionSource,
+}
+
+impl Default for VersionRouter {
+    fn default() -> VersionRouter {
+        VersionRouter {
+            versions: HashMap::new(),
+            default: None,
+            source: VersionSource::Header(DEFAULT_VERSION_HEADER.to_string()),
+        }
+    }
+}
+
+impl VersionRouter {
+    pub fn new() -> VersionRouter {
+        // VersionRouter::default is the builder method below.
+        <VersionRouter as Default>::default()
+    }
+
+    pub fn version(mut self, version: &str, router: impl Into<Arc<Router>>) -> VersionRouter {
+        self.versions.insert(version.to_string(), router.into());
+        self
+    }
+
+    // For requests that don't name a version; should be one of those added with version().
+    pub fn default(mut self, version: &str) -> VersionRouter {
+        self.default = Some(version.to_string());
+        self
+    }
+
+    pub fn from_header(mut self, name: &str) -> VersionRouter {
+        self.source = VersionSource::Header(name.to_string());
+        self
+    }
+
+    pub fn from_accept(mut self, vendor: &str) -> VersionRouter {
+        self.source = VersionSource::Accept(vendor.to_ascii_lowercase());
+        self
+    }
+
+    // The version the request asks for, if it asks.
+    fn requested(&self, request: &Request) -> Option<String> {
+        match &self.source {
+            VersionSource::Header(name) => request
+                .header(name)
+                .map(str::trim)
+                .filter(|version| !version.is_empty())
+                .map(str::to_string),
+            VersionSource::Accept(vendor) => {
+                let prefix = format!("application/vnd.{}.", vendor);
+                request.header("Accept")?.split(',').find_map(|range| {
+                    // "application/vnd.myapi.v1+json; q=0.9" => "v1"
+                    let media_type = range.split(';').next()?.trim().to_ascii_lowercase();
+                    let rest = media_type.strip_prefix(&prefix)?;
+                    let version = rest.split('+').next()?;
+                    (!version.is_empty()).then(|| version.to_string())
+                })
+            }
+        }
+    }
+
+    fn available(&self) -> Vec<&str> {
+        let mut versions: Vec<&str> = self.versions.keys().map(String::as_str).collect();
+        versions.sort_unstable();
+        versions
+    }
+}
+
+impl Middleware for VersionRouter {
+    fn before(&self, request: &Request, response: &mut Response) -> io::Result<bool> {
+        let version = match self.requested(request).or_else(|| self.default.clone()) {
+            Some(version) => version,
+            None => return Ok(true),
+        };
+
+        match self.versions.get(&version) {
+            Some(router) => router.respond(request, response)?,
+            None => {
+                let available = self.available().join(", ");
+                response.send(
+                    ResponseBuilder::new(StatusCode::NotAcceptable)
+                        .header("Content-Type", "text/plain; charset=utf-8")
+                        .body_str(&format!("Unknown API version {}; available versions: {}\n", version, available)),
+                )?;
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::router::exchange;
+
+    fn answering(body: &'static str) -> Router {
+        let mut router = Router::new();
+        router.route(Method::Get, "/users", move |_, response| response.send(ResponseBuilder::new(StatusCode::Ok).body_str(body)));
+        router
+    }
+
+    #[test]
+    fn header_picks_the_version() {
+        let mut router = Router::new();
+        router.wrap(VersionRouter::new().version("v1", answering("one")).version("v2", answering("two")).default("v2"));
+
+        assert!(exchange(&router, "GET /users HTTP/1.1\r\nAPI-Version: v1\r\n\r\n").ends_with("\r\n\r\none"));
+        assert!(exchange(&router, "GET /users HTTP/1.1\r\n\r\n").ends_with("\r\n\r\ntwo"));
+    }
+
+    #[test]
+    fn accept_picks_the_version() {
+        let mut router = Router::new();
+        router.wrap(VersionRouter::new().from_accept("myapi").version("v1", answering("one")).version("v2", answering("two")));
+
+        let request = "GET /users HTTP/1.1\r\nAccept: text/html, application/vnd.myapi.v1+json;q=0.9\r\n\r\n";
+        assert!(exchange(&router, request).ends_with("\r\n\r\none"));
+        // no version and no default: the outer router answers.
+        assert!(exchange(&router, "GET /users HTTP/1.1\r\nAccept: */*\r\n\r\n").starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[test]
+    fn unknown_versions_are_not_acceptable() {
+        let mut router = Router::new();
+        router.wrap(VersionRouter::new().version("v2", answering("two")).version("v1", answering("one")));
+
+        let response = exchange(&router, "GET /users HTTP/1.1\r\nAPI-Version: v3\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 406 Not Acceptable\r\n"), "{:?}", response);
+        assert!(response.ends_with("available versions: v1, v2\n"), "{:?}", response);
+    }
+}
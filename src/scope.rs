@@ -0,0 +1,107 @@
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use crate::{ExecuteError, ThreadPool};
+
+/* # Scoped jobs: borrowing from the caller's stack!
+let mut totals = [0; 4];
+pool.scope(|scope| {
+    for (chunk, total) in data.chunks(n).zip(totals.iter_mut()) {
+        scope.execute(move || *total = chunk.iter().sum()).unwrap();
+    }
+});
+// every scoped job has finished here, so `totals` can be read again.
+Like std::thread::scope, but the jobs run on the pool's existing workers.
+1. Each job holds a PendingJob guard that adds one to the scope's counter,
+and takes it off again when the job is dropped: after it ran, panicked or was
+never queued at all (the job is dropped along with the failed send).
+2. .scope only returns once the counter is back to zero, even if `f` panics,
+so no job can still be using a borrow once the borrowed data goes away.
+3. A panic in any scoped job is re-raised by .scope after everything has finished.
+Don't call .scope from inside one of the same pool's jobs: if every worker
+is waiting in a scope, nobody is left to run the scoped jobs. */
+
+pub struct Scope<'env> {
+    pool: &'env ThreadPool,
+    state: Arc<ScopeState>,
+    // Invariant in 'env, like std::thread::Scope, so 'env can't be shrunk to fit a shorter borrow.
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeState {
+    pending: Mutex<usize>,
+    all_done: Condvar,
+    job_panicked: AtomicBool,
+}
+
+struct PendingJob {
+    state: Arc<ScopeState>,
+}
+
+impl Drop for PendingJob {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.state.job_panicked.store(true, Ordering::Relaxed);
+        }
+        let mut pending = self.state.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        *pending -= 1;
+        if *pending == 0 {
+            self.state.all_done.notify_all();
+        }
+    }
+}
+
+impl<'env> Scope<'env> {
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+        where
+            F: FnOnce() + Send + 'env
+    {
+        *self.state.pending.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        let guard = PendingJob { state: Arc::clone(&self.state) };
+
+        let job: Box<dyn FnOnce() + Send + 'env> = Box::new(move || {
+            // moved in, so it's dropped when the job finishes (or unwinds).
+            let _guard = guard;
+            f();
+        });
+        // SAFETY: only the lifetime changes. ThreadPool::scope doesn't return before
+        // every PendingJob is dropped, i.e. before this job has run or been dropped,
+        // so nothing the job borrows for 'env is used after 'env ends.
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { std::mem::transmute(job) };
+
+        self.pool.execute(job)
+    }
+}
+
+impl ThreadPool {
+    pub fn scope<'env, F, T>(&'env self, f: F) -> T
+        where
+            F: FnOnce(&Scope<'env>) -> T
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                all_done: Condvar::new(),
+                job_panicked: AtomicBool::new(false),
+            }),
+            _env: PhantomData,
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+        let mut pending = scope.state.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        while *pending > 0 {
+            pending = scope.state.all_done.wait(pending).unwrap_or_else(PoisonError::into_inner);
+        }
+        drop(pending);
+
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.state.job_panicked.load(Ordering::Relaxed) => panic!("a scoped job panicked"),
+            Ok(value) => value,
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* # Connection statistics: is keep-alive working?
+Every accepted connection carries a ConnectionInfo, whose requests_served goes up
+with each request read from it. When the connection is closed (however that
+happens: the client hung up, an error, the idle timeout) the info goes down a
+channel to one aggregator thread, which keeps:
+- connections_total: connections closed so far.
+- a histogram of requests per connection (how many connections served 1 request,
+how many 2, ...), for p50_requests_per_connection and max_requests_per_connection.
+- the summed lifetimes, for mean_connection_lifetime_secs.
+Mostly 1 request per connection means clients (or a proxy in front) don't reuse
+connections; a high p50 means keep-alive does its job. ConnectionStats reads the
+aggregate from anywhere, e.g. for /metrics (to_prometheus). */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub accepted_at: Instant,
+    pub requests_served: u32,
+    pub remote_addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConnectionSummary {
+    pub connections_total: u64,
+    pub p50_requests_per_connection: u32,
+    pub max_requests_per_connection: u32,
+    pub mean_connection_lifetime_secs: f64,
+}
+
+// A cheap, cloneable handle to the aggregate.
+#[derive(Clone, Default)]
+pub struct ConnectionStats {
+    aggregate: Arc<Mutex<Aggregate>>,
+}
+
+#[derive(Default)]
+struct Aggregate {
+    // requests served => connections that served that many
+    requests_per_connection: BTreeMap<u32, u64>,
+    connections: u64,
+    lifetimes: Duration,
+}
+
+impl ConnectionStats {
+    // The sender goes to every connection; the aggregator thread runs until all senders are gone.
+    pub(super) fn spawn_aggregator(&self) -> io::Result<Sender<ConnectionInfo>> {
+        let (sender, receiver) = mpsc::channel();
+        let stats = self.clone();
+        thread::Builder::new()
+            .name("surff-connection-stats".to_string())
+            .spawn(move || stats.aggregate(receiver))?;
+        Ok(sender)
+    }
+
+    fn aggregate(&self, closed: Receiver<ConnectionInfo>) {
+        for info in closed {
+            self.record(&info, info.accepted_at.elapsed());
+        }
+    }
+
+    fn record(&self, info: &ConnectionInfo, lifetime: Duration) {
+        let mut aggregate = self.aggregate.lock().unwrap_or_else(PoisonError::into_inner);
+        *aggregate.requests_per_connection.entry(info.requests_served).or_insert(0) += 1;
+        aggregate.connections += 1;
+        aggregate.lifetimes += lifetime;
+    }
+
+    pub fn summary(&self) -> ConnectionSummary {
+        let aggregate = self.aggregate.lock().unwrap_or_else(PoisonError::into_inner);
+        if aggregate.connections == 0 {
+            return ConnectionSummary::default();
+        }
+
+        // the smallest count that at least half of the connections are at or below.
+        let half = aggregate.connections.div_ceil(2);
+        let mut seen = 0;
+        let p50 = aggregate
+            .requests_per_connection
+            .iter()
+            .find(|(_, connections)| {
+                seen += **connections;
+                seen >= half
+            })
+            .map_or(0, |(requests, _)| *requests);
+
+        ConnectionSummary {
+            connections_total: aggregate.connections,
+            p50_requests_per_connection: p50,
+            max_requests_per_connection: aggregate.requests_per_connection.keys().next_back().copied().unwrap_or(0),
+            mean_connection_lifetime_secs: aggregate.lifetimes.as_secs_f64() / aggregate.connections as f64,
+        }
+    }
+
+    // The summary in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let summary = self.summary();
+        let mut text = String::new();
+        let metrics = [
+            ("surff_connections_total", "counter", "Connections closed so far.", summary.connections_total.to_string()),
+            ("surff_requests_per_connection_p50", "gauge", "Median requests served per connection.", summary.p50_requests_per_connection.to_string()),
+            ("surff_requests_per_connection_max", "gauge", "Most requests served on one connection.", summary.max_requests_per_connection.to_string()),
+            ("surff_connection_lifetime_seconds_mean", "gauge", "Mean time from accept to close.", summary.mean_connection_lifetime_secs.to_string()),
+        ];
+        for (name, kind, help, value) in metrics {
+            let _ = write!(text, "# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closed(requests_served: u32) -> ConnectionInfo {
+        ConnectionInfo {
+            accepted_at: Instant::now(),
+            requests_served,
+            remote_addr: "127.0.0.1:5000".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn summary_of_closed_connections() {
+        let stats = ConnectionStats::default();
+        assert_eq!(stats.summary(), ConnectionSummary::default());
+
+        for (requests, secs) in [(1, 1), (1, 1), (3, 2), (10, 4)] {
+            stats.record(&closed(requests), Duration::from_secs(secs));
+        }
+        let summary = stats.summary();
+        assert_eq!(summary.connections_total, 4);
+        assert_eq!(summary.p50_requests_per_connection, 1);
+        assert_eq!(summary.max_requests_per_connection, 10);
+        assert_eq!(summary.mean_connection_lifetime_secs, 2.0);
+    }
+
+    #[test]
+    fn the_aggregator_collects_from_the_channel() {
+        let stats = ConnectionStats::default();
+        let sender = stats.spawn_aggregator().unwrap();
+        sender.send(closed(2)).unwrap();
+        sender.send(closed(4)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while stats.summary().connections_total < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(stats.summary().max_requests_per_connection, 4);
+        assert!(stats.to_prometheus().contains("\n# TYPE surff_connections_total counter\nsurff_connections_total 2\n"));
+    }
+}
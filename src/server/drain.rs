@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::handler_timeout::Gate;
+use super::parking::RequestParker;
+use super::watcher::Watcher;
+use crate::ThreadPool;
+
+/* # Draining: finishing what's running, for a deploy without dropped requests!
+let handle = server.handle();                    // before run() takes the server
+handle.drain_connections(Duration::from_secs(30));  // e.g. from a SIGTERM (see main.rs)
+1. Stop accepting: every accept thread is woken up (with a connection of our own),
+sees that the server is draining and closes its listener, so the OS refuses new
+connections from then on.
+2. Idle connections (in the watcher) are closed, except those whose next request
+has already arrived, which are answered like the ones still running.
+3. Every response that goes out from now on (including those of requests already
+running) gets Connection: close, and its connection is closed after it.
+4. Wait for the pools to run out of jobs (ThreadPool::drain), at most `timeout`.
+Requests still running after that get a 503 if their head hasn't gone out (and
+their connection is shut down either way); pipelined ones (see pipeline.rs) are
+cut off with their connection.
+Server::run returns once the drain is done. */
+
+#[derive(Default)]
+pub(super) struct Drain {
+    draining: AtomicBool,
+    // the listeners' addresses, for waking the accept threads up.
+    listeners: Mutex<Vec<SocketAddr>>,
+    watcher: Mutex<Option<Watcher>>,
+    in_flight: Mutex<HashMap<u64, InFlight>>,
+    next_id: AtomicU64,
+    finished: Mutex<bool>,
+    finished_changed: Condvar,
+}
+
+// A request being answered on its connection.
+struct InFlight {
+    stream: Arc<TcpStream>,
+    gate: Arc<Gate>,
+}
+
+// Unregisters the request when it's done.
+pub(super) struct Registered<'a> {
+    drain: &'a Drain,
+    id: u64,
+}
+
+impl Drop for Registered<'_> {
+    fn drop(&mut self) {
+        self.drain.in_flight.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.id);
+    }
+}
+
+// What drain_connections (and the admin API) need; handed out by Server::handle, cheap to clone.
+#[derive(Clone)]
+pub struct ServerHandle {
+    pub(super) drain: Arc<Drain>,
+    pub(super) parker: Arc<RequestParker>,
+    pub(super) pool: Arc<ThreadPool>,
+    pub(super) io_pool: Option<Arc<ThreadPool>>,
+}
+
+impl Drain {
+    pub(super) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn add_listener(&self, addr: SocketAddr) {
+        self.listeners.lock().unwrap_or_else(PoisonError::into_inner).push(addr);
+    }
+
+    pub(super) fn set_watcher(&self, watcher: Watcher) {
+        *self.watcher.lock().unwrap_or_else(PoisonError::into_inner) = Some(watcher);
+    }
+
+    pub(super) fn register(&self, stream: &Arc<TcpStream>, gate: &Arc<Gate>) -> Registered<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let in_flight = InFlight { stream: Arc::clone(stream), gate: Arc::clone(gate) };
+        self.in_flight.lock().unwrap_or_else(PoisonError::into_inner).insert(id, in_flight);
+        Registered { drain: self, id }
+    }
+
+    // Once drain_connections is done; until then (if it has started) after that.
+    pub(super) fn wait_finished(&self) {
+        if !self.is_draining() {
+            return;
+        }
+        let mut finished = self.finished.lock().unwrap_or_else(PoisonError::into_inner);
+        while !*finished {
+            finished = self.finished_changed.wait(finished).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    // Connection: close for the requests already running.
+    fn close_in_flight(&self) {
+        for request in self.in_flight.lock().unwrap_or_else(PoisonError::into_inner).values() {
+            request.gate.close();
+        }
+    }
+
+    // A 503 for every request that hasn't sent its head yet; shuts every connection down.
+    fn abort_in_flight(&self) -> usize {
+        let in_flight = std::mem::take(&mut *self.in_flight.lock().unwrap_or_else(PoisonError::into_inner));
+        for request in in_flight.values() {
+            if request.gate.time_out() {
+                let _ = (&*request.stream).write_all(crate::service_unavailable());
+            }
+            let _ = request.stream.shutdown(Shutdown::Both);
+        }
+        in_flight.len()
+    }
+}
+
+impl ServerHandle {
+    // The server's worker pool, e.g. for resizing it (see admin.rs).
+    pub fn pool(&self) -> &Arc<ThreadPool> {
+        &self.pool
+    }
+
+    // For holding new connections during a deploy (see parking.rs).
+    pub fn parker(&self) -> &Arc<RequestParker> {
+        &self.parker
+    }
+
+    // Returns once the server has drained (see the top of this file); a second call
+    // waits for the first one to be done.
+    pub fn drain_connections(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        if self.drain.draining.swap(true, Ordering::SeqCst) {
+            return self.drain.wait_finished();
+        }
+        println!("Draining connections (for at most {:?}).", timeout);
+        // a request registered after this has seen the flag.
+        self.drain.close_in_flight();
+        // parked connections would only wait for a server that isn't coming back.
+        self.parker.drain();
+
+        let listeners = self.drain.listeners.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        for addr in listeners {
+            // the accept thread only looks at the flag once accept returns.
+            let _ = TcpStream::connect_timeout(&reachable(addr), Duration::from_secs(1));
+        }
+        if let Some(watcher) = &*self.drain.watcher.lock().unwrap_or_else(PoisonError::into_inner) {
+            watcher.drain();
+        }
+
+        let left = || deadline.saturating_duration_since(Instant::now());
+        let io_drained = self.io_pool.as_ref().is_none_or(|io_pool| io_pool.drain(left()));
+        if !(io_drained && self.pool.drain(left())) {
+            let aborted = self.drain.abort_in_flight();
+            eprintln!("Drain timed out; aborted {} request(s).", aborted);
+        }
+
+        // before run returns (and with it, usually, the process).
+        println!("Drained.");
+        *self.drain.finished.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.drain.finished_changed.notify_all();
+    }
+}
+
+// A listener on 0.0.0.0 (or [::]) is reached through loopback.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port()),
+        _ => addr,
+    }
+}
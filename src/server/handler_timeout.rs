@@ -0,0 +1,238 @@
+use std::io;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::http::{Method, Request, ResponseBuilder, StatusCode};
+use crate::router::{Output, Router};
+
+/* # Giving up on handlers that never return (--handler-timeout)!
+Synchronous code can't be cancelled, so the handler runs on a thread of its own
+(with a clone of the connection) while the worker waits for it, at most the timeout:
+1. Done in time => as if the worker had run it itself.
+2. Still running => the worker moves on. A client still waiting for the head gets a
+503; one that's halfway through the body can't anymore, and the connection is
+shut down instead (which also fails the handler's next write). Either way the
+connection is closed.
+3. The handler's thread is detached: it keeps running until the handler returns
+on its own, and is counted in detached_handlers (shown in /metrics).
+The Gate decides who writes the head: the handler (HeadGate, right before its head
+goes out) or the timeout (the 503), whichever comes first, never both. A drain
+(drain.rs) uses the same Gate to close the connection after the response, and for
+its 503s when it runs out of time.
+CONNECT and Upgrade requests aren't timed: tunnels and upgraded protocols keep
+their handler busy for as long as the connection is open. */
+
+#[derive(Default)]
+pub(super) struct Gate {
+    state: Mutex<GateState>,
+}
+
+#[derive(Default)]
+struct GateState {
+    head_sent: bool,
+    // what went out, for the log (see SampledLogger).
+    status: Option<StatusCode>,
+    timed_out: bool,
+    close: bool,
+}
+
+impl Gate {
+    // The handler's side: may the head go out? Ok(true): with Connection: close.
+    pub(super) fn open(&self, status: StatusCode) -> io::Result<bool> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.timed_out {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the handler ran out of time"));
+        }
+        state.head_sent = true;
+        state.status = Some(status);
+        Ok(state.close)
+    }
+
+    // The drain's side: the head that goes out from now on closes the connection.
+    pub(super) fn close(&self) {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).close = true;
+    }
+
+    // The timeout's side: true if the 503 may go out instead.
+    pub(super) fn time_out(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.timed_out = true;
+        if state.head_sent {
+            return false;
+        }
+        state.status = Some(StatusCode::ServiceUnavailable);
+        true
+    }
+
+    // The status of the head that went out, whoever sent it; None if none did.
+    pub(super) fn status(&self) -> Option<StatusCode> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).status
+    }
+}
+
+pub(super) fn is_timed(request: &Request) -> bool {
+    request.method != Method::Connect && request.header("Upgrade").is_none()
+}
+
+// Router::dispatch_to on its own thread, for at most `timeout`.
+// `close`: see Router::dispatch_to.
+pub(super) fn dispatch(
+    router: &Arc<Router>,
+    request: &Request,
+    output: Output,
+    close: bool,
+    gate: &Arc<Gate>,
+    timeout: Duration,
+    detached: &AtomicU64,
+) -> io::Result<bool> {
+    let (done, finished) = mpsc::channel();
+    let handler = {
+        let router = Arc::clone(router);
+        let request = request.clone();
+        let gate = Arc::clone(gate);
+        move |output: Output| {
+            let open = |status| gate.open(status);
+            let result = router.dispatch_to(&request, output, close, Some(&open));
+            let _ = done.send(result);
+        }
+    };
+
+    match output {
+        Output::Stream(stream) => {
+            let mut clone = stream.try_clone()?;
+            thread::Builder::new().spawn(move || handler(Output::Stream(&mut clone)))?;
+
+            match finished.recv_timeout(timeout) {
+                Ok(result) => result,
+                Err(RecvTimeoutError::Disconnected) => Err(io::Error::other("the handler panicked")),
+                Err(RecvTimeoutError::Timeout) => {
+                    detached.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("Handler for {} {} timed out after {:?}", request.method, request.path, timeout);
+                    if gate.time_out() {
+                        let _ = unavailable().write_to(stream);
+                    }
+                    // the handler's clone is the same socket: this stops its writes too.
+                    let _ = stream.shutdown(Shutdown::Both);
+                    Ok(false)
+                }
+            }
+        }
+        Output::Buffer { buffer, peer } => {
+            // the handler writes into a buffer of its own, which is ours once it's done.
+            let (bytes_done, bytes) = mpsc::channel();
+            thread::Builder::new().spawn(move || {
+                let mut own = Vec::new();
+                handler(Output::Buffer { buffer: &mut own, peer });
+                let _ = bytes_done.send(own);
+            })?;
+
+            match finished.recv_timeout(timeout) {
+                Ok(result) => {
+                    buffer.extend(bytes.recv().unwrap_or_default());
+                    result
+                }
+                Err(RecvTimeoutError::Disconnected) => Err(io::Error::other("the handler panicked")),
+                Err(RecvTimeoutError::Timeout) => {
+                    detached.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("Handler for {} {} timed out after {:?}", request.method, request.path, timeout);
+                    if gate.time_out() {
+                        unavailable().write_to(buffer)?;
+                    }
+                    Ok(false)
+                }
+            }
+        }
+    }
+}
+
+fn unavailable() -> ResponseBuilder {
+    let mut response = ResponseBuilder::new(StatusCode::ServiceUnavailable);
+    response
+        .header("Content-Type", "text/plain")
+        .header("Connection", "close")
+        .body_str("the request took too long\n");
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Request;
+    use std::io::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Instant;
+
+    fn router() -> Arc<Router> {
+        let mut router = Router::new();
+        router.get("/quick", |_, response| response.send(ResponseBuilder::new(StatusCode::Ok).body_str("quick")));
+        router.get("/stuck", |_, response| {
+            thread::sleep(Duration::from_millis(500));
+            response.send(ResponseBuilder::new(StatusCode::Ok).body_str("too late"))
+        });
+        router.get("/halfway", |_, response| {
+            let mut body = response.start_chunked(ResponseBuilder::new(StatusCode::Ok))?;
+            body.write_all(b"half")?;
+            body.flush()?;
+            thread::sleep(Duration::from_millis(500));
+            body.finish()
+        });
+        Arc::new(router)
+    }
+
+    // The response the client sees, and what dispatch returned.
+    fn timed(path: &str) -> (String, io::Result<bool>, u64) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        let detached = AtomicU64::new(0);
+        let request = Request::parse(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).unwrap();
+        let gate = Arc::new(Gate::default());
+        let result = dispatch(&router(), &request, Output::Stream(&mut server), false, &gate, Duration::from_millis(100), &detached);
+        drop(server);
+
+        let mut response = String::new();
+        let _ = client.read_to_string(&mut response);
+        (response, result, detached.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn handlers_in_time_are_answered_as_usual() {
+        let (response, result, detached) = timed("/quick");
+        assert!(response.ends_with("\r\n\r\nquick"), "{:?}", response);
+        assert!(result.unwrap());
+        assert_eq!(detached, 0);
+    }
+
+    #[test]
+    fn stuck_handlers_get_a_503() {
+        let started = Instant::now();
+        let (response, result, detached) = timed("/stuck");
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{:?}", response);
+        assert!(!result.unwrap());
+        assert_eq!(detached, 1);
+    }
+
+    #[test]
+    fn too_late_for_a_503_closes_the_connection() {
+        let (response, result, _) = timed("/halfway");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("\r\n\r\n4\r\nhalf\r\n"), "{:?}", response);
+        assert!(!result.unwrap());
+    }
+
+    #[test]
+    fn buffered_responses_are_timed_too() {
+        let detached = AtomicU64::new(0);
+        let request = Request::parse(b"GET /stuck HTTP/1.1\r\n\r\n").unwrap();
+        let mut buffer = Vec::new();
+        let output = Output::Buffer { buffer: &mut buffer, peer: "127.0.0.1:5000".parse().unwrap() };
+        let gate = Arc::new(Gate::default());
+        assert!(!dispatch(&router(), &request, output, false, &gate, Duration::from_millis(100), &detached).unwrap());
+        assert!(buffer.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+}
@@ -0,0 +1,164 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::json::{json_string, ToJson};
+
+/* # Logging in JSON Lines, for log aggregators (ELK, Loki, ...)!
+surff --log-file /var/log/surff.jsonl --log-max-bytes 104857600
+{"timestamp":"2026-10-14T09:30:00.123Z","level":"info","target":"surff::server","message":"GET / HTTP/1.1 => 200","method":"GET","status":200,...}
+1. One JSON object per line: timestamp (RFC 3339, UTC), level, target (where in surff
+the line comes from), message, then the line's own fields, escaped by json.rs.
+With --log-file, the request log (see sampled_log.rs) goes here instead of stdout.
+2. Lines are appended to the file, through a BufWriter that's flushed after every line:
+one write per line, and nothing lost in a buffer when the process dies.
+3. With max_bytes, a line that would take the file past it first renames the file
+to <name>.1 (replacing the one before) and starts a new <name>. */
+
+struct Output {
+    writer: BufWriter<File>,
+    // the size of the file, including what was there when it was opened.
+    written: u64,
+}
+
+pub struct JsonLinesLogger {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    output: Mutex<Output>,
+}
+
+fn open(path: &Path) -> io::Result<Output> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let written = file.metadata()?.len();
+    Ok(Output { writer: BufWriter::new(file), written })
+}
+
+impl JsonLinesLogger {
+    pub fn new(path: &Path) -> io::Result<JsonLinesLogger> {
+        let output = open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Ok(JsonLinesLogger { path: path.to_path_buf(), max_bytes: None, output: Mutex::new(output) })
+    }
+
+    // 0: never rotated.
+    pub fn max_bytes(mut self, bytes: u64) -> JsonLinesLogger {
+        self.max_bytes = (bytes > 0).then_some(bytes);
+        self
+    }
+
+    // <name>.1: where the file goes when it's rotated.
+    pub fn rotated_path(&self) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(".1");
+        PathBuf::from(name)
+    }
+
+    pub fn log(&self, level: &str, target: &str, message: &str, fields: &[(&str, &dyn ToJson)]) -> io::Result<()> {
+        let mut line = format!(
+            "{{\"timestamp\":{},\"level\":{},\"target\":{},\"message\":{}",
+            json_string(&rfc3339(SystemTime::now())),
+            json_string(level),
+            json_string(target),
+            json_string(message),
+        );
+        for (name, value) in fields {
+            line.push(',');
+            line.push_str(&json_string(name));
+            line.push(':');
+            line.push_str(&value.to_json());
+        }
+        line.push_str("}\n");
+
+        let mut output = self.output.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.max_bytes.is_some_and(|max| output.written > 0 && output.written + line.len() as u64 > max) {
+            output.writer.flush()?;
+            fs::rename(&self.path, self.rotated_path())?;
+            *output = open(&self.path)?;
+        }
+        output.writer.write_all(line.as_bytes())?;
+        output.writer.flush()?;
+        output.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for JsonLinesLogger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JsonLinesLogger").field("path", &self.path).field("max_bytes", &self.max_bytes).finish_non_exhaustive()
+    }
+}
+
+// "2026-10-14T09:30:00.123Z". Days to a date as in Howard Hinnant's civil_from_days.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = ((seconds / 86_400) as i64, seconds % 86_400);
+
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_based_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * march_based_month + 2) / 5 + 1;
+    let month = if march_based_month < 10 { march_based_month + 3 } else { march_based_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::JsonValue;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamps_are_rfc_3339() {
+        let at = |seconds: u64, millis: u64| rfc3339(UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis));
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951_782_400, 5), "2000-02-29T00:00:00.005Z");
+        assert_eq!(at(1_000_000_000, 0), "2001-09-09T01:46:40.000Z");
+        assert_eq!(at(1_791_999_999, 999), "2026-10-14T17:46:39.999Z");
+    }
+
+    #[test]
+    fn writes_one_object_per_line_and_rotates() {
+        let path = std::env::temp_dir().join(format!("surff-json-log-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let logger = JsonLinesLogger::new(&path).unwrap().max_bytes(400);
+        let _ = fs::remove_file(logger.rotated_path());
+
+        logger.log("info", "surff::server", "a \"quoted\"\nline", &[("status", &200), ("client", &"192.0.2.1"), ("gone", &None::<u16>)]).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.ends_with('\n') && text.lines().count() == 1, "{:?}", text);
+        let line = JsonValue::parse(text.trim_end()).unwrap();
+        assert_eq!(line.get("level"), Some(&JsonValue::String("info".to_string())));
+        assert_eq!(line.get("target"), Some(&JsonValue::String("surff::server".to_string())));
+        assert_eq!(line.get("message"), Some(&JsonValue::String("a \"quoted\"\nline".to_string())));
+        assert_eq!(line.get("status"), Some(&JsonValue::Number(200.0)));
+        assert_eq!(line.get("gone"), Some(&JsonValue::Null));
+        let Some(JsonValue::String(timestamp)) = line.get("timestamp") else { panic!("{:?}", line) };
+        assert!(timestamp.ends_with('Z') && timestamp.len() == 24, "{}", timestamp);
+
+        // the next ones don't fit: the first goes to .1.
+        logger.log("warn", "surff::server", &"x".repeat(200), &[]).unwrap();
+        logger.log("warn", "surff::server", "third", &[]).unwrap();
+        assert_eq!(fs::read_to_string(logger.rotated_path()).unwrap(), text);
+        let current = fs::read_to_string(&path).unwrap();
+        assert_eq!(current.lines().count(), 2, "{:?}", current);
+        fs::remove_file(logger.rotated_path()).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
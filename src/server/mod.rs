@@ -0,0 +1,1056 @@
+use std::io::{self, prelude::*, BufReader, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client_ip::ClientIpExtractor;
+use crate::config::{Config, TcpKeepAliveConfig};
+use crate::http::{is_preface, read_request_with_progress, refuse_http2, BodyProgress, ReadError, Request, RequestLimits, ResponseBuilder, StatusCode};
+use crate::rate_limit::RateLimiter;
+use crate::router::{Output, Router};
+use crate::{os, PoolStats, ThreadPool, ThreadPoolBuilder, ThreadPoolError, WorkerRestartPolicy};
+
+mod connections;
+mod drain;
+mod handler_timeout;
+mod json_log;
+mod parking;
+mod pipeline;
+mod sampled_log;
+mod upload_progress;
+mod validate;
+mod watcher;
+
+pub use connections::{ConnectionInfo, ConnectionStats, ConnectionSummary};
+pub use drain::ServerHandle;
+pub use json_log::JsonLinesLogger;
+pub use parking::{ParkingMode, RequestParker, DEFAULT_MAX_PARK_DURATION};
+pub use sampled_log::{ForceLog, SampledLogger, DEFAULT_SLOW_REQUEST_THRESHOLD};
+pub use upload_progress::{Progress, UploadProgress, UPLOAD_ID_HEADER};
+pub use validate::{ConfigError, ConfigWarning, MAX_THREADS};
+use drain::Drain;
+use handler_timeout::Gate;
+use pipeline::{can_pipeline, PipelineQueue, MAX_PIPELINE_DEPTH};
+use watcher::Watcher;
+
+/* # Serving connections!
+let mut server = Server::new(&config)?;      // the worker pool, --threads big
+server.listen(addr, Arc::new(router))?;      // as many times as there are addresses,
+server.bind_all(&config.binds, router)?;     // or all of them at once
+server.run()?;                               // one accept thread per listener; blocks
+1. A worker only ever runs a connection that has something to read. Fresh
+connections, and kept-alive ones between requests, wait in the watcher (see
+watcher.rs) instead, which queues them on the pool once the next request starts.
+2. The worker reads the request (read_request), passes it to the listener's
+Router, and goes on with the next request if it's already in the buffer
+(pipelining). Otherwise the connection goes back to the watcher and the worker
+is free for someone else.
+3. Waiting is limited all the same: a new connection has FIRST_BYTE_TIMEOUT to
+start its first request, a kept-alive one --keepalive-idle-timeout (default
+KEEP_ALIVE_IDLE_TIMEOUT) to start the next; the watcher closes those that don't. Once a request has started, each read may take READ_TIMEOUT.
+4. With --io-threads (the default), reading a request, body included, happens on a
+separate pool of I/O threads, and only the complete request is queued on the
+workers: a client trickling its POST body in (slowloris) ties up an I/O thread,
+not a worker. --io-threads 0 reads on the workers instead. Pipelined requests
+are then answered in parallel, and sent in order (see pipeline.rs).
+5. With --handler-timeout, a handler that takes longer is given up on (see
+handler_timeout.rs).
+6. Draining (see drain.rs) finishes the requests that are running and closes the
+connections, without taking new ones. Parking (see parking.rs) holds new connections
+during a deploy instead, until they're released.
+7. Every connection counts its requests; once it's closed, that goes into the
+ConnectionStats (see connections.rs).
+8. Overload is decided by the workers and their queue: above 90% of the workers busy,
+the accept thread waits a millisecond after each connection; with all of them busy,
+or once --max-queued connections are waiting for a worker, new ones get a 503 straight
+from the accept thread instead of joining the queue. Connections already accepted
+aren't turned away: their next request waits its turn.
+9. A connection that opens with HTTP/2's preface is told to use HTTP/1.1 in HTTP/2
+(GOAWAY, see http/http2.rs) and closed.
+10. on_body_progress is told about request bodies as they're read; UploadProgress
+(see upload_progress.rs) keeps that per upload, for clients to ask after. */
+
+pub const FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const KEEP_ALIVE_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(10);
+
+// # Backpressure: above this share of busy workers, slow the accept loop down.
+const BACKPRESSURE_UTILIZATION: f64 = 0.9;
+const BACKPRESSURE_DELAY: Duration = Duration::from_millis(1);
+// Every worker busy: a new connection would only wait for one.
+const SATURATED_UTILIZATION: f64 = 1.0;
+
+pub struct Server {
+    pool: Arc<ThreadPool>,
+    io_pool: Option<Arc<ThreadPool>>,
+    listeners: Vec<(TcpListener, Arc<Router>)>,
+    settings: Settings,
+    accept_backlog: u32,
+    tcp_keepalive: Option<TcpKeepAliveConfig>,
+    max_queued: usize,
+    closed_idle: Arc<AtomicU64>,
+    detached_handlers: Arc<AtomicU64>,
+    connection_stats: ConnectionStats,
+    drain: Arc<Drain>,
+    parker: Arc<RequestParker>,
+    logger: SampledLogger,
+}
+
+#[derive(Clone)]
+struct Settings {
+    limits: RequestLimits,
+    client_ips: ClientIpExtractor,
+    limiter: Option<RateLimiter>,
+    first_byte_timeout: Duration,
+    idle_timeout: Duration,
+    read_timeout: Duration,
+    handler_timeout: Option<Duration>,
+    body_progress: Option<Arc<BodyProgress>>,
+}
+
+// What the accept threads, the watcher and every connection job share while the server runs.
+struct Shared {
+    pool: Arc<ThreadPool>,
+    io_pool: Option<Arc<ThreadPool>>,
+    watcher: Watcher,
+    settings: Settings,
+    closed: Sender<ConnectionInfo>,
+    detached_handlers: Arc<AtomicU64>,
+    drain: Arc<Drain>,
+    parker: Arc<RequestParker>,
+    logger: SampledLogger,
+}
+
+// A connection between requests: the BufReader may already hold the start of the next one.
+pub(crate) struct Connection {
+    reader: BufReader<TcpStream>,
+    info: ConnectionInfo,
+    router: Arc<Router>,
+    // the same socket again, for answering when the reader is out of reach (a 503 from
+    // try_execute_or_close, or from a drain that ran out of time).
+    handle: Arc<TcpStream>,
+    // gets the info once the connection is dropped (closed).
+    closed: Sender<ConnectionInfo>,
+}
+
+impl Connection {
+    fn stream(&self) -> &TcpStream {
+        self.reader.get_ref()
+    }
+
+    fn peer(&self) -> SocketAddr {
+        self.info.remote_addr
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // fails only once the aggregator is gone, and then nobody's counting anymore.
+        let _ = self.closed.send(self.info.clone());
+    }
+}
+
+impl Server {
+    pub fn new(config: &Config) -> Result<Server, ThreadPoolError> {
+        let io_pool = match config.io_threads {
+            0 => None,
+            io_threads => Some(Arc::new(ThreadPoolBuilder::new().thread_name("surff-io").build(io_threads)?)),
+        };
+        // serialized now rather than when the server is at its busiest.
+        crate::service_unavailable();
+        Ok(Server {
+            // a full queue turns new connections away (try_execute_or_close) instead of growing;
+            // a handler that keeps panicking doesn't get its workers respawned in a tight loop.
+            pool: Arc::new(
+                ThreadPoolBuilder::new()
+                    .queue_capacity(config.max_queued_connections)
+                    .restart_policy(WorkerRestartPolicy::circuit_breaker())
+                    .build(config.threads)?,
+            ),
+            io_pool,
+            listeners: Vec::new(),
+            settings: Settings {
+                limits: RequestLimits::default(),
+                client_ips: ClientIpExtractor::new(config.trusted_proxies.proxies.clone()),
+                limiter: None,
+                first_byte_timeout: FIRST_BYTE_TIMEOUT,
+                idle_timeout: config.keepalive_idle_timeout,
+                read_timeout: READ_TIMEOUT,
+                handler_timeout: match config.handler_timeout_secs {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                },
+                body_progress: None,
+            },
+            accept_backlog: config.accept_backlog,
+            tcp_keepalive: config.tcp_keepalive,
+            max_queued: config.max_queued_connections,
+            closed_idle: Arc::new(AtomicU64::new(0)),
+            detached_handlers: Arc::new(AtomicU64::new(0)),
+            connection_stats: ConnectionStats::default(),
+            drain: Arc::new(Drain::default()),
+            parker: Arc::new(RequestParker::default()),
+            logger: SampledLogger::new(config.log_sample_rate, Duration::from_millis(config.slow_request_threshold_ms)),
+        })
+    }
+
+    pub fn pool(&self) -> &Arc<ThreadPool> {
+        &self.pool
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    // For draining the server (ServerHandle::drain_connections) once run() has it.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            drain: Arc::clone(&self.drain),
+            parker: Arc::clone(&self.parker),
+            pool: Arc::clone(&self.pool),
+            io_pool: self.io_pool.clone(),
+        }
+    }
+
+    // How long a connection may stay parked (see parking.rs) before it's served anyway.
+    pub fn max_park_duration(&mut self, duration: Duration) {
+        self.parker.set_max_park_duration(duration);
+    }
+
+    // Like closed_idle_connections, a handle that keeps counting while the server runs.
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.connection_stats.clone()
+    }
+
+    // Returns a handle that keeps counting once run() has taken the server: how many
+    // connections were closed for not starting a request in time.
+    pub fn closed_idle_connections(&self) -> Counter {
+        Counter(Arc::clone(&self.closed_idle))
+    }
+
+    // Handlers given up on by --handler-timeout, whose threads may still be running.
+    pub fn detached_handlers(&self) -> Counter {
+        Counter(Arc::clone(&self.detached_handlers))
+    }
+
+    // Checked for every request (one connection can carry many), by client IP.
+    pub fn rate_limit(&mut self, limiter: RateLimiter) {
+        self.settings.limiter = Some(limiter);
+    }
+
+    // Called as request bodies come in, after every read (see http/read.rs), on the
+    // thread reading them: UploadProgress::record, say.
+    pub fn on_body_progress(&mut self, progress: impl Fn(&Request, u64, Option<u64>) + Send + Sync + 'static) {
+        self.settings.body_progress = Some(Arc::new(progress));
+    }
+
+    // The request log, as JSON Lines (--log-file) instead of on stdout.
+    pub fn log_to(&mut self, logger: Arc<JsonLinesLogger>) {
+        self.logger.log_to(logger);
+    }
+
+    // Binds now, so a bad address fails before anything runs; accepting starts with run().
+    // std binds with SO_REUSEADDR and a backlog of 128, which is raised to --backlog.
+    pub fn listen(&mut self, addr: SocketAddr, router: Arc<Router>) -> io::Result<SocketAddr> {
+        let listener = self.bind(addr)?;
+        self.add_listener(listener, router)
+    }
+
+    // # Several addresses, e.g. 0.0.0.0:80 and [::]:80, for the same router: all of them or none.
+    // Every address is bound before any of them is kept, so if one fails (already in use,
+    // no permission), the ones bound so far are closed again and the server listens on
+    // nothing new. Returns the local addresses, in the same order (port 0 => the one picked).
+    pub fn bind_all(&mut self, addrs: &[SocketAddr], router: Arc<Router>) -> io::Result<Vec<SocketAddr>> {
+        let mut bound = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let listener = self.bind(*addr).map_err(|e| io::Error::new(e.kind(), format!("failed to bind {}: {}", addr, e)))?;
+            bound.push(listener);
+        }
+        bound.into_iter().map(|listener| self.add_listener(listener, Arc::clone(&router))).collect()
+    }
+
+    fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let listener = TcpListener::bind(addr)?;
+        os::set_backlog(&listener, self.accept_backlog)?;
+        Ok(listener)
+    }
+
+    fn add_listener(&mut self, listener: TcpListener, router: Arc<Router>) -> io::Result<SocketAddr> {
+        let local = listener.local_addr()?;
+        self.drain.add_listener(local);
+        self.listeners.push((listener, router));
+        Ok(local)
+    }
+
+    // Runs until every accept thread has stopped, which is when the server has drained
+    // (ServerHandle::drain_connections), or their listeners fail for good.
+    // Err if the watcher or the connection stats thread can't be started.
+    pub fn run(self) -> io::Result<()> {
+        let (watcher, watcher_thread) = Watcher::new(Arc::clone(&self.closed_idle))?;
+        let shared = Arc::new(Shared {
+            pool: Arc::clone(&self.pool),
+            io_pool: self.io_pool,
+            watcher,
+            settings: self.settings,
+            closed: self.connection_stats.spawn_aggregator()?,
+            detached_handlers: self.detached_handlers,
+            drain: Arc::clone(&self.drain),
+            parker: self.parker,
+            logger: self.logger,
+        });
+        self.drain.set_watcher(shared.watcher.clone());
+
+        // The watcher holds on to Shared (and so to its own sender) for as long as the
+        // process runs, like the accept threads do.
+        let ready = Arc::clone(&shared);
+        watcher_thread.spawn(move |connection| ready.queue(connection))?;
+
+        let accept_threads: Vec<_> = self
+            .listeners
+            .into_iter()
+            .map(|(listener, router)| {
+                let acceptor = Arc::new(Acceptor {
+                    shared: Arc::clone(&shared),
+                    router,
+                    tcp_keepalive: self.tcp_keepalive,
+                    max_queued: self.max_queued,
+                });
+                thread::spawn(move || acceptor.run(listener))
+            })
+            .collect();
+
+        for accept_thread in accept_threads {
+            let _ = accept_thread.join();
+        }
+        self.drain.wait_finished();
+        Ok(())
+    }
+}
+
+// One of the server's counters, still readable once run() has taken the server.
+#[derive(Clone)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub(crate) fn new(count: Arc<AtomicU64>) -> Counter {
+        Counter(count)
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// One per listener, on its own thread (plain threads: the pool is for requests).
+struct Acceptor {
+    shared: Arc<Shared>,
+    router: Arc<Router>,
+    tcp_keepalive: Option<TcpKeepAliveConfig>,
+    max_queued: usize,
+}
+
+impl Acceptor {
+    fn run(self: &Arc<Acceptor>, listener: TcpListener) {
+        if let Ok(addr) = listener.local_addr() {
+            println!("Listening on {}", addr);
+        }
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // accept errors are usually transient (EAGAIN, ECONNABORTED, or EMFILE
+                    // when we're out of file descriptors) => log and keep accepting.
+                    eprintln!("Failed to accept connection: {}", e);
+                    if e.kind() != ErrorKind::Interrupted && e.kind() != ErrorKind::WouldBlock {
+                        // back off a little so EMFILE doesn't turn into a busy loop.
+                        thread::sleep(ACCEPT_ERROR_BACKOFF);
+                    }
+                    continue;
+                }
+            };
+            if self.shared.drain.is_draining() {
+                // dropping the listener (on return) closes it: no more connections.
+                return;
+            }
+            let Some(stream) = self.shared.parker.admit(stream, self) else {
+                continue;
+            };
+            self.serve(stream);
+
+            if self.shared.pool.utilization() > BACKPRESSURE_UTILIZATION {
+                thread::sleep(BACKPRESSURE_DELAY);
+            }
+        }
+    }
+
+    // Also where parked connections come back to, once they're released.
+    fn serve(&self, stream: TcpStream) {
+        println!("Connection established!");
+        if let Err(e) = self.accept(stream) {
+            log_connection_error(&e);
+        }
+    }
+
+    fn accept(&self, mut stream: TcpStream) -> io::Result<()> {
+        // # TCP keepalive: lets the kernel notice clients that vanished without a FIN.
+        if let Some(keepalive) = self.tcp_keepalive {
+            if let Err(e) = os::set_tcp_keepalive(&stream, keepalive.idle, keepalive.interval, keepalive.count) {
+                eprintln!("Failed to enable TCP keepalive: {}", e);
+            }
+        }
+
+        if self.shared.pool.utilization() >= SATURATED_UTILIZATION || self.shared.pool.metrics().queued_jobs >= self.max_queued {
+            // queueing it would only make the client wait behind everyone else.
+            return stream.write_all(crate::service_unavailable());
+        }
+
+        // only the reads inside a request block; waiting between requests is the watcher's job.
+        stream.set_read_timeout(Some(self.shared.settings.read_timeout))?;
+        let connection = Connection {
+            info: ConnectionInfo { accepted_at: Instant::now(), requests_served: 0, remote_addr: stream.peer_addr()? },
+            handle: Arc::new(stream.try_clone()?),
+            reader: BufReader::new(stream),
+            router: Arc::clone(&self.router),
+            closed: self.shared.closed.clone(),
+        };
+        self.shared.watcher.park(connection, self.shared.settings.first_byte_timeout);
+        Ok(())
+    }
+}
+
+impl Shared {
+    // Called by the watcher once the connection has something to read.
+    fn queue(self: &Arc<Shared>, connection: Connection) {
+        let stream = Arc::clone(&connection.handle);
+        let shared = Arc::clone(self);
+        let queued = match &self.io_pool {
+            Some(io_pool) => io_pool.try_execute_or_close(&stream, move || shared.read_then_answer(connection)),
+            None => self.pool.try_execute_or_close(&stream, move || {
+                if let Err(e) = shared.serve(connection) {
+                    log_connection_error(&e);
+                }
+            }),
+        };
+        if let Err(e) = queued {
+            // the client got a 503, and the connection is closed.
+            eprintln!("Turned a connection away: {}", e);
+        }
+    }
+
+    // # Keep-alive: serve the requests that have arrived, then hand the connection back.
+    fn serve(&self, mut connection: Connection) -> io::Result<()> {
+        loop {
+            let Some(request) = self.read(&mut connection)? else {
+                // returning drops the stream, which closes the connection.
+                return Ok(());
+            };
+            let output = Output::Stream(connection.reader.get_mut());
+            if !self.answer(connection.info.remote_addr, &connection.router, &request, output, Some(&connection.handle))? {
+                return Ok(());
+            }
+            if connection.reader.buffer().is_empty() {
+                self.watcher.park(connection, self.settings.idle_timeout);
+                return Ok(());
+            }
+            // the next (pipelined) request is already here.
+        }
+    }
+
+    // # With I/O threads: read here, answer on a worker, then come back for the next request.
+    fn read_then_answer(self: Arc<Shared>, mut connection: Connection) {
+        let request = match self.read(&mut connection) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => return log_connection_error(&e),
+        };
+        if connection.reader.buffer().is_empty() || !can_pipeline(&request) {
+            return self.answer_on_worker(connection, request);
+        }
+        if let Err(e) = self.pipeline(connection, request) {
+            log_connection_error(&e);
+        }
+    }
+
+    fn answer_on_worker(self: Arc<Shared>, mut connection: Connection, request: Request) {
+        let stream = Arc::clone(&connection.handle);
+        let shared = Arc::clone(&self);
+        let queued = self.pool.try_execute_or_close(&stream, move || {
+            let output = Output::Stream(connection.reader.get_mut());
+            match shared.answer(connection.info.remote_addr, &connection.router, &request, output, Some(&connection.handle)) {
+                Ok(true) => shared.next_request(connection),
+                Ok(false) => {}
+                Err(e) => log_connection_error(&e),
+            }
+        });
+        if let Err(e) = queued {
+            eprintln!("Turned a request away: {}", e);
+        }
+    }
+
+    // Back to the watcher, or to the I/O threads if the next (pipelined) request is already here.
+    fn next_request(self: &Arc<Shared>, connection: Connection) {
+        if connection.reader.buffer().is_empty() {
+            self.watcher.park(connection, self.settings.idle_timeout);
+        } else {
+            self.queue(connection);
+        }
+    }
+
+    // # Pipelining (see pipeline.rs): every request in the buffer goes to the workers at once,
+    // the responses go to the client in order, from this (I/O) thread.
+    fn pipeline(self: &Arc<Shared>, mut connection: Connection, first: Request) -> io::Result<()> {
+        let queue = PipelineQueue::new();
+        let mut request = first;
+        // a request that can't be pipelined, answered once the ones before it are out.
+        let mut after = None;
+        loop {
+            let keep_alive = request.keep_alive();
+            let slot = queue.reserve();
+            let shared = Arc::clone(self);
+            let router = Arc::clone(&connection.router);
+            let peer = connection.peer();
+            // if it can't be queued, the dropped Slot counts as a failed response.
+            let _ = self.pool.execute(move || {
+                let mut bytes = Vec::new();
+                let output = Output::Buffer { buffer: &mut bytes, peer };
+                let reusable = shared.answer(peer, &router, &request, output, None).unwrap_or_else(|e| {
+                    log_connection_error(&e);
+                    false
+                });
+                slot.complete(bytes, reusable);
+            });
+
+            if !keep_alive || connection.reader.buffer().is_empty() || queue.len() >= MAX_PIPELINE_DEPTH {
+                break;
+            }
+            // the error response to a malformed request has to wait its turn too.
+            match self.read_request(&mut connection) {
+                Ok(next) if can_pipeline(&next) => {
+                    connection.info.requests_served += 1;
+                    request = next;
+                }
+                Ok(next) => {
+                    connection.info.requests_served += 1;
+                    after = Some(next);
+                    break;
+                }
+                Err(ReadError::ConnectionClosed) => break,
+                Err(ReadError::Io(e)) => {
+                    // the responses so far still go out, then the connection is closed.
+                    log_connection_error(&e);
+                    queue.reserve().complete(Vec::new(), false);
+                    break;
+                }
+                Err(e) => {
+                    let mut bytes = Vec::new();
+                    error_response(&e).write_to(&mut bytes)?;
+                    queue.reserve().complete(bytes, false);
+                    break;
+                }
+            }
+        }
+
+        while let Some(answered) = queue.next() {
+            connection.reader.get_mut().write_all(&answered.bytes)?;
+            if !answered.reusable {
+                // returning drops the stream, which closes the connection.
+                return Ok(());
+            }
+        }
+        match after {
+            Some(request) => Arc::clone(self).answer_on_worker(connection, request),
+            None => self.next_request(connection),
+        }
+        Ok(())
+    }
+
+    fn read_request(&self, connection: &mut Connection) -> Result<Request, ReadError> {
+        read_request_with_progress(&mut connection.reader, &self.settings.limits, self.settings.body_progress.as_deref())
+    }
+
+    // The next request; None when the connection should be closed (an error was answered already).
+    fn read(&self, connection: &mut Connection) -> io::Result<Option<Request>> {
+        if connection.info.requests_served == 0 && is_preface(connection.reader.fill_buf()?) {
+            refuse_http2(connection.reader.get_mut())?;
+            return Ok(None);
+        }
+        match self.read_request(connection) {
+            Ok(request) => {
+                connection.info.requests_served += 1;
+                Ok(Some(request))
+            }
+            // the client hung up between requests: nothing to answer.
+            Err(ReadError::ConnectionClosed) => Ok(None),
+            Err(ReadError::Io(e)) => Err(e),
+            Err(e) => {
+                error_response(&e).write_to(connection.reader.get_mut())?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Answers one request; false when the connection should be closed.
+    // `stream` is the connection it's answered on (not for pipelined requests), for the drain.
+    fn answer(
+        &self,
+        peer: SocketAddr,
+        router: &Arc<Router>,
+        request: &Request,
+        mut output: Output,
+        stream: Option<&Arc<TcpStream>>,
+    ) -> io::Result<bool> {
+        let started = Instant::now();
+        let client_ip = self.settings.client_ips.client_ip_for(peer.ip(), request);
+        if let Some(limiter) = &self.settings.limiter {
+            if !limiter.check_and_consume(client_ip) {
+                ResponseBuilder::new(StatusCode::TooManyRequests)
+                    .header("Retry-After", "1")
+                    .header("Connection", "close")
+                    .write_to(&mut output)?;
+                self.logger.log(request, client_ip, Some(StatusCode::TooManyRequests), started.elapsed());
+                return Ok(false);
+            }
+        }
+
+        let gate = Arc::new(Gate::default());
+        let _in_flight = stream.map(|stream| self.drain.register(stream, &gate));
+        let close = self.drain.is_draining();
+        let reusable = match self.settings.handler_timeout {
+            Some(timeout) if handler_timeout::is_timed(request) => {
+                handler_timeout::dispatch(router, request, output, close, &gate, timeout, &self.detached_handlers)?
+            }
+            _ => {
+                let open = |status| gate.open(status);
+                router.dispatch_to(request, output, close, Some(&open))?
+            }
+        };
+        self.logger.log(request, client_ip, gate.status(), started.elapsed());
+        // a drain that started in the meantime closes it too.
+        Ok(reusable && request.keep_alive() && !self.drain.is_draining())
+    }
+}
+
+// After a malformed request we can't tell where the next one starts => close.
+fn error_response(e: &ReadError) -> ResponseBuilder {
+    let mut response = ResponseBuilder::new(e.status().unwrap_or(StatusCode::BadRequest));
+    response
+        .header("Content-Type", "text/plain")
+        .header("Connection", "close")
+        .body_str(&format!("{}\n", e));
+    response
+}
+
+// Clients hanging up on us is business as usual, so that's only worth a quiet note;
+// anything else (e.g. a missing HTML file) is a real error.
+fn log_connection_error(e: &io::Error) {
+    match e.kind() {
+        ErrorKind::BrokenPipe
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::TimedOut
+        | ErrorKind::WouldBlock
+        | ErrorKind::UnexpectedEof => println!("Connection closed: {}", e),
+        _ => eprintln!("Error handling connection: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use std::io::{Read, Write};
+    use std::time::Instant;
+
+    // A server on a free loopback port, with short timeouts and a route that echoes the query.
+    fn start(threads: usize) -> SocketAddr {
+        start_with(Config { threads, ..Config::default() }).0
+    }
+
+    fn start_with(config: Config) -> (SocketAddr, Counter) {
+        let (addr, closed_idle, _) = start_counting(config);
+        (addr, closed_idle)
+    }
+
+    fn start_counting(config: Config) -> (SocketAddr, Counter, ConnectionStats) {
+        let (server, addr) = listening(config);
+        let closed_idle = server.closed_idle_connections();
+        let connection_stats = server.connection_stats();
+        // the accept threads run for the rest of the test binary.
+        thread::spawn(move || server.run());
+        (addr, closed_idle, connection_stats)
+    }
+
+    fn listening(config: Config) -> (Server, SocketAddr) {
+        let mut server = Server::new(&config).unwrap();
+        server.settings.first_byte_timeout = Duration::from_millis(500);
+        server.settings.idle_timeout = Duration::from_millis(200);
+
+        let mut router = Router::new();
+        router.route(Method::Get, "/", |request, response| {
+            response.send(
+                ResponseBuilder::new(StatusCode::Ok)
+                    .body_str(&format!("{}?{}", request.path, request.query.as_deref().unwrap_or(""))),
+            )
+        });
+        router.route(Method::Get, "/slow", |request, response| {
+            thread::sleep(Duration::from_millis(200));
+            response.send(ResponseBuilder::new(StatusCode::Ok).body_str(&format!("slow {}", request.query.as_deref().unwrap_or(""))))
+        });
+        let addr = server.listen("127.0.0.1:0".parse().unwrap(), Arc::new(router)).unwrap();
+        (server, addr)
+    }
+
+    #[test]
+    fn bind_all_listens_on_every_address() {
+        let (mut server, first) = listening(Config { threads: 2, ..Config::default() });
+        let mut router = Router::new();
+        router.get("/", |_, response| response.send(ResponseBuilder::new(StatusCode::Ok).body_str("both")));
+        let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let addrs = server.bind_all(&[loopback, loopback], Arc::new(router)).unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_ne!(addrs[0], addrs[1]);
+        thread::spawn(move || server.run());
+
+        for addr in addrs {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+            assert!(read_all(&mut client).ends_with("\r\n\r\nboth"), "{}", addr);
+        }
+        // the listener from before is still there too.
+        let mut client = TcpStream::connect(first).unwrap();
+        client.write_all(b"GET /?first HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_all(&mut client).ends_with("/?first"));
+    }
+
+    #[test]
+    fn bind_all_is_all_or_nothing() {
+        let mut server = Server::new(&Config { threads: 1, ..Config::default() }).unwrap();
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = ["127.0.0.1:0".parse().unwrap(), taken.local_addr().unwrap()];
+        assert!(server.bind_all(&addrs, Arc::new(Router::new())).is_err());
+        assert!(server.listeners.is_empty());
+    }
+
+    fn read_all(stream: &mut TcpStream) -> String {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        for io_threads in [0, 2] {
+            let (addr, _) = start_with(Config { threads: 1, io_threads, ..Config::default() });
+            let mut client = TcpStream::connect(addr).unwrap();
+
+            // Both requests in one write: the second is already buffered when the first is done.
+            client.write_all(b"GET /?first HTTP/1.1\r\n\r\nGET /?second HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+            let responses = read_all(&mut client);
+
+            let first = responses.find("\r\n\r\n/?first").expect("first response");
+            let second = responses.find("\r\n\r\n/?second").expect("second response");
+            assert!(first < second);
+            assert_eq!(responses.matches("HTTP/1.1 200 OK").count(), 2);
+        }
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_parallel() {
+        let (addr, _) = start_with(Config { threads: 3, io_threads: 1, ..Config::default() });
+        let mut client = TcpStream::connect(addr).unwrap();
+
+        // the fast one finishes first, but has to wait for the slow ones before it.
+        let started = Instant::now();
+        client.write_all(b"GET /slow?1 HTTP/1.1\r\n\r\nGET /slow?2 HTTP/1.1\r\n\r\nGET /?3 HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let responses = read_all(&mut client);
+        assert!(started.elapsed() < Duration::from_millis(390), "{:?}", started.elapsed());
+
+        let positions: Vec<usize> = ["slow 1", "slow 2", "/?3"].iter().map(|body| responses.find(body).expect(body)).collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", responses);
+    }
+
+    #[test]
+    fn a_malformed_request_mid_pipeline_waits_its_turn() {
+        let (addr, _) = start_with(Config { threads: 2, io_threads: 1, ..Config::default() });
+        let mut client = TcpStream::connect(addr).unwrap();
+
+        client.write_all(b"GET /slow?1 HTTP/1.1\r\n\r\nNONSENSE\r\n\r\nGET /?never HTTP/1.1\r\n\r\n").unwrap();
+        let responses = read_all(&mut client);
+        let (first, rest) = responses.split_once("slow 1").expect("first response");
+        assert!(first.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}", responses);
+        assert!(rest.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", responses);
+        assert!(!rest.contains("never"));
+    }
+
+    #[test]
+    fn slow_bodies_dont_hold_workers() {
+        let (addr, _) = start_with(Config { threads: 1, io_threads: 2, ..Config::default() });
+        // the body never finishes: an I/O thread waits for it, the only worker doesn't.
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\nsome").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /?fast HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let response = read_all(&mut client);
+        assert!(response.ends_with("/?fast"), "{:?}", response);
+        assert!(started.elapsed() < Duration::from_millis(400), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn keep_alive_requests_share_the_connection() {
+        let mut client = TcpStream::connect(start(1)).unwrap();
+
+        client.write_all(b"GET /?one HTTP/1.1\r\n\r\n").unwrap();
+        let mut first = [0; 1024];
+        let n = client.read(&mut first).unwrap();
+        assert!(String::from_utf8_lossy(&first[..n]).ends_with("/?one"));
+
+        // the connection waited in the watcher in between, and comes back for the next request.
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"GET /?two HTTP/1.1\r\n\r\n").unwrap();
+        let started = Instant::now();
+        let rest = read_all(&mut client);
+        assert!(rest.ends_with("/?two"), "{:?}", rest);
+        // closed by the idle timeout, not left open.
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn idle_connections_dont_hold_workers() {
+        let addr = start(1);
+        let idle: Vec<TcpStream> = (0..4).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /?busy HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let response = read_all(&mut client);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{:?}", response);
+        assert!(started.elapsed() < Duration::from_millis(400), "{:?}", started.elapsed());
+
+        // connections that never send a byte are closed after the first-byte timeout.
+        for mut stream in idle {
+            assert_eq!(read_all(&mut stream), "");
+        }
+    }
+
+    #[test]
+    fn stale_keep_alive_connections_are_reaped() {
+        let (addr, closed_idle) = start_with(Config { threads: 1, ..Config::default() });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /?once HTTP/1.1\r\n\r\n").unwrap();
+
+        // keep-alive, but no second request: the watcher closes it after the idle timeout.
+        let started = Instant::now();
+        let response = read_all(&mut client);
+        assert!(response.ends_with("/?once"), "{:?}", response);
+        let idle = started.elapsed();
+        assert!(idle >= Duration::from_millis(150) && idle < Duration::from_secs(2), "{:?}", idle);
+        assert_eq!(closed_idle.get(), 1);
+    }
+
+    #[test]
+    fn closed_connections_are_counted() {
+        let (addr, _, stats) = start_counting(Config { threads: 1, ..Config::default() });
+        let mut reused = TcpStream::connect(addr).unwrap();
+        reused.write_all(b"GET /?1 HTTP/1.1\r\n\r\nGET /?2 HTTP/1.1\r\n\r\nGET /?3 HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        read_all(&mut reused);
+        let mut once = TcpStream::connect(addr).unwrap();
+        once.write_all(b"GET /?4 HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        read_all(&mut once);
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while stats.summary().connections_total < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let summary = stats.summary();
+        assert_eq!(summary.connections_total, 2);
+        assert_eq!(summary.p50_requests_per_connection, 1);
+        assert_eq!(summary.max_requests_per_connection, 3);
+    }
+
+    #[test]
+    fn draining_finishes_running_requests_and_closes_the_rest() {
+        let (server, addr) = listening(Config { threads: 2, ..Config::default() });
+        let handle = server.handle();
+        let run = thread::spawn(move || server.run());
+
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"GET /?idle HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = [0; 512];
+        let _ = idle.read(&mut response).unwrap();
+        let mut running = TcpStream::connect(addr).unwrap();
+        running.write_all(b"GET /slow?running HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        handle.drain_connections(Duration::from_secs(2));
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+        run.join().unwrap().unwrap();
+
+        // keep-alive was asked for, but the drain closes the connection after the response.
+        let response = read_all(&mut running);
+        assert!(response.contains("\r\nConnection: close\r\n") && response.ends_with("slow running"), "{:?}", response);
+        assert_eq!(read_all(&mut idle), "");
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    // Whatever arrives within `wait`, or "" if nothing did.
+    fn read_for(stream: &mut TcpStream, wait: Duration) -> String {
+        stream.set_read_timeout(Some(wait)).unwrap();
+        let mut response = [0; 1024];
+        match stream.read(&mut response) {
+            Ok(read) => String::from_utf8_lossy(&response[..read]).into_owned(),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => String::new(),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn parked_connections_wait_for_release() {
+        let (server, addr) = listening(Config { threads: 2, ..Config::default() });
+        let parker = server.handle().parker().clone();
+        thread::spawn(move || server.run());
+
+        // Normal: answered straight away.
+        assert_eq!(parker.mode(), ParkingMode::Normal);
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /?normal HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_all(&mut client).ends_with("/?normal"));
+
+        parker.park();
+        let mut parked = TcpStream::connect(addr).unwrap();
+        parked.write_all(b"GET /?parked HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert_eq!(read_for(&mut parked, Duration::from_millis(200)), "");
+        assert_eq!(parker.parked(), 1);
+
+        parker.release();
+        assert_eq!(parker.mode(), ParkingMode::Normal);
+        assert_eq!(parker.parked(), 0);
+        parked.set_read_timeout(None).unwrap();
+        assert!(read_all(&mut parked).ends_with("/?parked"));
+    }
+
+    #[test]
+    fn parked_connections_are_released_after_max_park_duration() {
+        let (mut server, addr) = listening(Config { threads: 2, ..Config::default() });
+        server.max_park_duration(Duration::from_millis(100));
+        let parker = server.handle().parker().clone();
+        thread::spawn(move || server.run());
+
+        parker.park();
+        let started = Instant::now();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /?late HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_all(&mut client).ends_with("/?late"));
+        assert!(started.elapsed() >= Duration::from_millis(100), "{:?}", started.elapsed());
+        // still parking: only that connection was let through.
+        assert_eq!(parker.mode(), ParkingMode::Parking);
+    }
+
+    #[test]
+    fn draining_parker_turns_connections_away() {
+        let (server, addr) = listening(Config { threads: 2, ..Config::default() });
+        let parker = server.handle().parker().clone();
+        thread::spawn(move || server.run());
+
+        parker.park();
+        // (no requests sent: closing with one unread would reset the connection, 503 and all.)
+        let mut parked = TcpStream::connect(addr).unwrap();
+        while parker.parked() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        parker.drain();
+        assert_eq!(parker.mode(), ParkingMode::Draining);
+        assert!(read_all(&mut parked).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert!(read_all(&mut client).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+
+
+    #[test]
+    fn http2_prior_knowledge_is_sent_back_to_http_1_1() {
+        use crate::http::{Frame, FrameError};
+        for io_threads in [0, 2] {
+            let (addr, _) = start_with(Config { threads: 1, io_threads, ..Config::default() });
+            let mut client = TcpStream::connect(addr).unwrap();
+            // the preface, then the client's (empty) SETTINGS.
+            client.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0").unwrap();
+            let mut answer = Vec::new();
+            client.read_to_end(&mut answer).unwrap();
+
+            let (settings, used) = Frame::parse(&answer).unwrap();
+            assert_eq!((settings.kind, settings.stream_id, settings.payload.len()), (0x4, 0, 0));
+            let (goaway, rest) = Frame::parse(&answer[used..]).unwrap();
+            assert_eq!(goaway.kind, 0x7);
+            assert_eq!(&goaway.payload[..8], &[0, 0, 0, 0, 0, 0, 0, 0xd]);
+            assert_eq!(Frame::parse(&answer[used + rest..]), Err(FrameError::Truncated));
+        }
+    }
+
+
+    #[test]
+    fn body_progress_is_reported_while_the_body_comes_in() {
+        let (mut server, _) = listening(Config { threads: 2, ..Config::default() });
+        let uploads = Arc::new(UploadProgress::new());
+        let recorder = Arc::clone(&uploads);
+        server.on_body_progress(move |request, read, total| recorder.record(request, read, total));
+        let mut router = Router::new();
+        router.post("/upload", |request, response| response.send(ResponseBuilder::new(StatusCode::Ok).body_str(&request.body.len().to_string())));
+        let progress = Arc::clone(&uploads);
+        router.get("/uploads", move |request, response| progress.handle(request, response));
+        let addr = server.listen("127.0.0.1:0".parse().unwrap(), Arc::new(router)).unwrap();
+        thread::spawn(move || server.run());
+
+        let mut upload = TcpStream::connect(addr).unwrap();
+        upload.write_all(b"POST /upload HTTP/1.1\r\nX-Request-Id: abc\r\nContent-Length: 10\r\nConnection: close\r\n\r\nhello").unwrap();
+        let started = Instant::now();
+        while uploads.get("abc").is_none() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let mut ask = TcpStream::connect(addr).unwrap();
+        ask.write_all(b"GET /uploads/abc/progress HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let answer = read_all(&mut ask);
+        assert!(answer.ends_with("{\"bytes_read\": 5, \"content_length\": 10}"), "{}", answer);
+
+        upload.write_all(b"world").unwrap();
+        assert!(read_all(&mut upload).ends_with("\r\n\r\n10"));
+        assert_eq!(uploads.get("abc"), Some(Progress { bytes_read: 10, content_length: Some(10) }));
+    }
+
+
+    #[test]
+    fn a_saturated_pool_turns_new_connections_away() {
+        let (server, addr) = listening(Config { threads: 1, ..Config::default() });
+        let pool = Arc::clone(server.pool());
+        thread::spawn(move || server.run());
+
+        let mut busy = TcpStream::connect(addr).unwrap();
+        busy.write_all(b"GET /slow?busy HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let started = Instant::now();
+        while pool.utilization() < 1.0 {
+            assert!(started.elapsed() < Duration::from_secs(5), "the worker never got busy");
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut turned_away = TcpStream::connect(addr).unwrap();
+        assert!(read_all(&mut turned_away).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        // once the worker is free again, so are new connections.
+        assert!(read_all(&mut busy).ends_with("slow busy"));
+        while pool.utilization() >= 1.0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /?free HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_all(&mut client).ends_with("/?free"));
+    }
+}
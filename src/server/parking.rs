@@ -0,0 +1,165 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{log_connection_error, Acceptor};
+
+/* # Parking: holding new connections while a deploy swaps the server!
+let parker = server.handle().parker().clone();
+parker.park();       // new connections wait, accepted but unanswered
+parker.release();    // ... the new server is ready: serve them (and everyone after)
+parker.drain();      // or: turn them (and everyone after) away with a 503
+Three modes, Normal to start with:
+Normal: connections are served as they're accepted.
+Parking: accepted connections are held, as they are, in a list. Requests already
+running aren't touched. A connection is released on its own once it has waited
+max_park_duration (Server::max_park_duration, 30s by default), so a deploy that
+never finishes doesn't leave clients hanging until they time out themselves.
+Draining: new connections, and the parked ones, get a 503 and are closed.
+Parked connections aren't on the pool's queue, so they don't count towards
+--max-queued; that's looked at when they're released. */
+
+pub const DEFAULT_MAX_PARK_DURATION: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParkingMode {
+    Normal,
+    Parking,
+    Draining,
+}
+
+pub struct RequestParker {
+    state: Mutex<State>,
+}
+
+struct State {
+    mode: ParkingMode,
+    max_park_duration: Duration,
+    parked: Vec<Parked>,
+    // a thread releasing connections that have waited too long, while Parking.
+    timer: bool,
+}
+
+struct Parked {
+    stream: TcpStream,
+    since: Instant,
+    // the listener's, which knows what to do with it.
+    acceptor: Arc<Acceptor>,
+}
+
+impl Default for RequestParker {
+    fn default() -> RequestParker {
+        RequestParker {
+            state: Mutex::new(State {
+                mode: ParkingMode::Normal,
+                max_park_duration: DEFAULT_MAX_PARK_DURATION,
+                parked: Vec::new(),
+                timer: false,
+            }),
+        }
+    }
+}
+
+impl RequestParker {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn mode(&self) -> ParkingMode {
+        self.state().mode
+    }
+
+    // How many connections are waiting to be released.
+    pub fn parked(&self) -> usize {
+        self.state().parked.len()
+    }
+
+    pub(super) fn set_max_park_duration(&self, duration: Duration) {
+        self.state().max_park_duration = duration;
+    }
+
+    pub fn park(self: &Arc<Self>) {
+        let mut state = self.state();
+        state.mode = ParkingMode::Parking;
+        println!("Parking new connections (for at most {:?}).", state.max_park_duration);
+        if !state.timer {
+            state.timer = true;
+            let parker = Arc::clone(self);
+            thread::spawn(move || parker.release_expired());
+        }
+    }
+
+    // Back to Normal; the parked connections are served, oldest first.
+    pub fn release(&self) {
+        let parked = {
+            let mut state = self.state();
+            state.mode = ParkingMode::Normal;
+            std::mem::take(&mut state.parked)
+        };
+        println!("Released {} parked connection(s).", parked.len());
+        for parked in parked {
+            parked.acceptor.serve(parked.stream);
+        }
+    }
+
+    pub fn drain(&self) {
+        let parked = {
+            let mut state = self.state();
+            state.mode = ParkingMode::Draining;
+            std::mem::take(&mut state.parked)
+        };
+        for parked in parked {
+            turn_away(parked.stream);
+        }
+    }
+
+    // The stream back when it should be served now; None when it's been parked or turned away.
+    pub(super) fn admit(&self, stream: TcpStream, acceptor: &Arc<Acceptor>) -> Option<TcpStream> {
+        let mut state = self.state();
+        match state.mode {
+            ParkingMode::Normal => Some(stream),
+            ParkingMode::Parking => {
+                state.parked.push(Parked { stream, since: Instant::now(), acceptor: Arc::clone(acceptor) });
+                None
+            }
+            ParkingMode::Draining => {
+                drop(state);
+                turn_away(stream);
+                None
+            }
+        }
+    }
+
+    // The timer thread: wakes up when the oldest parked connection is due, until the
+    // parker leaves Parking.
+    fn release_expired(&self) {
+        loop {
+            let (expired, wait) = {
+                let mut state = self.state();
+                if state.mode != ParkingMode::Parking {
+                    state.timer = false;
+                    return;
+                }
+                let max = state.max_park_duration;
+                let (expired, waiting) = std::mem::take(&mut state.parked)
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|parked| parked.since.elapsed() >= max);
+                state.parked = waiting;
+                let wait = state.parked.first().map_or(max, |oldest| max.saturating_sub(oldest.since.elapsed()));
+                (expired, wait)
+            };
+            for parked in expired {
+                parked.acceptor.serve(parked.stream);
+            }
+            thread::sleep(wait.max(Duration::from_millis(10)));
+        }
+    }
+}
+
+fn turn_away(mut stream: TcpStream) {
+    if let Err(e) = stream.write_all(crate::service_unavailable()) {
+        log_connection_error(&e);
+    }
+}
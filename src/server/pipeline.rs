@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::http::{Method, Request};
+
+/* # Pipelining: answering in parallel, sending in order!
+A client may send several requests without waiting for the responses, but has to
+get the responses in the same order. With I/O threads (see mod.rs), the requests
+that are already in the buffer are all queued on the workers at once, each
+answering into its own buffer (Output::Buffer):
+1. Every request gets the next sequence number (reserve), and with it a slot.
+2. Whichever worker finishes puts its response into its slot (Slot::complete),
+in any order.
+3. The I/O thread sends them (next): the oldest slot once it's filled, then the
+one after it, and so on, so request 2's response never overtakes request 1's.
+4. A handler that fails (an error, a panic or a full queue, which all drop the Slot
+without completing it) sends whatever it wrote, and the connection is closed after
+it; the responses after it are dropped, the client has to ask again.
+Only GET and HEAD are pipelined, and not when they ask for an Upgrade: those are
+safe to retry, and never take the connection over. Anything else waits until the
+responses before it are out, and is answered on the connection as usual. */
+
+// How many requests of one connection are answered at the same time, at most.
+pub(super) const MAX_PIPELINE_DEPTH: usize = 16;
+
+pub(super) struct PipelineQueue {
+    slots: Mutex<Slots>,
+    filled: Condvar,
+}
+
+struct Slots {
+    // sequence number of responses.front()
+    head: u64,
+    responses: VecDeque<Option<Answered>>,
+}
+
+pub(super) struct Answered {
+    pub(super) bytes: Vec<u8>,
+    // false: close the connection after these bytes.
+    pub(super) reusable: bool,
+}
+
+// A reserved place in the queue; dropping it uncompleted counts as a failed response.
+pub(super) struct Slot {
+    queue: Arc<PipelineQueue>,
+    sequence: u64,
+    completed: bool,
+}
+
+pub(super) fn can_pipeline(request: &Request) -> bool {
+    matches!(request.method, Method::Get | Method::Head) && request.header("Upgrade").is_none()
+}
+
+impl PipelineQueue {
+    pub(super) fn new() -> Arc<PipelineQueue> {
+        Arc::new(PipelineQueue {
+            slots: Mutex::new(Slots { head: 0, responses: VecDeque::new() }),
+            filled: Condvar::new(),
+        })
+    }
+
+    pub(super) fn reserve(self: &Arc<PipelineQueue>) -> Slot {
+        let mut slots = self.slots.lock().unwrap();
+        slots.responses.push_back(None);
+        Slot {
+            queue: Arc::clone(self),
+            sequence: slots.head + slots.responses.len() as u64 - 1,
+            completed: false,
+        }
+    }
+
+    // Responses reserved and not taken by next yet.
+    pub(super) fn len(&self) -> usize {
+        self.slots.lock().unwrap().responses.len()
+    }
+
+    // The oldest response, waiting until it's there; None once every reserved one was taken.
+    pub(super) fn next(&self) -> Option<Answered> {
+        let mut slots = self.slots.lock().unwrap();
+        loop {
+            match slots.responses.front_mut() {
+                None => return None,
+                Some(slot) if slot.is_some() => {
+                    let answered = slot.take();
+                    slots.responses.pop_front();
+                    slots.head += 1;
+                    return answered;
+                }
+                Some(_) => slots = self.filled.wait(slots).unwrap(),
+            }
+        }
+    }
+
+    fn fill(&self, sequence: u64, answered: Answered) {
+        let mut slots = self.slots.lock().unwrap();
+        // the I/O thread only takes filled slots, so this one is still there.
+        let index = (sequence - slots.head) as usize;
+        slots.responses[index] = Some(answered);
+        self.filled.notify_all();
+    }
+}
+
+impl Slot {
+    pub(super) fn complete(mut self, bytes: Vec<u8>, reusable: bool) {
+        self.completed = true;
+        self.queue.fill(self.sequence, Answered { bytes, reusable });
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.queue.fill(self.sequence, Answered { bytes: Vec::new(), reusable: false });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn responses_come_out_in_request_order() {
+        let queue = PipelineQueue::new();
+        let slots: Vec<Slot> = (0..3).map(|_| queue.reserve()).collect();
+
+        // the last request finishes first, the first one last.
+        let workers: Vec<_> = slots
+            .into_iter()
+            .enumerate()
+            .map(|(i, slot)| {
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(60 - 20 * i as u64));
+                    slot.complete(vec![b'0' + i as u8], true);
+                })
+            })
+            .collect();
+
+        let sent: Vec<u8> = std::iter::from_fn(|| queue.next()).flat_map(|answered| answered.bytes).collect();
+        assert_eq!(sent, b"012");
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn a_dropped_slot_is_a_failed_response() {
+        let queue = PipelineQueue::new();
+        let first = queue.reserve();
+        let second = queue.reserve();
+        second.complete(b"second".to_vec(), true);
+        drop(first);
+
+        let answered = queue.next().unwrap();
+        assert!(answered.bytes.is_empty() && !answered.reusable);
+        assert_eq!(queue.len(), 1);
+    }
+}
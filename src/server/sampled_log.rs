@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::JsonLinesLogger;
+use crate::http::{Request, StatusCode};
+use crate::json::ToJson;
+
+/* # Logging some of the requests, under load!
+surff --log-sample-rate 100 --slow-request-threshold 500
+A line per request is a lot of I/O at thousands of requests a second, and most of
+those lines say the same thing. With --log-sample-rate N only one request in N is
+logged (the 1st, the N+1th, ...), except for those worth seeing whatever the rate:
+1. errors: a 4xx or 5xx status (or no response at all);
+2. slow requests: answered in more than --slow-request-threshold ms;
+3. the first request from a client (IP, see client_ip.rs) not seen in the last hour;
+4. requests a middleware marked with request.extensions.insert(ForceLog).
+The default rate, 1, logs every request. A line goes to stdout, or with log_to
+(--log-file) to a JsonLinesLogger instead, as an object with each part a field of its own. */
+
+pub const DEFAULT_SLOW_REQUEST_THRESHOLD: Duration = Duration::from_secs(1);
+const NEW_CLIENT_WINDOW: Duration = Duration::from_secs(60 * 60);
+// Past this many clients the ones not seen within the window are forgotten;
+// if that's not enough (a flood from many addresses), all of them are.
+const MAX_REMEMBERED_CLIENTS: usize = 100_000;
+
+// The extension that gets a request logged whatever the sample rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForceLog;
+
+pub struct SampledLogger {
+    sample_rate: u64,
+    slow_threshold: Duration,
+    counter: AtomicU64,
+    clients: Mutex<HashMap<IpAddr, Instant>>,
+    json: Option<Arc<JsonLinesLogger>>,
+}
+
+impl SampledLogger {
+    // 0 is taken as 1: every request.
+    pub fn new(sample_rate: u32, slow_threshold: Duration) -> SampledLogger {
+        SampledLogger {
+            sample_rate: u64::from(sample_rate.max(1)),
+            slow_threshold,
+            counter: AtomicU64::new(0),
+            clients: Mutex::new(HashMap::new()),
+            json: None,
+        }
+    }
+
+    // Lines go to `logger` instead of stdout.
+    pub fn log_to(&mut self, logger: Arc<JsonLinesLogger>) {
+        self.json = Some(logger);
+    }
+
+    // Called once for every request: it's what the sampling counts.
+    pub fn should_log(&self, request: &Request, client: IpAddr, status: Option<StatusCode>, elapsed: Duration) -> bool {
+        let sampled = self.counter.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample_rate);
+        // looked at every time, so the client is remembered whether or not it's logged.
+        let new_client = self.first_seen(client);
+        let error = status.is_none_or(|status| status.code() >= 400);
+        sampled || new_client || error || elapsed > self.slow_threshold || request.extensions.contains::<ForceLog>()
+    }
+
+    pub fn log(&self, request: &Request, client: IpAddr, status: Option<StatusCode>, elapsed: Duration) {
+        if !self.should_log(request, client, status, elapsed) {
+            return;
+        }
+        let milliseconds = elapsed.as_secs_f64() * 1000.0;
+        let Some(json) = &self.json else {
+            let status = status.map_or_else(|| "no response".to_string(), |status| status.code().to_string());
+            println!(
+                "Request: {} {} {} => {} in {:.1}ms from {}",
+                request.method, request.path, request.version, status, milliseconds, client,
+            );
+            return;
+        };
+        let message = match status {
+            Some(status) => format!("{} {} {} => {}", request.method, request.path, request.version, status.code()),
+            None => format!("{} {} {} => no response", request.method, request.path, request.version),
+        };
+        let level = if status.is_none_or(|status| status.code() >= 500) { "error" } else { "info" };
+        let fields: [(&str, &dyn ToJson); 6] = [
+            ("method", &request.method.as_str()),
+            ("path", &request.path.as_str()),
+            ("version", &request.version.as_str()),
+            ("status", &status.map(|status| status.code())),
+            ("duration_ms", &((milliseconds * 10.0).round() / 10.0)),
+            ("client", &client.to_string()),
+        ];
+        if let Err(e) = json.log(level, "surff::server", &message, &fields) {
+            eprintln!("Failed to write the request log: {}", e);
+        }
+    }
+
+    fn first_seen(&self, client: IpAddr) -> bool {
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if clients.get(&client).is_some_and(|seen| now.duration_since(*seen) < NEW_CLIENT_WINDOW) {
+            return false;
+        }
+        if clients.len() >= MAX_REMEMBERED_CLIENTS {
+            clients.retain(|_, seen| now.duration_since(*seen) < NEW_CLIENT_WINDOW);
+            if clients.len() >= MAX_REMEMBERED_CLIENTS {
+                clients.clear();
+            }
+        }
+        clients.insert(client, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> Request {
+        Request::parse(b"GET / HTTP/1.1\r\n\r\n").unwrap()
+    }
+
+    #[test]
+    fn one_in_n_is_logged() {
+        let logger = SampledLogger::new(3, DEFAULT_SLOW_REQUEST_THRESHOLD);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let fast = Duration::from_millis(1);
+        let logged: Vec<bool> = (0..7).map(|_| logger.should_log(&request(), client, Some(StatusCode::Ok), fast)).collect();
+        // the first is logged anyway: a new client.
+        assert_eq!(logged, [true, false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn errors_slow_requests_new_clients_and_forced_ones_always_are() {
+        let logger = SampledLogger::new(1000, Duration::from_millis(100));
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let fast = Duration::from_millis(1);
+        assert!(logger.should_log(&request(), client, Some(StatusCode::Ok), fast));
+        assert!(!logger.should_log(&request(), client, Some(StatusCode::Ok), fast));
+
+        assert!(logger.should_log(&request(), client, Some(StatusCode::NotFound), fast));
+        assert!(logger.should_log(&request(), client, Some(StatusCode::InternalServerError), fast));
+        assert!(logger.should_log(&request(), client, None, fast));
+        assert!(logger.should_log(&request(), client, Some(StatusCode::Ok), Duration::from_millis(150)));
+        assert!(logger.should_log(&request(), "192.0.2.2".parse().unwrap(), Some(StatusCode::Ok), fast));
+
+        let forced = request();
+        forced.extensions.insert(ForceLog);
+        assert!(logger.should_log(&forced, client, Some(StatusCode::Ok), fast));
+        assert!(!logger.should_log(&request(), client, Some(StatusCode::Ok), fast));
+    }
+
+
+    #[test]
+    fn lines_go_to_the_json_log_when_there_is_one() {
+        let path = std::env::temp_dir().join(format!("surff-sampled-log-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut logger = SampledLogger::new(1, DEFAULT_SLOW_REQUEST_THRESHOLD);
+        logger.log_to(Arc::new(JsonLinesLogger::new(&path).unwrap()));
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        logger.log(&request(), client, Some(StatusCode::NotFound), Duration::from_micros(1250));
+        logger.log(&request(), client, None, Duration::ZERO);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2, "{:?}", text);
+        assert!(lines[0].contains(",\"level\":\"info\",\"target\":\"surff::server\",\"message\":\"GET / HTTP/1.1 => 404\""), "{}", lines[0]);
+        assert!(lines[0].ends_with(",\"method\":\"GET\",\"path\":\"/\",\"version\":\"HTTP/1.1\",\"status\":404,\"duration_ms\":1.3,\"client\":\"192.0.2.1\"}"), "{}", lines[0]);
+        assert!(lines[1].contains("\"level\":\"error\"") && lines[1].contains("\"status\":null"), "{}", lines[1]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Mutex, PoisonError};
+
+use crate::http::{Request, ResponseBuilder, StatusCode};
+use crate::router::Response;
+
+/* # How far along uploads are!
+let uploads = Arc::new(UploadProgress::new());
+let recorder = Arc::clone(&uploads);
+server.on_body_progress(move |request, read, total| recorder.record(request, read, total));
+router.get("/uploads", move |request, response| uploads.handle(request, response));
+GET /uploads/3f2a9c/progress  => {"bytes_read": 524288, "content_length": 1048576}
+1. An upload is known by the X-Request-Id its client sent (UPLOAD_ID_HEADER): the
+client picks one, and asks after it while its POST is still going. Requests without
+one aren't tracked.
+2. record runs on the thread reading the body, after every read: all it does is
+update one entry, under a lock nobody holds for long.
+3. content_length is null for a body without one. An upload that's done stays at
+bytes_read == content_length, until it's one of the oldest past the last 1024. An
+ID that was never seen (or has been forgotten since) is a 404. */
+
+pub const UPLOAD_ID_HEADER: &str = "X-Request-Id";
+const MAX_TRACKED: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub bytes_read: u64,
+    pub content_length: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Uploads {
+    by_id: HashMap<String, Progress>,
+    // oldest first, for forgetting.
+    order: VecDeque<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct UploadProgress {
+    uploads: Mutex<Uploads>,
+}
+
+impl UploadProgress {
+    pub fn new() -> UploadProgress {
+        UploadProgress::default()
+    }
+
+    pub fn record(&self, request: &Request, bytes_read: u64, content_length: Option<u64>) {
+        let Some(id) = request.header(UPLOAD_ID_HEADER) else {
+            return;
+        };
+        let mut uploads = self.uploads.lock().unwrap_or_else(PoisonError::into_inner);
+        let progress = Progress { bytes_read, content_length };
+        if let Some(known) = uploads.by_id.get_mut(id) {
+            *known = progress;
+            return;
+        }
+        if uploads.order.len() >= MAX_TRACKED {
+            if let Some(oldest) = uploads.order.pop_front() {
+                uploads.by_id.remove(&oldest);
+            }
+        }
+        uploads.order.push_back(id.to_string());
+        uploads.by_id.insert(id.to_string(), progress);
+    }
+
+    pub fn get(&self, id: &str) -> Option<Progress> {
+        self.uploads.lock().unwrap_or_else(PoisonError::into_inner).by_id.get(id).copied()
+    }
+
+    // GET /uploads/<id>/progress
+    pub fn handle(&self, request: &Request, response: &mut Response) -> io::Result<()> {
+        let id = request
+            .path
+            .strip_prefix("/uploads/")
+            .and_then(|rest| rest.strip_suffix("/progress"))
+            .filter(|id| !id.is_empty() && !id.contains('/'));
+        let Some(progress) = id.and_then(|id| self.get(id)) else {
+            return response.send(&mut ResponseBuilder::new(StatusCode::NotFound));
+        };
+        let content_length = progress.content_length.map_or("null".to_string(), |length| length.to_string());
+        response.send(
+            ResponseBuilder::new(StatusCode::Ok)
+                .header("Content-Type", "application/json")
+                .header("Cache-Control", "no-store")
+                .body_str(&format!("{{\"bytes_read\": {}, \"content_length\": {}}}", progress.bytes_read, content_length)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{exchange, Router};
+
+    #[test]
+    fn uploads_are_known_by_their_request_id() {
+        let uploads = UploadProgress::new();
+        let upload = Request::parse(b"POST /upload HTTP/1.1\r\nX-Request-Id: 3f2a9c\r\n\r\n").unwrap();
+        uploads.record(&upload, 512, Some(1024));
+        uploads.record(&Request::parse(b"POST /upload HTTP/1.1\r\n\r\n").unwrap(), 1, None);
+        assert_eq!(uploads.get("3f2a9c"), Some(Progress { bytes_read: 512, content_length: Some(1024) }));
+
+        let mut router = Router::new();
+        router.get("/uploads", move |request, response| uploads.handle(request, response));
+        let response = exchange(&router, "GET /uploads/3f2a9c/progress HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\n{\"bytes_read\": 512, \"content_length\": 1024}"), "{}", response);
+        for unknown in ["/uploads/other/progress", "/uploads//progress", "/uploads/3f2a9c"] {
+            let response = exchange(&router, &format!("GET {} HTTP/1.1\r\n\r\n", unknown));
+            assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}: {}", unknown, response);
+        }
+    }
+
+    #[test]
+    fn only_the_latest_uploads_are_kept() {
+        let uploads = UploadProgress::new();
+        for i in 0..=MAX_TRACKED {
+            let request = Request::parse(format!("POST / HTTP/1.1\r\nX-Request-Id: {}\r\n\r\n", i).as_bytes()).unwrap();
+            uploads.record(&request, 1, None);
+        }
+        assert_eq!(uploads.get("0"), None);
+        assert_eq!(uploads.get("1"), Some(Progress { bytes_read: 1, content_length: None }));
+    }
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::thread;
+
+use super::Server;
+use crate::config::Config;
+use crate::os;
+
+/* # Dry run: checking a config without starting anything!
+surff --dry-run --bind 0.0.0.0:80 --threads 64
+CI can run that and fail the build on a nonzero exit. validate_config binds nothing
+and spawns nothing; it looks at the config and at the machine it runs on:
+- errors (the server wouldn't start, or not as intended): no or too many threads,
+a port outside 1-65535, the same address twice, a --static-root that isn't a directory.
+- warnings (it would start, but maybe not as hoped): a port below 1024 without
+root, more threads than CPUs, no --static-root to serve, an admin API that's
+reachable from other machines.
+Every problem is reported, not just the first one. The server has no TLS or log
+files yet; when it does, checking that they're readable belongs here too. */
+
+// More would be a typo rather than a plan, and costs a stack per thread.
+pub const MAX_THREADS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    NoThreads,
+    TooManyThreads { flag: &'static str, threads: usize },
+    // Port 0 asks the OS for any free port, which nobody could then connect to.
+    InvalidPort(SocketAddr),
+    DuplicateBind(SocketAddr),
+    StaticRootNotADirectory(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigWarning {
+    PrivilegedPort(SocketAddr),
+    MoreThreadsThanCpus { threads: usize, cpus: usize },
+    MissingStaticRoot(PathBuf),
+    PublicAdminApi(SocketAddr),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NoThreads => write!(f, "--threads must be at least 1"),
+            ConfigError::TooManyThreads { flag, threads } => {
+                write!(f, "{} {} is more than the maximum of {}", flag, threads, MAX_THREADS)
+            },
+            ConfigError::InvalidPort(addr) => write!(f, "{} has no port (it must be 1-65535)", addr),
+            ConfigError::DuplicateBind(addr) => write!(f, "{} is listened on more than once", addr),
+            ConfigError::StaticRootNotADirectory(path) => write!(f, "--static-root {} is not a directory", path.display()),
+        }
+    }
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigWarning::PrivilegedPort(addr) => write!(f, "{} is below port 1024, which usually needs root", addr),
+            ConfigWarning::MoreThreadsThanCpus { threads, cpus } => {
+                write!(f, "--threads {} is more than this machine's CPU count ({})", threads, cpus)
+            },
+            ConfigWarning::MissingStaticRoot(path) => {
+                write!(f, "--static-root {} doesn't exist, so nothing is served under /static", path.display())
+            },
+            ConfigWarning::PublicAdminApi(addr) => {
+                write!(f, "the admin API on {} can be reached from other machines", addr)
+            },
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Server {
+    pub fn validate_config(config: &Config) -> Result<Vec<ConfigWarning>, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+
+        match config.threads {
+            0 => errors.push(ConfigError::NoThreads),
+            threads if threads > MAX_THREADS => errors.push(ConfigError::TooManyThreads { flag: "--threads", threads }),
+            threads => {
+                // a worker mostly waits on its client, so this is a hint, not a mistake.
+                if let Ok(cpus) = thread::available_parallelism() {
+                    if threads > cpus.get() {
+                        warnings.push(ConfigWarning::MoreThreadsThanCpus { threads, cpus: cpus.get() });
+                    }
+                }
+            },
+        }
+        if config.io_threads > MAX_THREADS {
+            errors.push(ConfigError::TooManyThreads { flag: "--io-threads", threads: config.io_threads });
+        }
+
+        let mut seen = Vec::new();
+        for &addr in config.binds.iter().chain(&config.admin_bind) {
+            if addr.port() == 0 {
+                errors.push(ConfigError::InvalidPort(addr));
+            } else if addr.port() < 1024 && os::running_as_root() == Some(false) {
+                warnings.push(ConfigWarning::PrivilegedPort(addr));
+            }
+            if seen.contains(&addr) {
+                errors.push(ConfigError::DuplicateBind(addr));
+            }
+            seen.push(addr);
+        }
+        if let Some(addr) = config.admin_bind {
+            if !addr.ip().is_loopback() {
+                warnings.push(ConfigWarning::PublicAdminApi(addr));
+            }
+        }
+
+        if config.static_root.is_file() {
+            errors.push(ConfigError::StaticRootNotADirectory(config.static_root.clone()));
+        } else if !config.static_root.exists() {
+            warnings.push(ConfigWarning::MissingStaticRoot(config.static_root.clone()));
+        }
+
+        if errors.is_empty() {
+            Ok(warnings)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            binds: vec!["127.0.0.1:8080".parse().unwrap()],
+            threads: 1,
+            static_root: PathBuf::from("src"),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn a_good_config_has_nothing_to_say() {
+        assert_eq!(Server::validate_config(&config()), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let config = Config {
+            binds: vec![addr, "127.0.0.1:0".parse().unwrap()],
+            threads: 0,
+            io_threads: MAX_THREADS + 1,
+            static_root: PathBuf::from("Cargo.toml"),
+            admin_bind: Some(addr),
+            admin_token: Some("s3cret".to_string()),
+            ..config()
+        };
+        assert_eq!(Server::validate_config(&config), Err(vec![
+            ConfigError::NoThreads,
+            ConfigError::TooManyThreads { flag: "--io-threads", threads: MAX_THREADS + 1 },
+            ConfigError::InvalidPort("127.0.0.1:0".parse().unwrap()),
+            ConfigError::DuplicateBind(addr),
+            ConfigError::StaticRootNotADirectory(PathBuf::from("Cargo.toml")),
+        ]));
+        assert!(Server::validate_config(&Config { threads: MAX_THREADS + 1, ..Config::default() }).is_err());
+    }
+
+    #[test]
+    fn warnings_dont_fail_the_config() {
+        let config = Config {
+            binds: vec!["0.0.0.0:80".parse().unwrap()],
+            threads: MAX_THREADS,
+            static_root: PathBuf::from("no such directory"),
+            admin_bind: Some("0.0.0.0:1999".parse().unwrap()),
+            admin_token: Some("s3cret".to_string()),
+            ..config()
+        };
+        let warnings = Server::validate_config(&config).unwrap();
+        let cpus = thread::available_parallelism().unwrap().get();
+        if cpus < MAX_THREADS {
+            assert!(warnings.contains(&ConfigWarning::MoreThreadsThanCpus { threads: MAX_THREADS, cpus }));
+        }
+        if os::running_as_root() == Some(false) {
+            assert!(warnings.contains(&ConfigWarning::PrivilegedPort("0.0.0.0:80".parse().unwrap())));
+        }
+        assert!(warnings.contains(&ConfigWarning::MissingStaticRoot(PathBuf::from("no such directory"))));
+        assert!(warnings.contains(&ConfigWarning::PublicAdminApi("0.0.0.0:1999".parse().unwrap())));
+    }
+}
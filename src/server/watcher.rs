@@ -0,0 +1,217 @@
+use std::io;
+use std::net::TcpStream;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::Connection;
+
+/* # Waiting for idle connections without tying up a worker!
+A connection with nothing to read yet (a fresh one, or a kept-alive one between
+requests) is parked here instead of blocking a worker in read(). One thread
+watches all parked connections at once:
+1. Readable (the next request has started, or the client hung up) => handed to
+on_ready, which queues it on the pool again.
+2. Still idle at its deadline => dropped, which closes it (the client sees a FIN).
+That's a quiet note in the log, and counted (Server::closed_idle_connections).
+3. Once the server drains (drain.rs), every parked connection that isn't readable
+is closed right away, and so is every one parked after that.
+On Linux the waiting is one poll(2) over every parked socket plus a wake-up
+socket that park() writes to, so a newly parked connection is watched at once.
+Elsewhere the watcher checks each socket with a non-blocking peek every
+SWEEP_INTERVAL, which costs that much latency at most. */
+
+#[cfg(not(target_os = "linux"))]
+const SWEEP_INTERVAL: Duration = Duration::from_millis(5);
+
+// How long to wait after poll itself fails (e.g. ENOMEM) before trying again.
+const ERROR_BACKOFF: Duration = Duration::from_millis(10);
+
+struct Parked {
+    connection: Connection,
+    parked_at: Instant,
+    deadline: Instant,
+}
+
+#[derive(Clone)]
+pub(super) struct Watcher {
+    parked: Sender<Parked>,
+    waker: Waker,
+    draining: Arc<AtomicBool>,
+}
+
+pub(super) struct WatcherThread {
+    parked: Receiver<Parked>,
+    waker: Waker,
+    closed_idle: Arc<AtomicU64>,
+    draining: Arc<AtomicBool>,
+}
+
+impl Watcher {
+    // The watcher only starts watching once its thread is spawned.
+    // `closed_idle` counts the connections closed for staying idle past their deadline.
+    pub(super) fn new(closed_idle: Arc<AtomicU64>) -> io::Result<(Watcher, WatcherThread)> {
+        let (sender, receiver) = mpsc::channel();
+        let waker = Waker::new()?;
+        let draining = Arc::new(AtomicBool::new(false));
+        Ok((
+            Watcher { parked: sender, waker: waker.clone(), draining: Arc::clone(&draining) },
+            WatcherThread { parked: receiver, waker, closed_idle, draining },
+        ))
+    }
+
+    pub(super) fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.waker.wake();
+    }
+
+    // If nothing arrives within `timeout`, the connection is closed.
+    pub(super) fn park(&self, connection: Connection, timeout: Duration) {
+        let now = Instant::now();
+        let parked = Parked { connection, parked_at: now, deadline: now + timeout };
+        // the send only fails once the watcher thread is gone, and then dropping the connection closes it.
+        if self.parked.send(parked).is_ok() {
+            self.waker.wake();
+        }
+    }
+}
+
+impl WatcherThread {
+    // Runs until every Watcher handle is gone and nothing is parked anymore.
+    pub(super) fn spawn<F>(self, on_ready: F) -> io::Result<thread::JoinHandle<()>>
+        where
+            F: Fn(Connection) + Send + 'static
+    {
+        thread::Builder::new()
+            .name("surff-watcher".to_string())
+            .spawn(move || self.run(on_ready))
+    }
+
+    fn run(self, on_ready: impl Fn(Connection)) {
+        let mut parked: Vec<Parked> = Vec::new();
+
+        loop {
+            // nothing to watch => block until something is parked.
+            if parked.is_empty() {
+                match self.parked.recv() {
+                    Ok(connection) => parked.push(connection),
+                    Err(_) => return,
+                }
+            }
+            loop {
+                match self.parked.try_recv() {
+                    Ok(connection) => parked.push(connection),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) if parked.is_empty() => return,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            }
+
+            let next_deadline = parked.iter().map(|p| p.deadline).min().unwrap_or_else(Instant::now);
+            let timeout = next_deadline.saturating_duration_since(Instant::now());
+            let streams: Vec<&TcpStream> = parked.iter().map(|p| p.connection.stream()).collect();
+            let ready = match self.waker.wait(&streams, timeout) {
+                Ok(ready) => ready,
+                Err(e) => {
+                    eprintln!("Failed to wait for idle connections: {}", e);
+                    thread::sleep(ERROR_BACKOFF);
+                    continue;
+                }
+            };
+
+            // Backwards, so swap_remove only ever moves an entry that's been looked at already.
+            let now = Instant::now();
+            let draining = self.draining.load(Ordering::SeqCst);
+            for i in (0..parked.len()).rev() {
+                if ready[i] {
+                    on_ready(parked.swap_remove(i).connection);
+                } else if draining {
+                    parked.swap_remove(i);
+                } else if parked[i].deadline <= now {
+                    // dropping it closes the connection.
+                    let idle = parked.swap_remove(i);
+                    self.closed_idle.fetch_add(1, Ordering::Relaxed);
+                    println!("Closing idle connection from {} after {:?}", idle.connection.peer(), now - idle.parked_at);
+                }
+            }
+        }
+    }
+}
+
+// # Waking the watcher up when a connection is parked!
+// A datagram socket pair: park() sends a byte on one end, and the other end is
+// part of every poll, so poll returns right away.
+#[cfg(target_os = "linux")]
+#[derive(Clone)]
+struct Waker {
+    sender: Arc<UnixDatagram>,
+    receiver: Arc<UnixDatagram>,
+}
+
+#[cfg(target_os = "linux")]
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        let (sender, receiver) = UnixDatagram::pair()?;
+        // a full buffer means a wake-up is pending already; never block on it.
+        sender.set_nonblocking(true)?;
+        receiver.set_nonblocking(true)?;
+        Ok(Waker { sender: Arc::new(sender), receiver: Arc::new(receiver) })
+    }
+
+    fn wake(&self) {
+        let _ = self.sender.send(&[1]);
+    }
+
+    // Which of `streams` are readable, after waiting at most `timeout` (less if woken up).
+    fn wait(&self, streams: &[&TcpStream], timeout: Duration) -> io::Result<Vec<bool>> {
+        let mut fds = Vec::with_capacity(streams.len() + 1);
+        fds.push(self.receiver.as_raw_fd());
+        fds.extend(streams.iter().map(|stream| stream.as_raw_fd()));
+
+        let ready = crate::os::linux::poll_readable(&fds, timeout)?;
+        if ready[0] {
+            let mut buf = [0; 64];
+            while self.receiver.recv(&mut buf).is_ok() {}
+        }
+        Ok(ready[1..].to_vec())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[derive(Clone)]
+struct Waker;
+
+#[cfg(not(target_os = "linux"))]
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        Ok(Waker)
+    }
+
+    // The sweep picks new connections up within SWEEP_INTERVAL anyway.
+    fn wake(&self) {}
+
+    fn wait(&self, streams: &[&TcpStream], timeout: Duration) -> io::Result<Vec<bool>> {
+        let ready: Vec<bool> = streams.iter().map(|stream| is_readable(stream)).collect();
+        if !ready.contains(&true) {
+            thread::sleep(timeout.min(SWEEP_INTERVAL));
+        }
+        Ok(ready)
+    }
+}
+
+// EOF and errors count as readable: the worker's read will find out what happened.
+#[cfg(not(target_os = "linux"))]
+fn is_readable(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let readable = !matches!(stream.peek(&mut [0]), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+    let _ = stream.set_nonblocking(false);
+    readable
+}
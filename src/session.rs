@@ -0,0 +1,352 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::crypto;
+use crate::htpasswd::HtpasswdFile;
+use crate::http::{Request, ResponseBuilder, StatusCode};
+use crate::middleware::AuthenticatedUser;
+use crate::router::{Middleware, Response, Router};
+use crate::template::escape_html;
+
+/* # Logging in with a form, and staying logged in with a session cookie!
+let sessions = Arc::new(SessionStore::new(Duration::from_secs(8 * 3600)));
+let users = Arc::new(InMemoryUserStore::new().user("alice", "correct horse"));
+AuthRouter::new(Arc::clone(&sessions), users).secure_cookie(true).register(&mut router);
+router.wrap(SessionMiddleware::new(sessions));    // AuthenticatedUser for logged-in requests
+GET  /login    the login form
+POST /login    username=alice&password=... => 303 to /, Set-Cookie: surff_session=<token>
+               a wrong user or password     => 401 and the form again
+POST /logout   the session is gone          => 303 to /login, and the cookie too
+1. A token is 256 bits from RandomState (seeded by the OS, see multipart.rs), hex:
+all the cookie holds. Who it belongs to stays in the SessionStore, in memory, until
+it expires (`ttl` after login) or its user logs out; a restart logs everyone out.
+2. The cookie is HttpOnly (no scripts), SameSite=Lax (not sent with other sites' POSTs,
+so they can't log anyone out either), and Secure with secure_cookie(true), which every
+deployment behind HTTPS wants: surff itself speaks plain HTTP.
+3. Logout is a POST, so that a link (or a prefetch) can't log anyone out.
+4. Passwords are compared in constant time (crypto::same_bytes on SHA-256 digests for
+InMemoryUserStore; HtpasswdFile is a UserStore too). A slow password hash like bcrypt
+is not something surff has. */
+
+pub const SESSION_COOKIE: &str = "surff_session";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UserId(pub String);
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Who a user name and password belong to, if anyone.
+pub trait UserStore: Send + Sync {
+    fn authenticate(&self, username: &str, password: &str) -> Option<UserId>;
+}
+
+// Users given in code, for tests and small deployments; the name is the UserId.
+#[derive(Default)]
+pub struct InMemoryUserStore {
+    users: HashMap<String, [u8; 32]>,
+}
+
+impl InMemoryUserStore {
+    pub fn new() -> InMemoryUserStore {
+        InMemoryUserStore::default()
+    }
+
+    pub fn user(mut self, username: &str, password: &str) -> InMemoryUserStore {
+        self.users.insert(username.to_string(), crypto::sha256(password.as_bytes()));
+        self
+    }
+}
+
+impl UserStore for InMemoryUserStore {
+    fn authenticate(&self, username: &str, password: &str) -> Option<UserId> {
+        let digest = crypto::sha256(password.as_bytes());
+        // a user that isn't there costs the same hash.
+        let stored = self.users.get(username)?;
+        crypto::same_bytes(&digest, stored).then(|| UserId(username.to_string()))
+    }
+}
+
+impl fmt::Debug for InMemoryUserStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InMemoryUserStore").field("users", &self.users.len()).finish()
+    }
+}
+
+impl UserStore for HtpasswdFile {
+    fn authenticate(&self, username: &str, password: &str) -> Option<UserId> {
+        self.verify(username, password).then(|| UserId(username.to_string()))
+    }
+}
+
+struct Session {
+    user: UserId,
+    expires: Instant,
+}
+
+pub struct SessionStore {
+    ttl: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> SessionStore {
+        SessionStore { ttl, sessions: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    // A new session for `user`; the token is what goes in the cookie.
+    pub fn create(&self, user: UserId) -> String {
+        let token = random_token();
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        // logins are rare next to requests: a good time to forget the expired sessions.
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(token.clone(), Session { user, expires: now + self.ttl });
+        token
+    }
+
+    pub fn get(&self, token: &str) -> Option<UserId> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        let session = sessions.get(token)?;
+        if session.expires <= Instant::now() {
+            sessions.remove(token);
+            return None;
+        }
+        Some(session.user.clone())
+    }
+
+    pub fn destroy(&self, token: &str) {
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner).remove(token);
+    }
+
+    // The user of the request's session cookie.
+    pub fn user(&self, request: &Request) -> Option<UserId> {
+        self.get(request.cookie(SESSION_COOKIE)?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// The users, never the tokens.
+impl fmt::Debug for SessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionStore").field("ttl", &self.ttl).field("sessions", &self.len()).finish()
+    }
+}
+
+// Like multipart's boundaries, with more bits: this one has to be unguessable.
+fn random_token() -> String {
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let call = CALLS.fetch_add(1, Ordering::Relaxed);
+
+    let mut token = String::with_capacity(64);
+    for i in 0..4u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_u64(call);
+        hasher.write_u64(i);
+        token.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    token
+}
+
+// # Sessions for every request: an AuthenticatedUser (no roles) in the extensions of
+// requests with a live session cookie, for handlers and RequireRole. Requests without
+// one go on as they are: it's for the routes to decide who has to be logged in.
+#[derive(Debug)]
+pub struct SessionMiddleware {
+    sessions: Arc<SessionStore>,
+}
+
+impl SessionMiddleware {
+    pub fn new(sessions: Arc<SessionStore>) -> SessionMiddleware {
+        SessionMiddleware { sessions }
+    }
+}
+
+impl Middleware for SessionMiddleware {
+    fn before(&self, request: &Request, _response: &mut Response) -> io::Result<bool> {
+        if let Some(user) = self.sessions.user(request) {
+            request.extensions.insert(AuthenticatedUser { id: user.0, roles: Vec::new() });
+        }
+        Ok(true)
+    }
+}
+
+const LOGIN_FORM: &str = "<!DOCTYPE html>\n\
+<html>\n<head><meta charset=\"utf-8\"><title>Log in</title></head>\n<body>\n{error}\
+<form method=\"post\" action=\"/login\">\n\
+<label>User name <input name=\"username\" autocomplete=\"username\" required></label>\n\
+<label>Password <input name=\"password\" type=\"password\" autocomplete=\"current-password\" required></label>\n\
+<button>Log in</button>\n</form>\n</body>\n</html>\n";
+
+pub struct AuthRouter {
+    sessions: Arc<SessionStore>,
+    users: Arc<dyn UserStore>,
+    secure_cookie: bool,
+}
+
+impl AuthRouter {
+    pub fn new(sessions: Arc<SessionStore>, users: Arc<dyn UserStore>) -> AuthRouter {
+        AuthRouter { sessions, users, secure_cookie: false }
+    }
+
+    // Secure on the cookie: only ever sent over HTTPS.
+    pub fn secure_cookie(mut self, secure: bool) -> AuthRouter {
+        self.secure_cookie = secure;
+        self
+    }
+
+    pub fn register(self, router: &mut Router) {
+        router.get("/login", |_, response| login_form(response, StatusCode::Ok, None));
+
+        let (sessions, users, secure) = (Arc::clone(&self.sessions), self.users, self.secure_cookie);
+        router.post("/login", move |request, response| {
+            let form = request.form().unwrap_or_default();
+            let field = |name: &str| form.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+            let (Some(username), Some(password)) = (field("username"), field("password")) else {
+                return login_form(response, StatusCode::BadRequest, Some("Enter a user name and a password."));
+            };
+            let Some(user) = users.authenticate(username, password) else {
+                return login_form(response, StatusCode::Unauthorized, Some("Wrong user name or password."));
+            };
+            let token = sessions.create(user);
+            let cookie = session_cookie(&token, sessions.ttl().as_secs(), secure);
+            response.send(ResponseBuilder::new(StatusCode::SeeOther).header("Location", "/").header("Set-Cookie", &cookie))
+        });
+
+        let (sessions, secure) = (self.sessions, self.secure_cookie);
+        router.post("/logout", move |request, response| {
+            if let Some(token) = request.cookie(SESSION_COOKIE) {
+                sessions.destroy(token);
+            }
+            response.send(ResponseBuilder::new(StatusCode::SeeOther).header("Location", "/login").header("Set-Cookie", &session_cookie("", 0, secure)))
+        });
+    }
+
+    pub fn into_router(self) -> Router {
+        let mut router = Router::new();
+        self.register(&mut router);
+        router
+    }
+}
+
+impl fmt::Debug for AuthRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthRouter").field("sessions", &self.sessions).field("secure_cookie", &self.secure_cookie).finish_non_exhaustive()
+    }
+}
+
+// Max-Age=0 deletes it.
+fn session_cookie(token: &str, max_age: u64, secure: bool) -> String {
+    let mut cookie = format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax", SESSION_COOKIE, token, max_age);
+    if secure {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+fn login_form(response: &mut Response, status: StatusCode, error: Option<&str>) -> io::Result<()> {
+    let error = error.map(|error| format!("<p role=\"alert\">{}</p>\n", escape_html(error))).unwrap_or_default();
+    response.send(
+        ResponseBuilder::new(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Cache-Control", "no-store")
+            .body_str(&LOGIN_FORM.replace("{error}", &error)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::exchange;
+
+    fn site(sessions: &Arc<SessionStore>) -> Router {
+        let users = Arc::new(InMemoryUserStore::new().user("alice", "correct horse"));
+        let mut router = AuthRouter::new(Arc::clone(sessions), users).secure_cookie(true).into_router();
+        router.get("/", |request, response| {
+            let who = request.extensions.get::<AuthenticatedUser>().map(|user| user.id.clone()).unwrap_or("nobody".to_string());
+            response.send(ResponseBuilder::new(StatusCode::Ok).body_str(&who))
+        });
+        router.wrap(SessionMiddleware::new(Arc::clone(sessions)));
+        router
+    }
+
+    fn login(router: &Router, body: &str) -> String {
+        exchange(router, &format!("POST /login HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}", body.len(), body))
+    }
+
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        response.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+    }
+
+    #[test]
+    fn logs_in_and_out_with_a_session_cookie() {
+        let sessions = Arc::new(SessionStore::new(Duration::from_secs(60)));
+        let router = site(&sessions);
+
+        let form = exchange(&router, "GET /login HTTP/1.1\r\n\r\n");
+        assert!(form.starts_with("HTTP/1.1 200 OK\r\n") && form.contains("<form method=\"post\" action=\"/login\">"), "{:?}", form);
+
+        let response = login(&router, "username=alice&password=correct+horse");
+        assert!(response.starts_with("HTTP/1.1 303 See Other\r\n"), "{:?}", response);
+        assert_eq!(header(&response, "Location"), Some("/"));
+        let cookie = header(&response, "Set-Cookie").unwrap();
+        assert!(cookie.ends_with("; Path=/; Max-Age=60; HttpOnly; SameSite=Lax; Secure"), "{:?}", cookie);
+        let token = cookie.strip_prefix("surff_session=").unwrap().split(';').next().unwrap();
+        assert_eq!(token.len(), 64);
+        assert_eq!(sessions.get(token), Some(UserId("alice".to_string())));
+
+        let with_cookie = |raw: &str| raw.replace("\r\n\r\n", &format!("\r\nCookie: {}={}\r\n\r\n", SESSION_COOKIE, token));
+        assert!(exchange(&router, &with_cookie("GET / HTTP/1.1\r\n\r\n")).ends_with("\r\n\r\nalice"));
+        assert!(exchange(&router, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nnobody"));
+
+        let response = exchange(&router, &with_cookie("POST /logout HTTP/1.1\r\nContent-Length: 0\r\n\r\n"));
+        assert!(response.starts_with("HTTP/1.1 303 See Other\r\n"), "{:?}", response);
+        assert_eq!(header(&response, "Location"), Some("/login"));
+        assert!(header(&response, "Set-Cookie").unwrap().starts_with("surff_session=; Path=/; Max-Age=0;"));
+        assert!(exchange(&router, &with_cookie("GET / HTTP/1.1\r\n\r\n")).ends_with("\r\n\r\nnobody"));
+        assert!(sessions.is_empty());
+    }
+
+    #[test]
+    fn wrong_passwords_and_old_sessions_get_nowhere() {
+        let sessions = Arc::new(SessionStore::new(Duration::from_secs(60)));
+        let router = site(&sessions);
+        for body in ["username=alice&password=wrong", "username=bob&password=correct+horse"] {
+            let response = login(&router, body);
+            assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{:?}", response);
+            assert!(response.contains("Wrong user name or password."));
+            assert_eq!(header(&response, "Set-Cookie"), None);
+        }
+        assert!(login(&router, "username=alice").starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(exchange(&router, "GET /logout HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 "));
+        assert!(sessions.is_empty());
+
+        let expired = SessionStore::new(Duration::ZERO);
+        let token = expired.create(UserId("alice".to_string()));
+        assert_eq!(expired.get(&token), None);
+        assert_eq!(expired.get("made-up"), None);
+        assert_ne!(random_token(), random_token());
+    }
+}
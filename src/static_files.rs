@@ -0,0 +1,328 @@
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::http::{unsatisfiable_content_range, HttpVersion, Method, RangeRequest, Request, ResponseBuilder, StatusCode};
+use crate::mime;
+use crate::range_cache::RangeCache;
+use crate::router::Response;
+
+/* # Serving a directory of static files!
+let files = StaticFileHandler::new("public")?.strip_prefix("/static");
+router.route(Method::Get, "/static", move |request, response| files.handle(request, response));
+1. GET /static/css/app.css => public/css/app.css (the prefix is dropped first).
+2. The resolved path is canonicalized, which follows ".." and symlinks;
+if it doesn't end up inside the (canonicalized) root, the answer is 403.
+That is what stops GET /static/../../etc/passwd.
+3. A directory is served as its index.html, or 403 when there is none (no listings).
+4. Content-Type comes from the extension (mime::from_path) and Content-Length
+from the metadata of the opened file (not the path, which could be swapped for
+something else in between); the bytes go out with Response::send_file, untouched,
+so images and other binary files arrive intact. A file that shrinks while it's
+being sent can't honour the Content-Length we promised: that's an error,
+and the connection is closed rather than left out of step.
+5. Files above the chunked threshold (64 KiB unless changed) are streamed to
+HTTP/1.1 clients with chunked encoding instead, 8 KiB at a time.
+6. HEAD (routed here by the Router's GET fallback) gets the same headers and no body.
+7. Accept-Ranges: bytes. A GET with a Range gets a 206 with that part of the file
+(never chunked: its length is known), or a 416 if it's past the end (see http/range.rs).
+With a range_cache, the parts asked for again come from memory (see range_cache.rs). */
+
+const INDEX_FILE: &str = "index.html";
+
+const DEFAULT_CHUNKED_THRESHOLD: u64 = 64 * 1024;
+const STREAM_BLOCK_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct StaticFileHandler {
+    root: PathBuf,
+    prefix: Option<String>,
+    chunked_threshold: u64,
+    range_cache: Option<Arc<RangeCache>>,
+}
+
+impl StaticFileHandler {
+    // Fails if the root doesn't exist.
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<StaticFileHandler> {
+        Ok(StaticFileHandler {
+            root: root.into().canonicalize()?,
+            prefix: None,
+            chunked_threshold: DEFAULT_CHUNKED_THRESHOLD,
+            range_cache: None,
+        })
+    }
+
+    // The part of the request path that belongs to the route, not to the file.
+    pub fn strip_prefix(mut self, prefix: &str) -> StaticFileHandler {
+        self.prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    // Files larger than this many bytes are sent with Transfer-Encoding: chunked.
+    pub fn chunked_threshold(mut self, bytes: u64) -> StaticFileHandler {
+        self.chunked_threshold = bytes;
+        self
+    }
+
+    // Shared with the other handlers it's given to, and with /metrics.
+    pub fn range_cache(mut self, cache: Arc<RangeCache>) -> StaticFileHandler {
+        self.range_cache = Some(cache);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Where a request path lives on disk.
+    // NotFound: nothing there (canonicalize needs a real file); PermissionDenied: outside the root.
+    pub fn resolve(&self, request_path: &str) -> io::Result<PathBuf> {
+        let relative = match &self.prefix {
+            Some(prefix) => request_path
+                .strip_prefix(prefix.as_str())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?,
+            None => request_path,
+        };
+        // Without this, Path::join would replace the root with an absolute path.
+        let relative = relative.trim_start_matches('/');
+
+        let path = self.root.join(relative).canonicalize()?;
+        if path.starts_with(&self.root) {
+            Ok(path)
+        } else {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "path is outside the static root"))
+        }
+    }
+
+    pub fn handle(&self, request: &Request, response: &mut Response) -> io::Result<()> {
+        let path = match self.resolve(&request.path) {
+            Ok(path) => path,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return write_status(response, StatusCode::Forbidden),
+            Err(_) => return write_status(response, StatusCode::NotFound),
+        };
+
+        let path = if path.is_dir() {
+            let index = path.join(INDEX_FILE);
+            if !index.is_file() {
+                return write_status(response, StatusCode::Forbidden);
+            }
+            index
+        } else {
+            path
+        };
+
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return write_status(response, StatusCode::NotFound),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return write_status(response, StatusCode::Forbidden),
+            Err(e) => return Err(e),
+        };
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return write_status(response, StatusCode::NotFound);
+        }
+        let length = metadata.len();
+
+        let mut head = ResponseBuilder::new(StatusCode::Ok);
+        head.header("Content-Type", mime::from_path(&path)).header("Accept-Ranges", "bytes");
+        match RangeRequest::of(request, length) {
+            RangeRequest::Full => {}
+            RangeRequest::Partial(range) => {
+                let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+                let Some(cache) = &self.range_cache else {
+                    return response.send_file_range(&mut head, &file, length, range).map_err(with_path);
+                };
+                let bytes = cache.read(&path, &file, range).map_err(with_path)?;
+                head.set_status(StatusCode::PartialContent).header("Content-Range", &range.content_range(length)).body_bytes(bytes.to_vec());
+                return response.send(&mut head);
+            }
+            RangeRequest::Unsatisfiable => {
+                head.set_status(StatusCode::RangeNotSatisfiable).header("Content-Range", &unsatisfiable_content_range(length));
+                return response.send(&mut head);
+            }
+        }
+
+        // HEAD gets the Content-Length even where a GET would be chunked: it's more useful, and there's no body to stream.
+        if length > self.chunked_threshold && request.version == HttpVersion::Http11 && request.method != Method::Head {
+            let mut body = response.start_chunked(head)?;
+            let mut block = vec![0; STREAM_BLOCK_SIZE];
+            loop {
+                let n = match file.read(&mut block) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                body.write_all(&block[..n])?;
+            }
+            return body.finish();
+        }
+
+        response.send_file(&mut head, &file, length).map_err(|e| {
+            io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+        })
+    }
+}
+
+fn write_status(response: &mut Response, status: StatusCode) -> io::Result<()> {
+    response.send(
+        ResponseBuilder::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body_str(&format!("{}\n", status)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::router::{self, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // public/ (with index.html, app.css and docs/index.html) next to secret.txt.
+    fn site() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "surff-static-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed),
+        ));
+        let public = dir.join("public");
+        fs::create_dir_all(public.join("docs")).unwrap();
+        fs::write(public.join("index.html"), "home").unwrap();
+        fs::write(public.join("app.css"), "body {}").unwrap();
+        fs::write(public.join("docs").join("index.html"), "docs").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        dir
+    }
+
+    // Hands `raw` to the handler, mounted at /static, and returns everything it wrote.
+    fn exchange(files: &StaticFileHandler, raw: &str) -> String {
+        let mut router = Router::new();
+        let files = files.clone();
+        router.route(Method::Get, "/static", move |request, response| files.handle(request, response));
+        router::exchange(&router, raw)
+    }
+
+    fn status_line(response: &str) -> &str {
+        response.lines().next().unwrap_or("")
+    }
+
+    #[test]
+    fn serves_files_below_the_root() {
+        let dir = site();
+        let files = StaticFileHandler::new(dir.join("public")).unwrap().strip_prefix("/static");
+
+        let response = exchange(&files, "GET /static/app.css HTTP/1.1\r\n\r\n");
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.ends_with("\r\n\r\nbody {}"), "{:?}", response);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dot_dot_stays_inside_the_root() {
+        let dir = site();
+        let files = StaticFileHandler::new(dir.join("public")).unwrap().strip_prefix("/static");
+
+        let err = files.resolve("/static/../secret.txt").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let response = exchange(&files, "GET /static/../secret.txt HTTP/1.1\r\n\r\n");
+        assert_eq!(status_line(&response), "HTTP/1.1 403 Forbidden");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn percent_encoded_dot_dot_stays_inside_the_root() {
+        let dir = site();
+        let files = StaticFileHandler::new(dir.join("public")).unwrap().strip_prefix("/static");
+
+        // Request::parse decodes %2e%2e into "..", so this is the same check as above.
+        let raw = "GET /static/%2e%2e/secret.txt HTTP/1.1\r\n\r\n";
+        assert_eq!(Request::parse(raw.as_bytes()).unwrap().path, "/static/../secret.txt");
+        let response = exchange(&files, raw);
+        assert_eq!(status_line(&response), "HTTP/1.1 403 Forbidden");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root_are_refused() {
+        let dir = site();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("public").join("link.txt")).unwrap();
+        let files = StaticFileHandler::new(dir.join("public")).unwrap().strip_prefix("/static");
+
+        let response = exchange(&files, "GET /static/link.txt HTTP/1.1\r\n\r\n");
+        assert_eq!(status_line(&response), "HTTP/1.1 403 Forbidden");
+        assert!(!response.contains("secret"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn directories_serve_their_index() {
+        let dir = site();
+        let files = StaticFileHandler::new(dir.join("public")).unwrap().strip_prefix("/static");
+
+        let response = exchange(&files, "GET /static/docs HTTP/1.1\r\n\r\n");
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.ends_with("\r\n\r\ndocs"), "{:?}", response);
+        assert!(response.contains("Content-Type: text/html"));
+
+        fs::remove_file(dir.join("public").join("docs").join("index.html")).unwrap();
+        let response = exchange(&files, "GET /static/docs HTTP/1.1\r\n\r\n");
+        assert_eq!(status_line(&response), "HTTP/1.1 403 Forbidden");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    // Like exchange, but into a buffer, as for a pipelined request.
+    fn exchange_buffered(files: &StaticFileHandler, raw: &str) -> String {
+        let request = Request::parse(raw.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        let mut response = Response::new(router::Output::Buffer { buffer: &mut out, peer: ([127, 0, 0, 1], 1).into() }, &request, None);
+        files.handle(&request, &mut response).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn ranges_get_part_of_the_file() {
+        let dir = site();
+        fs::write(dir.join("public").join("big.txt"), "0123456789".repeat(10)).unwrap();
+        // big.txt is above it: a Range still gets a Content-Length.
+        let files = StaticFileHandler::new(dir.join("public")).unwrap().strip_prefix("/static").chunked_threshold(50);
+
+        for exchange in [exchange, exchange_buffered] {
+            let response = exchange(&files, "GET /static/big.txt HTTP/1.1\r\nRange: bytes=95-\r\n\r\n");
+            assert_eq!(status_line(&response), "HTTP/1.1 206 Partial Content");
+            assert!(response.contains("\r\nAccept-Ranges: bytes\r\n"), "{:?}", response);
+            assert!(response.contains("\r\nContent-Range: bytes 95-99/100\r\n"), "{:?}", response);
+            assert!(response.ends_with("\r\nContent-Length: 5\r\n\r\n56789"), "{:?}", response);
+
+            let response = exchange(&files, "GET /static/app.css HTTP/1.1\r\nRange: bytes=7-\r\n\r\n");
+            assert_eq!(status_line(&response), "HTTP/1.1 416 Range Not Satisfiable");
+            assert!(response.contains("\r\nContent-Range: bytes */7\r\n"), "{:?}", response);
+        }
+        // several ranges: all of it.
+        let response = exchange(&files, "GET /static/app.css HTTP/1.1\r\nRange: bytes=0-1,3-4\r\n\r\n");
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.ends_with("\r\n\r\nbody {}"), "{:?}", response);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn a_range_cache_answers_repeated_ranges() {
+        let dir = site();
+        let cache = Arc::new(RangeCache::new(1024));
+        let files = StaticFileHandler::new(dir.join("public")).unwrap().strip_prefix("/static").range_cache(Arc::clone(&cache));
+        for _ in 0..2 {
+            let response = exchange(&files, "GET /static/app.css HTTP/1.1\r\nRange: bytes=0-3\r\n\r\n");
+            assert_eq!(status_line(&response), "HTTP/1.1 206 Partial Content");
+            assert!(response.contains("\r\nContent-Range: bytes 0-3/7\r\n"), "{:?}", response);
+            assert!(response.ends_with("\r\n\r\nbody"), "{:?}", response);
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.bytes_cached), (1, 1, 4));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
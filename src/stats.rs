@@ -0,0 +1,332 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+/* # Pool statistics!
+PoolStats is a cheap, cloneable handle to counters shared by the ThreadPool
+and its workers, so it can be handed to request handlers (e.g. /debug/pool)
+without borrowing the pool itself.
+- queue_depth: jobs sent with .execute that haven't finished yet.
+- per worker: whether it's busy right now, how many jobs it has completed
+and a histogram of how long those jobs took.
+- busy_workers / worker count = utilization, which the accept loop uses for backpressure.
+- workers_restarted_total: workers replaced after a job panicked.
+- metrics(): a PoolMetrics snapshot of idle vs. active workers and jobs still waiting to start.
+Workers are indexed by id. ThreadPool::resize can retire workers and add new ones,
+so the ids of live workers may have gaps: a retired worker's slot keeps its history
+(it still counts towards latency_histogram) and is reused by the next worker added.
+The counters are only for observation, so Relaxed ordering is enough. */
+
+#[derive(Clone)]
+pub struct PoolStats {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    queue_depth: AtomicUsize,
+    total_queued: AtomicUsize,
+    total_completed: AtomicUsize,
+    busy_workers: AtomicUsize,
+    workers_restarted_total: AtomicUsize,
+    // Only written by resize; every other access takes the (uncontended) read lock.
+    workers: RwLock<Vec<WorkerStats>>,
+}
+
+// Marks a worker busy for as long as it's alive.
+// Being a guard means the worker is marked idle again even if the job panics.
+pub(crate) struct UtilizationGuard<'a> {
+    stats: &'a PoolStats,
+    worker_id: usize,
+}
+
+impl Drop for UtilizationGuard<'_> {
+    fn drop(&mut self) {
+        self.stats.with_worker(self.worker_id, |worker| worker.busy.store(false, Ordering::Relaxed));
+        self.stats.inner.busy_workers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct WorkerStats {
+    live: AtomicBool,
+    busy: AtomicBool,
+    jobs_completed: AtomicUsize,
+    latency: [AtomicU64; BUCKETS],
+}
+
+// # Job latency histogram!
+// Exponential buckets: 0-1 ms, 1-10 ms, 10-100 ms, 100 ms-1 s, 1 s+.
+// The averages hide the slow tail; the buckets show whether slow jobs are common or rare.
+const BUCKETS: usize = 5;
+const BUCKET_UPPER_BOUNDS: [Duration; BUCKETS - 1] = [
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+// A point-in-time snapshot; the counters are read one at a time, so under load
+// the fields can be off by a job or two from each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolMetrics {
+    pub idle_workers: usize,
+    pub active_workers: usize,
+    // sent with .execute but not picked up by a worker yet.
+    pub queued_jobs: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+}
+
+impl Histogram {
+    // Job counts per bucket, fastest bucket first.
+    pub fn counts(&self) -> [u64; BUCKETS] {
+        self.counts
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // Percentiles are only as precise as the buckets: they return the upper bound
+    // of the bucket the percentile falls in. The last bucket is open-ended,
+    // so it reports Duration::MAX. An empty histogram reports zero.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let total = self.total();
+        if total == 0 {
+            return Duration::ZERO;
+        }
+
+        let rank = ((p / 100.0) * total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BUCKET_UPPER_BOUNDS.get(bucket).copied().unwrap_or(Duration::MAX);
+            }
+        }
+        Duration::MAX
+    }
+
+    pub fn p50(&self) -> Duration {
+        self.percentile(50.0)
+    }
+
+    pub fn p95(&self) -> Duration {
+        self.percentile(95.0)
+    }
+
+    pub fn p99(&self) -> Duration {
+        self.percentile(99.0)
+    }
+
+    pub fn p999(&self) -> Duration {
+        self.percentile(99.9)
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (mine, theirs) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *mine += theirs;
+        }
+    }
+}
+
+impl WorkerStats {
+    fn new() -> WorkerStats {
+        WorkerStats {
+            live: AtomicBool::new(true),
+            busy: AtomicBool::new(false),
+            jobs_completed: AtomicUsize::new(0),
+            latency: Default::default(),
+        }
+    }
+
+    fn histogram(&self) -> Histogram {
+        let mut histogram = Histogram::default();
+        for (count, bucket) in histogram.counts.iter_mut().zip(self.latency.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        histogram
+    }
+}
+
+fn bucket_for(elapsed: Duration) -> usize {
+    BUCKET_UPPER_BOUNDS
+        .iter()
+        .position(|bound| elapsed < *bound)
+        .unwrap_or(BUCKETS - 1)
+}
+
+impl PoolStats {
+    pub(crate) fn new(size: usize) -> PoolStats {
+        let workers = (0..size).map(|_| WorkerStats::new()).collect();
+
+        PoolStats {
+            inner: Arc::new(Inner {
+                queue_depth: AtomicUsize::new(0),
+                total_queued: AtomicUsize::new(0),
+                total_completed: AtomicUsize::new(0),
+                busy_workers: AtomicUsize::new(0),
+                workers_restarted_total: AtomicUsize::new(0),
+                workers: RwLock::new(workers),
+            }),
+        }
+    }
+
+    // Called by ThreadPool::resize before spawning a worker; returns the id it should use.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) fn add_worker(&self) -> usize {
+        let mut workers = self.inner.workers.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(id) = workers.iter().position(|worker| !worker.live.load(Ordering::Relaxed)) {
+            workers[id].live.store(true, Ordering::Relaxed);
+            return id;
+        }
+        workers.push(WorkerStats::new());
+        workers.len() - 1
+    }
+
+    // Called by ThreadPool::resize after a worker has been retired and joined.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) fn remove_worker(&self, worker_id: usize) {
+        self.with_worker(worker_id, |worker| worker.live.store(false, Ordering::Relaxed));
+    }
+
+    fn with_worker<R>(&self, worker_id: usize, f: impl FnOnce(&WorkerStats) -> R) -> R {
+        let workers = self.inner.workers.read().unwrap_or_else(PoisonError::into_inner);
+        f(&workers[worker_id])
+    }
+
+    // Called by ThreadPool::execute.
+    pub(crate) fn job_queued(&self) {
+        self.inner.queue_depth.fetch_add(1, Ordering::Relaxed);
+        self.inner.total_queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Called by ThreadPool::execute when the job couldn't be queued after all.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) fn job_rejected(&self) {
+        self.inner.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        self.inner.total_queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Called by a worker right before running a job; the worker is busy until the guard drops.
+    pub(crate) fn job_started(&self, worker_id: usize) -> UtilizationGuard<'_> {
+        self.with_worker(worker_id, |worker| worker.busy.store(true, Ordering::Relaxed));
+        self.inner.busy_workers.fetch_add(1, Ordering::Relaxed);
+        UtilizationGuard { stats: self, worker_id }
+    }
+
+    // Called by a worker after a job returned.
+    pub(crate) fn job_finished(&self, worker_id: usize, elapsed: Duration) {
+        self.with_worker(worker_id, |worker| {
+            worker.jobs_completed.fetch_add(1, Ordering::Relaxed);
+            worker.latency[bucket_for(elapsed)].fetch_add(1, Ordering::Relaxed);
+        });
+
+        self.inner.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        self.inner.total_completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Called by the supervisor after it replaced a worker whose job panicked.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) fn worker_restarted(&self) {
+        self.inner.workers_restarted_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.inner.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn total_queued(&self) -> usize {
+        self.inner.total_queued.load(Ordering::Relaxed)
+    }
+
+    pub fn total_completed(&self) -> usize {
+        self.inner.total_completed.load(Ordering::Relaxed)
+    }
+
+    pub fn busy_workers(&self) -> usize {
+        self.inner.busy_workers.load(Ordering::Relaxed)
+    }
+
+    // Fraction of workers running a job right now, from 0.0 to 1.0.
+    pub fn utilization(&self) -> f64 {
+        if self.worker_count() == 0 {
+            return 0.0;
+        }
+        self.busy_workers() as f64 / self.worker_count() as f64
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let active_workers = self.busy_workers();
+        PoolMetrics {
+            idle_workers: self.worker_count().saturating_sub(active_workers),
+            active_workers,
+            // queue_depth still counts the jobs that are running.
+            queued_jobs: self.queue_depth().saturating_sub(active_workers),
+        }
+    }
+
+    pub fn workers_restarted_total(&self) -> usize {
+        self.inner.workers_restarted_total.load(Ordering::Relaxed)
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.worker_ids().len()
+    }
+
+    // Ids of the live workers, in order.
+    pub fn worker_ids(&self) -> Vec<usize> {
+        let workers = self.inner.workers.read().unwrap_or_else(PoisonError::into_inner);
+        (0..workers.len())
+            .filter(|&id| workers[id].live.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub fn is_worker_busy(&self, worker_id: usize) -> bool {
+        self.with_worker(worker_id, |worker| worker.busy.load(Ordering::Relaxed))
+    }
+
+    pub fn worker_jobs_completed(&self, worker_id: usize) -> usize {
+        self.with_worker(worker_id, |worker| worker.jobs_completed.load(Ordering::Relaxed))
+    }
+
+    pub fn worker_latency_histogram(&self, worker_id: usize) -> Histogram {
+        self.with_worker(worker_id, WorkerStats::histogram)
+    }
+
+    // All workers' histograms merged into one, including those of retired workers.
+    pub fn latency_histogram(&self) -> Histogram {
+        let workers = self.inner.workers.read().unwrap_or_else(PoisonError::into_inner);
+        let mut combined = Histogram::default();
+        for worker in workers.iter() {
+            combined.merge(&worker.histogram());
+        }
+        combined
+    }
+
+    // {"queue_depth": N, "workers": [{"id": 0, "state": "busy", "jobs_completed": M}, ...], ...}
+    pub fn to_json(&self) -> String {
+        let workers: Vec<String> = self.worker_ids()
+            .into_iter()
+            .map(|id| {
+                format!(
+                    "{{\"id\": {}, \"state\": \"{}\", \"jobs_completed\": {}}}",
+                    id,
+                    if self.is_worker_busy(id) { "busy" } else { "idle" },
+                    self.worker_jobs_completed(id),
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"queue_depth\": {}, \"workers\": [{}], \"total_queued\": {}, \"total_completed\": {}, \"workers_restarted_total\": {}}}",
+            self.queue_depth(),
+            workers.join(", "),
+            self.total_queued(),
+            self.total_completed(),
+            self.workers_restarted_total(),
+        )
+    }
+}
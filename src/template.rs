@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/* # A tiny HTML template engine!
+format!() with user-supplied content is an XSS hole waiting to happen.
+Template compiles the source once into a list of segments:
+- literal text is copied as-is,
+- {{ name }} is replaced with the HTML-escaped value of `name`,
+- {{{ name }}} is replaced with the raw value (only for trusted HTML fragments).
+Variables missing from the map render as an empty string.
+An unterminated tag is kept as literal text. */
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Escaped(String),
+    Raw(String),
+}
+
+impl Template {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(src: &str) -> Template {
+        let mut segments = Vec::new();
+        let mut rest = src;
+
+        while let Some(start) = rest.find("{{") {
+            let (open, close) = if rest[start..].starts_with("{{{") {
+                ("{{{", "}}}")
+            } else {
+                ("{{", "}}")
+            };
+
+            let after_open = &rest[start + open.len()..];
+            let end = match after_open.find(close) {
+                Some(end) => end,
+                None => break,
+            };
+
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+
+            let name = after_open[..end].trim().to_string();
+            if open == "{{{" {
+                segments.push(Segment::Raw(name));
+            } else {
+                segments.push(Segment::Escaped(name));
+            }
+
+            rest = &after_open[end + close.len()..];
+        }
+
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        Template { segments }
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Template> {
+        let src = fs::read_to_string(path)?;
+        Ok(Template::from_str(&src))
+    }
+
+    pub fn render(&self, vars: &HashMap<&str, &str>) -> String {
+        let mut out = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Escaped(name) => {
+                    if let Some(value) = vars.get(name.as_str()) {
+                        escape_html_into(value, &mut out);
+                    }
+                },
+                Segment::Raw(name) => {
+                    if let Some(value) = vars.get(name.as_str()) {
+                        out.push_str(value);
+                    }
+                },
+            }
+        }
+
+        out
+    }
+}
+
+/* # Templates read once, and again when they change!
+let templates = Arc::new(TemplateCache::new());
+let watcher = TemplateWatcher::spawn(Arc::clone(&templates), Duration::from_secs(1))?;
+let page = templates.get(Path::new("templates/page.html"))?.render(&vars);
+1. get reads a file the first time it's asked for, and from then on answers from memory,
+without touching the filesystem.
+2. The watcher's thread looks at every cached file's modification time and length
+once per interval (as BasicAuthMiddleware does its htpasswd file): a file that changed,
+or was renamed over (a new file, with its own mtime), is read and compiled again.
+3. A file that's gone is dropped from the cache, so the next get fails as reading it
+would. One that can't be read or is half-written keeps the template loaded before,
+and that's logged; it's tried again on the next round.
+4. Polling and not inotify: std has no file events, and a stat per template per second
+is nothing next to rendering them. Dropping the TemplateWatcher (or stop) ends it. */
+
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[derive(Debug)]
+struct Cached {
+    template: Arc<Template>,
+    stamp: Stamp,
+}
+
+#[derive(Debug, Default)]
+pub struct TemplateCache {
+    templates: Mutex<HashMap<PathBuf, Cached>>,
+}
+
+impl TemplateCache {
+    pub fn new() -> TemplateCache {
+        TemplateCache::default()
+    }
+
+    pub fn get(&self, path: &Path) -> io::Result<Arc<Template>> {
+        if let Some(cached) = self.lock().get(path) {
+            return Ok(Arc::clone(&cached.template));
+        }
+        // the stamp first: a change while reading is seen on the next round.
+        let stamp = stamp(path);
+        let template = Arc::new(Template::from_file(path)?);
+        self.lock().insert(path.to_path_buf(), Cached { template: Arc::clone(&template), stamp });
+        Ok(template)
+    }
+
+    pub fn invalidate(&self, path: &Path) {
+        self.lock().remove(path);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // What a TemplateWatcher does every round; how many templates were reloaded or dropped.
+    pub fn reload_changed(&self) -> usize {
+        let stamps: Vec<(PathBuf, Stamp)> = self.lock().iter().map(|(path, cached)| (path.clone(), cached.stamp)).collect();
+        let mut changed = 0;
+        for (path, before) in stamps {
+            let now = stamp(&path);
+            if now == before {
+                continue;
+            }
+            match Template::from_file(&path) {
+                Ok(template) => {
+                    self.lock().insert(path, Cached { template: Arc::new(template), stamp: now });
+                    changed += 1;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.lock().remove(&path);
+                    changed += 1;
+                }
+                Err(e) => eprintln!("Kept the template {} loaded before: {}", path.display(), e),
+            }
+        }
+        changed
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Cached>> {
+        self.templates.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[derive(Debug)]
+pub struct TemplateWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TemplateWatcher {
+    pub fn spawn(cache: Arc<TemplateCache>, interval: Duration) -> io::Result<TemplateWatcher> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::Builder::new().name("surff-templates".to_string()).spawn(move || loop {
+            thread::park_timeout(interval);
+            if stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            cache.reload_changed();
+        })?;
+        Ok(TemplateWatcher { stop, thread: Some(thread) })
+    }
+
+    // Ends the thread, and waits for it.
+    pub fn stop(mut self) {
+        if let Some(thread) = self.wake_to_stop() {
+            let _ = thread.join();
+        }
+    }
+
+    fn wake_to_stop(&mut self) -> Option<thread::JoinHandle<()>> {
+        self.stop.store(true, Ordering::SeqCst);
+        let thread = self.thread.take()?;
+        thread.thread().unpark();
+        Some(thread)
+    }
+}
+
+impl Drop for TemplateWatcher {
+    fn drop(&mut self) {
+        self.wake_to_stop();
+    }
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    escape_html_into(s, &mut out);
+    out
+}
+
+fn escape_html_into(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_watcher_reloads_changed_templates() {
+        let path = std::env::temp_dir().join(format!("surff-template-{}.html", std::process::id()));
+        fs::write(&path, "<h1>{{ title }}</h1>").unwrap();
+        let cache = Arc::new(TemplateCache::new());
+        let vars = HashMap::from([("title", "Hi")]);
+        assert_eq!(cache.get(&path).unwrap().render(&vars), "<h1>Hi</h1>");
+
+        let watcher = TemplateWatcher::spawn(Arc::clone(&cache), Duration::from_millis(10)).unwrap();
+        // a longer file: another length, whatever the clock's resolution.
+        fs::write(&path, "<h2>{{ title }}!</h2>").unwrap();
+        let started = std::time::Instant::now();
+        while cache.get(&path).unwrap().render(&vars) != "<h2>Hi!</h2>" {
+            assert!(started.elapsed() < Duration::from_secs(5), "never reloaded");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // gone: dropped from the cache, and get fails as reading it would.
+        fs::remove_file(&path).unwrap();
+        while !cache.is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5), "never dropped");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(cache.get(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+        watcher.stop();
+    }
+}
@@ -0,0 +1,209 @@
+use std::fmt;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* # Load-testing harness!
+Spawns `concurrency` client threads that together send `total_requests`
+sequential GET requests to a running server and time each one.
+Every request opens a fresh connection, sends Connection: close and reads the
+response until EOF, so the numbers deliberately include the TCP handshake:
+they're what a client without a pooled connection sees, even though the server
+itself would keep the connection alive.
+A request counts as an error if the connection fails, the response can't be parsed,
+or the server answers with a 5xx status. */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadTestError {
+    ZeroConcurrency,
+    // The URL has no host.
+    InvalidUrl(String),
+}
+
+impl fmt::Display for LoadTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadTestError::ZeroConcurrency => write!(f, "a load test needs at least one client"),
+            LoadTestError::InvalidUrl(url) => write!(f, "load test url has no host: {}", url),
+        }
+    }
+}
+
+impl std::error::Error for LoadTestError {}
+
+pub struct LoadTest {
+    addr: String,
+    host: String,
+    path: String,
+    concurrency: usize,
+    total_requests: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadTestResult {
+    pub total_requests: usize,
+    pub errors: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub requests_per_second: f64,
+}
+
+impl LoadTest {
+    // url looks like "http://127.0.0.1:1998/sleep" (the scheme and path are optional);
+    // without a port, it's 80. IPv6 addresses go in brackets: "http://[::1]:1998/".
+    pub fn new(url: &str, concurrency: usize, total_requests: usize) -> Result<LoadTest, LoadTestError> {
+        if concurrency == 0 {
+            return Err(LoadTestError::ZeroConcurrency);
+        }
+
+        let without_scheme = url.strip_prefix("http://").unwrap_or(url);
+        let (authority, path) = match without_scheme.find('/') {
+            Some(i) => (&without_scheme[..i], &without_scheme[i..]),
+            None => (without_scheme, "/"),
+        };
+        if authority.is_empty() || authority == "[]" {
+            return Err(LoadTestError::InvalidUrl(url.to_string()));
+        }
+
+        // The colons inside "[::1]" aren't a port.
+        let has_port = match authority.strip_prefix('[') {
+            Some(bracketed) => bracketed.contains("]:"),
+            None => authority.contains(':'),
+        };
+        let addr = if has_port {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+
+        Ok(LoadTest {
+            addr,
+            host: authority.to_string(),
+            path: path.to_string(),
+            concurrency,
+            total_requests,
+        })
+    }
+
+    pub fn run(&self) -> LoadTestResult {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            self.path, self.host
+        );
+
+        let started = Instant::now();
+        let mut clients = Vec::with_capacity(self.concurrency);
+
+        for i in 0..self.concurrency {
+            // Spread the remainder over the first few clients.
+            let count = self.total_requests / self.concurrency
+                + usize::from(i < self.total_requests % self.concurrency);
+            let addr = self.addr.clone();
+            let request = request.clone();
+
+            clients.push(thread::spawn(move || {
+                let mut latencies = Vec::with_capacity(count);
+                let mut errors = 0;
+
+                for _ in 0..count {
+                    let start = Instant::now();
+                    match send_one(&addr, &request) {
+                        Ok(status) if status < 500 => latencies.push(start.elapsed()),
+                        _ => errors += 1,
+                    }
+                }
+
+                (latencies, errors)
+            }));
+        }
+
+        let mut latencies = Vec::with_capacity(self.total_requests);
+        let mut errors = 0;
+        for client in clients {
+            match client.join() {
+                Ok((mut l, e)) => {
+                    latencies.append(&mut l);
+                    errors += e;
+                },
+                Err(_) => errors += 1,
+            }
+        }
+        let elapsed = started.elapsed();
+
+        latencies.sort();
+
+        LoadTestResult {
+            total_requests: self.total_requests,
+            errors,
+            p50: percentile(&latencies, 50.0),
+            p95: percentile(&latencies, 95.0),
+            p99: percentile(&latencies, 99.0),
+            requests_per_second: self.total_requests as f64 / elapsed.as_secs_f64(),
+        }
+    }
+}
+
+// Sends one request and returns the status code of the response.
+fn send_one(addr: &str, request: &str) -> std::io::Result<u16> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    parse_status(&response).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed status line")
+    })
+}
+
+// "HTTP/1.1 200 OK" => 200
+fn parse_status(response: &[u8]) -> Option<u16> {
+    let line_end = response.iter().position(|&b| b == b'\r' || b == b'\n')?;
+    let line = std::str::from_utf8(&response[..line_end]).ok()?;
+    let mut parts = line.split(' ');
+
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+// Nearest-rank percentile over already-sorted samples.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_get_a_default_port() {
+        let test = LoadTest::new("http://127.0.0.1:1998/sleep", 1, 1).unwrap();
+        assert_eq!((test.addr.as_str(), test.path.as_str()), ("127.0.0.1:1998", "/sleep"));
+        assert_eq!(LoadTest::new("localhost", 1, 1).unwrap().addr, "localhost:80");
+        assert_eq!(LoadTest::new("http://[::1]/", 1, 1).unwrap().addr, "[::1]:80");
+        assert_eq!(LoadTest::new("http://[::1]:8080/", 1, 1).unwrap().addr, "[::1]:8080");
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        assert_eq!(LoadTest::new("http://127.0.0.1/", 0, 10).err(), Some(LoadTestError::ZeroConcurrency));
+        assert!(matches!(LoadTest::new("http:///path", 1, 1), Err(LoadTestError::InvalidUrl(_))));
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let samples: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&samples, 50.0), Duration::from_millis(5));
+        assert_eq!(percentile(&samples, 99.0), Duration::from_millis(10));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+        assert_eq!(parse_status(b"HTTP/1.1 404 Not Found\r\n"), Some(404));
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/* # Reading TOML, the little of it config files here need!
+# a comment
+name = "surff"               # string keys and values; "quoted keys" too
+[[route]]                    # an array of tables: one more entry each time
+path = '/'                   # 'literal strings', without escapes
+port = 8080                  # integers, and booleans (true/false)
+What's in: bare and quoted keys, "basic strings" (with \" \\ \n \t \r \uXXXX escapes),
+'literal strings', integers, booleans, comments, and [[array]] tables. Anything else
+TOML has (plain [tables], dotted keys, arrays, inline tables, floats, dates,
+multi-line strings) is an error naming the line, rather than something misread.
+A key may only appear once per table. */
+
+pub(crate) type Table = BTreeMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+impl Value {
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// One [[name]] entry, and the line its header is on, for errors about what's in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Section {
+    pub(crate) line: usize,
+    pub(crate) keys: Table,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Document {
+    // the keys before the first [[table]].
+    pub(crate) root: Table,
+    pub(crate) arrays: BTreeMap<String, Vec<Section>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TomlError {
+    // from 1.
+    pub(crate) line: usize,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for TomlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for TomlError {}
+
+pub(crate) fn parse(text: &str) -> Result<Document, TomlError> {
+    let mut document = Document::default();
+    // the array whose last entry the keys go into; None: the root.
+    let mut current: Option<String> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| TomlError { line: index + 1, message };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix("[[") {
+            let (name, rest) = header.split_once("]]").ok_or_else(|| error("unclosed [[".to_string()))?;
+            let name = name.trim();
+            if !is_bare_key(name) {
+                return Err(error(format!("bad table name {:?}", name)));
+            }
+            expect_end(rest).map_err(error)?;
+            let section = Section { line: index + 1, keys: Table::new() };
+            document.arrays.entry(name.to_string()).or_default().push(section);
+            current = Some(name.to_string());
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(error("only [[array]] tables are supported".to_string()));
+        }
+
+        let (key, rest) = key(line).map_err(error)?;
+        let rest = rest.trim_start().strip_prefix('=').ok_or_else(|| error(format!("expected = after {:?}", key)))?;
+        let (value, rest) = value(rest.trim_start()).map_err(error)?;
+        expect_end(rest).map_err(error)?;
+
+        let table = match &current {
+            Some(name) => &mut document.arrays.get_mut(name).and_then(|sections| sections.last_mut()).expect("opened above").keys,
+            None => &mut document.root,
+        };
+        if table.contains_key(&key) {
+            return Err(error(format!("duplicate key {:?}", key)));
+        }
+        table.insert(key, value);
+    }
+
+    Ok(document)
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty() && key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+// Only whitespace or a comment may follow.
+fn expect_end(rest: &str) -> Result<(), String> {
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected {:?}", rest))
+    }
+}
+
+// The key at the start of `s`, and what follows it.
+fn key(s: &str) -> Result<(String, &str), String> {
+    if let Some(quoted) = s.strip_prefix('"') {
+        return basic_string(quoted);
+    }
+    if let Some(quoted) = s.strip_prefix('\'') {
+        return literal_string(quoted);
+    }
+    let end = s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-')).unwrap_or(s.len());
+    let (key, rest) = s.split_at(end);
+    if key.is_empty() {
+        return Err(format!("expected a key, found {:?}", s));
+    }
+    if rest.trim_start().starts_with('.') {
+        return Err("dotted keys are not supported".to_string());
+    }
+    Ok((key.to_string(), rest))
+}
+
+fn value(s: &str) -> Result<(Value, &str), String> {
+    if s.starts_with("\"\"\"") || s.starts_with("'''") {
+        return Err("multi-line strings are not supported".to_string());
+    }
+    if let Some(quoted) = s.strip_prefix('"') {
+        return basic_string(quoted).map(|(value, rest)| (Value::String(value), rest));
+    }
+    if let Some(quoted) = s.strip_prefix('\'') {
+        return literal_string(quoted).map(|(value, rest)| (Value::String(value), rest));
+    }
+    if s.starts_with(['[', '{']) {
+        return Err("arrays and inline tables are not supported".to_string());
+    }
+
+    let end = s.find(|c: char| c.is_whitespace() || c == '#').unwrap_or(s.len());
+    let (token, rest) = s.split_at(end);
+    let value = match token {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        "" => return Err("missing value".to_string()),
+        _ => {
+            // 1_000 is 1000; the _ has to be between digits.
+            let digits = token.strip_prefix(['+', '-']).unwrap_or(token);
+            let well_placed = !digits.starts_with('_') && !digits.ends_with('_') && !digits.contains("__");
+            if digits.is_empty() || !well_placed || !digits.bytes().all(|b| b.is_ascii_digit() || b == b'_') {
+                return Err(format!("unsupported value {:?}", token));
+            }
+            let number = token.replace('_', "").parse().map_err(|_| format!("{} is out of range", token))?;
+            Value::Integer(number)
+        }
+    };
+    Ok((value, rest))
+}
+
+// After the opening ". Returns the string and what follows the closing ".
+fn basic_string(s: &str) -> Result<(String, &str), String> {
+    let mut string = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((string, &s[i + 1..])),
+            '\\' => {
+                let escaped = match chars.next().map(|(_, c)| c) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        let code = (hex.len() == 4).then(|| u32::from_str_radix(&hex, 16).ok()).flatten();
+                        code.and_then(char::from_u32).ok_or_else(|| format!("bad escape \\u{}", hex))?
+                    }
+                    Some(other) => return Err(format!("bad escape \\{}", other)),
+                    None => break,
+                };
+                string.push(escaped);
+            }
+            c if c.is_control() && c != '\t' => return Err("control character in a string".to_string()),
+            c => string.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+// After the opening ': everything up to the next ', as it is.
+fn literal_string(s: &str) -> Result<(String, &str), String> {
+    let end = s.find('\'').ok_or("unterminated string")?;
+    Ok((s[..end].to_string(), &s[end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+
+    #[test]
+    fn parses_keys_strings_and_array_tables() {
+        let document = parse(
+            "# routes\n\
+             name = \"surff\"   # trailing comment\n\
+             \"quoted key\" = 'C:\\raw'\n\
+             \n\
+             [[route]]\n\
+             path = \"/a \\\"b\\\"\\u00e9\\n\"\n\
+             port = 1_998\n\
+             [[ route ]]  # another\n\
+             enabled = false\n\
+             offset = -3\n",
+        )
+        .unwrap();
+
+        assert_eq!(document.root["name"], string("surff"));
+        assert_eq!(document.root["quoted key"], string("C:\\raw"));
+        let routes = &document.arrays["route"];
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].line, 5);
+        assert_eq!(routes[0].keys["path"].as_str(), Some("/a \"b\"é\n"));
+        assert_eq!(routes[0].keys["port"], Value::Integer(1998));
+        assert_eq!(routes[1].keys["enabled"], Value::Boolean(false));
+        assert_eq!(routes[1].keys["offset"], Value::Integer(-3));
+        assert_eq!(routes[1].keys["offset"].as_str(), None);
+    }
+
+    #[test]
+    fn what_it_doesnt_know_is_an_error_with_its_line() {
+        let cases = [
+            ("a = 1\nb = \"open\n", 2, "unterminated string"),
+            ("a = 1\na = 2\n", 2, "duplicate key \"a\""),
+            ("[server]\n", 1, "only [[array]] tables are supported"),
+            ("[[route]\n", 1, "unclosed [["),
+            ("\n\na.b = 1\n", 3, "dotted keys are not supported"),
+            ("a = [1, 2]\n", 1, "arrays and inline tables are not supported"),
+            ("a = 1.5\n", 1, "unsupported value \"1.5\""),
+            ("a = 1_\n", 1, "unsupported value \"1_\""),
+            ("a = \"\"\"\n", 1, "multi-line strings are not supported"),
+            ("a = \"x\" b\n", 1, "unexpected \"b\""),
+            ("a = \"\\q\"\n", 1, "bad escape \\q"),
+            ("a =\n", 1, "missing value"),
+            ("= 1\n", 1, "expected a key, found \"= 1\""),
+            ("a 1\n", 1, "expected = after \"a\""),
+            ("a = 99999999999999999999\n", 1, "99999999999999999999 is out of range"),
+        ];
+        for (text, line, message) in cases {
+            assert_eq!(parse(text), Err(TomlError { line, message: message.to_string() }), "{:?}", text);
+        }
+    }
+}
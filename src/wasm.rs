@@ -0,0 +1,169 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::{job, panic_message, CancellationToken, ExecuteError, JobHandle, TimedResult, PoolMetrics, PoolStats, ThreadPoolBuilder, ThreadPoolError};
+
+/* # ThreadPool on WebAssembly!
+Server-side WASM runtimes (Wasmtime, Wasmer) don't give us std::thread,
+so on wasm32 the "pool" runs every job synchronously and in order,
+on the caller's thread, as soon as it's passed to .execute.
+Builder thread names, stack sizes, queue capacities and shutdown timeouts don't apply
+(nothing ever waits in a queue, so .execute never returns QueueFull); the start/stop hooks
+and worker_init run once, when the pool is built and when it's dropped.
+The API matches the threaded pool, so code that takes a ThreadPool
+compiles unchanged. The caller's thread counts as the pool's only worker in the stats.
+A panicking job is caught, like on a worker thread, so the stats still see it finish
+and the caller carries on; worker_init runs again to replace the state it was using,
+the way a restarted worker would get a fresh one.
+Job latency isn't measured: std::time::Instant panics on wasm32-unknown-unknown.
+For the same reason (and the lack of a watchdog thread) execute_timeout can't enforce
+its deadline: the token is never cancelled and jobs always report Completed. */
+
+pub struct ThreadPool {
+    stats: PoolStats,
+    config: ThreadPoolBuilder,
+    // worker_init's result for the only "worker".
+    state: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl ThreadPool {
+    // Beyond rejecting zero (like the threaded pool), size is ignored:
+    // there is only ever the caller's thread.
+    pub fn new(size: usize) -> Result<ThreadPool, ThreadPoolError> {
+        ThreadPoolBuilder::new().build(size)
+    }
+
+    pub fn with_queue(size: usize, capacity: usize) -> Result<ThreadPool, ThreadPoolError> {
+        ThreadPoolBuilder::new().queue_capacity(capacity).build(size)
+    }
+
+    pub(crate) fn from_builder(config: ThreadPoolBuilder, size: usize) -> Result<ThreadPool, ThreadPoolError> {
+        if size == 0 {
+            return Err(ThreadPoolError::ZeroSize);
+        }
+
+        if let Some(on_start) = &config.on_thread_start {
+            on_start();
+        }
+
+        let state = config.worker_init.as_ref().map(|init| init());
+
+        Ok(ThreadPool {
+            stats: PoolStats::new(1),
+            config,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+        where
+            F: FnOnce() + Send + 'static
+    {
+        self.run_job(f);
+        Ok(())
+    }
+
+    // The job has already run by the time the handle is returned.
+    pub fn execute_with_handle<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
+        where
+            F: FnOnce() -> T + Send + 'static,
+            T: Send + 'static
+    {
+        let (job, handle) = job::with_handle(f);
+        self.execute(job)?;
+        Ok(handle)
+    }
+
+    pub fn execute_timeout<F>(&self, f: F, _timeout: Duration) -> Result<JobHandle<TimedResult<()>>, ExecuteError>
+        where
+            F: FnOnce(CancellationToken) + Send + 'static
+    {
+        self.execute_with_handle(move || {
+            f(CancellationToken::new());
+            TimedResult::Completed(())
+        })
+    }
+
+    pub fn execute_with_state<S, F>(&self, f: F) -> Result<(), ExecuteError>
+        where
+            S: Send + 'static,
+            F: FnOnce(&mut S) + Send + 'static
+    {
+        let mut slot = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = slot
+            .as_mut()
+            .and_then(|state| state.downcast_mut::<S>())
+            .unwrap_or_else(|| panic!("execute_with_state: no worker state of type {}", std::any::type_name::<S>()));
+        if !self.run_job(|| f(state)) {
+            // the job may have left it half-updated.
+            *slot = self.config.worker_init.as_ref().map(|init| init());
+        }
+        Ok(())
+    }
+
+    // The same bookkeeping as a worker thread, panic or not. false if the job panicked.
+    fn run_job(&self, job: impl FnOnce()) -> bool {
+        self.stats.job_queued();
+        let busy = self.stats.job_started(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+        self.stats.job_finished(0, Duration::ZERO);
+        drop(busy);
+
+        match result {
+            Ok(()) => true,
+            Err(payload) => {
+                eprintln!("A job panicked: {}", panic_message(&*payload));
+                false
+            },
+        }
+    }
+
+    pub fn execute_or_panic<F>(&self, f: F)
+        where
+            F: FnOnce() + Send + 'static
+    {
+        // can't fail here.
+        let _ = self.execute(f);
+    }
+
+    // There's nothing to grow or shrink; only zero is rejected.
+    pub fn resize(&self, new_size: usize) -> Result<(), ThreadPoolError> {
+        if new_size == 0 {
+            return Err(ThreadPoolError::ZeroSize);
+        }
+        Ok(())
+    }
+
+    // Every job has already run by the time execute returns.
+    pub fn drain(&self, _timeout: Duration) -> bool {
+        true
+    }
+
+    // Every job has already run, so there's never a worker to detach.
+    pub fn shutdown_timeout(self, _timeout: Duration) -> Vec<usize> {
+        Vec::new()
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.stats.clone()
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        self.stats.metrics()
+    }
+
+    pub fn utilization(&self) -> f64 {
+        self.stats.utilization()
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.state.lock().unwrap_or_else(PoisonError::into_inner).take());
+        if let Some(on_stop) = &self.config.on_thread_stop {
+            on_stop();
+        }
+    }
+}
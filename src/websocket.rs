@@ -0,0 +1,392 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, prelude::*, BufReader};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::base64;
+use crate::client::{self, ClientError, Url};
+use crate::crypto::sha1;
+
+/* # WebSocket (RFC 6455): frames, and a client to talk to an upstream with!
+let client = WsClient::connect("ws://127.0.0.1:9000/chat", &[])?;
+client.send(&WsFrame::text("hello"))?;
+let answer = client.recv()?;  // a WsFrame, Text or Binary or Close or Ping or Pong
+1. The handshake is an HTTP/1.1 GET with Upgrade: websocket, Connection: Upgrade,
+Sec-WebSocket-Version: 13 and a Sec-WebSocket-Key of 16 random bytes (base64). The server
+must answer 101, with Sec-WebSocket-Accept the base64 of the SHA-1 of the key followed
+by the protocol's GUID (accept_key): anything else fails connect.
+2. A frame is a FIN bit and an opcode, the payload length (7 bits, 16 or 64), and when
+masked, a 4-byte key the payload is XORed with. Clients must mask every frame they send,
+servers must never mask theirs (RFC 6455, 5.1): recv refuses a masked frame from the
+server, WsFrame::read_from(reader, true) an unmasked one from a client.
+3. Control frames (Close, Ping, Pong) carry at most 125 bytes and can't be fragmented.
+Nothing answers them by itself: what comes in is the caller's, fragments and pings too,
+which is what a proxy passing frames along wants (see proxy.rs).
+4. Payloads over 16 MiB are an InvalidData error rather than an allocation, and
+extensions (the RSV bits, permessage-deflate) aren't supported.
+5. send and recv take &self and lock only their own half of the connection, so one
+thread can send while another waits in recv. */
+
+pub const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_PAYLOAD: u64 = 16 * 1024 * 1024;
+const MAX_CONTROL_PAYLOAD: usize = 125;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Option<Opcode> {
+        match bits {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xa => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xa,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsFrame {
+    // false for every fragment of a message but its last.
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("WebSocket: {}", message))
+}
+
+impl WsFrame {
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> WsFrame {
+        WsFrame { fin: true, opcode, payload }
+    }
+
+    pub fn text(text: &str) -> WsFrame {
+        WsFrame::new(Opcode::Text, text.as_bytes().to_vec())
+    }
+
+    pub fn binary(data: Vec<u8>) -> WsFrame {
+        WsFrame::new(Opcode::Binary, data)
+    }
+
+    // 1000 is a normal close (RFC 6455, 7.4.1).
+    pub fn close(code: u16, reason: &str) -> WsFrame {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        WsFrame::new(Opcode::Close, payload)
+    }
+
+    // Masked with `mask` if there is one: a client's frames have to be.
+    pub fn write_to(&self, writer: &mut impl Write, mask: Option<[u8; 4]>) -> io::Result<()> {
+        if self.opcode.is_control() && (self.payload.len() > MAX_CONTROL_PAYLOAD || !self.fin) {
+            return Err(invalid("control frames are at most 125 bytes, unfragmented"));
+        }
+        let mut frame = vec![(self.fin as u8) << 7 | self.opcode.bits()];
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            length if length <= MAX_CONTROL_PAYLOAD => frame.push(mask_bit | length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(mask) => {
+                frame.extend_from_slice(&mask);
+                frame.extend(self.payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+            }
+            None => frame.extend_from_slice(&self.payload),
+        }
+        writer.write_all(&frame)?;
+        writer.flush()
+    }
+
+    // `masked`: whether the frame must be masked (it's from a client) or must not be.
+    pub fn read_from(reader: &mut impl Read, masked: bool) -> io::Result<WsFrame> {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head)?;
+        if head[0] & 0x70 != 0 {
+            return Err(invalid("RSV bits set without an extension"));
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = Opcode::from_bits(head[0] & 0x0f).ok_or_else(|| invalid("unknown opcode"))?;
+        if (head[1] & 0x80 != 0) != masked {
+            return Err(invalid(if masked { "client frame not masked" } else { "server frame masked" }));
+        }
+        let length = match head[1] & 0x7f {
+            126 => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0; 8];
+                reader.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+        if opcode.is_control() && (length > MAX_CONTROL_PAYLOAD as u64 || !fin) {
+            return Err(invalid("control frames are at most 125 bytes, unfragmented"));
+        }
+        if length > MAX_PAYLOAD {
+            return Err(invalid("frame too large"));
+        }
+        let mut mask = [0; 4];
+        if masked {
+            reader.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+        if masked {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        Ok(WsFrame { fin, opcode, payload })
+    }
+}
+
+// What a server answers to a Sec-WebSocket-Key with.
+pub fn accept_key(key: &str) -> String {
+    base64::encode(&sha1(format!("{}{}", key.trim(), GUID).as_bytes()))
+}
+
+// No rand crate: RandomState, as for multipart's boundaries. Masks only have to be
+// unpredictable to scripts in a browser, and keys only unique.
+fn random_bytes<const N: usize>() -> [u8; N] {
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+    let mut bytes = [0; N];
+    for (i, block) in bytes.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(CALLS.fetch_add(1, Ordering::Relaxed));
+        hasher.write_usize(i);
+        block.copy_from_slice(&hasher.finish().to_be_bytes()[..block.len()]);
+    }
+    bytes
+}
+
+pub struct WsClient {
+    // the 101's headers: Sec-WebSocket-Protocol, say.
+    pub headers: Vec<(String, String)>,
+    writer: Mutex<TcpStream>,
+    reader: Mutex<BufReader<TcpStream>>,
+}
+
+impl std::fmt::Debug for WsClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let peer = self.lock_writer().peer_addr().ok();
+        f.debug_struct("WsClient").field("peer", &peer).finish()
+    }
+}
+
+impl WsClient {
+    // `headers` go with the handshake, after the ones it needs (Origin, cookies, ...).
+    pub fn connect(url: &str, headers: &[(String, String)]) -> Result<WsClient, ClientError> {
+        let http = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("ws") => format!("http://{}", rest),
+            Some((scheme, _)) => return Err(ClientError::UnsupportedScheme(scheme.to_string())),
+            None => return Err(ClientError::InvalidUrl(url.to_string())),
+        };
+        let url = Url::parse(&http)?;
+        let mut stream = client::connect(&url, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(client::DEFAULT_TIMEOUT))?;
+        stream.set_write_timeout(Some(client::DEFAULT_TIMEOUT))?;
+
+        let key = base64::encode(&random_bytes::<16>());
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+            url.path, url.host_header(), key,
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+
+        // frames may follow the head at once: the reader keeps what it read past it.
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let response = client::parse_response(&client::read_head(&mut reader)?, true)?;
+        if response.status != 101 {
+            return Err(ClientError::InvalidResponse(format!("status {} instead of 101", response.status)));
+        }
+        if !response.header("Upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket")) {
+            return Err(ClientError::InvalidResponse("101 without Upgrade: websocket".to_string()));
+        }
+        if response.header("Sec-WebSocket-Accept").map(str::trim) != Some(accept_key(&key).as_str()) {
+            return Err(ClientError::InvalidResponse("wrong Sec-WebSocket-Accept".to_string()));
+        }
+
+        // a WebSocket can be quiet for as long as its ends like.
+        stream.set_read_timeout(None)?;
+        Ok(WsClient { headers: response.headers, writer: Mutex::new(stream), reader: Mutex::new(reader) })
+    }
+
+    // Masked, with a new key for every frame.
+    pub fn send(&self, frame: &WsFrame) -> io::Result<()> {
+        frame.write_to(&mut *self.lock_writer(), Some(random_bytes::<4>()))
+    }
+
+    pub fn recv(&self) -> io::Result<WsFrame> {
+        WsFrame::read_from(&mut *self.reader.lock().unwrap_or_else(PoisonError::into_inner), false)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.lock_writer().set_read_timeout(timeout)
+    }
+
+    // Both ways at once: a thread waiting in recv gets an error, or the end of the stream.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.lock_writer().shutdown(Shutdown::Both)
+    }
+
+    fn lock_writer(&self) -> std::sync::MutexGuard<'_, TcpStream> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// A WebSocket server for tests: every data frame sent back as it came, till a Close,
+// which is answered with one.
+#[cfg(test)]
+pub(crate) struct WsEcho;
+
+#[cfg(test)]
+impl crate::router::UpgradeHandler for WsEcho {
+    fn response_headers(&self, request: &crate::http::Request) -> Vec<(String, String)> {
+        let key = request.header("Sec-WebSocket-Key").unwrap_or("");
+        vec![("Sec-WebSocket-Accept".to_string(), accept_key(key))]
+    }
+
+    fn on_upgrade(&self, _request: &crate::http::Request, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        loop {
+            let frame = WsFrame::read_from(&mut reader, true)?;
+            frame.write_to(&mut writer, None)?;
+            if frame.opcode == Opcode::Close {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{self, Router};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn frames_round_trip_masked_or_not() {
+        // RFC 6455, 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        // RFC 6455, 5.7: "Hello", unmasked and masked.
+        let hello = WsFrame::text("Hello");
+        let mut bytes = Vec::new();
+        hello.write_to(&mut bytes, None).unwrap();
+        assert_eq!(bytes, [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+        assert_eq!(WsFrame::read_from(&mut &bytes[..], false).unwrap(), hello);
+        assert!(WsFrame::read_from(&mut &bytes[..], true).is_err());
+        let mut masked = Vec::new();
+        hello.write_to(&mut masked, Some([0x37, 0xfa, 0x21, 0x3d])).unwrap();
+        assert_eq!(masked, [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+        assert_eq!(WsFrame::read_from(&mut &masked[..], true).unwrap(), hello);
+        assert!(WsFrame::read_from(&mut &masked[..], false).is_err());
+
+        // 16- and 64-bit lengths.
+        for length in [126, 65_536] {
+            let frame = WsFrame { fin: false, opcode: Opcode::Binary, payload: vec![7; length] };
+            let mut bytes = Vec::new();
+            frame.write_to(&mut bytes, Some(random_bytes())).unwrap();
+            assert_eq!(WsFrame::read_from(&mut &bytes[..], true).unwrap(), frame);
+        }
+        assert!(WsFrame::new(Opcode::Ping, vec![0; 126]).write_to(&mut Vec::new(), None).is_err());
+        assert!(WsFrame::read_from(&mut &[0x83, 0x00][..], false).is_err());
+    }
+
+    // One connection, served by `router` the way the server would.
+    fn serve_once(router: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let head = client::read_head(&mut BufReader::new(stream.try_clone().unwrap())).unwrap();
+            let _ = router.dispatch(&crate::http::Request::parse(&head).unwrap(), &mut stream);
+        });
+        format!("ws://{}/echo", addr)
+    }
+
+    #[test]
+    fn clients_talk_to_the_upgrade_handler() {
+        let mut router = Router::new();
+        router.on_upgrade("websocket", WsEcho);
+        let client = WsClient::connect(&serve_once(router), &[]).unwrap();
+        client.send(&WsFrame::text("hello")).unwrap();
+        client.send(&WsFrame::binary(vec![1, 2, 3])).unwrap();
+        assert_eq!(client.recv().unwrap(), WsFrame::text("hello"));
+        assert_eq!(client.recv().unwrap(), WsFrame::binary(vec![1, 2, 3]));
+        client.send(&WsFrame::close(1000, "bye")).unwrap();
+        assert_eq!(client.recv().unwrap(), WsFrame::close(1000, "bye"));
+    }
+
+    #[test]
+    fn handshakes_are_checked() {
+        struct WrongAccept;
+        impl router::UpgradeHandler for WrongAccept {
+            fn response_headers(&self, _request: &crate::http::Request) -> Vec<(String, String)> {
+                vec![("Sec-WebSocket-Accept".to_string(), accept_key("another key"))]
+            }
+            fn on_upgrade(&self, _request: &crate::http::Request, _stream: TcpStream) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut router = Router::new();
+        router.on_upgrade("websocket", WrongAccept);
+        let error = WsClient::connect(&serve_once(router), &[]).unwrap_err();
+        assert!(error.to_string().contains("Sec-WebSocket-Accept"), "{}", error);
+
+        // no upgrade handler: the route's answer.
+        let mut router = Router::new();
+        router.get("/echo", |_, response| response.send(&mut crate::http::ResponseBuilder::new(crate::http::StatusCode::Ok)));
+        let error = WsClient::connect(&serve_once(router), &[]).unwrap_err();
+        assert!(error.to_string().contains("status 200"), "{}", error);
+        assert!(matches!(WsClient::connect("wss://example.com/", &[]), Err(ClientError::UnsupportedScheme(_))));
+    }
+}